@@ -0,0 +1,236 @@
+//! Build script helpers for applications whose migrations are managed by migr.
+//!
+//! Calling [emit_version] from `build.rs` exposes the migrations the application is built with as
+//! constants, so it can report the schema version it expects and compare it with the database:
+//!
+//! ```ignore
+//! // build.rs
+//! fn main() {
+//!     migr_build::emit_version().unwrap();
+//! }
+//!
+//! // src/main.rs
+//! include!(concat!(env!("OUT_DIR"), "/migr_version.rs"));
+//! ```
+
+use serde::Deserialize;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The name of the file written to `OUT_DIR`.
+const OUT_FILE: &str = "migr_version.rs";
+
+/// The name of the config file migr reads by default.
+const CONFIG_FILE: &str = "migr.toml";
+
+/// The entries of the migr config deciding which entries of the migrations directory are
+/// migrations. Anything else in the config is not of interest here.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Config {
+    ignore: Vec<String>,
+}
+
+/// Writes `migr_version.rs` to `OUT_DIR` for the `migrations` directory of the crate being built.
+/// See [emit_version_from].
+pub fn emit_version() -> io::Result<()> {
+    let root = env::var_os("CARGO_MANIFEST_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_default();
+    emit_version_from(root.join("migrations"))
+}
+
+/// Writes `migr_version.rs` to `OUT_DIR` for the migrations at `path`, defining
+///
+/// - `MIGRATIONS_LATEST_VERSION`, the version of the latest migration or an empty string if there
+///   are none,
+/// - `MIGRATIONS`, the IDs of the migrations from oldest to latest, including the ones moved into
+///   archives by `migr archive`.
+///
+/// The latest version is also set as the `MIGRATIONS_LATEST_VERSION` environment variable of the
+/// crate, for use with `env!`. Entries matching `.migrignore` or the `ignore` entry of the config
+/// are skipped like migr does, the config being read from `MIGR_CONFIG` or `migr.toml` next to
+/// `Cargo.toml`. The build script is rerun whenever the migrations or the config change.
+pub fn emit_version_from(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+
+    let out = env::var_os("OUT_DIR").ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "`OUT_DIR` is not set, `emit_version` must be called from a build script",
+        )
+    })?;
+
+    let config = config_path();
+    let ids = migrations(path, &load(&config)?)?;
+    let latest = ids
+        .last()
+        .map(|id| {
+            id.split_once('_')
+                .map_or(id.as_str(), |(version, _)| version)
+        })
+        .unwrap_or_default();
+
+    let mut constants = String::new();
+    let _ = writeln!(
+        constants,
+        "/// The version of the latest migration, or an empty string if there are no migrations."
+    );
+    let _ = writeln!(
+        constants,
+        "pub const MIGRATIONS_LATEST_VERSION: &str = {latest:?};"
+    );
+    let _ = writeln!(
+        constants,
+        "/// The IDs of the migrations from oldest to latest."
+    );
+    let _ = writeln!(constants, "pub const MIGRATIONS: &[&str] = &{ids:?};");
+
+    fs::write(Path::new(&out).join(OUT_FILE), constants)?;
+
+    for (archive, manifest) in unlisted(path) {
+        println!(
+            "cargo:warning=The archive {} has no manifest, so its migrations are missing from `MIGRATIONS`. List their IDs in {}",
+            archive.display(),
+            manifest.display()
+        );
+    }
+
+    println!("cargo:rerun-if-changed={}", path.display());
+    println!("cargo:rerun-if-changed={}", config.display());
+    println!("cargo:rerun-if-env-changed=MIGR_CONFIG");
+    println!("cargo:rustc-env=MIGRATIONS_LATEST_VERSION={latest}");
+
+    Ok(())
+}
+
+/// Returns the path of the migr config, relative paths in `MIGR_CONFIG` being resolved against
+/// the directory of `Cargo.toml` like the other paths of the crate.
+fn config_path() -> PathBuf {
+    let root = env::var_os("CARGO_MANIFEST_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_default();
+    root.join(env::var_os("MIGR_CONFIG").unwrap_or_else(|| CONFIG_FILE.into()))
+}
+
+/// Reads the config at `path`, which may not exist.
+fn load(path: &Path) -> io::Result<Config> {
+    match fs::read_to_string(path) {
+        Ok(contents) => toml::from_str(&contents).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid config {}: {e}", path.display()),
+            )
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns the sorted IDs of the migration directories at `path` and of the migrations `migr
+/// archive` moved into archives, which are listed by the manifests next to them. Entries matching
+/// `.migrignore` or the config, the directories reserved by migr and directories without a
+/// timestamp prefix are skipped like migr does.
+fn migrations(path: &Path, config: &Config) -> io::Result<Vec<String>> {
+    let mut patterns = config.ignore.clone();
+    patterns.extend(migr_common::ignore_file(path)?);
+
+    let mut ids = migr_common::archived(path)?;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if !entry.path().is_dir() {
+            continue;
+        }
+        let Ok(id) = entry.file_name().into_string() else {
+            continue;
+        };
+        ids.push(id);
+    }
+    ids.retain(|id| !migr_common::is_ignored(&patterns, id) && migr_common::has_timestamp(id));
+    // Migrations copied back from an archive are listed twice
+    ids.sort();
+    ids.dedup();
+
+    Ok(ids)
+}
+
+/// Returns the archives at `path` without a manifest, e.g. ones created by versions of migr which
+/// did not write them, with the path of their missing manifest.
+fn unlisted(path: &Path) -> Vec<(PathBuf, PathBuf)> {
+    let Ok(entries) = fs::read_dir(path.join(migr_common::ARCHIVE_DIR)) else {
+        return vec![];
+    };
+    entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter_map(|archive| {
+            let name = archive.file_name()?.to_str()?;
+            let manifest = format!(
+                "{}{}",
+                name.strip_suffix(".tar.gz")?,
+                migr_common::MANIFEST_EXTENSION
+            );
+            let manifest = archive.with_file_name(manifest);
+            (!manifest.exists()).then_some((archive, manifest))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn config_and_reserved_directories_are_skipped() {
+        let path = env::temp_dir().join(format!("migr-build-{}", process::id()));
+        for dir in [
+            "2024-01-01-000000_users",
+            "2024-01-02-000000_draft",
+            "2024-01-03-000000_posts",
+            "functions",
+            "seeds",
+        ] {
+            fs::create_dir_all(path.join(dir)).unwrap();
+        }
+        fs::write(path.join(".migrignore"), "*_posts\n").unwrap();
+
+        let config = Config {
+            ignore: vec![String::from("*_draft")],
+        };
+        let ids = migrations(&path, &config).unwrap();
+        fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(ids, ["2024-01-01-000000_users"]);
+    }
+
+    #[test]
+    fn archived_migrations_are_listed() {
+        let path = env::temp_dir().join(format!("migr-build-archive-{}", process::id()));
+        for dir in ["2024-01-01-000000_users", "2024-02-01-000000_posts"] {
+            fs::create_dir_all(path.join(dir)).unwrap();
+        }
+        fs::create_dir_all(path.join("archive")).unwrap();
+        // Only the manifest is read, the archive itself is not extracted
+        fs::write(path.join("archive").join("2024-01-15.tar.gz"), "").unwrap();
+        fs::write(
+            path.join("archive").join("2024-01-15.ids"),
+            "2023-12-01-000000_accounts\n2024-01-01-000000_users\n",
+        )
+        .unwrap();
+
+        let ids = migrations(&path, &Config::default()).unwrap();
+        fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(
+            ids,
+            [
+                "2023-12-01-000000_accounts",
+                "2024-01-01-000000_users",
+                "2024-02-01-000000_posts"
+            ]
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! The rules deciding which entries of a migrations directory are migrations, shared by migr and
+//! migr_build so the migrations embedded into applications are the ones the CLI runs.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The name of the ignore file, located in the migrations directory.
+pub const IGNORE_FILE: &str = ".migrignore";
+
+/// The directory in the migrations directory holding the repeatable definitions of functions and
+/// triggers.
+pub const FUNCTIONS_DIR: &str = "functions";
+
+/// The directory in the migrations directory holding the snippets `gen --snippet` expands.
+pub const SNIPPETS_DIR: &str = "snippets";
+
+/// The directory in the migrations directory holding the archives of old migrations.
+pub const ARCHIVE_DIR: &str = "archive";
+
+/// The extension of the manifests listing the IDs of the migrations in the archive of the same
+/// name, e.g. `2023-01-01.ids` for `2023-01-01.tar.gz`, so they can be read without `tar`.
+pub const MANIFEST_EXTENSION: &str = ".ids";
+
+/// The directory in the migrations directory holding the scripts scrubbing production data from
+/// copies of it.
+pub const SCRUB_DIR: &str = "scrub";
+
+/// Directories in the migrations directory holding files other than migrations.
+pub const RESERVED: &[&str] = &[FUNCTIONS_DIR, SNIPPETS_DIR, ARCHIVE_DIR, SCRUB_DIR];
+
+/// Returns the patterns of the ignore file in the migrations directory at `path`. The file contains
+/// a pattern per line, lines starting with `#` are comments.
+pub fn ignore_file(path: &Path) -> io::Result<Vec<String>> {
+    match fs::read_to_string(path.join(IGNORE_FILE)) {
+        Ok(contents) => Ok(contents
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| l.trim_end_matches('/').to_string())
+            .collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns true if the entry `name` matches any of the patterns or is [RESERVED].
+pub fn is_ignored(patterns: &[String], name: &str) -> bool {
+    RESERVED.contains(&name) || patterns.iter().any(|p| glob(p.as_bytes(), name.as_bytes()))
+}
+
+/// Matches `name` against a pattern where `*` matches any sequence of characters and `?` matches a
+/// single character.
+pub fn glob(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob(&pattern[1..], name) || (!name.is_empty() && glob(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Returns the IDs listed by the manifests of the archives in the migrations directory at `path`,
+/// one per line.
+pub fn archived(path: &Path) -> io::Result<Vec<String>> {
+    let entries = match fs::read_dir(path.join(ARCHIVE_DIR)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let mut ids = vec![];
+    for entry in entries {
+        let file = entry?.path();
+        if !file.to_string_lossy().ends_with(MANIFEST_EXTENSION) {
+            continue;
+        }
+        let contents = fs::read_to_string(&file)?;
+        ids.extend(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty())
+                .map(String::from),
+        );
+    }
+
+    Ok(ids)
+}
+
+/// Returns true if the directory name starts with a timestamp prefix, e.g. `2023-09-24-152246_`.
+pub fn has_timestamp(name: &str) -> bool {
+    name.split_once('_').is_some_and(|(ts, _)| {
+        ts.starts_with(|c: char| c.is_ascii_digit())
+            && ts.chars().all(|c| c.is_ascii_digit() || c == '-')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_matches_wildcards() {
+        assert!(glob(b"seed*", b"seeds"));
+        assert!(glob(b"*_draft", b"2024-01-01-000000_draft"));
+        assert!(glob(b"seed?", b"seeds"));
+        assert!(!glob(b"seed?", b"seed"));
+        assert!(!glob(b"seeds", b"seeds2"));
+    }
+
+    #[test]
+    fn reserved_directories_are_ignored() {
+        assert!(is_ignored(&[], "functions"));
+        assert!(is_ignored(&[String::from("tmp*")], "tmp_x"));
+        assert!(!is_ignored(&[], "2024-01-01-000000_a"));
+    }
+
+    #[test]
+    fn timestamps_prefix_the_name() {
+        assert!(has_timestamp("2023-09-24-152246_create_users"));
+        assert!(has_timestamp("0000000000_pg_migrator"));
+        assert!(!has_timestamp("seeds"));
+        assert!(!has_timestamp("v1_users"));
+    }
+}
@@ -0,0 +1,73 @@
+use crate::config::{AnalyzeMode, Config};
+use crate::sql;
+use crate::{debug, info, warn};
+use postgres::Client;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+
+/// Refreshes the planner statistics after the migrations with the given IDs were applied, as
+/// configured. Migrations are already committed at this point, so failures are only reported.
+pub fn analyze(path: &Path, applied: &[String], pg: &mut Client, config: &Config) {
+    if applied.is_empty() {
+        return;
+    }
+
+    let started = Instant::now();
+    let result = match config.analyze {
+        AnalyzeMode::Off => return,
+        AnalyzeMode::All => {
+            info!("Analyzing the database");
+            pg.batch_execute("ANALYZE").map(|_| 1)
+        }
+        AnalyzeMode::Touched => analyze_touched(path, applied, pg),
+    };
+
+    match result {
+        Ok(0) => debug!("No tables to analyze"),
+        Ok(_) => debug!("Analyzed in {:.2?}", started.elapsed()),
+        Err(e) => warn!("Unable to analyze: {e:#}\nHint: Statistics are refreshed by autovacuum eventually, or run `ANALYZE` manually"),
+    }
+}
+
+/// Analyzes the tables touched by the `up.sql` of the applied migrations which still exist.
+/// Returns the number of analyzed tables.
+fn analyze_touched(
+    path: &Path,
+    applied: &[String],
+    pg: &mut Client,
+) -> Result<usize, postgres::Error> {
+    let mut relations = BTreeSet::new();
+    for id in applied {
+        let Ok(sql) = fs::read_to_string(path.join(id).join("up.sql")) else {
+            continue;
+        };
+        for statement in sql::statements(&sql) {
+            relations.extend(sql::relations(&sql::normalize(&statement)).unwrap_or_default());
+        }
+    }
+
+    let mut analyzed = 0;
+    for relation in relations {
+        // Relations are resolved on the search path, and indexes or dropped tables are skipped
+        let table = match pg.query_opt(
+            "SELECT format('%I.%I', n.nspname, c.relname)
+             FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace
+             WHERE c.oid = to_regclass($1::TEXT) AND c.relkind IN ('r', 'p', 'm')",
+            &[&relation],
+        ) {
+            Ok(Some(row)) => row.get::<_, String>(0),
+            Ok(None) => continue,
+            Err(e) => {
+                debug!("Unable to resolve {relation}: {e}");
+                continue;
+            }
+        };
+        info!("Analyzing {table}");
+        pg.batch_execute(&format!("ANALYZE {table}"))?;
+        analyzed += 1;
+    }
+
+    Ok(analyzed)
+}
@@ -0,0 +1,198 @@
+use crate::cache;
+use crate::config::Config;
+use crate::migration::migration_dirs;
+use crate::{debug, info, summary, ArchiveArgs};
+use anyhow::{Context, Error};
+use postgres::Client;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+pub use migr_common::ARCHIVE_DIR;
+use migr_common::MANIFEST_EXTENSION;
+
+const EXTENSION: &str = ".tar.gz";
+
+/// Moves the applied migrations with a timestamp before `--before` into a compressed archive in
+/// [ARCHIVE_DIR], next to a manifest listing their IDs. The archived migrations are still read from
+/// the archive by every command.
+pub fn archive(
+    args: &ArchiveArgs,
+    path: &Path,
+    pg: &mut Client,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let before = args.before.as_str();
+    if !is_date(before) {
+        return Err(Error::msg(format!(
+            "Invalid date '{before}'\nHint: Pass a date like `2023-01-01`"
+        )));
+    }
+
+    let ids = migration_dirs(path, config)?
+        .into_iter()
+        // Migrations archived earlier are already extracted from their archives
+        .filter(|dir| dir.parent() == Some(path))
+        .filter_map(|dir| Some(dir.file_name()?.to_str()?.to_string()))
+        .filter(|id| id.split('_').next().is_some_and(|version| version < before))
+        .collect::<Vec<_>>();
+
+    if ids.is_empty() {
+        info!("No migrations before {before} to archive");
+        return Ok(());
+    }
+
+    let applied = pg
+        .query("SELECT id FROM __migr_meta__ WHERE NOT pending", &[])?
+        .into_iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<HashSet<_>>();
+    let pending = ids
+        .iter()
+        .filter(|id| !applied.contains(*id))
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    if !pending.is_empty() {
+        return Err(Error::msg(format!(
+            "Only applied migrations can be archived, these are pending:\n  {}\nHint: Run them first or pass an earlier date",
+            pending.join("\n  ")
+        )));
+    }
+
+    let dir = path.join(ARCHIVE_DIR);
+    let file = dir.join(format!("{before}{EXTENSION}"));
+    if file.exists() {
+        return Err(Error::msg(format!(
+            "The archive '{}' already exists\nHint: Pass a later date to archive the migrations applied since",
+            file.display()
+        )));
+    }
+    fs::create_dir_all(&dir).with_context(|| format!("Unable to create '{}'", dir.display()))?;
+
+    info!("Archiving {} migrations into {}", ids.len(), file.display());
+    let mut command = Command::new("tar");
+    command
+        .arg("-czf")
+        .arg(&file)
+        .arg("-C")
+        .arg(path)
+        .args(&ids);
+    if let Err(e) = tar(command) {
+        let _ = fs::remove_file(&file);
+        return Err(e);
+    }
+
+    // The migrations are only removed once they can be read back from the archive
+    let archived = extracted(path)?;
+    for id in &ids {
+        let readable = archived.iter().any(|dir| {
+            dir.file_name().is_some_and(|name| name == id.as_str())
+                && fs::read(dir.join("up.sql")).ok() == fs::read(path.join(id).join("up.sql")).ok()
+        });
+        if !readable {
+            let _ = fs::remove_file(&file);
+            return Err(Error::msg(format!(
+                "Unable to read {id} back from the archive, no migrations were archived"
+            )));
+        }
+    }
+    // Lets build scripts know the archived migrations without extracting the archive
+    let manifest = dir.join(format!("{before}{MANIFEST_EXTENSION}"));
+    if let Err(e) = fs::write(&manifest, ids.join("\n") + "\n") {
+        let _ = fs::remove_file(&file);
+        return Err(e).with_context(|| format!("Unable to write '{}'", manifest.display()));
+    }
+
+    for id in &ids {
+        debug!("Removing {id}");
+        fs::remove_dir_all(path.join(id))?;
+    }
+
+    summary!("Archived {} migrations into {}", ids.len(), file.display());
+
+    Ok(())
+}
+
+/// Extracts the archives in [ARCHIVE_DIR] into migr's cache directory, unless they were extracted
+/// before, and returns the directories of the archived migrations.
+pub(crate) fn extracted(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let Ok(entries) = fs::read_dir(path.join(ARCHIVE_DIR)) else {
+        return Ok(vec![]);
+    };
+
+    let mut archives = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|file| file.to_string_lossy().ends_with(EXTENSION))
+        .collect::<Vec<_>>();
+    archives.sort();
+
+    let mut dirs = vec![];
+    for archive in archives {
+        let contents = fs::read(&archive)
+            .with_context(|| format!("Unable to read '{}'", archive.display()))?;
+        // Keyed by the contents so replaced archives are extracted again
+        let checksum = cache::checksum(&contents);
+        let dest = cache::dir(path).join(ARCHIVE_DIR).join(&checksum[..16]);
+
+        if !dest.exists() {
+            debug!("Extracting {}", archive.display());
+            let partial = dest.with_extension("partial");
+            let _ = fs::remove_dir_all(&partial);
+            fs::create_dir_all(&partial)
+                .with_context(|| format!("Unable to create '{}'", partial.display()))?;
+            let mut command = Command::new("tar");
+            command.arg("-xzf").arg(&archive).arg("-C").arg(&partial);
+            tar(command).with_context(|| format!("Unable to extract '{}'", archive.display()))?;
+            fs::rename(&partial, &dest)?;
+        }
+
+        for entry in fs::read_dir(&dest)? {
+            let dir = entry?.path();
+            if dir.is_dir() {
+                dirs.push(dir);
+            }
+        }
+    }
+
+    Ok(dirs)
+}
+
+fn tar(mut command: Command) -> anyhow::Result<()> {
+    let output = command
+        .output()
+        .context("Unable to run `tar`\nHint: Archives require `tar` to be installed")?;
+    if !output.status.success() {
+        return Err(Error::msg(format!(
+            "`tar` failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Whether `s` is a `YYYY-MM-DD` date.
+pub(crate) fn is_date(s: &str) -> bool {
+    let parts = s.split('-').collect::<Vec<_>>();
+    let [year, month, day] = parts[..] else {
+        return false;
+    };
+    let digits = |s: &str, len: usize| s.len() == len && s.bytes().all(|b| b.is_ascii_digit());
+    if !(digits(year, 4) && digits(month, 2) && digits(day, 2)) {
+        return false;
+    }
+
+    let month = month
+        .parse::<u8>()
+        .ok()
+        .and_then(|m| time::Month::try_from(m).ok());
+    month.is_some_and(|month| {
+        time::Date::from_calendar_date(
+            year.parse().unwrap_or_default(),
+            month,
+            day.parse().unwrap_or_default(),
+        )
+        .is_ok()
+    })
+}
@@ -0,0 +1,243 @@
+use crate::config::{AuthConfig, AuthProvider};
+use crate::debug;
+use anyhow::{Context, Error};
+use serde_json::Value;
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Tokens are refreshed this long before they expire so connections opened late in a run still
+/// authenticate.
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// Resolves the password when connecting, either a short-lived token or a secret kept outside of
+/// the database URL.
+#[derive(Debug)]
+pub struct Auth {
+    source: Source,
+    password: Mutex<Option<(String, Instant)>>,
+}
+
+#[derive(Debug)]
+enum Source {
+    Provider {
+        provider: AuthProvider,
+        region: Option<String>,
+        host: String,
+        port: u16,
+        user: String,
+    },
+    /// A shell command printing the password.
+    Command(String),
+    /// A field of a secret in Vault.
+    Vault { path: String, field: String },
+}
+
+impl AuthProvider {
+    /// How long tokens issued by the provider are valid.
+    fn lifetime(&self) -> Duration {
+        match self {
+            AuthProvider::RdsIam => Duration::from_secs(15 * 60),
+            AuthProvider::CloudSqlIam => Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl Auth {
+    /// Returns `None` if the password is not resolved by migr. Providers issue tokens for the
+    /// database at `address`, which is `None` for unix sockets.
+    pub fn new(
+        config: &AuthConfig,
+        address: Option<(&str, u16)>,
+        user: Option<&str>,
+    ) -> anyhow::Result<Option<Self>> {
+        let configured = [
+            config.provider.is_some(),
+            config.password_command.is_some(),
+            config.password_source.is_some(),
+        ];
+        if configured.iter().filter(|c| **c).count() > 1 {
+            return Err(Error::msg(
+                "Only one of `provider`, `password_command` and `password_source` can be set in the auth config",
+            ));
+        }
+
+        let source = if let Some(provider) = config.provider {
+            let (host, port) = address.ok_or_else(|| {
+                Error::msg("Token auth is not supported when connecting through unix sockets")
+            })?;
+            let user = user.ok_or_else(|| {
+                Error::msg("The database URL must contain a user to authenticate with tokens")
+            })?;
+            Source::Provider {
+                provider,
+                region: config.region.clone(),
+                host: host.to_string(),
+                port,
+                user: user.to_string(),
+            }
+        } else if let Some(ref command) = config.password_command {
+            Source::Command(command.clone())
+        } else if let Some(ref source) = config.password_source {
+            parse_source(source)?
+        } else {
+            return Ok(None);
+        };
+
+        Ok(Some(Self {
+            source,
+            password: Mutex::new(None),
+        }))
+    }
+
+    /// Returns the password, resolving it on first use. Tokens are fetched again once they are
+    /// about to expire.
+    pub fn password(&self) -> anyhow::Result<String> {
+        let mut password = self.password.lock().unwrap();
+
+        if let Some((ref password, fetched)) = *password {
+            let valid = match self.source {
+                Source::Provider { provider, .. } => {
+                    fetched.elapsed() + REFRESH_MARGIN < provider.lifetime()
+                }
+                Source::Command(_) | Source::Vault { .. } => true,
+            };
+            if valid {
+                return Ok(password.clone());
+            }
+        }
+
+        let fetched = Instant::now();
+        let new = self.fetch()?;
+        *password = Some((new.clone(), fetched));
+
+        Ok(new)
+    }
+
+    fn fetch(&self) -> anyhow::Result<String> {
+        let mut command = match self.source {
+            Source::Provider {
+                provider: AuthProvider::RdsIam,
+                ref region,
+                ref host,
+                port,
+                ref user,
+            } => {
+                debug!("Generating RDS auth token for {user}@{host}:{port}");
+                let mut command = Command::new("aws");
+                command
+                    .args(["rds", "generate-db-auth-token", "--hostname", host])
+                    .args(["--port", &port.to_string(), "--username", user]);
+                if let Some(region) = region {
+                    command.args(["--region", region]);
+                }
+                command
+            }
+            Source::Provider {
+                provider: AuthProvider::CloudSqlIam,
+                ref user,
+                ..
+            } => {
+                debug!("Generating Cloud SQL login token for {user}");
+                let mut command = Command::new("gcloud");
+                command.args(["sql", "generate-login-token"]);
+                command
+            }
+            Source::Command(ref cmd) => {
+                debug!("Running password command");
+                shell(cmd)
+            }
+            Source::Vault { ref path, .. } => {
+                debug!("Reading password from Vault at {path}");
+                let mut command = Command::new("vault");
+                command.args(["read", "-format=json", path]);
+                command
+            }
+        };
+
+        let program = command.get_program().to_string_lossy().to_string();
+
+        let output = command.output().with_context(|| match self.source {
+            Source::Command(_) => String::from("Unable to run the password command"),
+            _ => format!("Unable to run `{program}`\nHint: The auth config requires the {program} CLI to be installed"),
+        })?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let reason = match stderr.trim() {
+                "" => output.status.to_string(),
+                stderr => stderr.to_string(),
+            };
+            return Err(Error::msg(format!(
+                "Unable to fetch the password: {reason}"
+            )));
+        }
+
+        let stdout = String::from_utf8(output.stdout).context("The password is not valid UTF-8")?;
+
+        let password = match self.source {
+            Source::Vault {
+                ref path,
+                ref field,
+            } => vault_field(&stdout, field).ok_or_else(|| {
+                Error::msg(format!("No field '{field}' in the secret at '{path}'"))
+            })?,
+            _ => stdout.trim().to_string(),
+        };
+
+        if password.is_empty() {
+            return Err(Error::msg("The password is empty"));
+        }
+
+        Ok(password)
+    }
+}
+
+/// Parses a `password_source`, e.g. `vault:kv/data/db#password`.
+fn parse_source(source: &str) -> anyhow::Result<Source> {
+    let invalid = || {
+        Error::msg(format!(
+            "Invalid password source '{source}', expected `vault:<path>#<field>`"
+        ))
+    };
+
+    let secret = source.strip_prefix("vault:").ok_or_else(invalid)?;
+    let (path, field) = secret.split_once('#').ok_or_else(invalid)?;
+
+    if path.is_empty() || field.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok(Source::Vault {
+        path: path.to_string(),
+        field: field.to_string(),
+    })
+}
+
+/// Reads `field` from the JSON output of `vault read`. Secrets of the KV version 2 engine are
+/// nested in another `data` object.
+fn vault_field(output: &str, field: &str) -> Option<String> {
+    let secret = serde_json::from_str::<Value>(output).ok()?;
+    let data = secret.get("data")?;
+    let value = data
+        .get("data")
+        .and_then(|nested| nested.get(field))
+        .or_else(|| data.get(field))?;
+
+    match value {
+        Value::String(value) => Some(value.clone()),
+        _ => None,
+    }
+}
+
+pub fn shell(cmd: &str) -> Command {
+    if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.args(["/C", cmd]);
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.args(["-c", cmd]);
+        command
+    }
+}
@@ -0,0 +1,126 @@
+use crate::auth::shell;
+use crate::config::BackupConfig;
+use crate::destructive::Loss;
+use crate::{debug, info};
+use anyhow::{Context, Error};
+use postgres::Client;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::time::Instant;
+
+/// Backs up the tables the pending migrations delete data from with the backup config and records
+/// where the backup is in the metadata of each of the migrations. Does nothing if backups are not
+/// configured.
+pub fn backup(pg: &mut Client, losses: &[Loss], config: &BackupConfig) -> anyhow::Result<()> {
+    if losses.is_empty() || (config.command.is_none() && config.dir.is_none()) {
+        return Ok(());
+    }
+
+    let started = Instant::now();
+    let tables = losses
+        .iter()
+        .map(|loss| loss.table.as_str())
+        .collect::<BTreeSet<_>>();
+
+    let database = pg
+        .query_one("SELECT current_database()::TEXT", &[])?
+        .get::<_, String>(0);
+    let date = time::OffsetDateTime::now_utc();
+    let (date, (h, m, s)) = (date.date(), date.time().as_hms());
+    let timestamp = format!("{date}-{h:02}{m:02}{s:02}");
+
+    info!(
+        "Backing up {}",
+        tables.iter().copied().collect::<Vec<_>>().join(", ")
+    );
+
+    let location = match (&config.command, &config.dir) {
+        (Some(command), _) => {
+            let tables = tables
+                .iter()
+                .map(|t| shell_quote(t))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let command = command
+                .replace("{tables}", &tables)
+                .replace("{database}", &shell_quote(&database))
+                .replace("{timestamp}", &timestamp);
+            run(&command)?.unwrap_or(command)
+        }
+        (None, Some(dir)) => {
+            let dir = dir.join(format!("{database}-{timestamp}"));
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("Unable to create '{}'", dir.display()))?;
+            for table in &tables {
+                let file = dir.join(format!("{}.csv", table.replace('"', "")));
+                debug!("Copying {table} to {}", file.display());
+                let mut out = fs::File::create(&file)
+                    .with_context(|| format!("Unable to create '{}'", file.display()))?;
+                let mut reader =
+                    pg.copy_out(&format!("COPY {table} TO STDOUT (FORMAT csv, HEADER)"))?;
+                io::copy(&mut reader, &mut out)
+                    .with_context(|| format!("Unable to back up {table}"))?;
+            }
+            dir.display().to_string()
+        }
+        (None, None) => unreachable!(),
+    };
+
+    let ids = losses
+        .iter()
+        .filter_map(|loss| loss.file.parent()?.file_name()?.to_str())
+        .collect::<BTreeSet<_>>();
+    for id in ids {
+        pg.execute(
+            "UPDATE __migr_meta__ SET backup = $2 WHERE id = $1",
+            &[&id, &location],
+        )?;
+    }
+
+    info!("Backed up to {location} in {:.2?}", started.elapsed());
+
+    Ok(())
+}
+
+/// Runs the backup command. Returns the last line it printed, which is taken as the location of the
+/// backup.
+fn run(command: &str) -> anyhow::Result<Option<String>> {
+    debug!("Running backup command");
+    let output = shell(command)
+        .output()
+        .context("Unable to run the backup command")?;
+
+    if !output.status.success() {
+        let message = format!(
+            "The backup command failed with {}, no migrations were executed\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return Err(Error::msg(message.trim_end().to_string()));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .rfind(|l| !l.is_empty())
+        .map(String::from))
+}
+
+/// Quotes `value` as a single word of a shell command, since table and database names may contain
+/// characters the shell interprets.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_quoted_as_single_words() {
+        assert_eq!(shell_quote("app"), "'app'");
+        assert_eq!(shell_quote("app; rm -rf /"), "'app; rm -rf /'");
+        assert_eq!(shell_quote("o'brien"), r"'o'\''brien'");
+    }
+}
@@ -0,0 +1,262 @@
+use crate::config::Config;
+use crate::directive;
+use crate::migration::{migration_dirs, register};
+use crate::schema::VERSION_COLUMN;
+use crate::sql::{self, quote_ident};
+use crate::{debug, info, summary, trace, warn, BaselineArgs};
+use anyhow::{Context, Error};
+use colored::Colorize;
+use postgres::Client;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Instant;
+
+/// The file in the migrations directory fresh databases are created from instead of replaying the
+/// migrations it covers.
+pub const BASELINE_FILE: &str = "baseline.sql";
+
+/// Writes the schema of the database, as dumped by `pg_dump`, to [BASELINE_FILE]. It covers the
+/// migrations up to the latest applied one, which is recorded in its header. The rows of the tables
+/// the covered migrations insert into are dumped along with it.
+pub fn generate(
+    args: &BaselineArgs,
+    path: &Path,
+    url: &str,
+    pg: &mut Client,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let file = path.join(BASELINE_FILE);
+    if file.exists() && !args.force {
+        return Err(Error::msg(format!(
+            "'{}' already exists\nHint: Pass `--force` to replace it",
+            file.display()
+        )));
+    }
+
+    let Some(latest) = pg
+        .query_opt(
+            &format!("SELECT id FROM __migr_meta__ WHERE NOT pending ORDER BY {VERSION_COLUMN} DESC, id DESC LIMIT 1"),
+            &[],
+        )?
+        .map(|row| row.get::<_, String>(0))
+    else {
+        return Err(Error::msg("No migrations are applied to take a baseline of"));
+    };
+
+    // The baseline stands in for every migration up to the latest one, skipped ones included
+    let gaps = pg
+        .query(
+            &format!(
+                "SELECT id FROM __migr_meta__ WHERE pending AND ({VERSION_COLUMN}, id) < (split_part($1, '_', 1), $1) ORDER BY id"
+            ),
+            &[&latest],
+        )?
+        .into_iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<Vec<_>>();
+    if !gaps.is_empty() {
+        return Err(Error::msg(format!(
+            "Migrations before {latest} are pending:\n  {}\nHint: Apply them first, the baseline covers every migration up to the latest applied one",
+            gaps.join("\n  ")
+        )));
+    }
+
+    let dirs = migration_dirs(path, config)?;
+    let covered = match dirs.iter().position(|dir| dir.ends_with(&latest)) {
+        Some(end) => &dirs[..=end],
+        None => &dirs[..],
+    };
+    let tables = seeded(covered)?;
+
+    info!("Dumping the schema with pg_dump");
+    let mut statements = dump(url, &["--schema-only"])?;
+
+    if !tables.is_empty() {
+        info!("Dumping the rows of {}", tables.join(", "));
+        let mut args = vec![
+            String::from("--data-only"),
+            String::from("--column-inserts"),
+        ];
+        args.extend(tables.iter().map(|table| format!("--table={table}")));
+        statements.extend(dump(url, &args)?);
+    }
+
+    let baseline = format!(
+        "-- baseline: {latest}\n-- Generated by `migr baseline`. Fresh databases apply it instead of the migrations up to {latest}.\n\n{}\n",
+        statements.join("\n\n")
+    );
+    fs::write(&file, baseline).with_context(|| format!("Unable to write '{}'", file.display()))?;
+
+    summary!(
+        "Generated {} covering the migrations up to {}",
+        file.display(),
+        latest.green()
+    );
+
+    Ok(())
+}
+
+/// Dumps the database with `pg_dump` and the given arguments, returning the statements of the dump
+/// without the objects of migr.
+fn dump(url: &str, args: &[impl AsRef<std::ffi::OsStr>]) -> anyhow::Result<Vec<String>> {
+    let output = Command::new("pg_dump")
+        .args(args)
+        .args(["--no-owner", "--no-privileges", "--exclude-table=__migr_*"])
+        .arg(url)
+        .output()
+        .context("Unable to run `pg_dump`\nHint: Generating a baseline requires `pg_dump` to be installed")?;
+    if !output.status.success() {
+        return Err(Error::msg(format!(
+            "`pg_dump` failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    // psql meta-commands can't be executed by the server, and the objects of migr are created
+    // by migr itself
+    let dump = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.starts_with('\\'))
+        .collect::<Vec<_>>()
+        .join("\n");
+    Ok(sql::statements(&dump)
+        .into_iter()
+        .filter(|statement| !statement.contains("__migr_"))
+        .map(|statement| format!("{statement};"))
+        .collect())
+}
+
+/// Returns the tables the migrations in `dirs` write rows to, e.g. seeded lookup tables, as they are
+/// named in the migrations. Warns about migrations executing procedural code, whose rows can't be
+/// attributed to tables.
+fn seeded(dirs: &[PathBuf]) -> anyhow::Result<Vec<String>> {
+    let mut tables = vec![];
+    for dir in dirs {
+        let file = dir.join("up.sql");
+        let Ok(sql) = fs::read_to_string(&file) else {
+            continue;
+        };
+        let id = dir.file_name().unwrap_or_default().to_string_lossy();
+
+        let mut procedural = false;
+        for step in directive::steps(&sql, &file)? {
+            let directive::Step::Sql(sql) = step else {
+                continue;
+            };
+            for statement in sql::statements(&sql) {
+                // Comments preceding the statement
+                let statement = statement
+                    .lines()
+                    .skip_while(|l| l.trim().is_empty() || l.trim_start().starts_with("--"))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                let normalized = sql::normalize(&statement);
+                let words = statement.split_whitespace().collect::<Vec<_>>();
+                let table = if ["INSERT INTO ", "MERGE INTO ", "UPDATE ONLY "]
+                    .iter()
+                    .any(|p| normalized.starts_with(p))
+                {
+                    words.get(2)
+                } else if normalized.starts_with("UPDATE ")
+                    || (normalized.starts_with("COPY ") && normalized.contains(" FROM "))
+                {
+                    words.get(1)
+                } else {
+                    procedural |= normalized.starts_with("DO ") || normalized.starts_with("CALL ");
+                    None
+                };
+                let Some(table) = table else {
+                    continue;
+                };
+                let table = table.split('(').next().unwrap_or(table).to_string();
+                if !table.is_empty() && !tables.contains(&table) {
+                    tables.push(table);
+                }
+            }
+        }
+
+        if procedural {
+            warn!("{id} executes procedural code, the rows it writes are not in the baseline unless migrations also write to their tables directly\nHint: Add them to the baseline by hand");
+        }
+    }
+    Ok(tables)
+}
+
+/// Applies [BASELINE_FILE] to a database without applied migrations and marks the migrations it
+/// covers as applied, in a single transaction. Databases which were migrated before are left to
+/// their pending migrations.
+pub fn apply(path: &Path, pg: &mut Client, config: &Config) -> anyhow::Result<()> {
+    let file = path.join(BASELINE_FILE);
+    let Ok(baseline) = fs::read_to_string(&file) else {
+        return Ok(());
+    };
+
+    let applied = pg
+        .query_one("SELECT count(*) FROM __migr_meta__ WHERE NOT pending", &[])?
+        .get::<_, i64>(0);
+    if applied > 0 {
+        debug!("Skipping the baseline, {applied} migrations are applied");
+        return Ok(());
+    }
+
+    let Some(covered) = sql::header_field(&baseline, "baseline") else {
+        return Err(Error::msg(format!(
+            "'{}' does not name the last migration it covers\nHint: Add `-- baseline: <id>` to its header",
+            file.display()
+        )));
+    };
+    let dirs = migration_dirs(path, config)?;
+    let Some(end) = dirs
+        .iter()
+        .position(|dir| dir.file_name().is_some_and(|name| name == covered))
+    else {
+        return Err(Error::msg(format!(
+            "'{}' covers the migrations up to {covered}, which does not exist",
+            file.display()
+        )));
+    };
+    let dirs = &dirs[..=end];
+
+    info!(
+        "Applying the baseline in place of {} migrations",
+        dirs.len()
+    );
+    let started = Instant::now();
+
+    let mut tx = pg.transaction()?;
+    if let Some(ref role) = config.run_as {
+        trace!("Switching to role {role}");
+        tx.batch_execute(&format!("SET LOCAL ROLE {}", quote_ident(role)))?;
+    }
+    tx.batch_execute(&baseline)
+        .with_context(|| format!("while applying {}", file.display().to_string().red()))?;
+    // Dumps change settings of the session, e.g. `pg_dump` clears the search path
+    tx.batch_execute("RESET ALL")?;
+
+    register(dirs, &mut tx)?;
+    for dir in dirs {
+        let id = dir.file_name().unwrap_or_default().to_string_lossy();
+        let up = dir.join("up.sql");
+        let checksum = fs::read(&up).ok().map(|sql| directive::checksum(&sql, &up));
+        // Batch 0, so `rev --last-batch` never reverts them
+        tx.execute(
+            "UPDATE __migr_meta__ SET pending = FALSE, checksum = $2, applied_at = now(), batch = 0 WHERE id = $1",
+            &[&id, &checksum],
+        )?;
+        tx.execute(
+            "INSERT INTO __migr_history__(migration, direction, checksum, duration_ms) VALUES ($1, 'base', $2, 0)",
+            &[&id, &checksum],
+        )?;
+    }
+    tx.commit()?;
+
+    summary!(
+        "Applied the baseline up to {} in {:.2?}",
+        covered.green(),
+        started.elapsed()
+    );
+
+    Ok(())
+}
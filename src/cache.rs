@@ -0,0 +1,275 @@
+use crate::debug;
+use crate::volume;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// The directory holding migr's local artifacts, located next to the migrations directory.
+pub const CACHE_DIR: &str = ".migr";
+
+const CACHE_FILE: &str = "cache.json";
+
+/// An on-disk cache of the migrations directory listing and the checksums of migration files.
+///
+/// Directory listings are only re-read when the modification time of the directory changes and
+/// checksums are only recomputed when the modification time of the file changes, so validating the
+/// cache costs a `stat` per migration file. On filesystems with unreliable modification times,
+/// e.g. network shares and Docker volumes, everything is listed and checksummed on every load.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Cache {
+    /// Modification time of the migrations directory.
+    mtime: u128,
+
+    /// Migration directory names mapped to their contents.
+    migrations: BTreeMap<String, CachedMigration>,
+
+    /// Lossy representations of directory names which are not valid UTF-8. These can never be
+    /// stored in the metadata table, so they are never treated as migrations.
+    invalid: Vec<String>,
+
+    /// Names of symlinks pointing nowhere, e.g. into a volume which is not mounted.
+    #[serde(default)]
+    broken: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CachedMigration {
+    mtime: u128,
+    files: BTreeMap<String, CachedFile>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CachedFile {
+    mtime: u128,
+    checksum: String,
+}
+
+impl Cache {
+    /// Loads the cache of the migrations directory at `path` and brings it up to date with the
+    /// directory contents. The cache is written back if anything changed.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let cache_path = cache_path(path);
+
+        // A missing or corrupt cache is simply rebuilt
+        let mut cache = fs::read(&cache_path)
+            .ok()
+            .and_then(|c| serde_json::from_slice::<Self>(&c).ok())
+            .unwrap_or_default();
+
+        // Modification times are only a hint on filesystems which do not update them reliably,
+        // so everything is listed and checksummed again
+        let verify = volume::unreliable_mtimes(path);
+        if let Some(ref fs_type) = verify {
+            debug!("Not trusting modification times on the {fs_type} filesystem of the migrations");
+        }
+
+        if cache.refresh(path, verify.is_some())? {
+            debug!("Updating cache at {}", cache_path.display());
+            // The cache is an optimisation, failing to write it is not fatal
+            if let Some(parent) = cache_path.parent() {
+                let _ = fs::create_dir_all(parent);
+            }
+            let _ = fs::write(&cache_path, serde_json::to_vec(&cache)?);
+        }
+
+        Ok(cache)
+    }
+
+    /// Returns the sorted paths of the migration directories.
+    pub fn migrations(&self, path: &Path) -> Vec<PathBuf> {
+        self.migrations.keys().map(|id| path.join(id)).collect()
+    }
+
+    /// Returns the names of the directories which were skipped because they are not valid UTF-8.
+    pub fn invalid(&self) -> impl Iterator<Item = &str> {
+        self.invalid.iter().map(String::as_str)
+    }
+
+    /// Returns the names of the symlinks which were skipped because they point nowhere.
+    pub fn broken(&self) -> impl Iterator<Item = &str> {
+        self.broken.iter().map(String::as_str)
+    }
+
+    /// Returns the names of the files in the migration directory `id`.
+    pub fn files(&self, id: &str) -> impl Iterator<Item = &str> {
+        self.migrations
+            .get(id)
+            .into_iter()
+            .flat_map(|m| m.files.keys().map(String::as_str))
+    }
+
+    /// Returns the checksum of the file `name` in the migration directory `id`.
+    pub fn checksum(&self, id: &str, name: &str) -> Option<&str> {
+        let file = self.migrations.get(id)?.files.get(name)?;
+        Some(file.checksum.as_str())
+    }
+
+    /// Validates the cache against the directory contents, updating entries which changed.
+    /// Returns whether anything was updated.
+    fn refresh(&mut self, path: &Path, verify: bool) -> anyhow::Result<bool> {
+        let mut changed = false;
+        let modified = mtime(path)?;
+
+        if verify || modified != self.mtime {
+            let previous = self.migrations.keys().cloned().collect::<Vec<_>>();
+            let mut migrations = BTreeMap::new();
+            let mut invalid = vec![];
+            let mut broken = vec![];
+            for entry in fs::read_dir(path)? {
+                let entry = entry?;
+                if !entry.path().is_dir() {
+                    // Symlinks are followed, the ones pointing nowhere are easily missed otherwise
+                    if entry.file_type()?.is_symlink() && !entry.path().exists() {
+                        broken.push(entry.file_name().to_string_lossy().into_owned());
+                    }
+                    continue;
+                }
+                let id = match entry.file_name().into_string() {
+                    Ok(id) => id,
+                    Err(name) => {
+                        invalid.push(name.to_string_lossy().into_owned());
+                        continue;
+                    }
+                };
+                let migration = self.migrations.remove(&id).unwrap_or_default();
+                migrations.insert(id, migration);
+            }
+            invalid.sort();
+            broken.sort();
+            changed |= migrations.keys().ne(previous.iter()) || invalid != self.invalid;
+            changed |= broken != self.broken || modified != self.mtime;
+            self.migrations = migrations;
+            self.invalid = invalid;
+            self.broken = broken;
+            self.mtime = modified;
+        }
+
+        for (id, migration) in self.migrations.iter_mut() {
+            changed |= migration.refresh(&path.join(id), verify)?;
+        }
+
+        Ok(changed)
+    }
+}
+
+impl CachedMigration {
+    fn refresh(&mut self, path: &Path, verify: bool) -> anyhow::Result<bool> {
+        let mut changed = false;
+        let modified = mtime(path)?;
+
+        if verify || modified != self.mtime {
+            let previous = self.files.keys().cloned().collect::<Vec<_>>();
+            let mut files = BTreeMap::new();
+            for entry in fs::read_dir(path)? {
+                let entry = entry?;
+                if !entry.path().is_file() {
+                    continue;
+                }
+                let Ok(name) = entry.file_name().into_string() else {
+                    continue;
+                };
+                let file = self.files.remove(&name).unwrap_or_default();
+                files.insert(name, file);
+            }
+            changed |= files.keys().ne(previous.iter()) || modified != self.mtime;
+            self.files = files;
+            self.mtime = modified;
+        }
+
+        for (name, file) in self.files.iter_mut() {
+            let path = path.join(name);
+            let modified = mtime(&path)?;
+            if verify || modified != file.mtime {
+                let checksum = checksum(&fs::read(&path)?);
+                changed |= checksum != file.checksum || modified != file.mtime;
+                file.checksum = checksum;
+                file.mtime = modified;
+            }
+        }
+
+        Ok(changed)
+    }
+}
+
+/// Returns the hex encoded SHA-256 checksum of `contents`.
+pub fn checksum(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+/// Returns the directory of migr's local artifacts for the migrations directory at `path`.
+pub fn dir(path: &Path) -> PathBuf {
+    let root = path.parent().unwrap_or(Path::new("."));
+    root.join(CACHE_DIR)
+}
+
+fn cache_path(path: &Path) -> PathBuf {
+    dir(path).join(CACHE_FILE)
+}
+
+fn mtime(path: &Path) -> std::io::Result<u128> {
+    Ok(fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// A fresh migrations directory inside its own temporary project directory.
+    fn migrations_dir(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("migr-cache-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let path = root.join("migrations");
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn migrations_are_joined_onto_the_directory() {
+        let path = migrations_dir("join");
+        fs::create_dir(path.join("2024-01-01-000000_a")).unwrap();
+        fs::write(path.join("2024-01-01-000000_a").join("up.sql"), "SELECT 1;").unwrap();
+
+        let cache = Cache::load(&path).unwrap();
+        assert_eq!(
+            cache.migrations(&path),
+            vec![path.join("2024-01-01-000000_a")]
+        );
+        assert_eq!(
+            cache.files("2024-01-01-000000_a").collect::<Vec<_>>(),
+            ["up.sql"]
+        );
+
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_are_reported_as_invalid() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = migrations_dir("invalid");
+        fs::create_dir(path.join("2024-01-01-000000_a")).unwrap();
+        fs::create_dir(path.join(OsStr::from_bytes(b"2024-01-02-000000_\xff"))).unwrap();
+
+        let cache = Cache::load(&path).unwrap();
+        assert_eq!(
+            cache.migrations(&path),
+            vec![path.join("2024-01-01-000000_a")]
+        );
+        assert_eq!(
+            cache.invalid().collect::<Vec<_>>(),
+            ["2024-01-02-000000_\u{FFFD}"]
+        );
+
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
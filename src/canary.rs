@@ -0,0 +1,136 @@
+use crate::cluster::redact;
+use crate::config::Config;
+use crate::db::{self, Pids, Pool};
+use crate::migration::ensure;
+use crate::ssh::Jump;
+use crate::{debug, info, summary, warn, RunRevMigration};
+use anyhow::{Context, Error};
+use colored::Colorize;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a terminated canary has to notice before it is left behind.
+const GRACE: Duration = Duration::from_secs(10);
+
+/// Applies the pending migrations with `apply`, e.g. `migration_run` or `ensure`.
+type Apply = fn(&RunRevMigration, std::path::PathBuf, &Pool, &Config) -> anyhow::Result<()>;
+
+/// Applies the pending migrations to the canary database at `--canary` first, and to the primary
+/// only if the canary succeeds within `--canary-budget`. A canary exceeding the budget is
+/// terminated, rolling back its transaction.
+pub fn run(
+    args: &RunRevMigration,
+    path: &Path,
+    max_connections: u32,
+    jump: Option<&Jump>,
+    config: &Config,
+    primary: &Pool,
+    apply: Apply,
+) -> anyhow::Result<()> {
+    let Some(ref url) = args.canary else {
+        return apply(args, path.to_path_buf(), primary, config);
+    };
+    if args.metrics.metrics_pushgateway.is_some() || args.metrics.metrics_file.is_some() {
+        return Err(Error::msg(
+            "Metrics are not supported with `--canary`\nHint: Metrics of the canary would be reported as the ones of the primary",
+        ));
+    }
+    if args.report_file.is_some() {
+        return Err(Error::msg(
+            "`--report-file` is not supported with `--canary`\nHint: The report of the canary would be overwritten by the one of the primary",
+        ));
+    }
+
+    let budget = Duration::from_secs(args.canary_budget);
+    let pids = Pids::default();
+    let canary = db::tracked_pool(url, max_connections, jump, &config.auth, &pids)?;
+
+    info!("Migrating canary {}", redact(url).blue());
+    let started = Instant::now();
+    let (tx, rx) = mpsc::channel();
+
+    // Not scoped, so a canary which does not notice its termination can be left behind
+    let (canary_args, canary_path, canary_config) =
+        (args.clone(), path.to_path_buf(), config.clone());
+    thread::spawn(move || {
+        // Migrations are generated against the primary, the canary only learns about them by
+        // syncing
+        let _ = tx.send(ensure(&canary_args, canary_path, &canary, &canary_config));
+    });
+
+    let result = match rx.recv_timeout(budget) {
+        Ok(result) => result,
+        Err(_) => {
+            warn!("The canary exceeded its budget of {budget:.0?}, terminating it");
+            if let Err(e) = terminate(url, jump, config, &pids.get()) {
+                warn!("Unable to terminate the canary: {e:#}");
+            }
+            // Wait for the canary to notice, so its connections are not left behind
+            if rx.recv_timeout(GRACE).is_err() {
+                warn!("The canary did not stop within {GRACE:.0?} of being terminated");
+            }
+            Err(Error::msg(format!(
+                "The canary did not finish within {budget:.0?}\nHint: Increase `--canary-budget` if the migrations are expected to take longer"
+            )))
+        }
+    };
+    let canary_outcome = (result, started.elapsed());
+
+    let primary_outcome = match canary_outcome.0 {
+        Ok(_) => {
+            info!("Migrating primary");
+            let started = Instant::now();
+            Some((
+                apply(args, path.to_path_buf(), primary, config),
+                started.elapsed(),
+            ))
+        }
+        Err(_) => None,
+    };
+
+    // The JSON summaries of the runs are the only output with `--json`
+    if !args.json {
+        print(&canary_outcome, primary_outcome.as_ref());
+    }
+
+    let (canary_result, _) = canary_outcome;
+    canary_result.context("The canary failed, the primary was not migrated")?;
+    match primary_outcome {
+        Some((result, _)) => result,
+        None => Ok(()),
+    }
+}
+
+/// Terminates the sessions of the canary with the backend PIDs `pids` over a connection of its
+/// own, rolling back their transactions. Other sessions on the canary are left alone.
+fn terminate(url: &str, jump: Option<&Jump>, config: &Config, pids: &[i32]) -> anyhow::Result<()> {
+    let mut pg = db::pool(url, 1, false, jump, &config.auth)?.get()?;
+    let terminated = pg
+        .query(
+            "SELECT pid FROM unnest($1::INT4[]) AS pid WHERE pg_terminate_backend(pid)",
+            &[&pids],
+        )?
+        .len();
+    debug!(
+        "Terminated {terminated} of {} canary session(s)",
+        pids.len()
+    );
+    Ok(())
+}
+
+fn print(
+    canary: &(anyhow::Result<()>, Duration),
+    primary: Option<&(anyhow::Result<()>, Duration)>,
+) {
+    let state = |outcome: Option<&(anyhow::Result<()>, Duration)>| match outcome {
+        Some((Ok(_), duration)) => format!("{:<8} {duration:.2?}", "migrated".green()),
+        Some((Err(_), duration)) => format!("{:<8} {duration:.2?}", "failed".red()),
+        None => "skipped".yellow().to_string(),
+    };
+
+    summary!("Outcome:");
+    summary!("{:.<10} {}", "canary", state(Some(canary)));
+    summary!("{:.<10} {}", "primary", state(primary));
+}
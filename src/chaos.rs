@@ -0,0 +1,62 @@
+//! Injecting failures into runs, to test that rollbacks, orchestration and `resume` handle a run
+//! aborting partway through.
+//!
+//! The CLI injects a failure with the hidden `--fail-after <MIGRATION>` flag, applications and test
+//! harnesses with [fail_after].
+
+use crate::warn;
+use anyhow::Error;
+use std::fmt::Display;
+use std::sync::Mutex;
+
+static FAIL_AFTER: Mutex<Option<String>> = Mutex::new(None);
+
+/// Returned by the run which was aborted by an injected failure.
+#[derive(Debug)]
+pub struct InjectedFailure {
+    /// The ID of the migration the run was aborted after.
+    pub after: String,
+}
+
+impl Display for InjectedFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Injected failure after {}", self.after)
+    }
+}
+
+impl std::error::Error for InjectedFailure {}
+
+/// Aborts the next execution of the migration with the given ID or name with an
+/// [InjectedFailure] once it executed, as if the migration after it failed. Migrations sharing a
+/// transaction with it are rolled back, including itself, while a migration executed outside of a
+/// transaction is committed by then and stays applied. The migration after it in the run is
+/// quarantined until `resume`. Fires once, so the following `resume` succeeds. `None` disarms it.
+pub fn fail_after(migration: Option<&str>) {
+    *FAIL_AFTER.lock().unwrap() = migration.map(String::from);
+}
+
+/// Errors with an [InjectedFailure] if a failure is to be injected after the migration `id`.
+pub(crate) fn check(id: &str) -> anyhow::Result<()> {
+    let mut armed = FAIL_AFTER.lock().unwrap();
+    let hit = armed.as_deref().is_some_and(|migration| {
+        migration == id
+            || id
+                .split_once('_')
+                .is_some_and(|(_, name)| name == migration)
+    });
+    if !hit {
+        return Ok(());
+    }
+
+    *armed = None;
+    warn!("Injecting a failure after {id}");
+    Err(InjectedFailure {
+        after: id.to_string(),
+    }
+    .into())
+}
+
+/// Whether `error` is an [InjectedFailure], which the migration it was injected after did not cause.
+pub(crate) fn injected(error: &Error) -> bool {
+    error.downcast_ref::<InjectedFailure>().is_some()
+}
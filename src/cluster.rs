@@ -0,0 +1,279 @@
+use crate::config::Config;
+use crate::db::{self, Pin};
+use crate::migration::ensure;
+use crate::ssh::Jump;
+use crate::{info, summary, ClusterArgs, RunRevMigration};
+use anyhow::{Context, Error};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+type Outcome = (anyhow::Result<()>, Duration);
+
+/// Syncs every shard with the migrations at `path` and applies the pending ones, on up to
+/// `--shard-parallel` shards at once. Stops migrating further shards once one fails, unless `--keep-going` is set.
+/// Every shard is checked against `pin` before anything is executed on it.
+pub fn run(
+    args: &RunRevMigration,
+    path: &Path,
+    max_connections: u32,
+    jump: Option<&Jump>,
+    config: &Config,
+    pin: &Pin,
+) -> anyhow::Result<()> {
+    if args.metrics.metrics_pushgateway.is_some() || args.metrics.metrics_file.is_some() {
+        return Err(Error::msg(
+            "Metrics are not supported when migrating several shards",
+        ));
+    }
+    if args.report_file.is_some() {
+        return Err(Error::msg(
+            "`--report-file` is not supported when migrating several shards",
+        ));
+    }
+
+    let shards = shards(&args.cluster)?;
+    let workers = args.cluster.shard_parallel.clamp(1, shards.len());
+
+    let queue = Mutex::new(shards.iter().enumerate());
+    let outcomes = Mutex::new(
+        shards
+            .iter()
+            .map(|_| None)
+            .collect::<Vec<Option<Outcome>>>(),
+    );
+    let failed = AtomicBool::new(false);
+
+    thread::scope(|s| {
+        for _ in 0..workers {
+            s.spawn(|| loop {
+                if failed.load(Ordering::Relaxed) && !args.cluster.keep_going {
+                    return;
+                }
+                let Some((i, url)) = queue.lock().unwrap().next() else {
+                    return;
+                };
+
+                info!("Migrating shard {}", redact(url).blue());
+
+                let started = Instant::now();
+                let result =
+                    db::pool(url, max_connections, false, jump, &config.auth).and_then(|pool| {
+                        if pin.is_set() {
+                            db::check_pin(&mut *pool.get()?, url, pin)?;
+                        }
+                        // Migrations are generated against a single database, the shards only learn
+                        // about them by syncing
+                        ensure(args, path.to_path_buf(), &pool, config)
+                    });
+
+                if result.is_err() {
+                    failed.store(true, Ordering::Relaxed);
+                }
+                outcomes.lock().unwrap()[i] = Some((result, started.elapsed()));
+            });
+        }
+    });
+
+    let outcomes = outcomes.into_inner().unwrap();
+    let shards = shards.iter().map(|url| redact(url)).collect::<Vec<_>>();
+
+    // The JSON summaries of the shards are the only output with `--json`
+    if !args.json {
+        print(&shards, &outcomes);
+    }
+
+    let errors = shards
+        .iter()
+        .zip(&outcomes)
+        .filter_map(|(url, outcome)| match outcome {
+            Some((Err(e), _)) => Some((url, e)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    let mut message = format!(
+        "Failed to migrate {} of {} shards",
+        errors.len(),
+        shards.len()
+    );
+    for (url, e) in errors {
+        let _ = write!(message, "\n{url}: {e:#}");
+    }
+
+    Err(Error::msg(message))
+}
+
+fn print(shards: &[String], outcomes: &[Option<Outcome>]) {
+    let width = shards.iter().map(|s| s.len()).max().unwrap_or(0) + 3;
+
+    summary!("Shards:");
+    for (url, outcome) in shards.iter().zip(outcomes) {
+        match outcome {
+            Some((Ok(_), duration)) => {
+                summary!("{url:.<width$} {:<8} {duration:.2?}", "migrated".green())
+            }
+            Some((Err(_), duration)) => {
+                summary!("{url:.<width$} {:<8} {duration:.2?}", "failed".red())
+            }
+            None => summary!("{url:.<width$} {}", "skipped".yellow()),
+        }
+    }
+}
+
+/// Returns the URLs given with `--url` followed by the ones in `--urls-file`. Empty lines and lines
+/// starting with `#` in the file are skipped.
+fn shards(args: &ClusterArgs) -> anyhow::Result<Vec<String>> {
+    let mut shards = args.urls.clone();
+
+    if let Some(ref file) = args.urls_file {
+        let contents = fs::read_to_string(file)
+            .with_context(|| format!("Unable to read shards from '{}'", file.display()))?;
+        shards.extend(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(String::from),
+        );
+    }
+
+    if shards.is_empty() {
+        return Err(Error::msg("No shards to migrate"));
+    }
+
+    Ok(shards)
+}
+
+/// Hides the passwords in `url` so it can be printed, whether it is a URL with the password in its
+/// user info or query, or a string of `key=value` pairs.
+pub(crate) fn redact(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return redact_pairs(url);
+    };
+
+    let (authority, tail) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+    let authority = match authority.rfind('@') {
+        Some(at) => match authority[..at].split_once(':') {
+            Some((user, _)) => format!("{user}:***{}", &authority[at..]),
+            None => authority.to_string(),
+        },
+        None => authority.to_string(),
+    };
+    let tail = match tail.split_once('?') {
+        Some((path, query)) => {
+            let query = query
+                .split('&')
+                .map(|pair| match pair.split_once('=') {
+                    Some((key, _)) if secret(key) => format!("{key}=***"),
+                    _ => pair.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join("&");
+            format!("{path}?{query}")
+        }
+        None => tail.to_string(),
+    };
+
+    format!("{scheme}://{authority}{tail}")
+}
+
+/// Hides the passwords in a connection string of `key=value` pairs, whose values may be quoted.
+fn redact_pairs(dsn: &str) -> String {
+    let mut redacted = String::new();
+    let mut rest = dsn;
+    while let Some(eq) = rest.find('=') {
+        let key = rest[..eq].trim();
+        redacted.push_str(&rest[..=eq]);
+
+        let after = &rest[eq + 1..];
+        let value = after.trim_start();
+        redacted.push_str(&after[..after.len() - value.len()]);
+
+        // Quoted values end at the first unescaped quote, others at whitespace
+        let end = match value.strip_prefix('\'') {
+            Some(quoted) => {
+                let mut escaped = false;
+                quoted
+                    .char_indices()
+                    .find(|(_, c)| match (*c, escaped) {
+                        ('\\', false) => {
+                            escaped = true;
+                            false
+                        }
+                        ('\'', false) => true,
+                        _ => {
+                            escaped = false;
+                            false
+                        }
+                    })
+                    .map(|(i, _)| i + 2)
+                    .unwrap_or(value.len())
+            }
+            None => value.find(char::is_whitespace).unwrap_or(value.len()),
+        };
+        match secret(key) {
+            true => redacted.push_str("***"),
+            false => redacted.push_str(&value[..end]),
+        }
+        rest = &value[end..];
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+/// Whether the connection parameter `key` holds a password, e.g. `password` or `sslpassword`.
+fn secret(key: &str) -> bool {
+    key.to_ascii_lowercase().ends_with("password")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_in_user_info_are_hidden() {
+        assert_eq!(
+            redact("postgres://app:secret@db:5432/app"),
+            "postgres://app:***@db:5432/app"
+        );
+        assert_eq!(
+            redact("postgres://app:p@ss@db?sslmode=require"),
+            "postgres://app:***@db?sslmode=require"
+        );
+        assert_eq!(redact("postgres://app@db/app"), "postgres://app@db/app");
+    }
+
+    #[test]
+    fn passwords_in_queries_are_hidden() {
+        assert_eq!(
+            redact("postgresql:///app?host=/run/postgresql&user=app&password=secret"),
+            "postgresql:///app?host=/run/postgresql&user=app&password=***"
+        );
+        assert_eq!(
+            redact("postgres://db/app?sslpassword=secret&sslmode=require"),
+            "postgres://db/app?sslpassword=***&sslmode=require"
+        );
+    }
+
+    #[test]
+    fn passwords_in_key_value_pairs_are_hidden() {
+        assert_eq!(
+            redact("host=db user=app password=secret dbname=app"),
+            "host=db user=app password=*** dbname=app"
+        );
+        assert_eq!(
+            redact("host=db password = 'it\\'s secret' dbname=app"),
+            "host=db password = *** dbname=app"
+        );
+        assert_eq!(redact("host=db dbname=app"), "host=db dbname=app");
+    }
+}
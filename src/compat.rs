@@ -0,0 +1,113 @@
+//! Leases of the running instances of an application on the schema versions they support.
+//!
+//! Instances register the version of the latest migration they were built with and renew the lease
+//! while they run. `migr run` refuses to apply a migration marked as `incompatible` in its
+//! `meta.toml` while an instance built before it holds a lease, so live instances don't break
+//! mid-deploy.
+
+use crate::meta::MigrationMeta;
+use crate::migration::migration_id;
+use crate::warn;
+use anyhow::Error;
+use postgres::GenericClient;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Registers or renews the lease of `instance` on the schema at `version`, the version or ID of the
+/// latest migration it was built with, e.g. `MIGRATIONS_LATEST_VERSION` of `migr_build`. The lease
+/// expires after `ttl` unless renewed, so instances which die without releasing it stop holding
+/// back migrations.
+pub fn lease(
+    pg: &mut impl GenericClient,
+    instance: &str,
+    version: &str,
+    ttl: Duration,
+) -> anyhow::Result<()> {
+    ensure_table(pg)?;
+    pg.execute(
+        "INSERT INTO __migr_compat__(instance, version, expires_at)
+         VALUES ($1, $2, now() + make_interval(secs => $3))
+         ON CONFLICT (instance) DO UPDATE
+         SET version = EXCLUDED.version, expires_at = EXCLUDED.expires_at, renewed_at = now()",
+        &[&instance, &version, &ttl.as_secs_f64()],
+    )?;
+    Ok(())
+}
+
+/// Releases the lease of `instance`, e.g. when it shuts down.
+pub fn release(pg: &mut impl GenericClient, instance: &str) -> anyhow::Result<()> {
+    ensure_table(pg)?;
+    pg.execute(
+        "DELETE FROM __migr_compat__ WHERE instance = $1",
+        &[&instance],
+    )?;
+    Ok(())
+}
+
+/// Errors if any of the `pending` migrations is marked as incompatible while an instance built
+/// before it holds a lease, unless `force` is set.
+pub(crate) fn check(
+    pg: &mut impl GenericClient,
+    pending: &[PathBuf],
+    force: bool,
+) -> anyhow::Result<()> {
+    let mut blocked = String::new();
+
+    for file in pending {
+        let dir = file.parent().unwrap_or(Path::new("."));
+        if !MigrationMeta::load(dir)?.incompatible {
+            continue;
+        }
+        let Some(id) = migration_id(file) else {
+            continue;
+        };
+
+        let leases = pg.query(
+            "SELECT instance, version, ceil(extract(epoch FROM expires_at - now()))::BIGINT
+             FROM __migr_compat__
+             WHERE expires_at > now() AND split_part(version, '_', 1) < split_part($1, '_', 1)
+             ORDER BY instance",
+            &[&id],
+        )?;
+        if leases.is_empty() {
+            continue;
+        }
+
+        let _ = write!(blocked, "\n  {id}, held back by:");
+        for lease in leases {
+            let _ = write!(
+                blocked,
+                "\n    {} at {} (expires in {}s)",
+                lease.get::<_, String>(0),
+                lease.get::<_, String>(1),
+                lease.get::<_, i64>(2)
+            );
+        }
+    }
+
+    if blocked.is_empty() {
+        return Ok(());
+    }
+
+    if force {
+        warn!("Applying incompatible migrations while older instances hold leases:{blocked}");
+        return Ok(());
+    }
+    Err(Error::msg(format!(
+        "Pending migrations are incompatible with running instances of the application:{blocked}\nHint: Wait for the instances to be upgraded, or pass `--force-incompatible` to apply them anyway"
+    )))
+}
+
+/// Errors if the lease table does not exist, instead of aborting a caller's transaction.
+fn ensure_table(pg: &mut impl GenericClient) -> anyhow::Result<()> {
+    let exists = pg
+        .query_one("SELECT to_regclass('__migr_compat__') IS NOT NULL", &[])?
+        .get::<_, bool>(0);
+    if !exists {
+        return Err(Error::msg(
+            "The lease table does not exist\nHint: Run `migr setup` or `migr run` with this release of migr first",
+        ));
+    }
+    Ok(())
+}
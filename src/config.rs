@@ -0,0 +1,587 @@
+use crate::db::Pin;
+use crate::meta::Risk;
+use anyhow::{Context, Error};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The default location of the config file, relative to the current directory.
+pub const CONFIG_FILE: &str = "migr.toml";
+
+/// Project configuration loaded from `migr.toml`. Every section is optional.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Glob patterns of entries in the migrations directory which are not migrations.
+    pub ignore: Vec<String>,
+
+    /// The migrations directories to choose from when no path is given, relative to the current
+    /// directory, instead of searching for one.
+    pub paths: Vec<PathBuf>,
+
+    /// The role migrations are executed as, overridden by `--run-as`.
+    pub run_as: Option<String>,
+
+    /// The name of the database `DATABASE_URL` must point at. Commands refuse to run against any
+    /// other.
+    pub expected_database: Option<String>,
+
+    /// The host `DATABASE_URL` must point at.
+    pub expected_host: Option<String>,
+
+    /// The system identifier of the cluster `DATABASE_URL` must point at.
+    pub expected_system_identifier: Option<i64>,
+
+    /// The values of the parameters named by `-- migr:params` directives, overridden by `--var`.
+    pub params: HashMap<String, String>,
+
+    /// Extensions which must be available on the server before migrations are run.
+    pub required_extensions: Vec<String>,
+
+    /// Which tables `ANALYZE` refreshes the planner statistics of after a run executed migrations.
+    pub analyze: AnalyzeMode,
+
+    pub lint: LintConfig,
+    pub setup: SetupConfig,
+    pub gen: GenConfig,
+    pub fmt: FmtConfig,
+    pub notify: NotifyConfig,
+    pub grants: GrantsConfig,
+    pub auth: AuthConfig,
+    pub backup: BackupConfig,
+    pub history: HistoryConfig,
+
+    /// Organizational rules migrations must follow, enforced on `run` and `verify`.
+    #[serde(rename = "policy")]
+    pub policies: Vec<Policy>,
+
+    /// Databases managed by the project, selected with `--target`.
+    #[serde(rename = "target")]
+    pub targets: Vec<Target>,
+
+    /// Deployments of the migrations, e.g. staging and production, compared by `status --env`.
+    #[serde(rename = "env")]
+    pub envs: Vec<Env>,
+
+    /// The name of the target the command is executed against, set when iterating `--target`.
+    #[serde(skip)]
+    pub target: Option<String>,
+}
+
+impl Config {
+    /// Loads the config at `path`. If `path` is the default location and the file does not exist,
+    /// the default config is returned.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() && path == Path::new(CONFIG_FILE) {
+            return Ok(Self::default());
+        }
+
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read config at '{}'", path.display()))?;
+
+        toml::from_str(&contents).with_context(|| format!("Invalid config at '{}'", path.display()))
+    }
+
+    /// Returns the pin of the database at `DATABASE_URL`. `path` is the path of the config.
+    pub fn pin(&self, path: &Path) -> Pin {
+        Pin {
+            database: self.expected_database.clone(),
+            host: self.expected_host.clone(),
+            system_identifier: self.expected_system_identifier,
+            owner: path.display().to_string(),
+        }
+    }
+
+    /// Returns the target with the given name, or every target if the name is `all`.
+    pub fn targets(&self, name: &str) -> anyhow::Result<Vec<&Target>> {
+        if self.targets.is_empty() {
+            return Err(Error::msg(
+                "No targets are configured\nHint: Add `[[target]]` sections to migr.toml",
+            ));
+        }
+
+        if name == "all" {
+            return Ok(self.targets.iter().collect());
+        }
+
+        match self.targets.iter().find(|t| t.name == name) {
+            Some(target) => Ok(vec![target]),
+            None => Err(Error::msg(format!(
+                "Unknown target '{name}', expected `all` or one of: {}",
+                self.targets
+                    .iter()
+                    .map(|t| t.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))),
+        }
+    }
+
+    /// Returns the environments with the given comma separated names, or every environment if the
+    /// name is `all`.
+    pub fn envs(&self, names: &str) -> anyhow::Result<Vec<&Env>> {
+        if self.envs.is_empty() {
+            return Err(Error::msg(
+                "No environments are configured\nHint: Add `[[env]]` sections to migr.toml",
+            ));
+        }
+
+        if names == "all" {
+            return Ok(self.envs.iter().collect());
+        }
+
+        names.split(',').map(|name| self.env(name.trim())).collect()
+    }
+
+    /// Returns the environment with the given name.
+    pub fn env(&self, name: &str) -> anyhow::Result<&Env> {
+        self.envs
+            .iter()
+            .find(|env| env.name == name)
+            .ok_or_else(|| {
+                let names = self
+                    .envs
+                    .iter()
+                    .map(|env| env.name.as_str())
+                    .collect::<Vec<_>>();
+                match names.is_empty() {
+                    true => Error::msg(format!(
+                        "Unknown environment '{name}'\nHint: Add `[[env]]` sections to migr.toml"
+                    )),
+                    false => Error::msg(format!(
+                        "Unknown environment '{name}', expected one of: {}",
+                        names.join(", ")
+                    )),
+                }
+            })
+    }
+}
+
+/// A database the migrations are deployed to, e.g. production.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Env {
+    pub name: String,
+
+    /// The database URL. Takes precedence over `url_env`.
+    pub url: Option<String>,
+
+    /// The env variable holding the database URL.
+    pub url_env: Option<String>,
+
+    /// The name of the database the URL must point at.
+    pub expected_database: Option<String>,
+
+    /// The host the URL must point at.
+    pub expected_host: Option<String>,
+
+    /// The system identifier of the cluster the URL must point at.
+    pub expected_system_identifier: Option<i64>,
+}
+
+impl Env {
+    pub fn pin(&self) -> Pin {
+        Pin {
+            database: self.expected_database.clone(),
+            host: self.expected_host.clone(),
+            system_identifier: self.expected_system_identifier,
+            owner: format!("the environment '{}'", self.name),
+        }
+    }
+
+    pub fn url(&self) -> anyhow::Result<String> {
+        if let Some(ref url) = self.url {
+            return Ok(url.clone());
+        }
+        let Some(ref var) = self.url_env else {
+            return Err(Error::msg(format!(
+                "The environment '{}' has no database URL\nHint: Set its `url` or `url_env`",
+                self.name
+            )));
+        };
+        env::var(var).with_context(|| {
+            format!(
+                "`{var}` must be set in the env to connect to the environment '{}'",
+                self.name
+            )
+        })
+    }
+}
+
+/// A database and the migrations directory it is migrated with.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    pub name: String,
+
+    /// The migrations directory of the target, relative to the current directory.
+    pub path: PathBuf,
+
+    /// The database URL. Takes precedence over `url_env`.
+    pub url: Option<String>,
+
+    /// The env variable holding the database URL, `DATABASE_URL` by default.
+    pub url_env: Option<String>,
+
+    /// Pending migrations assessed riskier than this are refused unless `--max-risk` allows them.
+    pub max_risk: Option<Risk>,
+
+    /// The name of the database the URL must point at.
+    pub expected_database: Option<String>,
+
+    /// The host the URL must point at.
+    pub expected_host: Option<String>,
+
+    /// The system identifier of the cluster the URL must point at.
+    pub expected_system_identifier: Option<i64>,
+
+    /// Values of `-- migr:params` parameters specific to the target, taking precedence over
+    /// `[params]`.
+    #[serde(default)]
+    pub params: HashMap<String, String>,
+}
+
+impl Target {
+    pub fn pin(&self) -> Pin {
+        Pin {
+            database: self.expected_database.clone(),
+            host: self.expected_host.clone(),
+            system_identifier: self.expected_system_identifier,
+            owner: format!("the target '{}'", self.name),
+        }
+    }
+
+    pub fn url(&self) -> anyhow::Result<String> {
+        if let Some(ref url) = self.url {
+            return Ok(url.clone());
+        }
+        let var = self.url_env.as_deref().unwrap_or("DATABASE_URL");
+        env::var(var).with_context(|| {
+            format!(
+                "`{var}` must be set in the env to migrate target '{}'",
+                self.name
+            )
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnalyzeMode {
+    /// Leave the statistics to autovacuum.
+    #[default]
+    Off,
+    /// Analyze the tables the executed migrations create, alter or write to.
+    Touched,
+    /// Analyze every table of the database.
+    All,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LintConfig {
+    /// Enables the replication analysis pass on `run` and `verify`.
+    pub replication: bool,
+
+    /// Overrides the level of individual lint rules by their name.
+    pub rules: HashMap<String, LintLevel>,
+}
+
+impl LintConfig {
+    /// Returns the configured level of `rule`, or `Warn` if it is not configured.
+    pub fn level(&self, rule: &str) -> LintLevel {
+        self.level_or(rule, LintLevel::Warn)
+    }
+
+    /// Returns the configured level of `rule`, or `default` if it is not configured.
+    pub fn level_or(&self, rule: &str, default: LintLevel) -> LintLevel {
+        self.rules.get(rule).copied().unwrap_or(default)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SetupConfig {
+    /// Whether `setup` creates the initial migration. `--no-initial` disables it as well.
+    pub initial: bool,
+
+    /// The contents of the initial `up.sql`, e.g. extensions every database of the project needs.
+    pub initial_up: String,
+
+    /// The contents of the initial `down.sql`.
+    pub initial_down: String,
+}
+
+impl Default for SetupConfig {
+    fn default() -> Self {
+        Self {
+            initial: true,
+            initial_up: String::from("-- Set up initial SQL dependencies here"),
+            initial_down: String::from("-- Revert everything from up.sql"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GenConfig {
+    /// The header written at the top of generated `up.sql` and `down.sql` files.
+    /// Supports the `{name}`, `{id}`, `{author}` and `{created_at}` placeholders.
+    pub header: String,
+
+    /// Where the timestamps of generated migrations are taken from.
+    pub clock: GenClock,
+
+    /// The `up.sql` generated by `gen add-table`. Supports the `{table}`, `{table_name}` (without
+    /// the schema) and `{columns}` placeholders, where the columns are indented and each followed
+    /// by a comma.
+    pub table_up: String,
+
+    /// The `down.sql` generated by `gen add-table`, supporting the same placeholders.
+    pub table_down: String,
+}
+
+impl Default for GenConfig {
+    fn default() -> Self {
+        Self {
+            header: String::from(
+                "-- name: {name}\n-- author: {author}\n-- created_at: {created_at}\n-- ticket: \n",
+            ),
+            clock: GenClock::Local,
+            table_up: String::from(
+                "CREATE TABLE {table}(
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+{columns}    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE FUNCTION {table}_set_updated_at() RETURNS TRIGGER AS $$
+BEGIN
+    NEW.updated_at = now();
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER {table_name}_set_updated_at BEFORE UPDATE ON {table}
+FOR EACH ROW EXECUTE FUNCTION {table}_set_updated_at();
+",
+            ),
+            table_down: String::from(
+                "DROP TABLE {table};\nDROP FUNCTION {table}_set_updated_at();\n",
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GenClock {
+    /// The clock of the machine generating the migration.
+    Local,
+    /// The clock of the database server, shared by everyone generating migrations against it.
+    Database,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FmtConfig {
+    /// The number of spaces statements are indented with.
+    pub indent: u8,
+
+    /// Indent with tabs instead of spaces.
+    pub tabs: bool,
+
+    /// Write keywords in uppercase.
+    pub uppercase: bool,
+
+    /// The number of line breaks after each statement.
+    pub lines_between_statements: u8,
+
+    /// Format the SQL of migrations created by `gen` and `capture`.
+    pub on_gen: bool,
+}
+
+impl Default for FmtConfig {
+    fn default() -> Self {
+        Self {
+            indent: 2,
+            tabs: false,
+            uppercase: true,
+            lines_between_statements: 2,
+            on_gen: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifyConfig {
+    /// The webhook POSTed to after `run`, `rev` and `redo`. Disabled if not set.
+    pub url: Option<String>,
+
+    /// When to notify.
+    pub on: NotifyOn,
+
+    /// The request body. Supports the `{action}`, `{status}`, `{count}`, `{current_version}` and
+    /// `{error}` placeholders, whose values are JSON escaped, and the `{migrations}` and `{summary}`
+    /// placeholders, which are replaced by JSON values.
+    pub template: String,
+
+    /// The content type of the request body.
+    pub content_type: String,
+}
+
+impl Default for NotifyConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            on: NotifyOn::Always,
+            template: String::from("{summary}"),
+            content_type: String::from("application/json"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyOn {
+    /// Notify after every command.
+    Always,
+    /// Only notify when a command fails.
+    Failure,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Fetches short-lived tokens used as the password when connecting. Disabled if not set.
+    pub provider: Option<AuthProvider>,
+
+    /// The AWS region of the database for `rds_iam`. Defaults to the region configured for the
+    /// AWS CLI.
+    pub region: Option<String>,
+
+    /// A shell command printing the password, executed when connecting.
+    pub password_command: Option<String>,
+
+    /// Where to read the password from when connecting, e.g. `vault:kv/data/db#password`.
+    pub password_source: Option<String>,
+
+    /// A PEM file with the CA certificates the server certificate of encrypted connections is
+    /// verified against, e.g. the RDS bundle. Without it, the server certificate of connections
+    /// authenticated by a provider is verified against the system roots, while the server of URLs
+    /// with `sslmode=require` is not verified, like in libpq.
+    pub ca_file: Option<PathBuf>,
+
+    /// Don't verify the server certificate of connections authenticated by a provider either. The
+    /// token is then sent to whichever server answers, so only set it when the network is trusted.
+    pub accept_invalid_certs: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthProvider {
+    /// IAM database authentication for AWS RDS, with tokens from the AWS CLI.
+    RdsIam,
+    /// IAM database authentication for GCP Cloud SQL, with tokens from the gcloud CLI.
+    CloudSqlIam,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrantsConfig {
+    /// Statements executed after each successful up migration, in its transaction.
+    /// Supports the `{id}`, `{version}` and `{name}` placeholders.
+    pub after_up: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupConfig {
+    /// A shell command backing up the tables pending migrations delete data from before they are
+    /// executed. Supports the `{tables}`, `{database}` and `{timestamp}` placeholders, the names
+    /// being shell quoted. The last line it prints is recorded as the location of the backup.
+    pub command: Option<String>,
+
+    /// The directory the tables are copied to as CSV files if no command is set, relative to the
+    /// current directory.
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Store the SQL executed by each migration in the history table, with included files expanded.
+    pub sql: bool,
+
+    /// The directory the SQL executed by each migration is written to, relative to the current
+    /// directory.
+    pub artifacts: Option<PathBuf>,
+}
+
+/// A rule migrations must follow. Each of the checks set on the policy is applied to the statements
+/// of the `up.sql` of migrations.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    pub name: String,
+
+    /// Explains the policy in reports of its violations.
+    pub description: Option<String>,
+
+    /// Whether violations only warn or refuse to proceed. `deny` by default.
+    #[serde(default = "Policy::default_level")]
+    pub level: LintLevel,
+
+    /// The targets the policy is enforced for. Enforced everywhere if empty.
+    #[serde(default)]
+    pub targets: Vec<String>,
+
+    /// Migrations listing this tag in the `tags` field of their header are exempt from the policy.
+    pub exempt_tag: Option<String>,
+
+    /// Migrations with a version before this one are exempt from the policy, e.g. the ones applied
+    /// before it was introduced.
+    pub since: Option<String>,
+
+    /// Tables created by migrations must have a primary key.
+    #[serde(default)]
+    pub require_primary_key: bool,
+
+    /// Columns every table created by migrations must have.
+    #[serde(default)]
+    pub require_columns: Vec<String>,
+
+    /// Phrases statements must not contain, e.g. `DROP COLUMN`. Matched case-insensitively on
+    /// whole words.
+    #[serde(default)]
+    pub forbid: Vec<String>,
+
+    /// Annotations of `meta.toml` every migration must set, e.g. `ticket`.
+    #[serde(default)]
+    pub require_meta: Vec<String>,
+
+    /// Restricts the policy to migrations touching objects in these schemas. Enforced for all
+    /// migrations if empty.
+    #[serde(default)]
+    pub schemas: Vec<String>,
+}
+
+impl Policy {
+    fn default_level() -> LintLevel {
+        LintLevel::Deny
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LintLevel {
+    /// Do not report the rule.
+    Allow,
+    /// Report the rule, but proceed.
+    Warn,
+    /// Report the rule and refuse to proceed.
+    Deny,
+}
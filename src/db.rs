@@ -0,0 +1,336 @@
+use crate::auth::Auth;
+use crate::config::AuthConfig;
+use crate::output;
+use crate::ssh::Jump;
+use anyhow::{Context, Error};
+use colored::Colorize;
+use native_tls::{Certificate, TlsConnector};
+use postgres::config::{Host, SslMode};
+use postgres::{Client, NoTls};
+use postgres_native_tls::MakeTlsConnector;
+use r2d2::{CustomizeConnection, ManageConnection};
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+pub type Pool = r2d2::Pool<Manager>;
+
+/// Opens the connections of a pool. If migr resolves the password, each connection is opened with
+/// the current password.
+#[derive(Debug)]
+pub struct Manager {
+    config: postgres::Config,
+    auth: Option<Auth>,
+    tls: Option<TlsConnector>,
+}
+
+#[derive(Debug)]
+pub enum ConnectError {
+    Postgres(postgres::Error),
+    Auth(Error),
+}
+
+/// Creates a connection pool for the database at `url`. Connections are opened on demand up to
+/// `max_connections`. Sessions of read-only pools default to read-only transactions. If a jump host
+/// is given, the database is connected to through an SSH tunnel. The password is resolved with the
+/// auth config, if set.
+pub fn pool(
+    url: &str,
+    max_connections: u32,
+    read_only: bool,
+    jump: Option<&Jump>,
+    auth: &AuthConfig,
+) -> anyhow::Result<Pool> {
+    let manager = manager(url, read_only, jump, auth)?;
+
+    Ok(r2d2::Pool::builder()
+        .max_size(max_connections.max(1))
+        .min_idle(Some(0))
+        .build_unchecked(manager))
+}
+
+/// Like [pool], recording the backend PID of every connection the pool opens in `pids`, so the
+/// sessions of the pool can be told apart from other sessions on the server.
+pub fn tracked_pool(
+    url: &str,
+    max_connections: u32,
+    jump: Option<&Jump>,
+    auth: &AuthConfig,
+    pids: &Pids,
+) -> anyhow::Result<Pool> {
+    let manager = manager(url, false, jump, auth)?;
+
+    Ok(r2d2::Pool::builder()
+        .max_size(max_connections.max(1))
+        .min_idle(Some(0))
+        .connection_customizer(Box::new(pids.clone()))
+        .build_unchecked(manager))
+}
+
+/// The backend PIDs of the connections opened by a [tracked_pool].
+#[derive(Debug, Clone, Default)]
+pub struct Pids(Arc<Mutex<Vec<i32>>>);
+
+impl Pids {
+    pub fn get(&self) -> Vec<i32> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl CustomizeConnection<Client, ConnectError> for Pids {
+    fn on_acquire(&self, conn: &mut Client) -> Result<(), ConnectError> {
+        let pid = conn
+            .query_one("SELECT pg_backend_pid()", &[])
+            .map_err(ConnectError::Postgres)?
+            .get::<_, i32>(0);
+        self.0.lock().unwrap().push(pid);
+        Ok(())
+    }
+}
+
+/// Returns a manager opening connections to the database at `url`, for commands which connect to
+/// more than one database on the server.
+pub fn manager(
+    url: &str,
+    read_only: bool,
+    jump: Option<&Jump>,
+    auth: &AuthConfig,
+) -> anyhow::Result<Manager> {
+    let mut config = url
+        .parse::<postgres::Config>()
+        .context("Invalid database URL")?;
+
+    let tls = tls(&config, auth, jump.is_some())?;
+
+    // Tokens are issued for the database host, not the local end of a tunnel
+    let auth = Auth::new(auth, address(&config), config.get_user())?;
+
+    if let Some(jump) = jump {
+        config = tunnel(&config, jump)?;
+    }
+    if tls.is_some() {
+        config.ssl_mode(SslMode::Require);
+    }
+
+    if read_only {
+        let options = match config.get_options() {
+            Some(options) => format!("{options} -c default_transaction_read_only=on"),
+            None => String::from("-c default_transaction_read_only=on"),
+        };
+        config.options(&options);
+    }
+
+    if output::enabled(output::NOTICE) {
+        config.notice_callback(|notice| {
+            output::print("PG".cyan(), format_args!("{}", notice.message()))
+        });
+    }
+
+    let manager = Manager { config, auth, tls };
+
+    // Connect once up front so connection errors are reported immediately instead of once the pool times out
+    manager
+        .connect()
+        .context("Could not establish PG connection")?;
+
+    Ok(manager)
+}
+
+/// Returns the connector encrypting the connections if they must be, i.e. if the URL requires it or
+/// tokens of an IAM provider are sent as the password, which servers only accept over TLS.
+fn tls(
+    config: &postgres::Config,
+    auth: &AuthConfig,
+    tunneled: bool,
+) -> anyhow::Result<Option<TlsConnector>> {
+    if auth.provider.is_none() && config.get_ssl_mode() != SslMode::Require {
+        return Ok(None);
+    }
+
+    let mut builder = TlsConnector::builder();
+    match auth.ca_file {
+        Some(ref file) => {
+            let pem = fs::read(file)
+                .with_context(|| format!("Unable to read the CA file '{}'", file.display()))?;
+            let certificate = Certificate::from_pem(&pem)
+                .with_context(|| format!("Invalid CA file '{}'", file.display()))?;
+            builder.add_root_certificate(certificate);
+            // The certificate names the database host, not the local end of the tunnel
+            builder.danger_accept_invalid_hostnames(tunneled);
+        }
+        // Tokens are credentials, so they are only sent to servers proving they are the database
+        None if auth.provider.is_some() && !auth.accept_invalid_certs => {
+            builder.danger_accept_invalid_hostnames(tunneled);
+        }
+        None => {
+            builder.danger_accept_invalid_certs(true);
+        }
+    }
+
+    Ok(Some(builder.build().context("Unable to set up TLS")?))
+}
+
+/// The database a target, an environment or the config pins, which [check_pin] verifies.
+#[derive(Debug, Default, Clone)]
+pub struct Pin {
+    pub database: Option<String>,
+    pub host: Option<String>,
+    pub system_identifier: Option<i64>,
+    /// Who pins the database, e.g. `the target 'prod'`.
+    pub owner: String,
+}
+
+impl Pin {
+    pub fn is_set(&self) -> bool {
+        self.database.is_some() || self.host.is_some() || self.system_identifier.is_some()
+    }
+}
+
+/// Errors unless the database at `url`, which `pg` is connected to, is the one `pin` pins. The host
+/// is compared with the one in the URL, so URLs copied from another environment are refused before
+/// anything is executed. The system identifier is read from the server, so it identifies the
+/// cluster whatever URL reaches it, e.g. through a tunnel or a pooler.
+pub fn check_pin(pg: &mut Client, url: &str, pin: &Pin) -> anyhow::Result<()> {
+    let owner = &pin.owner;
+
+    if let Some(ref expected) = pin.host {
+        let config = url
+            .parse::<postgres::Config>()
+            .context("Invalid database URL")?;
+        let actual = match config.get_hosts().first() {
+            Some(Host::Tcp(host)) => host.clone(),
+            #[cfg(unix)]
+            Some(Host::Unix(path)) => path.display().to_string(),
+            None => String::from("localhost"),
+        };
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(Error::msg(format!(
+                "The database URL points at host '{actual}', but {owner} expects '{expected}'\nHint: Check the URL, it may have been copied from another environment"
+            )));
+        }
+    }
+
+    if let Some(ref expected) = pin.database {
+        let actual = pg
+            .query_one("SELECT current_database()::TEXT", &[])?
+            .get::<_, String>(0);
+        if actual != *expected {
+            return Err(Error::msg(format!(
+                "Connected to database '{actual}', but {owner} expects '{expected}'\nHint: Check the URL, it may have been copied from another environment"
+            )));
+        }
+    }
+
+    if let Some(expected) = pin.system_identifier {
+        let actual = pg
+            .query_one("SELECT system_identifier FROM pg_control_system()", &[])?
+            .get::<_, i64>(0);
+        if actual != expected {
+            return Err(Error::msg(format!(
+                "Connected to the cluster with system identifier {actual}, but {owner} expects {expected}\nHint: Check the URL, it may have been copied from another environment"
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the host and port of the first host of `config`, or `None` if it is a unix socket.
+fn address(config: &postgres::Config) -> Option<(&str, u16)> {
+    let host = match config.get_hosts().first() {
+        Some(Host::Tcp(host)) => host.as_str(),
+        Some(_) => return None,
+        None => "localhost",
+    };
+    Some((host, config.get_ports().first().copied().unwrap_or(5432)))
+}
+
+/// Opens a tunnel to the first host of `config` and returns a config connecting through it.
+fn tunnel(config: &postgres::Config, jump: &Jump) -> anyhow::Result<postgres::Config> {
+    let (host, port) = address(config)
+        .ok_or_else(|| Error::msg("Unix sockets can not be reached through an SSH tunnel"))?;
+
+    let local = jump.forward(host, port)?;
+
+    let mut tunneled = postgres::Config::new();
+    tunneled.host("127.0.0.1").port(local);
+
+    if let Some(user) = config.get_user() {
+        tunneled.user(user);
+    }
+    if let Some(password) = config.get_password() {
+        tunneled.password(password);
+    }
+    if let Some(dbname) = config.get_dbname() {
+        tunneled.dbname(dbname);
+    }
+    if let Some(options) = config.get_options() {
+        tunneled.options(options);
+    }
+    if let Some(name) = config.get_application_name() {
+        tunneled.application_name(name);
+    }
+    if let Some(timeout) = config.get_connect_timeout() {
+        tunneled.connect_timeout(*timeout);
+    }
+
+    Ok(tunneled)
+}
+
+impl Manager {
+    /// Opens a connection to another database on the same server, e.g. one created by migr.
+    pub fn connect_to(&self, dbname: &str) -> anyhow::Result<Client> {
+        let mut config = self.config.clone();
+        config.dbname(dbname);
+        self.open(config)
+            .with_context(|| format!("Could not connect to database '{dbname}'"))
+    }
+
+    fn open(&self, mut config: postgres::Config) -> Result<Client, ConnectError> {
+        if let Some(ref auth) = self.auth {
+            config.password(auth.password().map_err(ConnectError::Auth)?);
+        }
+        match self.tls {
+            Some(ref tls) => config.connect(MakeTlsConnector::new(tls.clone())),
+            None => config.connect(NoTls),
+        }
+        .map_err(ConnectError::Postgres)
+    }
+}
+
+impl ManageConnection for Manager {
+    type Connection = Client;
+    type Error = ConnectError;
+
+    fn connect(&self) -> Result<Client, ConnectError> {
+        self.open(self.config.clone())
+    }
+
+    fn is_valid(&self, conn: &mut Client) -> Result<(), ConnectError> {
+        conn.simple_query("")
+            .map(|_| ())
+            .map_err(ConnectError::Postgres)
+    }
+
+    fn has_broken(&self, conn: &mut Client) -> bool {
+        conn.is_closed()
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::Postgres(e) => e.fmt(f),
+            ConnectError::Auth(e) => write!(f, "{e:#}"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConnectError::Postgres(e) => e.source(),
+            ConnectError::Auth(_) => None,
+        }
+    }
+}
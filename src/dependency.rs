@@ -0,0 +1,115 @@
+use crate::directive::{self, Directive, Step};
+use crate::meta::MigrationMeta;
+use crate::sql;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The objects a migration touches. `None` means the migration contains statements that could not be
+/// analyzed and must be treated as depending on everything.
+type Relations = Option<HashSet<String>>;
+
+/// Computes the dependencies of each migration in `paths` on the migrations preceding it.
+///
+/// Dependencies declared with `depends_on` in the migration's `meta.toml` take precedence. Otherwise,
+/// a migration depends on every earlier migration touching one of the same relations.
+pub fn dependencies(paths: &[PathBuf]) -> anyhow::Result<Vec<HashSet<usize>>> {
+    let mut relations = Vec::with_capacity(paths.len());
+    let mut deps = Vec::with_capacity(paths.len());
+
+    for (i, path) in paths.iter().enumerate() {
+        let dir = path.parent().unwrap_or(path);
+        let meta = MigrationMeta::load(dir)?;
+
+        if let Some(ref declared) = meta.depends_on {
+            let mut d = HashSet::new();
+            for dep in declared {
+                let Some(j) = paths[..i].iter().position(|p| matches_id(p, dep)) else {
+                    continue;
+                };
+                d.insert(j);
+            }
+            relations.push(None);
+            deps.push(d);
+            continue;
+        }
+
+        let rel = analyze(path, &meta)?;
+
+        let d = relations
+            .iter()
+            .enumerate()
+            .filter(|(_, other): &(_, &Relations)| match (&rel, other) {
+                (Some(rel), Some(other)) => !rel.is_disjoint(other),
+                _ => true,
+            })
+            .map(|(j, _)| j)
+            .collect();
+
+        relations.push(rel);
+        deps.push(d);
+    }
+
+    Ok(deps)
+}
+
+/// Groups the migrations into levels where each migration only depends on migrations in previous levels.
+/// Migrations in the same level are independent of each other.
+pub fn levels(deps: &[HashSet<usize>]) -> Vec<Vec<usize>> {
+    let mut level_of: Vec<usize> = Vec::with_capacity(deps.len());
+    let mut levels: Vec<Vec<usize>> = vec![];
+
+    for (i, d) in deps.iter().enumerate() {
+        let level = d.iter().map(|j| level_of[*j] + 1).max().unwrap_or(0);
+        level_of.push(level);
+        if levels.len() <= level {
+            levels.push(vec![]);
+        }
+        levels[level].push(i);
+    }
+
+    levels
+}
+
+/// Returns true if `dep` is the full id of the migration at `path` or its name without the timestamp.
+fn matches_id(path: &Path, dep: &str) -> bool {
+    let Some(id) = path
+        .parent()
+        .and_then(|p| p.file_name())
+        .and_then(|n| n.to_str())
+    else {
+        return false;
+    };
+    id == dep || id.split_once('_').is_some_and(|(_, name)| name == dep)
+}
+
+fn analyze(path: &Path, meta: &MigrationMeta) -> anyhow::Result<Relations> {
+    let sql = fs::read_to_string(path)?;
+    let mut relations = HashSet::new();
+
+    let mut statements = vec![];
+    for step in directive::steps(&sql, path)? {
+        match step {
+            Step::Sql(sql) | Step::Directive(Directive::Params { sql, .. }) => {
+                statements.extend(sql::statements(&sql))
+            }
+            Step::Directive(Directive::Copy { target, .. }) => {
+                statements.push(format!("INSERT INTO {target}"))
+            }
+            // The views depending on the table are only known to the database
+            Step::Directive(Directive::RecreateViews { .. }) => return Ok(None),
+            Step::Directive(Directive::Irreversible | Directive::WaitUntil { .. }) => {}
+            Step::Directive(Directive::After(statement)) => statements.push(statement),
+        }
+    }
+    statements.extend(meta.up.iter().map(|s| s.statement.clone()));
+
+    for statement in statements {
+        let Some(rel) = sql::relations(&sql::normalize(&statement)) else {
+            return Ok(None);
+        };
+        relations.extend(rel);
+    }
+
+    Ok(Some(relations))
+}
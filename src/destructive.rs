@@ -0,0 +1,377 @@
+use crate::sql;
+use crate::{output, warn};
+use anyhow::Error;
+use postgres::Client;
+use sqlparser::ast::{AlterTableOperation, ObjectType, Statement};
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+/// A statement of a pending migration deleting data which exists in the database.
+#[derive(Debug)]
+pub struct Loss {
+    /// The migration file containing the statement.
+    pub file: PathBuf,
+    pub kind: LossKind,
+    /// The schema qualified, quoted name of the table.
+    pub table: String,
+    pub rows: i64,
+    /// Whether `rows` is the planner's estimate instead of an exact count.
+    pub estimated: bool,
+}
+
+#[derive(Debug)]
+pub enum LossKind {
+    DropTable,
+    Truncate,
+    DropColumn(String),
+}
+
+impl Display for Loss {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rows = approx(self.rows);
+        let rows = if self.estimated {
+            format!("~{rows}")
+        } else {
+            rows
+        };
+        match self.kind {
+            LossKind::DropTable | LossKind::Truncate => {
+                write!(f, "this will delete {rows} rows from {}", self.table)
+            }
+            LossKind::DropColumn(ref column) => write!(
+                f,
+                "this will drop column {column} of {rows} rows from {}",
+                self.table
+            ),
+        }
+    }
+}
+
+/// Scans the migration files for statements dropping or truncating tables and dropping columns,
+/// and estimates how many rows each of them deletes. Tables and columns which do not exist yet,
+/// e.g. because they are created by another pending migration, and empty tables are skipped.
+pub fn scan(pg: &mut Client, files: &[PathBuf]) -> anyhow::Result<Vec<Loss>> {
+    let mut losses = vec![];
+
+    for file in files {
+        let sql = fs::read_to_string(file)?;
+        for statement in sql::statements(&sql) {
+            for (kind, table) in destructive(&statement) {
+                if let Some(loss) = estimate(pg, file, kind, &table)? {
+                    losses.push(loss);
+                }
+            }
+        }
+    }
+
+    Ok(losses)
+}
+
+/// Reports the losses and asks for confirmation when attached to a terminal. Errors unless the
+/// data loss is allowed or confirmed.
+pub fn confirm(losses: &[Loss], allow: bool) -> anyhow::Result<()> {
+    if losses.is_empty() {
+        return Ok(());
+    }
+
+    let interactive = output::interactive();
+    if allow || interactive {
+        for loss in losses {
+            warn!("{}: {loss}", display_file(&loss.file));
+        }
+    }
+
+    if allow {
+        return Ok(());
+    }
+
+    if interactive {
+        // Locked so prompts of concurrently migrated shards do not interleave
+        let mut stdin = io::stdin().lock();
+        eprint!("Apply the migrations anyway? [y/N] ");
+        io::stderr().flush()?;
+        let mut answer = String::new();
+        stdin.read_line(&mut answer)?;
+        if matches!(answer.trim(), "y" | "Y" | "yes") {
+            return Ok(());
+        }
+        return Err(Error::msg("Aborted, no migrations were executed"));
+    }
+
+    Err(Error::msg(format!(
+        "Pending migrations delete data:\n{}\nHint: Pass `--allow-data-loss` to apply them anyway",
+        losses
+            .iter()
+            .map(|loss| format!("  {}: {loss}", display_file(&loss.file)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    )))
+}
+
+/// Returns the tables, and the columns, a statement deletes data from.
+fn destructive(statement: &str) -> Vec<(LossKind, String)> {
+    match sql::parse(statement) {
+        Some(parsed) => parsed_destructive(&parsed),
+        None => keyword_destructive(statement),
+    }
+}
+
+/// Returns the tables, and the columns, a parsed statement deletes data from. Names are kept as
+/// written, schema and quotes included.
+fn parsed_destructive(statement: &Statement) -> Vec<(LossKind, String)> {
+    match statement {
+        Statement::Drop {
+            object_type: ObjectType::Table,
+            names,
+            ..
+        } => names
+            .iter()
+            .map(|name| (LossKind::DropTable, name.to_string()))
+            .collect(),
+        Statement::Truncate { table_names, .. } => table_names
+            .iter()
+            .map(|target| (LossKind::Truncate, target.name.to_string()))
+            .collect(),
+        Statement::AlterTable {
+            name, operations, ..
+        } => operations
+            .iter()
+            .filter_map(|operation| match operation {
+                AlterTableOperation::DropColumn { column_name, .. } => Some((
+                    LossKind::DropColumn(column_name.to_string()),
+                    name.to_string(),
+                )),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Returns the tables, and the columns, a statement the parser does not support deletes data from.
+fn keyword_destructive(statement: &str) -> Vec<(LossKind, String)> {
+    let tokens = statement
+        .replace(',', " , ")
+        .split_whitespace()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    let is = |i: usize, kw: &str| keyword(&tokens, i, kw);
+
+    if is(0, "DROP") && is(1, "TABLE") {
+        let start = if is(2, "IF") && is(3, "EXISTS") { 4 } else { 2 };
+        return names(&tokens[start..], &["CASCADE", "RESTRICT"])
+            .into_iter()
+            .map(|table| (LossKind::DropTable, table))
+            .collect();
+    }
+
+    if is(0, "TRUNCATE") {
+        let mut start = 1;
+        if is(start, "TABLE") {
+            start += 1;
+        }
+        if is(start, "ONLY") {
+            start += 1;
+        }
+        let end = ["RESTART", "CONTINUE", "CASCADE", "RESTRICT"];
+        return names(&tokens[start..], &end)
+            .into_iter()
+            .map(|table| (LossKind::Truncate, table))
+            .collect();
+    }
+
+    if is(0, "ALTER") && is(1, "TABLE") {
+        let mut i = 2;
+        if is(i, "IF") && is(i + 1, "EXISTS") {
+            i += 2;
+        }
+        if is(i, "ONLY") {
+            i += 1;
+        }
+        let Some(table) = tokens.get(i) else {
+            return vec![];
+        };
+        let table = table.trim_end_matches('*');
+
+        // Actions are separated by commas outside of parentheses
+        let rest = &tokens[i + 1..];
+        let mut actions = vec![];
+        let mut depth = 0;
+        let mut start = 0;
+        for (j, token) in rest.iter().enumerate() {
+            depth += token.matches('(').count() as i32 - token.matches(')').count() as i32;
+            if depth == 0 && token == "," {
+                actions.push(&rest[start..j]);
+                start = j + 1;
+            }
+        }
+        actions.push(&rest[start..]);
+
+        return actions
+            .into_iter()
+            .filter_map(dropped_column)
+            .map(|column| (LossKind::DropColumn(column), table.to_string()))
+            .collect();
+    }
+
+    vec![]
+}
+
+/// Returns the column dropped by an `ALTER TABLE` action.
+fn dropped_column(action: &[String]) -> Option<String> {
+    if !keyword(action, 0, "DROP") || keyword(action, 1, "CONSTRAINT") {
+        return None;
+    }
+    let mut i = 1;
+    if keyword(action, i, "COLUMN") {
+        i += 1;
+    }
+    if keyword(action, i, "IF") && keyword(action, i + 1, "EXISTS") {
+        i += 2;
+    }
+    action.get(i).cloned()
+}
+
+fn keyword(tokens: &[String], i: usize, keyword: &str) -> bool {
+    tokens
+        .get(i)
+        .is_some_and(|t| t.eq_ignore_ascii_case(keyword))
+}
+
+/// Collects the comma separated names at the start of `tokens`, up to one of the `end` keywords.
+fn names(tokens: &[String], end: &[&str]) -> Vec<String> {
+    tokens
+        .iter()
+        .take_while(|t| !end.iter().any(|e| t.eq_ignore_ascii_case(e)))
+        .filter(|t| *t != ",")
+        .map(|t| t.trim_end_matches('*').to_string())
+        .collect()
+}
+
+/// Estimates the rows lost to a statement. Returns `None` if the table or column does not exist or
+/// the table is empty. An estimate of 0 rows is not trusted, since it is stale as soon as rows are
+/// inserted into a table analyzed while empty, so the rows are counted instead.
+fn estimate(
+    pg: &mut Client,
+    file: &Path,
+    kind: LossKind,
+    table: &str,
+) -> anyhow::Result<Option<Loss>> {
+    // Partitioned tables have no statistics of their own and -1 means the table was never analyzed
+    let row = pg.query_opt(
+        "SELECT format('%I.%I', n.nspname, c.relname),
+             CASE WHEN c.reltuples < 0 OR c.relkind = 'p' THEN NULL ELSE c.reltuples::BIGINT END
+         FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace
+         WHERE c.oid = to_regclass($1::TEXT) AND c.relkind IN ('r', 'p')",
+        &[&table],
+    )?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let table = row.get::<_, String>(0);
+
+    if let LossKind::DropColumn(ref column) = kind {
+        let exists = pg
+            .query_one(
+                "SELECT EXISTS (SELECT 1 FROM pg_attribute
+                 WHERE attrelid = $1::TEXT::REGCLASS AND attname = $2 AND attnum > 0 AND NOT attisdropped)",
+                &[&table, &ident(column)],
+            )?
+            .get::<_, bool>(0);
+        if !exists {
+            return Ok(None);
+        }
+    }
+
+    let (rows, estimated) = match row.get::<_, Option<i64>>(1) {
+        Some(rows) if rows > 0 => (rows, true),
+        _ => {
+            let rows = pg
+                .query_one(&format!("SELECT count(*) FROM {table}"), &[])?
+                .get::<_, i64>(0);
+            (rows, false)
+        }
+    };
+
+    if rows == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(Loss {
+        file: file.to_path_buf(),
+        kind,
+        table,
+        rows,
+        estimated,
+    }))
+}
+
+/// Resolves an identifier as written in SQL to the name stored in the catalogs.
+pub(crate) fn ident(ident: &str) -> String {
+    match ident.strip_prefix('"').and_then(|i| i.strip_suffix('"')) {
+        Some(quoted) => quoted.replace("\"\"", "\""),
+        None => ident.to_lowercase(),
+    }
+}
+
+/// Formats a row count with a magnitude suffix, e.g. `2.3M`.
+pub(crate) fn approx(rows: i64) -> String {
+    match rows {
+        ..=999 => rows.to_string(),
+        1_000..=999_999 => format!("{:.1}K", rows as f64 / 1e3),
+        1_000_000..=999_999_999 => format!("{:.1}M", rows as f64 / 1e6),
+        _ => format!("{:.1}B", rows as f64 / 1e9),
+    }
+}
+
+/// Shows the migration directory and file, e.g. `2024-01-01-000000_drop_orders/up.sql`.
+pub(crate) fn display_file(file: &Path) -> String {
+    let mut components = file.components().rev().take(2).collect::<Vec<_>>();
+    components.reverse();
+    components
+        .iter()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    /// Needs a disposable database, given by `MIGR_TEST_DATABASE_URL`, and is skipped without one.
+    #[test]
+    fn tables_analyzed_while_empty_are_counted() {
+        let Ok(url) = env::var("MIGR_TEST_DATABASE_URL") else {
+            return;
+        };
+        let table = format!("loss_{}", process::id());
+        let mut pg = Client::connect(&url, postgres::NoTls).unwrap();
+        // Sent one by one, since `VACUUM` can't run in the implicit transaction of a batch
+        for statement in [
+            format!("DROP TABLE IF EXISTS {table}"),
+            format!("CREATE TABLE {table} (id INT)"),
+            format!("VACUUM ANALYZE {table}"),
+            format!("INSERT INTO {table} SELECT generate_series(1, 5000)"),
+        ] {
+            pg.batch_execute(&statement).unwrap();
+        }
+
+        let file = Path::new("up.sql");
+        let loss = estimate(&mut pg, file, LossKind::DropTable, &table)
+            .unwrap()
+            .unwrap();
+        assert_eq!(loss.rows, 5000);
+        assert!(!loss.estimated);
+
+        pg.batch_execute(&format!("TRUNCATE {table}")).unwrap();
+        assert!(estimate(&mut pg, file, LossKind::Truncate, &table)
+            .unwrap()
+            .is_none());
+
+        pg.batch_execute(&format!("DROP TABLE {table}")).unwrap();
+    }
+}
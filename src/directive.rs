@@ -0,0 +1,372 @@
+use crate::cache;
+use anyhow::{Context, Error};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The prefix of comment lines interpreted by migr.
+pub const PREFIX: &str = "-- migr:";
+
+/// How long [Directive::WaitUntil] waits for its condition unless it sets a timeout.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// A step of a migration file. Migration files are split into plain SQL and directives
+/// so they can be executed in the order they are written.
+#[derive(Debug)]
+pub enum Step {
+    Sql(String),
+    Directive(Directive),
+}
+
+#[derive(Debug)]
+pub enum Directive {
+    /// `-- migr:copy <table> FROM <file> [options]`
+    ///
+    /// Streams `file`, relative to the migration directory, into `table` via `COPY FROM STDIN`.
+    Copy {
+        target: String,
+        file: PathBuf,
+        options: String,
+    },
+
+    /// `-- migr:recreate-views <table>`
+    ///
+    /// Executes the SQL following the directive, up to the next directive, with the views depending
+    /// on `table` dropped before and recreated after it, e.g. to change the type of a column they
+    /// select.
+    RecreateViews { table: String, sql: String },
+
+    /// `-- migr:irreversible`
+    ///
+    /// Marks a `down.sql` which can not revert its migration, so `rev` and `redo` refuse to revert it
+    /// instead of executing a placeholder.
+    Irreversible,
+
+    /// `-- migr:after <statement>`
+    ///
+    /// Executes the statement once the migration is committed, outside of a transaction, e.g.
+    /// `REINDEX INDEX CONCURRENTLY`. The migration stays applied if the statement fails.
+    After(String),
+
+    /// `-- migr:wait-until "<query>" [timeout=<duration>]`
+    ///
+    /// Polls the query, which must return a single boolean, until it returns true before
+    /// executing the rest of the migration, e.g. to wait for a queue to drain. Fails the migration
+    /// once the timeout is exceeded.
+    WaitUntil { query: String, timeout: Duration },
+
+    /// `-- migr:params <name> [<name>...]`
+    ///
+    /// Executes the SQL following the directive, up to the next directive, with the values of the
+    /// named parameters bound to the settings `migr.<name>` for its duration, e.g. to read
+    /// environment specific values in a `DO` block with `current_setting('migr.<name>')`.
+    Params { names: Vec<String>, sql: String },
+}
+
+impl Directive {
+    /// Parses the directive following the [PREFIX].
+    fn parse(directive: &str, dir: &Path) -> anyhow::Result<Self> {
+        let (name, args) = directive
+            .trim()
+            .split_once(char::is_whitespace)
+            .unwrap_or((directive.trim(), ""));
+
+        match name {
+            "copy" => {
+                // Uppercasing ASCII only keeps the offsets valid in `args`
+                let Some(from) = args.to_ascii_uppercase().find(" FROM ") else {
+                    return Err(Error::msg(format!(
+                        "Invalid copy directive '{directive}'\nHint: Use `{PREFIX}copy <table> FROM <file> [options]`"
+                    )));
+                };
+                let target = args[..from].trim().to_string();
+                let rest = args[from + 6..].trim();
+                let (file, options) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                Ok(Self::Copy {
+                    target,
+                    file: dir.join(file.trim_matches('\'')),
+                    options: options.trim().to_string(),
+                })
+            }
+            "recreate-views" => {
+                let table = args.trim();
+                if table.is_empty() || table.contains(char::is_whitespace) {
+                    return Err(Error::msg(format!(
+                        "Invalid recreate-views directive '{directive}'\nHint: Use `{PREFIX}recreate-views <table>`"
+                    )));
+                }
+                Ok(Self::RecreateViews {
+                    table: table.to_string(),
+                    sql: String::new(),
+                })
+            }
+            "irreversible" if args.trim().is_empty() => Ok(Self::Irreversible),
+            "irreversible" => Err(Error::msg(format!(
+                "Invalid irreversible directive '{directive}'\nHint: Use `{PREFIX}irreversible`"
+            ))),
+            "after" | "after:" if !args.trim().is_empty() => {
+                Ok(Self::After(args.trim().trim_end_matches(';').to_string()))
+            }
+            "after" | "after:" => Err(Error::msg(format!(
+                "Invalid after directive '{directive}'\nHint: Use `{PREFIX}after <statement>`"
+            ))),
+            "wait-until" => {
+                let invalid = || {
+                    Error::msg(format!(
+                        "Invalid wait-until directive '{directive}'\nHint: Use `{PREFIX}wait-until \"<query>\" [timeout=<duration>]`, e.g. `timeout=10m`"
+                    ))
+                };
+                // Queries can contain double quotes themselves, e.g. to quote identifiers
+                let args = args.trim();
+                let (query, options) = args
+                    .strip_prefix('"')
+                    .and_then(|args| args.rsplit_once('"'))
+                    .ok_or_else(invalid)?;
+                if query.trim().is_empty() {
+                    return Err(invalid());
+                }
+
+                let mut timeout = WAIT_TIMEOUT;
+                for option in options.split_whitespace() {
+                    timeout = option
+                        .strip_prefix("timeout=")
+                        .and_then(duration)
+                        .ok_or_else(invalid)?;
+                }
+                Ok(Self::WaitUntil {
+                    query: query.trim().trim_end_matches(';').to_string(),
+                    timeout,
+                })
+            }
+            "params" => {
+                let names = args
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect::<Vec<_>>();
+                let valid = |name: &String| {
+                    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                };
+                if names.is_empty() || !names.iter().all(valid) {
+                    return Err(Error::msg(format!(
+                        "Invalid params directive '{directive}'\nHint: Use `{PREFIX}params <name>...` with names made of letters, digits and underscores"
+                    )));
+                }
+                Ok(Self::Params {
+                    names,
+                    sql: String::new(),
+                })
+            }
+            _ => Err(Error::msg(format!("Unknown directive '{PREFIX}{name}'"))),
+        }
+    }
+}
+
+/// Parses durations like `500ms`, `30s`, `10m` and `1h`.
+fn duration(s: &str) -> Option<Duration> {
+    let unit = s.find(|c: char| !c.is_ascii_digit())?;
+    let value = s[..unit].parse::<u64>().ok()?;
+    match &s[unit..] {
+        "ms" => Some(Duration::from_millis(value)),
+        "s" => Some(Duration::from_secs(value)),
+        "m" => Some(Duration::from_secs(value * 60)),
+        "h" => Some(Duration::from_secs(value * 3600)),
+        _ => None,
+    }
+}
+
+/// Splits the contents of the migration file at `path` into [Step]s, inlining the files it includes.
+pub fn steps(sql: &str, path: &Path) -> anyhow::Result<Vec<Step>> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    file_steps(sql, &path, &mut vec![])
+}
+
+/// Splits the contents of the file at `path` into [Step]s. `stack` holds the files including it,
+/// to detect files which end up including themselves.
+fn file_steps(sql: &str, path: &Path, stack: &mut Vec<PathBuf>) -> anyhow::Result<Vec<Step>> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut steps = vec![];
+    let mut current = String::new();
+
+    stack.push(path.to_path_buf());
+
+    for line in sql.lines() {
+        if let Some(file) = included(line)? {
+            if !current.trim().is_empty() {
+                push_sql(&mut steps, std::mem::take(&mut current));
+            }
+            current.clear();
+
+            let file = dir.join(file);
+            let file = fs::canonicalize(&file)
+                .with_context(|| format!("Unable to include '{}'", file.display()))?;
+            if stack.contains(&file) {
+                let cycle = stack
+                    .iter()
+                    .chain([&file])
+                    .map(|f| f.display().to_string())
+                    .collect::<Vec<_>>();
+                return Err(Error::msg(format!("Include cycle: {}", cycle.join(" -> "))));
+            }
+
+            let sql = fs::read_to_string(&file)
+                .with_context(|| format!("Unable to include '{}'", file.display()))?;
+            // The included SQL may be what a preceding directive applies to
+            for step in file_steps(&sql, &file, stack)? {
+                match step {
+                    Step::Sql(sql) => push_sql(&mut steps, sql),
+                    step => steps.push(step),
+                }
+            }
+            continue;
+        }
+
+        let Some(directive) = line.trim().strip_prefix(PREFIX) else {
+            current.push_str(line);
+            current.push('\n');
+            continue;
+        };
+
+        if !current.trim().is_empty() {
+            push_sql(&mut steps, std::mem::take(&mut current));
+        }
+        current.clear();
+
+        take_sql(&steps)?;
+        steps.push(Step::Directive(Directive::parse(directive, dir)?));
+    }
+
+    if !current.trim().is_empty() {
+        push_sql(&mut steps, current);
+    }
+    take_sql(&steps)?;
+
+    stack.pop();
+
+    Ok(steps)
+}
+
+/// Returns the checksum of the migration file at `path` with the contents `sql`, covering the
+/// contents of the files it includes, so changing them shows up as drift like changing the
+/// migration does. Files without includes keep the checksum of their contents.
+pub fn checksum(sql: &[u8], path: &Path) -> String {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut files = vec![];
+    file_includes(&String::from_utf8_lossy(sql), &path, &mut files);
+    if files.is_empty() {
+        return cache::checksum(sql);
+    }
+
+    let mut contents = sql.to_vec();
+    for file in files {
+        contents.push(0);
+        contents.extend(fs::read(file).unwrap_or_default());
+    }
+    cache::checksum(&contents)
+}
+
+/// Collects the files included by the file at `path`, directly or through other includes, in the
+/// order they are inlined. Includes which can't be resolved or end up including themselves are
+/// skipped, executing the migration reports them.
+fn file_includes(sql: &str, path: &Path, files: &mut Vec<PathBuf>) {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    for line in sql.lines() {
+        let Ok(Some(file)) = included(line) else {
+            continue;
+        };
+        let Ok(file) = fs::canonicalize(dir.join(file)) else {
+            continue;
+        };
+        if file == path || files.contains(&file) {
+            continue;
+        }
+        files.push(file.clone());
+        if let Ok(sql) = fs::read_to_string(&file) {
+            file_includes(&sql, &file, files);
+        }
+    }
+}
+
+/// Returns the file included by the line with `-- migr:include <file>`, or psql's `\i <file>` and
+/// `\ir <file>`. Files are relative to the including file.
+fn included(line: &str) -> anyhow::Result<Option<&str>> {
+    let line = line.trim();
+
+    let file = match line.strip_prefix(PREFIX) {
+        Some(directive) => match directive.split_once(char::is_whitespace) {
+            Some(("include", file)) => file,
+            _ if directive == "include" => "",
+            _ => return Ok(None),
+        },
+        None => match line.split_once(char::is_whitespace) {
+            Some(("\\i" | "\\ir" | "\\include" | "\\include_relative", file)) => file,
+            _ => return Ok(None),
+        },
+    };
+
+    let file = file.trim().trim_matches('\'');
+    if file.is_empty() {
+        return Err(Error::msg(format!(
+            "Invalid include '{line}'\nHint: Use `{PREFIX}include <file>`"
+        )));
+    }
+
+    Ok(Some(file))
+}
+
+/// Whether the migration file at `path` is missing or marked as [Directive::Irreversible].
+pub fn irreversible(path: &Path) -> anyhow::Result<bool> {
+    let Ok(sql) = fs::read_to_string(path) else {
+        return Ok(!path.exists());
+    };
+    Ok(steps(&sql, path)?
+        .iter()
+        .any(|step| matches!(step, Step::Directive(Directive::Irreversible))))
+}
+
+/// Returns the statements of the [Directive::After] directives of the migration file at `path`.
+pub fn after(path: &Path) -> anyhow::Result<Vec<String>> {
+    let Ok(sql) = fs::read_to_string(path) else {
+        return Ok(vec![]);
+    };
+    Ok(steps(&sql, path)?
+        .into_iter()
+        .filter_map(|step| match step {
+            Step::Directive(Directive::After(statement)) => Some(statement),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Pushes the SQL into the preceding directive if it applies to the SQL following it.
+fn push_sql(steps: &mut Vec<Step>, sql: String) {
+    match steps.last_mut() {
+        Some(Step::Directive(Directive::RecreateViews { sql: target, .. }))
+            if target.is_empty() =>
+        {
+            *target = sql;
+        }
+        Some(Step::Directive(Directive::Params { sql: target, .. })) if target.is_empty() => {
+            *target = sql;
+        }
+        _ => steps.push(Step::Sql(sql)),
+    }
+}
+
+/// Errors if the last step is a directive still waiting for the SQL it applies to.
+fn take_sql(steps: &[Step]) -> anyhow::Result<()> {
+    match steps.last() {
+        Some(Step::Directive(Directive::RecreateViews { table, sql })) if sql.is_empty() => {
+            Err(Error::msg(format!(
+                "The directive '{PREFIX}recreate-views {table}' must be followed by SQL"
+            )))
+        }
+        Some(Step::Directive(Directive::Params { names, sql })) if sql.is_empty() => {
+            Err(Error::msg(format!(
+                "The directive '{PREFIX}params {}' must be followed by SQL",
+                names.join(" ")
+            )))
+        }
+        _ => Ok(()),
+    }
+}
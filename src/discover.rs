@@ -0,0 +1,248 @@
+//! Locating the migrations directory of a project when no path is given.
+
+use crate::{debug, info, output};
+use anyhow::Error;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+/// The name of the directories searched for.
+pub const MIGRATIONS_DIR: &str = "migrations";
+
+/// Files marking the root of a project.
+const PROJECT_MARKERS: &[&str] = &["Cargo.toml", "migr.toml"];
+
+/// A migrations directory found by [find].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub path: PathBuf,
+    /// How many directories the candidate is away from the directory the search started in, in
+    /// either direction.
+    pub distance: usize,
+    /// Whether the directory containing the candidate is the root of a project.
+    pub project: bool,
+}
+
+impl Candidate {
+    fn new(path: PathBuf, distance: usize) -> Self {
+        let project = path
+            .parent()
+            .is_some_and(|parent| PROJECT_MARKERS.iter().any(|m| parent.join(m).is_file()));
+        Self {
+            path,
+            distance,
+            project,
+        }
+    }
+
+    /// Orders candidates from most to least likely. The migrations directory of the starting
+    /// directory comes first, then the ones nearest to a project root.
+    fn rank(&self) -> (bool, Reverse<bool>, usize) {
+        (self.distance != 0, Reverse(self.project), self.distance)
+    }
+}
+
+/// Finds the migrations directories in `start`, its subdirectories up to `max_depth` levels deep
+/// and in the root of the project containing `start`, i.e. the nearest ancestor with a `Cargo.toml`
+/// or `migr.toml`. Skips `target` and directories starting with `.`. Candidates are sorted from most
+/// to least likely, and symlinks to a candidate are dropped in favour of the likelier path.
+pub fn find(start: &Path, max_depth: usize) -> io::Result<Vec<Candidate>> {
+    info!(
+        "Searching for migrations in {}",
+        start.display().to_string().purple()
+    );
+
+    let mut candidates = vec![];
+    search(start, 0, max_depth, &mut candidates)?;
+
+    let root = start
+        .ancestors()
+        .enumerate()
+        .find(|(_, dir)| PROJECT_MARKERS.iter().any(|m| dir.join(m).is_file()));
+    if let Some((distance, root)) = root {
+        let path = root.join(MIGRATIONS_DIR);
+        if distance > 0 && path.is_dir() {
+            debug!("Found project root at {}", root.display());
+            candidates.push(Candidate::new(path, distance));
+        }
+    }
+
+    candidates.sort_by_key(Candidate::rank);
+
+    // Symlinks to a migrations directory found elsewhere are the same candidate
+    let mut seen = HashSet::new();
+    candidates.retain(|c| {
+        let resolved = fs::canonicalize(&c.path).unwrap_or_else(|_| c.path.clone());
+        if seen.contains(&resolved) {
+            debug!("{} resolves to {}", c.path.display(), resolved.display());
+            return false;
+        }
+        seen.insert(resolved)
+    });
+
+    Ok(candidates)
+}
+
+fn search(
+    path: &Path,
+    depth: usize,
+    max_depth: usize,
+    candidates: &mut Vec<Candidate>,
+) -> io::Result<()> {
+    if depth > max_depth || !path.is_dir() {
+        return Ok(());
+    }
+
+    debug!("in {}", path.display().to_string().as_str().blue());
+
+    let mut entries = path
+        .read_dir()?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+
+    for path in entries {
+        let Some(name) = path.file_name() else {
+            continue;
+        };
+        if !path.is_dir() || name == "target" || name.as_encoded_bytes().starts_with(b".") {
+            continue;
+        }
+
+        if name == MIGRATIONS_DIR {
+            candidates.push(Candidate::new(path, depth));
+            continue;
+        }
+
+        search(&path, depth + 1, max_depth, candidates)?;
+    }
+
+    Ok(())
+}
+
+/// Chooses the migrations directory from candidates sorted by [find]. If several are equally
+/// likely, asks which one to use when attached to a terminal and errors otherwise.
+pub fn choose(candidates: &[Candidate]) -> anyhow::Result<PathBuf> {
+    let Some(best) = candidates.first() else {
+        return Err(Error::msg(
+            "Unable to locate migrations directory\nHint: Pass the directory with `--path`",
+        ));
+    };
+
+    let tied = candidates
+        .iter()
+        .take_while(|c| c.rank() == best.rank())
+        .map(|c| c.path.clone())
+        .collect::<Vec<_>>();
+
+    let path = match tied.as_slice() {
+        [path] => path.clone(),
+        _ => ask(&tied)?,
+    };
+
+    info!(
+        "Found migrations at {}",
+        path.display().to_string().purple()
+    );
+
+    Ok(path)
+}
+
+/// Chooses one of the configured candidates which exist, asking which one to use if several do.
+pub fn choose_configured(paths: &[PathBuf]) -> anyhow::Result<PathBuf> {
+    let existing = paths
+        .iter()
+        .filter(|path| path.is_dir())
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let path = match existing.as_slice() {
+        [] => {
+            return Err(Error::msg(format!(
+                "None of the configured migrations directories exist: {}",
+                paths
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )))
+        }
+        [path] => path.clone(),
+        _ => ask(&existing)?,
+    };
+
+    info!(
+        "Found migrations at {}",
+        path.display().to_string().purple()
+    );
+
+    Ok(path)
+}
+
+fn ask(paths: &[PathBuf]) -> anyhow::Result<PathBuf> {
+    let list = paths
+        .iter()
+        .enumerate()
+        .map(|(i, path)| format!("  {}) {}", i + 1, path.display()))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let stdin = io::stdin();
+    if !output::interactive() {
+        return Err(Error::msg(format!(
+            "Found several migrations directories:\n{list}\nHint: Pass the directory with `--path` or list the candidates in `paths` in the config"
+        )));
+    }
+
+    eprintln!("Found several migrations directories:\n{list}");
+    loop {
+        eprint!("Which one should be used? [1-{}] ", paths.len());
+        io::stderr().flush()?;
+
+        let mut answer = String::new();
+        if stdin.lock().read_line(&mut answer)? == 0 {
+            return Err(Error::msg("Aborted"));
+        }
+
+        match answer.trim().parse::<usize>() {
+            Ok(i) if (1..=paths.len()).contains(&i) => return Ok(paths[i - 1].clone()),
+            _ => continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn candidates_are_found_in_the_same_order() {
+        let root = env::temp_dir().join(format!("migr-discover-{}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        // Created out of order, since read order follows creation order on some filesystems
+        for dir in ["zeta", "alpha"] {
+            fs::create_dir_all(root.join(dir).join(MIGRATIONS_DIR)).unwrap();
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(root.join("alpha"), root.join("linked")).unwrap();
+
+        let paths = || {
+            find(&root, 2)
+                .unwrap()
+                .into_iter()
+                .map(|c| c.path)
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![
+            root.join("alpha").join(MIGRATIONS_DIR),
+            root.join("zeta").join(MIGRATIONS_DIR),
+        ];
+        assert_eq!(paths(), expected);
+        assert_eq!(paths(), expected);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,101 @@
+use crate::archive::is_date;
+use crate::config::Config;
+use crate::impact;
+use crate::meta::MigrationMeta;
+use crate::migration::migration_dirs;
+use crate::sql;
+use crate::{summary, DocsArgs};
+use anyhow::Context;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+/// Statements writing to a table.
+const DML: &[&str] = &["INSERT INTO ", "UPDATE ", "DELETE FROM "];
+
+/// Renders a Markdown changelog of the migrations, newest first, with their dates, annotations and
+/// the tables their `up.sql` touches. Writes it to `--output`, or prints it.
+pub fn docs(args: &DocsArgs, path: &Path, config: &Config) -> anyhow::Result<()> {
+    let dirs = migration_dirs(path, config)?;
+
+    let mut out = String::from(
+        "# Migrations\n\n<!-- Generated by `migr docs`, changes are overwritten -->\n",
+    );
+
+    for dir in dirs.iter().rev() {
+        let id = dir.file_name().unwrap_or_default().to_string_lossy();
+        let (version, name) = id.split_once('_').unwrap_or((&id, ""));
+        let meta = MigrationMeta::load(dir)?;
+
+        // Timestamps start with the date, except the one of the initial migration
+        let date = version.get(..10).filter(|date| is_date(date));
+        let _ = match date {
+            Some(date) => writeln!(out, "\n## {date} {name}\n"),
+            None => writeln!(out, "\n## {name}\n"),
+        };
+        let _ = writeln!(out, "`{id}`\n");
+
+        if let Some(ref description) = meta.description {
+            let _ = writeln!(out, "{}\n", description.trim());
+        }
+
+        let sql = fs::read_to_string(dir.join("up.sql")).unwrap_or_default();
+        let tables = tables(&sql);
+        if !tables.is_empty() {
+            let _ = writeln!(
+                out,
+                "- Tables: {}",
+                tables
+                    .iter()
+                    .map(|table| format!("`{table}`"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        if let Some(phase) = meta.phase {
+            let _ = writeln!(out, "- Phase: {phase}");
+        }
+        if let Some(risk) = meta.risk {
+            let _ = writeln!(out, "- Risk: {risk}");
+        }
+        if let Some(ref ticket) = meta.ticket {
+            let _ = writeln!(out, "- Ticket: {ticket}");
+        }
+        if let Some(ref author) = meta.author {
+            let _ = writeln!(out, "- Author: {author}");
+        }
+    }
+
+    let Some(ref file) = args.output else {
+        print!("{out}");
+        return Ok(());
+    };
+    fs::write(file, out).with_context(|| format!("Unable to write '{}'", file.display()))?;
+
+    summary!("Documented {} migrations in {}", dirs.len(), file.display());
+
+    Ok(())
+}
+
+/// Returns the sorted names of the tables the statements of `sql` create, alter or write to.
+fn tables(sql: &str) -> Vec<String> {
+    let mut tables = impact::changes(sql)
+        .into_iter()
+        .map(|change| change.table)
+        .collect::<Vec<_>>();
+
+    for statement in sql::statements(sql) {
+        let normalized = sql::normalize(&statement);
+        if !DML.iter().any(|p| normalized.starts_with(p)) {
+            continue;
+        }
+        // The table written to comes before the ones read from
+        if let Some(table) = sql::relations(&normalized).and_then(|r| r.into_iter().next()) {
+            tables.push(table.to_lowercase());
+        }
+    }
+
+    tables.sort();
+    tables.dedup();
+    tables
+}
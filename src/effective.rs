@@ -0,0 +1,238 @@
+//! Printing the configuration a command would use once the CLI, the env and the config are
+//! resolved, e.g. to find out why migr points at a database.
+
+use crate::cluster::redact;
+use crate::config::{Config, Target};
+use crate::{schema, Migr};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory};
+use colored::Colorize;
+use std::collections::BTreeMap;
+use std::env;
+use std::path::PathBuf;
+
+/// Prints the resolved settings of `target`, or the default database and migrations directory if
+/// there is none, each with where it came from. `path` is the resolved migrations directory.
+/// Passwords in URLs are masked and nothing connects to the database.
+pub fn print(
+    migr: &Migr,
+    matches: &ArgMatches,
+    config: &Config,
+    target: Option<&Target>,
+    path: anyhow::Result<PathBuf>,
+) {
+    let source = |id: &str| source(matches, id);
+
+    let mut rows = vec![];
+
+    let exists = match migr.config.exists() {
+        true => "",
+        false => ", not found",
+    };
+    rows.push((
+        "Config",
+        migr.config.display().to_string(),
+        format!("{}{exists}", source("config")),
+    ));
+
+    if let Some(target) = target {
+        rows.push(("Target", target.name.clone(), source("target")));
+    }
+
+    let (url, from) = match target {
+        Some(Target { url: Some(url), .. }) => {
+            (Some(url.clone()), migr.config.display().to_string())
+        }
+        Some(Target { url_env, .. }) => {
+            let var = url_env.as_deref().unwrap_or("DATABASE_URL");
+            (env::var(var).ok(), format!("env {var}"))
+        }
+        None => (
+            env::var("DATABASE_URL").ok(),
+            String::from("env DATABASE_URL"),
+        ),
+    };
+    rows.push((
+        "Database",
+        url.as_deref()
+            .map(redact)
+            .unwrap_or_else(|| String::from("not set")),
+        from,
+    ));
+
+    let pin = match target {
+        Some(target) => target.pin(),
+        None => config.pin(&migr.config),
+    };
+    let pinned = [
+        pin.database.map(|database| format!("database {database}")),
+        pin.host.map(|host| format!("host {host}")),
+        pin.system_identifier
+            .map(|identifier| format!("system identifier {identifier}")),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+    if !pinned.is_empty() {
+        rows.push((
+            "Expected",
+            pinned.join(", "),
+            migr.config.display().to_string(),
+        ));
+    }
+
+    let auth = &config.auth;
+    let password = match (auth.provider, &auth.password_source, &auth.password_command) {
+        (Some(provider), _, _) => Some(format!("{provider:?} tokens")),
+        (_, Some(source), _) => Some(format!("read from {source}")),
+        (_, _, Some(_)) => Some(String::from("printed by password_command")),
+        _ => None,
+    };
+    if let Some(password) = password {
+        rows.push(("Password", password, migr.config.display().to_string()));
+    }
+
+    if let Some(ref ssh) = migr.ssh {
+        rows.push(("Tunnel", ssh.clone(), source("ssh")));
+    }
+
+    let from = match (target, &migr.path, &migr.crate_name) {
+        (Some(target), _, _) => format!("target {}", target.name),
+        (None, Some(_), _) => source("path"),
+        (None, None, Some(name)) => format!("crate {name}, {}", source("crate_name")),
+        (None, None, None) if !config.paths.is_empty() => {
+            format!("paths in {}", migr.config.display())
+        }
+        (None, None, None) => format!("discovered, depth {}", migr.depth),
+    };
+    let path = match path {
+        Ok(path) => path.display().to_string(),
+        Err(e) => format!(
+            "unresolved, {}",
+            e.to_string().lines().next().unwrap_or_default()
+        ),
+    };
+    rows.push(("Migrations", path, from));
+
+    rows.push((
+        "Metadata",
+        String::from("__migr_meta__"),
+        format!("schema version {}", schema::VERSION),
+    ));
+
+    if let Some(ref role) = config.run_as {
+        rows.push(("Run as", role.clone(), migr.config.display().to_string()));
+    }
+
+    if !config.envs.is_empty() {
+        let envs = config
+            .envs
+            .iter()
+            .map(|env| env.name.as_str())
+            .collect::<Vec<_>>();
+        rows.push((
+            "Environments",
+            envs.join(", "),
+            migr.config.display().to_string(),
+        ));
+    }
+
+    if !config.params.is_empty() {
+        let mut params = config.params.keys().map(String::as_str).collect::<Vec<_>>();
+        params.sort();
+        rows.push((
+            "Params",
+            params.join(", "),
+            migr.config.display().to_string(),
+        ));
+    }
+
+    table(&rows);
+
+    println!();
+    println!("{}", "Flags".bold());
+    let command = Migr::command();
+    let mut flags = vec![];
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        let Some(long) = arg.get_long() else {
+            continue;
+        };
+        if matches.value_source(id).is_none() {
+            continue;
+        }
+        let value = matches
+            .get_raw(id)
+            .map(|values| {
+                values
+                    .map(|v| v.to_string_lossy().into_owned())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+            .unwrap_or_default();
+        let value = match (id, value.is_empty()) {
+            ("verbose", _) => migr.verbose.to_string(),
+            (_, true) => String::from("true"),
+            (_, false) => value,
+        };
+        flags.push((format!("--{long}"), value, source(id)));
+    }
+    table(&flags);
+
+    // Flags of other commands are read from the env by the commands taking them
+    let mut set = BTreeMap::<String, (String, Vec<String>)>::new();
+    for subcommand in command.get_subcommands() {
+        for arg in subcommand.get_arguments() {
+            let (Some(var), Some(long)) = (arg.get_env(), arg.get_long()) else {
+                continue;
+            };
+            let Some(value) = env::var_os(var) else {
+                continue;
+            };
+            let var = var.to_string_lossy().into_owned();
+            let (_, commands) = set
+                .entry(var)
+                .or_insert_with(|| (format!("--{long}={}", value.to_string_lossy()), vec![]));
+            commands.push(subcommand.get_name().to_string());
+        }
+    }
+    if !set.is_empty() {
+        println!();
+        println!("{}", "Set in the env".bold());
+        let set = set
+            .into_iter()
+            .map(|(var, (flag, commands))| (var, flag, commands.join(", ")))
+            .collect::<Vec<_>>();
+        table(&set);
+    }
+}
+
+/// Where the value of the global argument `id` came from.
+fn source(matches: &ArgMatches, id: &str) -> String {
+    let command = Migr::command();
+    match matches.value_source(id) {
+        Some(ValueSource::CommandLine) => String::from("cli"),
+        Some(ValueSource::EnvVariable) => command
+            .get_arguments()
+            .find(|arg| arg.get_id() == id)
+            .and_then(|arg| arg.get_env())
+            .map(|var| format!("env {}", var.to_string_lossy()))
+            .unwrap_or_default(),
+        _ => String::from("default"),
+    }
+}
+
+fn table<K: AsRef<str>>(rows: &[(K, String, String)]) {
+    let width = rows
+        .iter()
+        .map(|(key, ..)| key.as_ref().len())
+        .max()
+        .unwrap_or(0);
+    for (key, value, from) in rows {
+        println!(
+            "{:<width$}  {value}  {}",
+            key.as_ref(),
+            format!("({from})").dimmed()
+        );
+    }
+}
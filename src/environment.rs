@@ -0,0 +1,260 @@
+use crate::cluster::redact;
+use crate::config::{Config, Env};
+use crate::db;
+use crate::migration::{check_table, ensure, migration_dirs};
+use crate::schema::VERSION_COLUMN;
+use crate::ssh::Jump;
+use crate::{info, summary, PromoteArgs, RunRevMigration, StatusArgs};
+use anyhow::Error;
+use colored::Colorize;
+use postgres::Client;
+use r2d2::ManageConnection;
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::path::Path;
+use std::thread;
+
+/// The state of an environment compared to the migrations directory.
+struct EnvStatus {
+    /// Migrations in the directory which are not applied to the environment.
+    pending: usize,
+    /// Migrations which failed and are quarantined until `migr resume`.
+    failed: usize,
+    /// Applied migrations which are not in the directory, e.g. applied from another branch.
+    unknown: usize,
+    /// The version of the latest applied migration.
+    latest: Option<String>,
+}
+
+/// Queries the environments selected by `--env` concurrently and prints how many migrations of
+/// the directory each of them lags behind, so the ones lagging stand out.
+pub fn status(
+    args: &StatusArgs,
+    path: &Path,
+    read_only: bool,
+    jump: Option<&Jump>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let envs = config.envs(args.env.as_deref().unwrap_or("all"))?;
+    let ids = migration_dirs(path, config)?
+        .into_iter()
+        .filter_map(|dir| Some(dir.file_name()?.to_str()?.to_string()))
+        .collect::<Vec<_>>();
+
+    info!("Querying {} environments", envs.len());
+    let outcomes = thread::scope(|s| {
+        let handles = envs
+            .iter()
+            .map(|env| s.spawn(|| env_status(env, &ids, read_only, jump, config)))
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|_| Err(Error::msg("Querying the environment panicked")))
+            })
+            .collect::<Vec<_>>()
+    });
+
+    const HEADER: &str = "Environment";
+    let width = envs
+        .iter()
+        .map(|env| env.name.len() + 3)
+        .max()
+        .unwrap_or(0)
+        .max(HEADER.len());
+    summary!(
+        "{:<width$} {:<8} {:<8} {:<18} {}",
+        HEADER,
+        "pending",
+        "failed",
+        "latest",
+        "unknown"
+    );
+    for (env, outcome) in envs.iter().zip(&outcomes) {
+        let name = &env.name;
+        let Ok(status) = outcome else {
+            summary!("{name:.<width$} {}", "error".red());
+            continue;
+        };
+        let pending = match status.pending {
+            0 => format!("{:<8}", 0).green(),
+            n => format!("{n:<8}").yellow(),
+        };
+        let failed = match status.failed {
+            0 => format!("{:<8}", 0).normal(),
+            n => format!("{n:<8}").red(),
+        };
+        let latest = status.latest.as_deref().unwrap_or("-");
+        let unknown = match status.unknown {
+            0 => "0".normal(),
+            n => n.to_string().red(),
+        };
+        summary!("{name:.<width$} {pending} {failed} {latest:<18} {unknown}");
+    }
+
+    let errors = envs
+        .iter()
+        .zip(outcomes)
+        .filter_map(|(env, outcome)| outcome.err().map(|e| (env, e)))
+        .collect::<Vec<_>>();
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    let mut message = format!(
+        "Unable to query {} of {} environments",
+        errors.len(),
+        envs.len()
+    );
+    for (env, e) in errors {
+        let _ = write!(message, "\n{}: {e:#}", env.name);
+    }
+    Err(Error::msg(message))
+}
+
+/// Compares the migrations applied to an environment with the ones in the directory.
+fn env_status(
+    env: &Env,
+    ids: &[String],
+    read_only: bool,
+    jump: Option<&Jump>,
+    config: &Config,
+) -> anyhow::Result<EnvStatus> {
+    let url = env.url()?;
+    info!("Querying {} at {}", env.name, redact(&url).blue());
+    let mut pg = db::manager(&url, read_only, jump, &config.auth)?.connect()?;
+    pin(&mut pg, &url, env)?;
+    check_table(&mut pg)?;
+
+    let rows = pg.query(
+        &format!("SELECT id, pending, error IS NOT NULL FROM __migr_meta__ ORDER BY {VERSION_COLUMN}, id"),
+        &[],
+    )?;
+    let applied = rows
+        .iter()
+        .filter(|row| !row.get::<_, bool>(1))
+        .map(|row| row.get::<_, String>(0))
+        .collect::<Vec<_>>();
+    let known = ids.iter().collect::<HashSet<_>>();
+    let applied_set = applied.iter().collect::<HashSet<_>>();
+
+    Ok(EnvStatus {
+        pending: ids.iter().filter(|id| !applied_set.contains(id)).count(),
+        failed: rows.iter().filter(|row| row.get::<_, bool>(2)).count(),
+        unknown: applied.iter().filter(|id| !known.contains(id)).count(),
+        latest: applied
+            .last()
+            .map(|id| id.split('_').next().unwrap_or(id).to_string()),
+    })
+}
+
+/// Applies the migrations applied to the `--from` environment but not to the `--to` environment,
+/// and nothing else, so environments promoted from each other converge. Refuses if the target has
+/// migrations applied which the source does not.
+pub fn promote(
+    args: &PromoteArgs,
+    path: &Path,
+    max_connections: u32,
+    jump: Option<&Jump>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    if args.run.exact.is_some() || args.run.count.is_some() {
+        return Err(Error::msg(
+            "`--exact` and `--count` are not supported when promoting\nHint: The migrations to apply are the ones applied to the source",
+        ));
+    }
+    if args.run.canary.is_some() || args.run.cluster.is_set() || args.run.sandbox {
+        return Err(Error::msg(
+            "`--canary`, `--sandbox` and shards are not supported when promoting",
+        ));
+    }
+
+    let from = config.env(&args.from)?;
+    let to = config.env(&args.to)?;
+
+    let source = from.url()?;
+    info!(
+        "Reading the applied migrations of {} at {}",
+        from.name,
+        redact(&source).blue()
+    );
+    let mut pg = db::manager(&source, true, jump, &config.auth)?.connect()?;
+    pin(&mut pg, &source, from)?;
+    check_table(&mut pg)?;
+    let source = applied(&mut pg)?;
+
+    let target = to.url()?;
+    info!("Promoting to {} at {}", to.name, redact(&target).blue());
+    let pool = db::pool(&target, max_connections, false, jump, &config.auth)?;
+    let target = {
+        let mut pg = pool.get()?;
+        pin(&mut pg, &target, to)?;
+        let exists = pg
+            .query_one("SELECT to_regclass('__migr_meta__') IS NOT NULL", &[])?
+            .get::<_, bool>(0);
+        match exists {
+            true => applied(&mut pg)?,
+            false => HashSet::new(),
+        }
+    };
+
+    let mut extra = target.difference(&source).collect::<Vec<_>>();
+    if !extra.is_empty() {
+        extra.sort();
+        return Err(Error::msg(format!(
+            "{} has migrations applied which {} does not:\n  {}\nHint: Revert them on {} first, promoting only ever adds migrations",
+            to.name,
+            from.name,
+            extra.iter().map(|id| id.as_str()).collect::<Vec<_>>().join("\n  "),
+            to.name
+        )));
+    }
+
+    let only = source.difference(&target).cloned().collect::<HashSet<_>>();
+    if only.is_empty() {
+        summary!("{} is level with {}", to.name, from.name);
+        return Ok(());
+    }
+
+    let ids = migration_dirs(path, config)?
+        .into_iter()
+        .filter_map(|dir| Some(dir.file_name()?.to_str()?.to_string()))
+        .collect::<HashSet<_>>();
+    let mut missing = only.difference(&ids).collect::<Vec<_>>();
+    if !missing.is_empty() {
+        missing.sort();
+        return Err(Error::msg(format!(
+            "Migrations applied to {} are missing from the migrations directory:\n  {}\nHint: Check out the revision {} was migrated from",
+            from.name,
+            missing.iter().map(|id| id.as_str()).collect::<Vec<_>>().join("\n  "),
+            from.name
+        )));
+    }
+
+    info!(
+        "Applying {} migrations applied to {}",
+        only.len(),
+        from.name
+    );
+    let args = RunRevMigration {
+        only: Some(only),
+        ..args.run.clone()
+    };
+    ensure(&args, path.to_path_buf(), &pool, config)
+}
+
+/// Returns the IDs of the migrations applied to the database.
+fn applied(pg: &mut Client) -> anyhow::Result<HashSet<String>> {
+    Ok(pg
+        .query("SELECT id FROM __migr_meta__ WHERE NOT pending", &[])?
+        .into_iter()
+        .map(|row| row.get(0))
+        .collect())
+}
+
+/// Errors unless `pg` is connected to the database the environment pins, if any.
+fn pin(pg: &mut Client, url: &str, env: &Env) -> anyhow::Result<()> {
+    db::check_pin(pg, url, &env.pin())
+}
@@ -0,0 +1,113 @@
+use crate::destructive::{approx, display_file};
+use crate::sql;
+use crate::{debug, info, warn};
+use postgres::{Client, SimpleQueryMessage};
+use serde_json::Value;
+use std::fs;
+use std::path::PathBuf;
+
+/// Sequential scans over tables estimated to hold at least this many rows are reported as warnings.
+const HUGE_TABLE_ROWS: i64 = 1_000_000;
+
+/// Explains the `UPDATE` and `DELETE` statements of the migration files without executing them and
+/// reports how many rows the planner estimates each of them to touch, warning about sequential
+/// scans over huge tables. Statements which can't be planned yet, e.g. because they depend on
+/// earlier statements of the pending migrations, are skipped.
+pub fn report(pg: &mut Client, files: &[PathBuf]) -> anyhow::Result<()> {
+    let mut explained = 0;
+
+    for file in files {
+        let sql = fs::read_to_string(file)?;
+        for statement in sql::statements(&sql) {
+            let normalized = sql::normalize(&statement);
+            let verb = match () {
+                _ if normalized.starts_with("UPDATE ") => "updates",
+                _ if normalized.starts_with("DELETE ") => "deletes",
+                _ => continue,
+            };
+
+            let plan = match explain(pg, &statement) {
+                Ok(plan) => plan,
+                Err(e) => {
+                    debug!(
+                        "{}: Unable to explain `{normalized}`: {e:#}",
+                        display_file(file)
+                    );
+                    continue;
+                }
+            };
+            explained += 1;
+
+            let root = &plan[0]["Plan"];
+            let (schema, name) = relation(root);
+            let table = format!("{schema}.{name}");
+            // The modified rows are the output of the scan below the modifying node
+            let rows = root["Plans"][0]["Plan Rows"].as_f64().unwrap_or_default() as i64;
+
+            let mut scans = vec![];
+            seq_scans(root, &mut scans);
+
+            let mut huge = vec![];
+            for (schema, name) in scans {
+                // -1 means the table was never analyzed
+                let rows = pg
+                    .query_opt(
+                        "SELECT CASE WHEN c.reltuples < 0 THEN 0 ELSE c.reltuples::BIGINT END
+                         FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace
+                         WHERE n.nspname = $1 AND c.relname = $2",
+                        &[&schema, &name],
+                    )?
+                    .map_or(0, |row| row.get::<_, i64>(0));
+                if rows >= HUGE_TABLE_ROWS {
+                    huge.push(format!("{schema}.{name} (~{})", approx(rows)));
+                }
+            }
+
+            let file = display_file(file);
+            match huge.is_empty() {
+                true => info!("{file}: {verb} ~{} rows of {table}", approx(rows)),
+                false => warn!(
+                    "{file}: {verb} ~{} rows of {table} with a sequential scan of {}",
+                    approx(rows),
+                    huge.join(", ")
+                ),
+            }
+        }
+    }
+
+    if explained == 0 {
+        info!("No updates or deletes to explain");
+    }
+
+    Ok(())
+}
+
+/// Returns the plan of the statement in the JSON format.
+fn explain(pg: &mut Client, statement: &str) -> anyhow::Result<Value> {
+    let messages = pg.simple_query(&format!("EXPLAIN (VERBOSE, FORMAT JSON) {statement}"))?;
+    let plan = messages
+        .iter()
+        .find_map(|message| match message {
+            SimpleQueryMessage::Row(row) => row.get(0),
+            _ => None,
+        })
+        .unwrap_or("[{}]");
+    Ok(serde_json::from_str(plan)?)
+}
+
+/// Collects the schemas and names of the relations scanned sequentially by the node and its
+/// children.
+fn seq_scans(node: &Value, scans: &mut Vec<(String, String)>) {
+    if node["Node Type"] == "Seq Scan" {
+        scans.push(relation(node));
+    }
+    for child in node["Plans"].as_array().into_iter().flatten() {
+        seq_scans(child, scans);
+    }
+}
+
+/// Returns the schema and name of the relation of the node.
+fn relation(node: &Value) -> (String, String) {
+    let field = |key: &str| node[key].as_str().unwrap_or_default().to_string();
+    (field("Schema"), field("Relation Name"))
+}
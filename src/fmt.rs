@@ -0,0 +1,92 @@
+use crate::config::FmtConfig;
+use crate::sql;
+use sqlformat::{FormatOptions, Indent, QueryParams};
+
+/// Formats a migration file with the formatting options from the config. The comment block at the
+/// top of the file, e.g. its header, is kept as is. Returns `None` if the formatter changed anything
+/// besides whitespace and the case of keywords, which happens with syntax it does not understand.
+pub fn format(sql: &str, config: &FmtConfig) -> Option<String> {
+    let body_start = sql
+        .lines()
+        .take_while(|line| line.trim().is_empty() || line.trim_start().starts_with("--"))
+        .map(|line| line.len() + 1)
+        .sum::<usize>()
+        .min(sql.len());
+    let (header, body) = sql.split_at(body_start);
+
+    if body.trim().is_empty() {
+        return Some(sql.to_string());
+    }
+
+    let options = FormatOptions {
+        indent: match config.tabs {
+            true => Indent::Tabs,
+            false => Indent::Spaces(config.indent),
+        },
+        uppercase: config.uppercase,
+        lines_between_queries: config.lines_between_statements,
+    };
+    let (protected, bodies) = protect(body);
+    let mut formatted = sqlformat::format(&protected, &QueryParams::None, options);
+
+    for (i, body) in bodies.iter().enumerate() {
+        formatted = formatted.replacen(&placeholder(i), body, 1);
+    }
+
+    if squash(&formatted) != squash(body) {
+        return None;
+    }
+
+    Some(format!("{header}{}\n", formatted.trim_end()))
+}
+
+/// Replaces dollar quoted bodies, e.g. of functions, with placeholders so their contents are kept
+/// as written. Returns the bodies to put back in place of the placeholders.
+fn protect(sql: &str) -> (String, Vec<&str>) {
+    let mut protected = String::new();
+    let mut bodies = vec![];
+    let mut i = 0;
+
+    while let Some(c) = sql[i..].chars().next() {
+        let rest = &sql[i..];
+
+        // Comments, string literals and quoted identifiers may contain dollar signs
+        let end = if rest.starts_with("--") {
+            rest.find('\n').map(|e| i + e)
+        } else if rest.starts_with("/*") {
+            rest.find("*/").map(|e| i + e + 2)
+        } else if c == '\'' || c == '"' {
+            rest[1..].find(c).map(|e| i + e + 2)
+        } else if let Some(tag) = (c == '$').then(|| sql::dollar_tag(rest)).flatten() {
+            let end = rest[tag.len()..]
+                .find(tag)
+                .map(|e| i + tag.len() + e + tag.len())
+                .unwrap_or(sql.len());
+            protected.push_str(&placeholder(bodies.len()));
+            bodies.push(&sql[i..end]);
+            i = end;
+            continue;
+        } else {
+            Some(i + c.len_utf8())
+        };
+
+        let end = end.unwrap_or(sql.len());
+        protected.push_str(&sql[i..end]);
+        i = end;
+    }
+
+    (protected, bodies)
+}
+
+/// A string literal the formatter keeps intact.
+fn placeholder(i: usize) -> String {
+    format!("'__migr_body_{i}__'")
+}
+
+/// Strips whitespace and case from `sql`, leaving what the formatter must not change.
+fn squash(sql: &str) -> String {
+    sql.chars()
+        .filter(|c| !c.is_whitespace())
+        .flat_map(char::to_uppercase)
+        .collect()
+}
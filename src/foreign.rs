@@ -0,0 +1,131 @@
+use crate::destructive::display_file;
+use crate::sql;
+use crate::warn;
+use anyhow::Error;
+use postgres::Client;
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+
+/// Statements starting with these prefixes only define routines, so the `dblink` calls in their
+/// bodies are not executed by the migration.
+const ROUTINE_PREFIXES: &[&str] = &[
+    "CREATE FUNCTION ",
+    "CREATE OR REPLACE FUNCTION ",
+    "CREATE PROCEDURE ",
+    "CREATE OR REPLACE PROCEDURE ",
+];
+
+/// A statement of a pending migration reaching a foreign server or another database. Its effects
+/// there are not part of the transaction of the migration and are not rolled back with it.
+#[derive(Debug)]
+pub struct Foreign {
+    /// The migration file containing the statement.
+    pub file: PathBuf,
+    pub reason: String,
+}
+
+/// Scans the migration files for statements calling `dblink` functions, importing foreign schemas,
+/// or reading or writing the foreign tables which exist in the database.
+pub fn scan(pg: &mut Client, files: &[PathBuf]) -> anyhow::Result<Vec<Foreign>> {
+    let tables = pg
+        .query(
+            "SELECT upper(c.relname::TEXT) FROM pg_foreign_table f JOIN pg_class c ON c.oid = f.ftrelid",
+            &[],
+        )?
+        .into_iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<HashSet<_>>();
+
+    let mut found = vec![];
+
+    for file in files {
+        let sql = fs::read_to_string(file)?;
+        for statement in sql::statements(&sql) {
+            let statement = sql::normalize(&statement);
+            let mut reasons = vec![];
+
+            if !ROUTINE_PREFIXES.iter().any(|p| statement.starts_with(p)) {
+                for function in dblink_calls(&statement) {
+                    reasons.push(format!("calls {}", function.to_lowercase()));
+                }
+            }
+            if statement.starts_with("IMPORT FOREIGN SCHEMA ") {
+                reasons.push(String::from("imports a schema from a foreign server"));
+            }
+            let mut relations = sql::relations(&statement).unwrap_or_default();
+            relations.sort();
+            relations.dedup();
+            for table in relations.iter().filter(|table| tables.contains(*table)) {
+                reasons.push(format!("touches foreign table {}", table.to_lowercase()));
+            }
+
+            found.extend(reasons.into_iter().map(|reason| Foreign {
+                file: file.clone(),
+                reason,
+            }));
+        }
+    }
+
+    Ok(found)
+}
+
+/// Errors unless statements reaching outside of the database are allowed, in which case they are
+/// reported as warnings.
+pub fn check(found: &[Foreign], allow: bool) -> anyhow::Result<()> {
+    if found.is_empty() {
+        return Ok(());
+    }
+
+    if allow {
+        for foreign in found {
+            warn!(
+                "{}: {}, which is not rolled back if the migration fails",
+                display_file(&foreign.file),
+                foreign.reason
+            );
+        }
+        return Ok(());
+    }
+
+    Err(Error::msg(format!(
+        "Pending migrations reach outside of the database, which is not rolled back if they fail:\n{}\nHint: Pass `--allow-fdw` to apply them anyway",
+        found
+            .iter()
+            .map(|foreign| format!("  {}: {}", display_file(&foreign.file), foreign.reason))
+            .collect::<Vec<_>>()
+            .join("\n")
+    )))
+}
+
+/// Returns the `dblink` functions called by a [normalized][sql::normalize] statement, e.g.
+/// `DBLINK_EXEC`, skipping string literals and quoted identifiers.
+fn dblink_calls(statement: &str) -> Vec<&str> {
+    let mut calls = vec![];
+    let mut quote = None;
+    let mut start = None;
+
+    for (i, c) in statement.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => continue,
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None => {}
+        }
+
+        if c.is_ascii_alphanumeric() || c == '_' {
+            start.get_or_insert(i);
+            continue;
+        }
+        let Some(s) = start.take() else {
+            continue;
+        };
+        let word = &statement[s..i];
+        let call = statement[i..].trim_start().starts_with('(');
+        if word.starts_with("DBLINK") && call {
+            calls.push(word);
+        }
+    }
+
+    calls
+}
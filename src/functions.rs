@@ -0,0 +1,94 @@
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::sql::quote_ident;
+use crate::{debug, info, trace};
+use anyhow::Context;
+use colored::Colorize;
+use postgres::GenericClient;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+
+pub use migr_common::FUNCTIONS_DIR;
+
+/// Returns the names of the `.sql` files in the functions directory of the migrations at `path`
+/// and their checksums, sorted by name. Returns nothing if the directory does not exist.
+pub fn checksums(path: &Path) -> anyhow::Result<Vec<(String, String)>> {
+    let cache = Cache::load(path)?;
+    let mut files = cache
+        .files(FUNCTIONS_DIR)
+        .filter(|name| name.ends_with(".sql"))
+        .filter_map(|name| {
+            let checksum = cache.checksum(FUNCTIONS_DIR, name)?;
+            Some((name.to_string(), checksum.to_string()))
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
+
+/// Executes the function definitions whose checksum changed since they were last applied, in the
+/// order of their names. Definitions must be idempotent, e.g. `CREATE OR REPLACE FUNCTION`, since
+/// they are executed again on every change. Returns the number of executed definitions.
+pub fn apply(path: &Path, pg: &mut impl GenericClient, config: &Config) -> anyhow::Result<usize> {
+    let files = checksums(path)?;
+    if files.is_empty() {
+        return Ok(0);
+    }
+
+    let applied = pg
+        .query("SELECT name, checksum FROM __migr_functions__", &[])?
+        .into_iter()
+        .map(|row| (row.get::<_, String>(0), row.get::<_, String>(1)))
+        .collect::<HashMap<_, _>>();
+
+    let changed = files
+        .into_iter()
+        .filter(|(name, checksum)| applied.get(name) != Some(checksum))
+        .collect::<Vec<_>>();
+
+    if changed.is_empty() {
+        debug!("Functions unchanged");
+        return Ok(0);
+    }
+
+    let mut tx = pg.transaction()?;
+
+    for (name, checksum) in &changed {
+        let started = Instant::now();
+        let file = path.join(FUNCTIONS_DIR).join(name);
+        let sql = fs::read_to_string(&file)
+            .with_context(|| format!("Unable to read '{}'", file.display()))?;
+
+        if let Some(ref role) = config.run_as {
+            tx.batch_execute(&format!("SET LOCAL ROLE {}", quote_ident(role)))?;
+        }
+        trace!("{}", sql.trim());
+        tx.batch_execute(&sql).with_context(|| {
+            format!(
+                "while applying functions {}",
+                file.display().to_string().red()
+            )
+        })?;
+        if config.run_as.is_some() {
+            tx.batch_execute("RESET ROLE")?;
+        }
+
+        tx.execute(
+            "INSERT INTO __migr_functions__(name, checksum, applied_at) VALUES ($1, $2, now())
+             ON CONFLICT (name) DO UPDATE SET checksum = EXCLUDED.checksum, applied_at = EXCLUDED.applied_at",
+            &[name, checksum],
+        )?;
+
+        info!(
+            "Applied functions {} in {:.2?}",
+            name.blue(),
+            started.elapsed()
+        );
+    }
+
+    tx.commit()?;
+
+    Ok(changed.len())
+}
@@ -0,0 +1,125 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::process::Command;
+
+/// A commit touching a migration.
+#[derive(Debug, Clone)]
+pub struct Commit {
+    pub hash: String,
+    pub author: String,
+    pub date: String,
+    pub subject: String,
+}
+
+/// Separates the fields of a commit in the log output.
+const FORMAT: &str = "--format=%x00%h%x09%an%x09%ad%x09%s";
+
+/// Returns the author from the git config in the form of `name <email>`.
+pub fn author() -> Option<String> {
+    let git_config = |key| {
+        let output = Command::new("git").args(["config", key]).output().ok()?;
+        let value = String::from_utf8(output.stdout).ok()?;
+        let value = value.trim();
+        (!value.is_empty()).then(|| value.to_string())
+    };
+
+    let name = git_config("user.name")?;
+
+    match git_config("user.email") {
+        Some(email) => Some(format!("{name} <{email}>")),
+        None => Some(name),
+    }
+}
+
+/// Returns the commits which added each migration directory in the migrations directory at `path`,
+/// keyed by migration ID. Returns `None` if `path` is not inside a git repository.
+pub fn introduced(path: &Path) -> Option<HashMap<String, Commit>> {
+    let log = log(
+        path,
+        &["--diff-filter=A", "--name-only", "--relative", "--", "."],
+    )?;
+
+    let mut introduced = HashMap::new();
+
+    // The log is ordered from newest to oldest, so the oldest commit adding a file wins
+    for entry in log.split('\0').filter(|e| !e.is_empty()) {
+        let mut lines = entry.lines();
+        let Some(commit) = lines.next().and_then(parse) else {
+            continue;
+        };
+        for file in lines.filter(|l| !l.is_empty()) {
+            let Some((id, _)) = file.split_once('/') else {
+                continue;
+            };
+            introduced.insert(id.to_string(), commit.clone());
+        }
+    }
+
+    Some(introduced)
+}
+
+/// Returns all commits touching the migration directory at `path`, from newest to oldest.
+/// Returns `None` if `path` is not inside a git repository.
+pub fn history(path: &Path) -> Option<Vec<Commit>> {
+    let log = log(path, &["--", "."])?;
+    Some(
+        log.split('\0')
+            .filter_map(|e| e.lines().next().and_then(parse))
+            .collect(),
+    )
+}
+
+/// Returns the IDs of migrations in the migrations directory at `path` which have untracked files
+/// or changes not yet committed. Returns `None` if `path` is not inside a git repository.
+pub fn uncommitted(path: &Path) -> Option<HashSet<String>> {
+    let untracked = git(
+        path,
+        &["ls-files", "--others", "--exclude-standard", "--", "."],
+    )?;
+
+    // Without any commits, every tracked file is uncommitted
+    let changed = git(
+        path,
+        &["diff", "HEAD", "--name-only", "--relative", "--", "."],
+    )
+    .or_else(|| git(path, &["ls-files", "--", "."]))?;
+
+    Some(
+        untracked
+            .lines()
+            .chain(changed.lines())
+            .filter_map(|file| file.split_once('/'))
+            .map(|(id, _)| id.to_string())
+            .collect(),
+    )
+}
+
+fn log(path: &Path, args: &[&str]) -> Option<String> {
+    git(path, &[&["log", FORMAT, "--date=short"], args].concat())
+}
+
+/// Runs git in `path` and returns its output, or `None` if it failed.
+fn git(path: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(path)
+        .args(args)
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8(output.stdout).ok()
+}
+
+fn parse(line: &str) -> Option<Commit> {
+    let mut fields = line.splitn(4, '\t');
+    Some(Commit {
+        hash: fields.next()?.to_string(),
+        author: fields.next()?.to_string(),
+        date: fields.next()?.to_string(),
+        subject: fields.next().unwrap_or_default().to_string(),
+    })
+}
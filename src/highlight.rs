@@ -0,0 +1,145 @@
+use crate::sql;
+use colored::Colorize;
+
+/// Keywords highlighted in SQL. Anything else is left as is.
+const KEYWORDS: &[&str] = &[
+    "ADD",
+    "ALL",
+    "ALTER",
+    "AND",
+    "AS",
+    "ASC",
+    "BEGIN",
+    "BETWEEN",
+    "BY",
+    "CASCADE",
+    "CASE",
+    "CHECK",
+    "COLUMN",
+    "COMMENT",
+    "COMMIT",
+    "CONCURRENTLY",
+    "CONSTRAINT",
+    "CREATE",
+    "DEFAULT",
+    "DELETE",
+    "DESC",
+    "DISTINCT",
+    "DO",
+    "DROP",
+    "ELSE",
+    "END",
+    "ENUM",
+    "EXISTS",
+    "EXTENSION",
+    "FOREIGN",
+    "FROM",
+    "FUNCTION",
+    "GRANT",
+    "GROUP",
+    "HAVING",
+    "IF",
+    "IN",
+    "INDEX",
+    "INSERT",
+    "INTO",
+    "IS",
+    "JOIN",
+    "KEY",
+    "LANGUAGE",
+    "LEFT",
+    "LIKE",
+    "LIMIT",
+    "MATERIALIZED",
+    "NOT",
+    "NULL",
+    "ON",
+    "OR",
+    "ORDER",
+    "PRIMARY",
+    "REFERENCES",
+    "RENAME",
+    "REPLACE",
+    "RESTRICT",
+    "RETURN",
+    "RETURNS",
+    "REVOKE",
+    "ROLLBACK",
+    "SCHEMA",
+    "SELECT",
+    "SEQUENCE",
+    "SET",
+    "TABLE",
+    "THEN",
+    "TO",
+    "TRIGGER",
+    "TRUNCATE",
+    "TYPE",
+    "UNIQUE",
+    "UPDATE",
+    "USING",
+    "VALUES",
+    "VIEW",
+    "WHEN",
+    "WHERE",
+    "WITH",
+];
+
+/// Highlights keywords, literals and comments of a SQL script for the terminal. Returns the script
+/// unchanged when colors are disabled.
+pub fn sql(script: &str) -> String {
+    let mut out = String::new();
+    let mut i = 0;
+
+    while let Some(c) = script[i..].chars().next() {
+        let rest = &script[i..];
+
+        let (end, colored) = if rest.starts_with("--") {
+            let end = rest.find('\n').map_or(script.len(), |e| i + e);
+            (end, script[i..end].dimmed().to_string())
+        } else if rest.starts_with("/*") {
+            let end = rest.find("*/").map_or(script.len(), |e| i + e + 2);
+            (end, script[i..end].dimmed().to_string())
+        } else if c == '\'' {
+            let end = rest[1..].find('\'').map_or(script.len(), |e| i + e + 2);
+            (end, script[i..end].green().to_string())
+        } else if c == '"' {
+            let end = rest[1..].find('"').map_or(script.len(), |e| i + e + 2);
+            (end, script[i..end].to_string())
+        } else if let Some(tag) = (c == '$').then(|| sql::dollar_tag(rest)).flatten() {
+            // Bodies are highlighted like the rest of the script, their quotes like strings
+            let body_end = rest[tag.len()..].find(tag).map(|e| tag.len() + e);
+            let Some(body_end) = body_end else {
+                out.push_str(rest);
+                break;
+            };
+            out.push_str(&tag.green().to_string());
+            out.push_str(&sql(&rest[tag.len()..body_end]));
+            out.push_str(&tag.green().to_string());
+            i += body_end + tag.len();
+            continue;
+        } else if c.is_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !c.is_alphanumeric() && c != '_' && c != '$')
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            let colored = match KEYWORDS.iter().any(|k| word.eq_ignore_ascii_case(k)) {
+                true => word.blue().bold().to_string(),
+                false => word.to_string(),
+            };
+            (i + len, colored)
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_digit() && c != '.')
+                .unwrap_or(rest.len());
+            (i + len, rest[..len].yellow().to_string())
+        } else {
+            (i + c.len_utf8(), c.to_string())
+        };
+
+        out.push_str(&colored);
+        i = end;
+    }
+
+    out
+}
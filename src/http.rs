@@ -0,0 +1,101 @@
+use anyhow::{Context, Error};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sends a request with `body` to `url` and returns the response status code.
+///
+/// This is a minimal HTTP/1.1 client for reporting to monitoring endpoints and webhooks. Plain
+/// `http://` URLs are handled directly, `https://` URLs are delegated to `curl`.
+pub fn send(method: &str, url: &str, content_type: &str, body: &[u8]) -> anyhow::Result<u16> {
+    if url.starts_with("https://") {
+        return curl(method, url, content_type, body);
+    }
+
+    let rest = url.strip_prefix("http://").ok_or_else(|| {
+        Error::msg(format!(
+            "Unsupported URL '{url}', expected an `http://` or `https://` URL"
+        ))
+    })?;
+
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+
+    // Credentials in the URL are not supported, strip them from the host
+    let host = authority.rsplit('@').next().unwrap_or(authority);
+    let addr = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{host}:80")
+    };
+
+    let addr = addr
+        .to_socket_addrs()
+        .with_context(|| format!("Unable to resolve '{host}'"))?
+        .next()
+        .ok_or_else(|| Error::msg(format!("Unable to resolve '{host}'")))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)
+        .with_context(|| format!("Unable to connect to '{host}'"))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let head = format!(
+        "{method} {path} HTTP/1.1\r\nHost: {host}\r\nUser-Agent: migr/{}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        env!("CARGO_PKG_VERSION"),
+        body.len()
+    );
+
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+
+    let mut response = vec![];
+    stream.read_to_end(&mut response)?;
+
+    let response = String::from_utf8_lossy(&response);
+    response
+        .lines()
+        .next()
+        .and_then(|status| status.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| Error::msg(format!("Invalid response from '{host}'")))
+}
+
+fn curl(method: &str, url: &str, content_type: &str, body: &[u8]) -> anyhow::Result<u16> {
+    let null = if cfg!(windows) { "NUL" } else { "/dev/null" };
+
+    let mut child = Command::new("curl")
+        .args(["--silent", "--show-error", "--request", method])
+        .args(["--header", &format!("Content-Type: {content_type}")])
+        .args(["--max-time", &TIMEOUT.as_secs().to_string()])
+        .args(["--data-binary", "@-", "--output", null])
+        .args(["--write-out", "%{http_code}", url])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Unable to run `curl`\nHint: `https://` URLs require curl to be installed")?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(body)?;
+    }
+
+    let output = child.wait_with_output()?;
+
+    if !output.status.success() {
+        return Err(Error::msg(format!(
+            "curl failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .map_err(|_| Error::msg(format!("Invalid response from '{url}'")))
+}
@@ -0,0 +1,200 @@
+use crate::sql;
+
+/// Objects created with `CREATE <object> IF NOT EXISTS`.
+const IF_NOT_EXISTS: &[&str] = &["TABLE", "SCHEMA", "SEQUENCE", "EXTENSION"];
+
+/// Objects created with `CREATE OR REPLACE <object>`.
+const OR_REPLACE: &[&str] = &["VIEW", "FUNCTION", "PROCEDURE"];
+
+/// Objects dropped with `DROP <object> IF EXISTS`.
+const IF_EXISTS: &[&str] = &[
+    "TABLE",
+    "VIEW",
+    "SEQUENCE",
+    "SCHEMA",
+    "FUNCTION",
+    "PROCEDURE",
+    "TYPE",
+    "DOMAIN",
+    "EXTENSION",
+    "TRIGGER",
+    "INDEX",
+];
+
+/// Keywords following `ADD` in `ALTER TABLE` which add a constraint instead of a column.
+const CONSTRAINT_KEYWORDS: &[&str] = &[
+    "CONSTRAINT",
+    "PRIMARY",
+    "UNIQUE",
+    "CHECK",
+    "FOREIGN",
+    "EXCLUDE",
+];
+
+/// A word, quoted identifier, literal or single punctuation character of a script.
+pub(crate) struct Token<'a> {
+    pub(crate) end: usize,
+    pub(crate) text: &'a str,
+}
+
+/// Rewrites the statements of a script which Postgres can make idempotent to their idempotent
+/// forms, e.g. `CREATE TABLE` to `CREATE TABLE IF NOT EXISTS`, keeping everything else as written.
+/// Returns the script and the number of rewrites.
+pub fn rewrite(script: &str) -> (String, usize) {
+    let tokens = tokenize(script);
+    let mut edits = vec![];
+
+    for statement in tokens.split(|t| t.text == ";") {
+        edit(statement, &mut edits);
+    }
+
+    let mut rewritten = script.to_string();
+    for (at, insert) in edits.iter().rev() {
+        rewritten.insert_str(*at, insert);
+    }
+
+    (rewritten, edits.len())
+}
+
+/// Collects the insertions making the statement idempotent, in the order of their offsets.
+fn edit(t: &[Token], edits: &mut Vec<(usize, String)>) {
+    let kw = |i: usize, keyword: &str| {
+        t.get(i)
+            .is_some_and(|t| t.text.eq_ignore_ascii_case(keyword))
+    };
+    let any = |i: usize, keywords: &[&str]| keywords.iter().any(|k| kw(i, k));
+    let mut insert = |i: usize, keywords: &str| {
+        // Match the case of the statement
+        let keywords = match t[0].text.chars().any(char::is_uppercase) {
+            true => keywords.to_string(),
+            false => keywords.to_lowercase(),
+        };
+        edits.push((t[i].end, keywords));
+    };
+
+    if kw(0, "CREATE") && !kw(1, "OR") {
+        let mut i = 1;
+        while any(
+            i,
+            &["UNLOGGED", "TEMP", "TEMPORARY", "GLOBAL", "LOCAL", "UNIQUE"],
+        ) {
+            i += 1;
+        }
+
+        if any(i, IF_NOT_EXISTS) && !kw(i + 1, "IF") {
+            insert(i, " IF NOT EXISTS");
+        } else if kw(i, "MATERIALIZED") && kw(i + 1, "VIEW") && !kw(i + 2, "IF") {
+            insert(i + 1, " IF NOT EXISTS");
+        } else if kw(i, "INDEX") {
+            // Indexes without a name can't be created conditionally
+            let i = if kw(i + 1, "CONCURRENTLY") { i + 1 } else { i };
+            if !kw(i + 1, "IF") && !kw(i + 1, "ON") {
+                insert(i, " IF NOT EXISTS");
+            }
+        } else if any(i, OR_REPLACE) {
+            insert(0, " OR REPLACE");
+        }
+    } else if kw(0, "DROP") {
+        let i = match () {
+            _ if kw(1, "MATERIALIZED") && kw(2, "VIEW") => 2,
+            _ if kw(1, "INDEX") && kw(2, "CONCURRENTLY") => 2,
+            _ if any(1, IF_EXISTS) => 1,
+            _ => return,
+        };
+        if !kw(i + 1, "IF") {
+            insert(i, " IF EXISTS");
+        }
+    } else if kw(0, "ALTER") && kw(1, "TABLE") {
+        let mut i = 2;
+        if kw(i, "IF") {
+            i += 2;
+        }
+        if kw(i, "ONLY") {
+            i += 1;
+        }
+
+        // The table name, possibly schema qualified and followed by `*`
+        i += 1;
+        while t.get(i).is_some_and(|t| t.text == ".") {
+            i += 2;
+        }
+        if t.get(i).is_some_and(|t| t.text == "*") {
+            i += 1;
+        }
+
+        // Actions are separated by commas outside of parentheses
+        let mut depth = 0;
+        let mut action = true;
+        for (j, token) in t.iter().enumerate().skip(i) {
+            if action && kw(j, "ADD") {
+                if kw(j + 1, "COLUMN") && !kw(j + 2, "IF") {
+                    insert(j + 1, " IF NOT EXISTS");
+                } else if !kw(j + 1, "COLUMN")
+                    && !kw(j + 1, "IF")
+                    && !any(j + 1, CONSTRAINT_KEYWORDS)
+                {
+                    insert(j, " COLUMN IF NOT EXISTS");
+                }
+            } else if action && kw(j, "DROP") {
+                let i = if any(j + 1, &["COLUMN", "CONSTRAINT"]) {
+                    j + 1
+                } else {
+                    j
+                };
+                if !kw(i + 1, "IF") {
+                    insert(i, " IF EXISTS");
+                }
+            }
+
+            action = false;
+            match token.text {
+                "(" => depth += 1,
+                ")" => depth -= 1,
+                "," if depth == 0 => action = true,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Splits a script into tokens, skipping whitespace and comments. String literals, quoted
+/// identifiers and dollar quoted bodies are single tokens, so statements inside of them are never
+/// rewritten.
+pub(crate) fn tokenize(script: &str) -> Vec<Token<'_>> {
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while let Some(c) = script[i..].chars().next() {
+        let rest = &script[i..];
+
+        let end = if c.is_whitespace() {
+            i += c.len_utf8();
+            continue;
+        } else if rest.starts_with("--") {
+            i = rest.find('\n').map_or(script.len(), |e| i + e);
+            continue;
+        } else if rest.starts_with("/*") {
+            i = rest.find("*/").map_or(script.len(), |e| i + e + 2);
+            continue;
+        } else if c == '\'' || c == '"' {
+            rest[1..].find(c).map_or(script.len(), |e| i + e + 2)
+        } else if let Some(tag) = (c == '$').then(|| sql::dollar_tag(rest)).flatten() {
+            rest[tag.len()..]
+                .find(tag)
+                .map_or(script.len(), |e| i + tag.len() + e + tag.len())
+        } else if c.is_alphanumeric() || c == '_' {
+            rest.find(|c: char| !c.is_alphanumeric() && c != '_' && c != '$')
+                .map_or(script.len(), |e| i + e)
+        } else {
+            i + c.len_utf8()
+        };
+
+        tokens.push(Token {
+            end,
+            text: &script[i..end],
+        });
+        i = end;
+    }
+
+    tokens
+}
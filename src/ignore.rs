@@ -0,0 +1,27 @@
+use crate::config::Config;
+use std::path::Path;
+
+pub use migr_common::{has_timestamp, IGNORE_FILE};
+
+/// Glob patterns of entries in the migrations directory which are not migrations.
+#[derive(Debug, Default)]
+pub struct Ignore {
+    patterns: Vec<String>,
+}
+
+impl Ignore {
+    /// Loads the patterns from `.migrignore` in the migrations directory at `path` and the `ignore`
+    /// entry of the config. The ignore file contains a pattern per line, lines starting with `#`
+    /// are comments.
+    pub fn load(path: &Path, config: &Config) -> std::io::Result<Self> {
+        let mut patterns = config.ignore.clone();
+        patterns.extend(migr_common::ignore_file(path)?);
+        Ok(Self { patterns })
+    }
+
+    /// Returns true if the entry `name` matches any of the patterns or holds files other than
+    /// migrations, e.g. functions.
+    pub fn is_ignored(&self, name: &str) -> bool {
+        migr_common::is_ignored(&self.patterns, name)
+    }
+}
@@ -0,0 +1,396 @@
+use crate::config::Config;
+use crate::idempotent::{tokenize, Token};
+use crate::migration::migration_dirs;
+use crate::sql;
+use crate::{info, summary, WhichMigrationsArgs};
+use sqlparser::ast::{AlterTableOperation, Expr, Ident, ObjectName, ObjectType, Statement};
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+
+/// Keywords starting a table constraint instead of a column definition.
+const CONSTRAINT_KEYWORDS: &[&str] = &[
+    "CONSTRAINT",
+    "PRIMARY",
+    "UNIQUE",
+    "CHECK",
+    "FOREIGN",
+    "EXCLUDE",
+    "LIKE",
+];
+
+/// What a statement does to a table or column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Action {
+    Create,
+    Alter,
+    Index,
+    Rename,
+    Drop,
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Create => write!(f, "creates"),
+            Action::Alter => write!(f, "alters"),
+            Action::Index => write!(f, "indexes"),
+            Action::Rename => write!(f, "renames"),
+            Action::Drop => write!(f, "drops"),
+        }
+    }
+}
+
+/// A change a statement makes to the structure of a table, or one of its columns.
+#[derive(Debug)]
+pub(crate) struct Change {
+    pub(crate) action: Action,
+    pub(crate) table: String,
+    pub(crate) column: Option<String>,
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.column {
+            Some(ref column) => write!(f, "{} {}.{column}", self.action, self.table),
+            None => write!(f, "{} {}", self.action, self.table),
+        }
+    }
+}
+
+/// Lists the migrations whose `up.sql` creates, alters, indexes, renames or drops the table given
+/// by `--table`, or only its column given by `--column`, in the order they are applied.
+pub fn which_migrations(
+    args: &WhichMigrationsArgs,
+    path: &Path,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let table = ident(args.table.rsplit('.').next().unwrap_or_default());
+    let column = args.column.as_deref().map(ident);
+    let mut found = 0;
+
+    for dir in migration_dirs(path, config)? {
+        let Ok(up) = fs::read_to_string(dir.join("up.sql")) else {
+            continue;
+        };
+        let matching = changes(&up)
+            .into_iter()
+            .filter(|change| change.table == table)
+            .filter(|change| match (&column, &change.column) {
+                (None, _) => true,
+                (Some(column), Some(changed)) => column == changed,
+                // The column goes with the table
+                (Some(_), None) => matches!(change.action, Action::Rename | Action::Drop),
+            })
+            .map(|change| change.to_string())
+            .collect::<Vec<_>>();
+        if matching.is_empty() {
+            continue;
+        }
+
+        found += 1;
+        summary!(
+            "{}: {}",
+            dir.file_name().unwrap_or_default().to_string_lossy().blue(),
+            matching.join(", ")
+        );
+    }
+
+    let subject = match column {
+        Some(column) => format!("{table}.{column}"),
+        None => table,
+    };
+    match found {
+        0 => info!("No migrations change {subject}"),
+        _ => info!("{found} migrations change {subject}"),
+    }
+
+    Ok(())
+}
+
+/// Returns the changes the statements of a script make to the structure of tables. Names are
+/// lowercased and stripped of their schema.
+pub(crate) fn changes(script: &str) -> Vec<Change> {
+    let mut changes = vec![];
+
+    for statement in sql::statements(script) {
+        match sql::parse(&statement) {
+            Some(parsed) => parsed_changes(&parsed, &mut changes),
+            None => keyword_changes(&tokenize(&statement), &mut changes),
+        }
+    }
+
+    changes
+}
+
+fn parsed_changes(statement: &Statement, changes: &mut Vec<Change>) {
+    let mut push = |action: Action, table: &ObjectName, column: Option<&Ident>| {
+        changes.push(Change {
+            action,
+            table: parsed_ident(table.0.last()),
+            column: column.map(|column| parsed_ident(Some(column))),
+        })
+    };
+
+    match statement {
+        Statement::CreateTable(table) => {
+            push(Action::Create, &table.name, None);
+            for column in &table.columns {
+                push(Action::Create, &table.name, Some(&column.name));
+            }
+        }
+        Statement::CreateIndex(index) => {
+            push(Action::Index, &index.table_name, None);
+            for column in &index.columns {
+                // Expressions are not columns
+                if let Expr::Identifier(ref ident) = column.expr {
+                    push(Action::Index, &index.table_name, Some(ident));
+                }
+            }
+        }
+        Statement::Drop {
+            object_type: ObjectType::Table,
+            names,
+            ..
+        } => {
+            for name in names {
+                push(Action::Drop, name, None);
+            }
+        }
+        Statement::AlterTable {
+            name, operations, ..
+        } => {
+            for operation in operations {
+                match operation {
+                    AlterTableOperation::AddColumn { column_def, .. } => {
+                        push(Action::Create, name, Some(&column_def.name))
+                    }
+                    AlterTableOperation::DropColumn { column_name, .. } => {
+                        push(Action::Drop, name, Some(column_name))
+                    }
+                    AlterTableOperation::AlterColumn { column_name, .. } => {
+                        push(Action::Alter, name, Some(column_name))
+                    }
+                    AlterTableOperation::RenameColumn {
+                        old_column_name,
+                        new_column_name,
+                    } => {
+                        push(Action::Rename, name, Some(old_column_name));
+                        push(Action::Rename, name, Some(new_column_name));
+                    }
+                    AlterTableOperation::RenameTable { table_name } => {
+                        push(Action::Rename, name, None);
+                        push(Action::Rename, table_name, None);
+                    }
+                    _ => push(Action::Alter, name, None),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Lowercases unquoted identifiers like Postgres does.
+fn parsed_ident(ident: Option<&Ident>) -> String {
+    match ident {
+        Some(ident) if ident.quote_style.is_some() => ident.value.clone(),
+        Some(ident) => ident.value.to_lowercase(),
+        None => String::new(),
+    }
+}
+
+/// Collects the changes of a statement the parser does not support from its tokens.
+fn keyword_changes(t: &[Token], changes: &mut Vec<Change>) {
+    let kw = |i: usize, keyword: &str| {
+        t.get(i)
+            .is_some_and(|t| t.text.eq_ignore_ascii_case(keyword))
+    };
+    let mut push = |action: Action, table: &str, column: Option<String>| {
+        changes.push(Change {
+            action,
+            table: table.to_string(),
+            column,
+        })
+    };
+
+    if kw(0, "CREATE") {
+        let mut i = 1;
+        while ["UNLOGGED", "TEMP", "TEMPORARY", "GLOBAL", "LOCAL", "UNIQUE"]
+            .iter()
+            .any(|k| kw(i, k))
+        {
+            i += 1;
+        }
+
+        if kw(i, "TABLE") {
+            i += 1;
+            if kw(i, "IF") {
+                i += 3;
+            }
+            let Some((table, i)) = name(t, i) else {
+                return;
+            };
+            push(Action::Create, &table, None);
+            if t.get(i).is_some_and(|t| t.text == "(") {
+                for definition in elements(&t[i + 1..]) {
+                    let Some(first) = definition.first() else {
+                        continue;
+                    };
+                    if !CONSTRAINT_KEYWORDS
+                        .iter()
+                        .any(|k| first.text.eq_ignore_ascii_case(k))
+                    {
+                        push(Action::Create, &table, Some(ident(first.text)));
+                    }
+                }
+            }
+        } else if kw(i, "INDEX") {
+            let Some(on) = t.iter().position(|t| t.text.eq_ignore_ascii_case("ON")) else {
+                return;
+            };
+            let i = if kw(on + 1, "ONLY") { on + 2 } else { on + 1 };
+            let Some((table, mut i)) = name(t, i) else {
+                return;
+            };
+            push(Action::Index, &table, None);
+            if kw(i, "USING") {
+                i += 2;
+            }
+            if t.get(i).is_some_and(|t| t.text == "(") {
+                for element in elements(&t[i + 1..]) {
+                    // Expressions are not columns
+                    match element {
+                        [column] => push(Action::Index, &table, Some(ident(column.text))),
+                        [column, next, ..] if column.text != "(" && next.text != "(" => {
+                            push(Action::Index, &table, Some(ident(column.text)))
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    } else if kw(0, "DROP") && kw(1, "TABLE") {
+        let mut i = if kw(2, "IF") { 4 } else { 2 };
+        while let Some((table, next)) = name(t, i) {
+            push(Action::Drop, &table, None);
+            if t.get(next).map(|t| t.text) != Some(",") {
+                break;
+            }
+            i = next + 1;
+        }
+    } else if kw(0, "ALTER") && kw(1, "TABLE") {
+        let mut i = 2;
+        if kw(i, "IF") {
+            i += 2;
+        }
+        if kw(i, "ONLY") {
+            i += 1;
+        }
+        let Some((table, mut i)) = name(t, i) else {
+            return;
+        };
+        if t.get(i).is_some_and(|t| t.text == "*") {
+            i += 1;
+        }
+
+        for action in elements_at_depth_zero(&t[i..]) {
+            let kw = |i: usize, keyword: &str| {
+                action
+                    .get(i)
+                    .is_some_and(|t| t.text.eq_ignore_ascii_case(keyword))
+            };
+            let column = |i: usize| {
+                let i = if kw(i, "COLUMN") { i + 1 } else { i };
+                let i = match () {
+                    _ if kw(i, "IF") && kw(i + 1, "NOT") => i + 3,
+                    _ if kw(i, "IF") => i + 2,
+                    _ => i,
+                };
+                action.get(i).map(|t| ident(t.text))
+            };
+
+            if kw(0, "ADD") && !CONSTRAINT_KEYWORDS.iter().any(|k| kw(1, k)) {
+                push(Action::Create, &table, column(1));
+            } else if kw(0, "DROP") && !kw(1, "CONSTRAINT") {
+                push(Action::Drop, &table, column(1));
+            } else if kw(0, "ALTER") && !kw(1, "CONSTRAINT") {
+                push(Action::Alter, &table, column(1));
+            } else if kw(0, "RENAME") && kw(1, "TO") {
+                push(Action::Rename, &table, None);
+                if let Some((renamed, _)) = name(action, 2) {
+                    push(Action::Rename, &renamed, None);
+                }
+            } else if kw(0, "RENAME") && !kw(1, "CONSTRAINT") {
+                let from = if kw(1, "COLUMN") { 2 } else { 1 };
+                push(Action::Rename, &table, column(1));
+                if kw(from + 1, "TO") {
+                    if let Some(to) = action.get(from + 2) {
+                        push(Action::Rename, &table, Some(ident(to.text)));
+                    }
+                }
+            } else {
+                push(Action::Alter, &table, None);
+            }
+        }
+    }
+}
+
+/// Reads the possibly schema qualified name starting at `i`, returning it without the schema and
+/// the index after it.
+fn name(t: &[Token], mut i: usize) -> Option<(String, usize)> {
+    let mut name = t.get(i)?.text;
+    while t.get(i + 1).is_some_and(|t| t.text == ".") {
+        name = t.get(i + 2)?.text;
+        i += 2;
+    }
+    if name.starts_with(|c: char| !c.is_alphanumeric() && c != '_' && c != '"') {
+        return None;
+    }
+    Some((ident(name), i + 1))
+}
+
+/// Lowercases unquoted identifiers like Postgres does and strips the quotes of quoted ones.
+fn ident(text: &str) -> String {
+    match text.starts_with('"') {
+        true => text.trim_matches('"').to_string(),
+        false => text.to_lowercase(),
+    }
+}
+
+/// Splits the tokens after an opening parenthesis into the comma separated elements inside of it.
+fn elements<'a, 'b>(t: &'a [Token<'b>]) -> Vec<&'a [Token<'b>]> {
+    let mut depth = 0;
+    let end = t
+        .iter()
+        .position(|t| {
+            match t.text {
+                "(" => depth += 1,
+                ")" if depth == 0 => return true,
+                ")" => depth -= 1,
+                _ => {}
+            }
+            false
+        })
+        .unwrap_or(t.len());
+    elements_at_depth_zero(&t[..end])
+}
+
+/// Splits tokens at the commas outside of parentheses.
+fn elements_at_depth_zero<'a, 'b>(t: &'a [Token<'b>]) -> Vec<&'a [Token<'b>]> {
+    let mut elements = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, token) in t.iter().enumerate() {
+        match token.text {
+            "(" => depth += 1,
+            ")" => depth -= 1,
+            "," if depth == 0 => {
+                elements.push(&t[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    elements.push(&t[start..]);
+    elements
+}
@@ -0,0 +1,309 @@
+use anyhow::Error;
+use postgres::types::Oid;
+use postgres::Client;
+use std::fmt::Write;
+
+/// A database object read from the system catalogs.
+#[derive(Debug)]
+pub enum Object {
+    Table(Table),
+    View {
+        name: String,
+        materialized: bool,
+        definition: String,
+    },
+    Function {
+        /// The signature of the function, e.g. `public.add(integer, integer)`.
+        signature: String,
+        procedure: bool,
+        definition: String,
+    },
+}
+
+#[derive(Debug)]
+pub struct Table {
+    /// The schema qualified, quoted name of the table.
+    pub name: String,
+    pub columns: Vec<Column>,
+    pub constraints: Vec<Constraint>,
+    pub indexes: Vec<Index>,
+    /// Sequences owned by columns of the table, e.g. the ones created for `SERIAL` columns.
+    pub sequences: Vec<(String, String)>,
+}
+
+#[derive(Debug)]
+pub struct Column {
+    /// The quoted name of the column.
+    pub name: String,
+    pub ty: String,
+    pub not_null: bool,
+    pub default: Option<String>,
+    /// `ALWAYS` or `BY DEFAULT` for identity columns.
+    pub identity: Option<&'static str>,
+    /// Whether `default` is the expression of a stored generated column.
+    pub generated: bool,
+}
+
+#[derive(Debug)]
+pub struct Constraint {
+    /// The quoted name of the constraint.
+    pub name: String,
+    pub definition: String,
+}
+
+#[derive(Debug)]
+pub struct Index {
+    pub name: String,
+    pub definition: String,
+    /// Whether the index backs a constraint, in which case it is created with the constraint.
+    pub constraint: bool,
+}
+
+/// Reads the object with the given name. Tables, views and materialized views are looked up with
+/// the search path like in SQL, functions and procedures by name or by their signature.
+/// Returns several objects for overloaded functions.
+pub fn objects(pg: &mut Client, name: &str) -> anyhow::Result<Vec<Object>> {
+    let relation = pg.query_opt(
+        "SELECT c.oid, format('%I.%I', n.nspname, c.relname), c.relkind::TEXT
+         FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace
+         WHERE c.oid = to_regclass($1::TEXT)",
+        &[&name],
+    )?;
+
+    if let Some(row) = relation {
+        let (oid, name, kind) = (row.get::<_, Oid>(0), row.get(1), row.get::<_, String>(2));
+        return match kind.as_str() {
+            "r" | "p" => Ok(vec![Object::Table(table(pg, oid, name)?)]),
+            "v" | "m" => {
+                let definition = pg
+                    .query_one("SELECT pg_get_viewdef($1::OID, TRUE)", &[&oid])?
+                    .get::<_, String>(0);
+                Ok(vec![Object::View {
+                    name,
+                    materialized: kind == "m",
+                    definition: definition.trim().trim_end_matches(';').to_string(),
+                }])
+            }
+            _ => Err(Error::msg(format!(
+                "'{name}' is neither a table, a view nor a function"
+            ))),
+        };
+    }
+
+    // Aggregates and window functions have no definition to capture
+    let query = if name.contains('(') {
+        "SELECT p.oid::regprocedure::TEXT, p.prokind = 'p', pg_get_functiondef(p.oid)
+         FROM pg_proc p WHERE p.oid = to_regprocedure($1::TEXT) AND p.prokind IN ('f', 'p')"
+    } else {
+        "SELECT p.oid::regprocedure::TEXT, p.prokind = 'p', pg_get_functiondef(p.oid)
+         FROM pg_proc p JOIN pg_namespace n ON n.oid = p.pronamespace
+         WHERE p.prokind IN ('f', 'p')
+         AND CASE WHEN strpos($1, '.') > 0
+             THEN n.nspname = split_part($1, '.', 1) AND p.proname = split_part($1, '.', 2)
+             ELSE p.proname = $1 AND pg_function_is_visible(p.oid)
+         END
+         ORDER BY 1"
+    };
+
+    let functions = pg
+        .query(query, &[&name])?
+        .into_iter()
+        .map(|row| Object::Function {
+            signature: row.get(0),
+            procedure: row.get(1),
+            definition: row.get::<_, String>(2).trim().to_string(),
+        })
+        .collect::<Vec<_>>();
+
+    if functions.is_empty() {
+        return Err(Error::msg(format!(
+            "No table, view or function named '{name}'"
+        )));
+    }
+
+    Ok(functions)
+}
+
+fn table(pg: &mut Client, oid: Oid, name: String) -> anyhow::Result<Table> {
+    let columns = pg
+        .query(
+            "SELECT quote_ident(a.attname), format_type(a.atttypid, a.atttypmod), a.attnotnull,
+                    pg_get_expr(d.adbin, d.adrelid), a.attidentity::TEXT, a.attgenerated::TEXT
+             FROM pg_attribute a
+             LEFT JOIN pg_attrdef d ON d.adrelid = a.attrelid AND d.adnum = a.attnum
+             WHERE a.attrelid = $1 AND a.attnum > 0 AND NOT a.attisdropped
+             ORDER BY a.attnum",
+            &[&oid],
+        )?
+        .into_iter()
+        .map(|row| Column {
+            name: row.get(0),
+            ty: row.get(1),
+            not_null: row.get(2),
+            default: row.get(3),
+            identity: match row.get::<_, String>(4).as_str() {
+                "a" => Some("ALWAYS"),
+                "d" => Some("BY DEFAULT"),
+                _ => None,
+            },
+            generated: row.get::<_, String>(5) == "s",
+        })
+        .collect();
+
+    // Primary keys and unique constraints first, as foreign keys may reference them
+    let constraints = pg
+        .query(
+            "SELECT quote_ident(conname), pg_get_constraintdef(oid)
+             FROM pg_constraint WHERE conrelid = $1
+             ORDER BY contype = 'f', contype <> 'p', conname",
+            &[&oid],
+        )?
+        .into_iter()
+        .map(|row| Constraint {
+            name: row.get(0),
+            definition: row.get(1),
+        })
+        .collect();
+
+    let indexes = pg
+        .query(
+            "SELECT quote_ident(c.relname), pg_get_indexdef(i.indexrelid),
+                    EXISTS (SELECT 1 FROM pg_constraint WHERE conindid = i.indexrelid AND conrelid = i.indrelid)
+             FROM pg_index i JOIN pg_class c ON c.oid = i.indexrelid
+             WHERE i.indrelid = $1
+             ORDER BY c.relname",
+            &[&oid],
+        )?
+        .into_iter()
+        .map(|row| Index {
+            name: row.get(0),
+            definition: row.get(1),
+            constraint: row.get(2),
+        })
+        .collect();
+
+    let sequences = pg
+        .query(
+            "SELECT format('%I.%I', n.nspname, s.relname), quote_ident(a.attname)
+             FROM pg_depend d
+             JOIN pg_class s ON s.oid = d.objid AND s.relkind = 'S'
+             JOIN pg_namespace n ON n.oid = s.relnamespace
+             JOIN pg_attribute a ON a.attrelid = d.refobjid AND a.attnum = d.refobjsubid
+             WHERE d.refobjid = $1 AND d.deptype = 'a'
+             ORDER BY 1",
+            &[&oid],
+        )?
+        .into_iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect();
+
+    Ok(Table {
+        name,
+        columns,
+        constraints,
+        indexes,
+        sequences,
+    })
+}
+
+impl Object {
+    /// Returns the statements creating the object.
+    pub fn create(&self) -> String {
+        match self {
+            Object::Table(table) => table.create(),
+            Object::View {
+                name,
+                materialized,
+                definition,
+            } => {
+                let kind = if *materialized {
+                    "MATERIALIZED VIEW"
+                } else {
+                    "VIEW"
+                };
+                format!("CREATE {kind} {name} AS\n{definition};\n")
+            }
+            Object::Function { definition, .. } => format!("{definition};\n"),
+        }
+    }
+
+    /// Returns the statement dropping the object.
+    pub fn drop(&self) -> String {
+        match self {
+            Object::Table(table) => format!("DROP TABLE {};\n", table.name),
+            Object::View {
+                name,
+                materialized: true,
+                ..
+            } => format!("DROP MATERIALIZED VIEW {name};\n"),
+            Object::View { name, .. } => format!("DROP VIEW {name};\n"),
+            Object::Function {
+                signature,
+                procedure: true,
+                ..
+            } => format!("DROP PROCEDURE {signature};\n"),
+            Object::Function { signature, .. } => format!("DROP FUNCTION {signature};\n"),
+        }
+    }
+}
+
+impl Table {
+    fn create(&self) -> String {
+        let mut sql = String::new();
+
+        for (sequence, _) in &self.sequences {
+            let _ = writeln!(sql, "CREATE SEQUENCE {sequence};");
+        }
+
+        let mut lines = self
+            .columns
+            .iter()
+            .map(|c| {
+                let mut line = format!("{} {}", c.name, c.ty);
+                if let Some(identity) = c.identity {
+                    let _ = write!(line, " GENERATED {identity} AS IDENTITY");
+                }
+                match c.default {
+                    Some(ref expr) if c.generated => {
+                        let _ = write!(line, " GENERATED ALWAYS AS ({expr}) STORED");
+                    }
+                    Some(ref expr) => {
+                        let _ = write!(line, " DEFAULT {expr}");
+                    }
+                    None => {}
+                }
+                if c.not_null {
+                    line.push_str(" NOT NULL");
+                }
+                line
+            })
+            .collect::<Vec<_>>();
+
+        lines.extend(
+            self.constraints
+                .iter()
+                .map(|c| format!("CONSTRAINT {} {}", c.name, c.definition)),
+        );
+
+        let _ = writeln!(
+            sql,
+            "CREATE TABLE {} (\n    {}\n);",
+            self.name,
+            lines.join(",\n    ")
+        );
+
+        for index in self.indexes.iter().filter(|i| !i.constraint) {
+            let _ = writeln!(sql, "{};", index.definition);
+        }
+
+        for (sequence, column) in &self.sequences {
+            let _ = writeln!(
+                sql,
+                "ALTER SEQUENCE {sequence} OWNED BY {}.{column};",
+                self.name
+            );
+        }
+
+        sql
+    }
+}
@@ -0,0 +1,936 @@
+use clap::builder::BoolishValueParser;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Args, Parser, Subcommand};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+pub use config::Config;
+pub use meta::{Phase, Risk};
+pub use migration::{assert_up_to_date, health, run_pending_with, MigrationHealth};
+pub use summary::{Executed, Report};
+
+mod analyze;
+pub mod archive;
+mod auth;
+mod backup;
+pub mod baseline;
+mod cache;
+pub mod canary;
+pub mod chaos;
+pub mod cluster;
+pub mod compat;
+pub mod config;
+pub mod db;
+mod dependency;
+mod destructive;
+mod directive;
+pub mod discover;
+pub mod docs;
+pub mod effective;
+pub mod environment;
+mod explain;
+mod fmt;
+mod foreign;
+mod functions;
+mod git;
+mod highlight;
+mod http;
+mod idempotent;
+mod ignore;
+pub mod impact;
+pub mod init;
+mod introspect;
+mod lint;
+mod lock;
+mod meta;
+mod metrics;
+pub mod migration;
+mod notify;
+pub mod output;
+mod picker;
+pub mod plugin;
+mod policy;
+mod risk;
+mod safe_ddl;
+mod scaffold;
+mod schema;
+pub mod scrub;
+pub mod snippet;
+mod sql;
+pub mod ssh;
+mod summary;
+pub mod template;
+pub mod test;
+mod views;
+mod volume;
+pub mod workspace;
+
+#[derive(Debug, Parser)]
+#[command(name = "migr", author = "biblius", version = "0.1", about = "Minimal PG migration tool", long_about = None)]
+pub struct Migr {
+    #[clap(subcommand)]
+    pub command: MigrationSubcommand,
+
+    /// If provided, migr will setup/load migrations in the given directory.
+    #[arg(long, short, env = "MIGR_PATH")]
+    pub path: Option<PathBuf>,
+
+    /// Use the migrations directory of the crate with the given name in the Cargo workspace
+    /// containing the current directory, e.g. `billing-service`.
+    #[arg(
+        long = "crate",
+        global = true,
+        env = "MIGR_CRATE",
+        value_name = "NAME",
+        conflicts_with = "target"
+    )]
+    pub crate_name: Option<String>,
+
+    /// If a path is not provided, migr will search for a 'migrations' directory `depth` levels deep from the current one.
+    #[arg(long, short, env = "MIGR_DEPTH", default_value = "2")]
+    pub depth: usize,
+
+    /// The maximum number of connections migr will open to the database.
+    #[arg(long, env = "MIGR_MAX_CONNECTIONS", default_value = "8")]
+    pub max_connections: u32,
+
+    /// Run the command against the target with the given name from the config, or `all` targets
+    /// in the order they are configured.
+    #[arg(long, global = true, env = "MIGR_TARGET")]
+    pub target: Option<String>,
+
+    /// Path to the migr config file.
+    #[arg(long, env = "MIGR_CONFIG", default_value = config::CONFIG_FILE)]
+    pub config: PathBuf,
+
+    /// Print migr plumbing to stdout. Repeat to print executed statements (`-vv`) and server
+    /// notices (`-vvv`). `MIGR_VERBOSE` takes the level, e.g. `MIGR_VERBOSE=2`.
+    #[arg(long, short, env = "MIGR_VERBOSE", action = ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Only print errors and the outcome of the command.
+    #[arg(long, short, env = "MIGR_QUIET", value_parser = BoolishValueParser::new(), action)]
+    pub quiet: bool,
+
+    /// Disable colored output. Colors are also disabled when `NO_COLOR` is set or stdout is not a terminal.
+    #[arg(long, env = "MIGR_NO_COLOR", value_parser = BoolishValueParser::new(), action)]
+    pub no_color: bool,
+
+    /// Never prompt, even when attached to a terminal. Commands which would ask fail instead, naming
+    /// the flag which answers the question, so CI jobs can't hang on a prompt nobody sees. Always
+    /// the case when stdin is not a terminal.
+    #[arg(long, global = true, env = "MIGR_NON_INTERACTIVE", value_parser = BoolishValueParser::new(), action)]
+    pub non_interactive: bool,
+
+    /// Open read-only sessions and refuse commands which write to the database, e.g. to safely
+    /// point `status` at production.
+    #[arg(long, global = true, env = "MIGR_READ_ONLY", value_parser = BoolishValueParser::new(), action)]
+    pub read_only: bool,
+
+    /// Connect to databases through an SSH tunnel opened to the given jump host, e.g.
+    /// `user@bastion`. Database hosts are resolved by the jump host.
+    #[arg(long, global = true, env = "MIGR_SSH", value_name = "DESTINATION")]
+    pub ssh: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MigrationSubcommand {
+    /// Show the state of migrations in the metadata table.
+    Status(StatusArgs),
+    /// Scaffold a project using migr: the config, a `.env.example`, the migrations directory, CI
+    /// snippets and a `.gitignore` entry. Does not touch the database.
+    Init(InitArgs),
+    /// Initialise a migration directory, set up the initial migration and create the metadata table,
+    /// skipping the ones which already exist.
+    Setup(SetupArgs),
+    /// Sync existing/edited migrations with migr.
+    Sync(SyncArgs),
+    /// Generate a new migration
+    Gen(GenMigration),
+    /// Generate a migration from the current definitions of tables, views or functions in the database
+    Capture(CaptureArgs),
+    /// Run pending migrations
+    Run(RunRevMigration),
+    /// Create the metadata table if it is missing, sync it with the migrations directory and run
+    /// pending migrations. Safe to run on every deploy.
+    Ensure(RunRevMigration),
+    /// Run `ensure` whenever a migration is added or edited, until interrupted, e.g. while
+    /// developing migrations against a local database
+    Watch(WatchArgs),
+    /// Retry the migration which failed in a previous run and run the pending migrations after it
+    Resume(RunRevMigration),
+    /// Mark a pending migration as applied without executing it, e.g. a failed migration which was
+    /// applied manually
+    Skip(SkipArgs),
+    /// Reverse migrations
+    Rev(RunRevMigration),
+    /// Redo migrations
+    Redo(RunRevMigration),
+    /// Run the enabled analysis passes on migrations without touching the database
+    Verify(VerifyArgs),
+    /// Format the SQL of migrations without touching the database
+    Fmt(FmtArgs),
+    /// Rewrite the SQL of migrations to fix common problems without touching the database
+    Fix(FixArgs),
+    /// Show the columns, indexes and constraints of a table in the database
+    Describe(DescribeArgs),
+    /// Show the commits which introduced and changed a migration
+    Blame(BlameArgs),
+    /// Render a Markdown changelog of the migrations with their dates, annotations and the tables
+    /// they touch, e.g. to commit next to them
+    Docs(DocsArgs),
+    /// List the migrations which create, alter or drop a table or column, in the order they are
+    /// applied
+    WhichMigrations(WhichMigrationsArgs),
+    /// Show the SQL, metadata and history of a migration
+    Show(ShowArgs),
+    /// Build a fully migrated template database and create databases from it, e.g. for tests
+    Template(TemplateArgs),
+    /// Manage the snippets `gen --snippet` expands into migrations
+    Snippet(SnippetArgs),
+    /// Move old applied migrations into a compressed archive which migr still reads them from
+    Archive(ArchiveArgs),
+    /// Generate `baseline.sql` from the schema of the database, which fresh databases apply instead
+    /// of the migrations up to the latest applied one
+    Baseline(BaselineArgs),
+    /// Execute the scripts in `scrub` which anonymize production data copied into another
+    /// database, e.g. a staging database restored from a backup
+    Scrub(ScrubArgs),
+    /// Apply exactly the migrations applied to one environment but not another, e.g. to bring
+    /// staging level with production
+    Promote(PromoteArgs),
+    /// Print the versions of migr, the metadata table, the server and the latest applied migration
+    /// as JSON, e.g. for support tickets and deploy manifests
+    VersionInfo,
+    /// Print the resolved configuration, i.e. the database URL with its password masked, the
+    /// migrations directory and the flags, with where each came from. Does not connect to the
+    /// database.
+    Env,
+    /// Print the IDs of pending migrations, one per line, for scripts. Exits with 1 if none are
+    /// pending and with 2 on errors
+    Pending,
+    /// Runs the executable `migr-<command>` on the `PATH`
+    #[command(external_subcommand)]
+    External(Vec<OsString>),
+}
+
+impl MigrationSubcommand {
+    /// Whether the command writes to the database.
+    pub fn writes(&self) -> bool {
+        !matches!(
+            self,
+            Self::Status(_)
+                | Self::Init(_)
+                | Self::Verify(_)
+                | Self::Fmt(_)
+                | Self::Fix(_)
+                | Self::Describe(_)
+                | Self::Blame(_)
+                | Self::Docs(_)
+                | Self::WhichMigrations(_)
+                | Self::Show(_)
+                | Self::Snippet(_)
+                | Self::Archive(_)
+                | Self::Baseline(_)
+                | Self::VersionInfo
+                | Self::Env
+                | Self::Pending
+                // External commands are told about `--read-only` and enforce it themselves
+                | Self::External(_)
+        )
+    }
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct StatusArgs {
+    /// Show the commit which added each migration when the migrations are in a git repository.
+    #[arg(long, env = "MIGR_GIT", value_parser = BoolishValueParser::new(), action)]
+    pub git: bool,
+
+    /// Show which migrations were applied at the given date or time, e.g. `2024-06-01` or
+    /// `'2024-06-01 14:30+02'`, reconstructed from the history of executions.
+    #[arg(long, env = "MIGR_AT", value_name = "TIME")]
+    pub at: Option<String>,
+
+    /// Show the migrations on disk without connecting to the database, with the checksums of their
+    /// `up.sql` and the problems which can be found without one.
+    #[arg(long, env = "MIGR_OFFLINE", value_parser = BoolishValueParser::new(), action, conflicts_with = "at")]
+    pub offline: bool,
+
+    /// Compare the environments configured in migr.toml with the migrations on disk instead, e.g.
+    /// `all` or `staging,prod`. They are queried concurrently.
+    #[arg(long, env = "MIGR_ENVS", value_name = "ENVS", conflicts_with_all = ["at", "offline", "git"])]
+    pub env: Option<String>,
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct InitArgs {
+    /// Also write a CI job verifying the migrations for the given provider. Can be repeated.
+    #[arg(long, env = "MIGR_CI", value_enum, value_delimiter = ',')]
+    pub ci: Vec<Ci>,
+
+    /// Overwrite files which already exist. The `.gitignore` is only ever appended to.
+    #[arg(long, env = "MIGR_FORCE", value_parser = BoolishValueParser::new(), action)]
+    pub force: bool,
+}
+
+/// CI providers `init` writes jobs for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Ci {
+    Github,
+    Gitlab,
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct SetupArgs {
+    /// Do not create the initial migration.
+    #[arg(long, env = "MIGR_NO_INITIAL", value_parser = BoolishValueParser::new(), action)]
+    pub no_initial: bool,
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct SyncArgs {
+    #[arg(long, short, env = "MIGR_TRIM", value_parser = BoolishValueParser::new(), action)]
+    /// Diffs the migrations directory with entries from the metadata table and removes all
+    /// table entries that do not exist in the directory.
+    pub trim: bool,
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct VerifyArgs {
+    /// Run the replication analysis pass even if it is not enabled in the config.
+    #[arg(long, env = "MIGR_REPLICATION", value_parser = BoolishValueParser::new(), action)]
+    pub replication: bool,
+
+    /// Check that migration directories have valid, unique timestamps and names that do not differ only in case.
+    #[arg(long, env = "MIGR_NAMING", value_parser = BoolishValueParser::new(), action)]
+    pub naming: bool,
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct FmtArgs {
+    /// The migrations to format, by ID or name. Formats every migration if none are given.
+    pub migrations: Vec<String>,
+
+    /// List the files which are not formatted and fail if there are any, without writing them.
+    #[arg(long, env = "MIGR_CHECK", value_parser = BoolishValueParser::new(), action)]
+    pub check: bool,
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct FixArgs {
+    /// The migrations to rewrite, by ID or name. Rewrites every pending migration of the database
+    /// if none are given.
+    pub migrations: Vec<String>,
+
+    /// Rewrite statements to their idempotent forms where Postgres supports them, e.g.
+    /// `CREATE TABLE IF NOT EXISTS`, so they succeed on databases which already contain their changes.
+    #[arg(long, env = "MIGR_IDEMPOTENT", value_parser = BoolishValueParser::new(), action)]
+    pub idempotent: bool,
+
+    /// Rewrite foreign keys and `SET NOT NULL` to constraints added as `NOT VALID` and validated
+    /// once the migration is committed, so large tables are not scanned while writes are blocked.
+    #[arg(long, env = "MIGR_REWRITE_SAFE", value_parser = BoolishValueParser::new(), action)]
+    pub rewrite_safe: bool,
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct CaptureArgs {
+    /// Migration name
+    pub name: String,
+
+    /// Tables, views or functions to capture, optionally schema qualified. Overloaded functions are
+    /// captured with all their overloads unless a signature like `add(integer, integer)` is given.
+    #[arg(required = true)]
+    pub objects: Vec<String>,
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct DescribeArgs {
+    /// The table to describe, optionally schema qualified
+    pub table: String,
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct BlameArgs {
+    /// Migration name without the timestamp, or the full migration ID
+    pub name: String,
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct DocsArgs {
+    /// Write the changelog to this file instead of printing it, e.g. `MIGRATIONS.md`
+    #[arg(long, short, env = "MIGR_OUTPUT", value_name = "FILE")]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct WhichMigrationsArgs {
+    /// The table, optionally schema qualified
+    #[arg(long, env = "MIGR_TABLE")]
+    pub table: String,
+
+    /// Only list the migrations which change this column of the table, or create, rename or drop
+    /// the table itself
+    #[arg(long, env = "MIGR_COLUMN")]
+    pub column: Option<String>,
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct ShowArgs {
+    /// Migration name without the timestamp, the full migration ID, a prefix of the ID or part of
+    /// the name
+    pub name: String,
+
+    /// Print the SQL recorded for the last execution of the migration instead of its files. Requires
+    /// `history.sql` in the config.
+    #[arg(long, env = "MIGR_EXECUTED", value_parser = BoolishValueParser::new(), action)]
+    pub executed: bool,
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct SkipArgs {
+    /// Migration name without the timestamp or the full migration ID
+    pub migration: String,
+}
+
+#[derive(Debug, Args, Clone)]
+pub struct TemplateArgs {
+    #[command(subcommand)]
+    pub command: TemplateCommand,
+
+    /// The name of the template database. Defaults to the name of the database with a `_template`
+    /// suffix.
+    #[arg(long, global = true, env = "MIGR_TEMPLATE")]
+    pub template: Option<String>,
+}
+
+#[derive(Debug, Subcommand, Clone)]
+pub enum TemplateCommand {
+    /// Create the template database with all migrations applied, rebuilding it if the migrations
+    /// changed since it was created
+    Create(TemplateCreateArgs),
+    /// Create a database from the template
+    Clone(TemplateCloneArgs),
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct TemplateCreateArgs {
+    /// Rebuild the template even if it is up to date.
+    #[arg(long, env = "MIGR_FORCE", value_parser = BoolishValueParser::new(), action)]
+    pub force: bool,
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct TemplateCloneArgs {
+    /// The name of the database to create.
+    pub name: String,
+}
+
+#[derive(Debug, Args, Clone)]
+pub struct ArchiveArgs {
+    /// Archive the applied migrations with a timestamp before this date, e.g. `2023-01-01`
+    #[arg(long, env = "MIGR_BEFORE", value_name = "DATE")]
+    pub before: String,
+}
+
+#[derive(Debug, Args, Clone)]
+pub struct BaselineArgs {
+    /// Replace an existing `baseline.sql`.
+    #[arg(long, env = "MIGR_FORCE", value_parser = BoolishValueParser::new(), action)]
+    pub force: bool,
+}
+
+#[derive(Debug, Args, Clone)]
+pub struct ScrubArgs {
+    /// Execute every scrub script, including the ones executed on the database before.
+    #[arg(long, env = "MIGR_SCRUB_ALL", value_parser = BoolishValueParser::new(), action)]
+    pub all: bool,
+
+    /// Scrub without asking for confirmation.
+    #[arg(long, short, env = "MIGR_YES", value_parser = BoolishValueParser::new(), action)]
+    pub yes: bool,
+}
+
+#[derive(Debug, Args, Clone)]
+pub struct SnippetArgs {
+    #[command(subcommand)]
+    pub command: SnippetCommand,
+}
+
+#[derive(Debug, Subcommand, Clone)]
+pub enum SnippetCommand {
+    /// Create a new snippet in the `snippets` directory
+    Add(SnippetAddArgs),
+    /// List the snippets with their versions and the migrations which expanded them
+    List,
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct SnippetAddArgs {
+    /// The name of the snippet, referenced with `gen --snippet <name>`.
+    pub name: String,
+
+    /// Copy the SQL of the snippet from the given file.
+    #[arg(long, env = "MIGR_SNIPPET_FROM", value_name = "FILE")]
+    pub from: Option<PathBuf>,
+
+    /// Also create `<name>.down.sql`, which is expanded into the `down.sql` of migrations.
+    #[arg(long, env = "MIGR_DOWN", value_parser = BoolishValueParser::new(), action)]
+    pub down: bool,
+}
+
+#[derive(Debug, Args, Default, Clone)]
+#[command(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
+pub struct GenMigration {
+    #[command(subcommand)]
+    pub generator: Option<GenCommand>,
+
+    /// Migration name. Defaults to `enable_<extension>` with `--extension` and
+    /// `<type>_add_<value>` with `--enum`.
+    #[arg(required_unless_present_any = ["extension", "enum_change"])]
+    pub name: Option<String>,
+
+    /// Generate a migration enabling the given extension.
+    #[arg(
+        long,
+        env = "MIGR_EXTENSION",
+        value_name = "NAME",
+        conflicts_with = "enum_change"
+    )]
+    pub extension: Option<String>,
+
+    /// Drop the extension when reverting the migration generated with `--extension`. By default it
+    /// is left installed, since other objects may depend on it.
+    #[arg(long, env = "MIGR_DROP_EXTENSION", value_parser = BoolishValueParser::new(), action, requires = "extension")]
+    pub drop_extension: bool,
+
+    /// Generate a migration changing an enum type, e.g. `--enum user_role add-value auditor`.
+    /// The migration is executed outside of a transaction, which adding values requires.
+    #[arg(long = "enum", num_args = 3, value_names = ["TYPE", "ACTION", "VALUE"])]
+    pub enum_change: Vec<String>,
+
+    /// Expand the snippet with the given name from the `snippets` directory into the migration.
+    /// Can be repeated.
+    #[arg(
+        long = "snippet",
+        env = "MIGR_SNIPPETS",
+        value_name = "NAME",
+        value_delimiter = ','
+    )]
+    pub snippets: Vec<String>,
+
+    /// Replace the `{KEY}` placeholders of expanded snippets with the value, e.g.
+    /// `--var table=users`. Can be repeated. Without it, the comma separated pairs of
+    /// `MIGR_SNIPPET_VARS` are used.
+    #[arg(long = "var", value_name = "KEY=VALUE", requires = "snippets")]
+    pub vars: Vec<String>,
+
+    /// Generate the migration without connecting to the database. It is registered in the
+    /// metadata table by the next `sync` or `ensure`.
+    #[arg(long, env = "MIGR_OFFLINE", value_parser = BoolishValueParser::new(), action)]
+    pub offline: bool,
+}
+
+#[derive(Debug, Subcommand, Clone)]
+pub enum GenCommand {
+    /// Generate a migration creating a table with an `id` primary key, `created_at` and
+    /// `updated_at` columns and a trigger keeping `updated_at` current
+    AddTable(GenTableArgs),
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct GenTableArgs {
+    /// The name of the table, optionally schema qualified.
+    pub table: String,
+
+    /// Additional columns, e.g. `"email:text:unique, name:text"`. Each column is given as
+    /// `name:type` followed by any of the `unique`, `not-null`, `default=<expr>` and
+    /// `references=<table>` modifiers.
+    #[arg(long, env = "MIGR_COLUMNS", default_value = "")]
+    pub columns: String,
+
+    /// Migration name. Defaults to `create_<table>`.
+    #[arg(long, env = "MIGR_NAME")]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct PromoteArgs {
+    /// The environment whose applied migrations are replicated
+    #[arg(long, env = "MIGR_FROM", value_name = "ENV")]
+    pub from: String,
+
+    /// The environment the migrations are applied to
+    #[arg(long, env = "MIGR_TO", value_name = "ENV")]
+    pub to: String,
+
+    #[command(flatten)]
+    pub run: RunRevMigration,
+}
+
+#[derive(Debug, Args, Clone)]
+pub struct WatchArgs {
+    /// How often the migrations directory is checked for changes, in seconds.
+    #[arg(
+        long,
+        env = "MIGR_WATCH_INTERVAL",
+        default_value = "1",
+        value_name = "SECONDS"
+    )]
+    pub interval: u64,
+
+    #[command(flatten)]
+    pub run: RunRevMigration,
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct RunRevMigration {
+    /// The exact migration to perform the action on. This will disregard the entry in the metadata table and will also update it.
+    #[arg(long, short, env = "MIGR_EXACT")]
+    pub exact: Option<String>,
+
+    /// The number of migrations to run/revert/redo. Defaults to `1` when reverting, or asks how many
+    /// to revert when attached to a terminal.
+    #[arg(long, short, env = "MIGR_COUNT")]
+    pub count: Option<usize>,
+
+    /// If true, performs the action on all migrations. Defaults to `true` when running.
+    #[arg(long, short, env = "MIGR_ALL", value_parser = BoolishValueParser::new(), action)]
+    pub all: bool,
+
+    /// Revert exactly the migrations applied by the most recent `run`, however many there are. Only
+    /// supported when reverting.
+    #[arg(long, env = "MIGR_LAST_BATCH", value_parser = BoolishValueParser::new(), action, conflicts_with_all = ["exact", "count", "all"])]
+    pub last_batch: bool,
+
+    /// Revert migrations whose `down.sql` changed since they were applied without asking for
+    /// confirmation. Only supported when reverting.
+    #[arg(long, env = "MIGR_ALLOW_CHANGED_DOWN", value_parser = BoolishValueParser::new(), action)]
+    pub allow_changed_down: bool,
+
+    /// Run independent pending migrations on up to the given number of connections. Each migration
+    /// is executed in its own transaction. Only supported when running.
+    #[arg(long, env = "MIGR_PARALLEL")]
+    pub parallel: Option<usize>,
+
+    /// Apply each pending migration in a savepoint, so a failing one is rolled back on its own and
+    /// the ones which don't depend on it are still applied. Failures are reported once all of
+    /// them were tried. Only supported when running.
+    #[arg(long, env = "MIGR_ISOLATE_FAILURES", value_parser = BoolishValueParser::new(), action)]
+    pub isolate_failures: bool,
+
+    /// If another instance of migr is executing migrations, wait for it to finish and verify the
+    /// migrations were applied instead of failing, e.g. when every replica runs migr on startup.
+    /// Only supported when running.
+    #[arg(long, env = "MIGR_WAIT_IF_LOCKED", value_parser = BoolishValueParser::new(), action)]
+    pub wait_if_locked: bool,
+
+    /// How many seconds to wait for another instance of migr with `--wait-if-locked`.
+    #[arg(
+        long,
+        env = "MIGR_LOCK_TIMEOUT",
+        value_name = "SECONDS",
+        default_value_t = 600
+    )]
+    pub lock_timeout: u64,
+
+    /// Apply pending migrations which drop or truncate tables or drop columns holding data without
+    /// asking for confirmation. Only supported when running.
+    #[arg(long, env = "MIGR_ALLOW_DATA_LOSS", value_parser = BoolishValueParser::new(), action)]
+    pub allow_data_loss: bool,
+
+    /// Apply pending migrations which call `dblink`, import foreign schemas or touch foreign tables.
+    /// Their effects on other databases are not rolled back if the migration fails. Only supported
+    /// when running.
+    #[arg(long, env = "MIGR_ALLOW_FDW", value_parser = BoolishValueParser::new(), action)]
+    pub allow_fdw: bool,
+
+    /// Explain the `UPDATE` and `DELETE` statements of pending migrations before running them and
+    /// report the planner's estimates of the rows they touch, warning about sequential scans of huge
+    /// tables. Nothing is executed to estimate them. Only supported when running.
+    #[arg(long, env = "MIGR_EXPLAIN", value_parser = BoolishValueParser::new(), action)]
+    pub explain: bool,
+
+    /// Refuse to run pending migrations whose assessed risk exceeds this level. The risk is scored
+    /// from lint violations, the kinds of statements and the sizes of the tables they lock.
+    /// Overrides the `max_risk` of the target. Only supported when running.
+    #[arg(long, env = "MIGR_MAX_RISK", value_name = "RISK")]
+    pub max_risk: Option<Risk>,
+
+    /// Execute the pending migrations in a transaction which is rolled back, reporting which of
+    /// them would fail and how long they take. Only supported when running.
+    #[arg(long, env = "MIGR_SANDBOX", value_parser = BoolishValueParser::new(), action)]
+    pub sandbox: bool,
+
+    /// Apply migrations marked as incompatible even though instances of the application built
+    /// before them hold leases. Only supported when running.
+    #[arg(long, env = "MIGR_FORCE_INCOMPATIBLE", value_parser = BoolishValueParser::new(), action)]
+    pub force_incompatible: bool,
+
+    /// Only apply pending migrations up to the first one of a later phase, e.g. `expand` before the
+    /// new release of the application is deployed and `contract` once the old one is gone. Only
+    /// supported when running.
+    #[arg(long, env = "MIGR_PHASE")]
+    pub phase: Option<Phase>,
+
+    /// Replay every migration on a fresh database instead of applying `baseline.sql`, e.g. to check
+    /// that the full history still applies. Only supported when running.
+    #[arg(long, env = "MIGR_NO_BASELINE", value_parser = BoolishValueParser::new(), action)]
+    pub no_baseline: bool,
+
+    /// Fail instead of warning when pending migrations are empty or only contain comments, e.g. in
+    /// CI. Only supported when running.
+    #[arg(long, env = "MIGR_FAIL_ON_EMPTY", value_parser = BoolishValueParser::new(), action, conflicts_with = "allow_empty")]
+    pub fail_on_empty: bool,
+
+    /// Mark pending migrations which are empty or only contain comments as applied instead of
+    /// leaving them pending. Only supported when running.
+    #[arg(long, env = "MIGR_ALLOW_EMPTY", value_parser = BoolishValueParser::new(), action)]
+    pub allow_empty: bool,
+
+    /// Execute each migration as the given role with `SET ROLE`, so the objects it creates are owned
+    /// by the role instead of the user migr connects as.
+    #[arg(long, env = "MIGR_RUN_AS", value_name = "ROLE")]
+    pub run_as: Option<String>,
+
+    /// Set the value of a parameter of `-- migr:params` directives, e.g. `--var region=eu`,
+    /// overriding the config. Can be repeated. Without it, the comma separated pairs of `MIGR_VARS`
+    /// are used.
+    #[arg(long = "var", value_name = "KEY=VALUE")]
+    pub vars: Vec<String>,
+
+    /// Print the summary as a single JSON object on stdout instead of log lines. Combine with
+    /// `--quiet` to only print the JSON.
+    #[arg(long, env = "MIGR_JSON", value_parser = BoolishValueParser::new(), action)]
+    pub json: bool,
+
+    /// Abort the run once the migration with the given ID or name executed, as if the one after
+    /// it failed. For testing rollbacks and resumption.
+    #[arg(long, hide = true, env = "MIGR_FAIL_AFTER", value_name = "MIGRATION")]
+    pub fail_after: Option<String>,
+
+    /// Write a machine-readable report of the command to the given file: the plan, the status,
+    /// duration, checksum and error of each migration and the versions before and after. Written
+    /// whether the command succeeds or fails, for deployment systems to archive.
+    #[arg(long, env = "MIGR_REPORT_FILE", value_name = "FILE")]
+    pub report_file: Option<PathBuf>,
+
+    /// Apply pending migrations to the database at the given URL first, e.g. a staging clone, and
+    /// only to the primary if they succeed there within `--canary-budget`. Only supported when
+    /// running.
+    #[arg(long, env = "MIGR_CANARY", value_name = "URL", conflicts_with_all = ["urls", "urls_file"])]
+    pub canary: Option<String>,
+
+    /// How many seconds the canary may take before it is terminated and the run fails.
+    #[arg(
+        long,
+        env = "MIGR_CANARY_BUDGET",
+        value_name = "SECONDS",
+        default_value_t = 600
+    )]
+    pub canary_budget: u64,
+
+    #[command(flatten)]
+    pub metrics: MetricsArgs,
+
+    #[command(flatten)]
+    pub cluster: ClusterArgs,
+
+    /// Restricts the run to these migrations, leaving the other pending ones pending. Set by
+    /// `promote`.
+    #[arg(skip)]
+    pub only: Option<HashSet<String>>,
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct MetricsArgs {
+    /// Push metrics about the run to the Prometheus pushgateway at the given `http://` URL.
+    #[arg(long, env = "MIGR_METRICS_PUSHGATEWAY", value_name = "URL")]
+    pub metrics_pushgateway: Option<String>,
+
+    /// Write metrics about the run to the given file, e.g. for the node exporter's textfile collector.
+    #[arg(long, env = "MIGR_METRICS_FILE", value_name = "PATH")]
+    pub metrics_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Args, Default, Clone)]
+pub struct ClusterArgs {
+    /// Apply pending migrations to the database at the given URL instead of `DATABASE_URL`. Can be
+    /// repeated to migrate several shards. Only supported when running.
+    #[arg(long = "url", env = "MIGR_URL", value_name = "URL")]
+    pub urls: Vec<String>,
+
+    /// Apply pending migrations to every database in the given file, one URL per line. Only
+    /// supported when running.
+    #[arg(long, env = "MIGR_URLS_FILE", value_name = "PATH")]
+    pub urls_file: Option<PathBuf>,
+
+    /// Migrate up to the given number of shards at once.
+    #[arg(
+        long,
+        env = "MIGR_SHARD_PARALLEL",
+        value_name = "N",
+        default_value_t = 1
+    )]
+    pub shard_parallel: usize,
+
+    /// Keep migrating the remaining shards after one fails. Failures are reported once every shard
+    /// was tried. Only supported when running.
+    #[arg(long, env = "MIGR_KEEP_GOING", value_parser = BoolishValueParser::new(), action)]
+    pub keep_going: bool,
+}
+
+impl RunRevMigration {
+    /// Resets the options which are only supported when running if they were set through the env,
+    /// so the env of a deploy running migrations does not break reverting them.
+    pub fn reset_run_only_env(&mut self, matches: &ArgMatches) {
+        let from_env = |id: &str| matches.value_source(id) == Some(ValueSource::EnvVariable);
+        if from_env("parallel") {
+            self.parallel = None;
+        }
+        if from_env("isolate_failures") {
+            self.isolate_failures = false;
+        }
+        if from_env("keep_going") {
+            self.cluster.keep_going = false;
+        }
+        if from_env("wait_if_locked") {
+            self.wait_if_locked = false;
+        }
+        if from_env("allow_data_loss") {
+            self.allow_data_loss = false;
+        }
+        if from_env("allow_fdw") {
+            self.allow_fdw = false;
+        }
+        if from_env("explain") {
+            self.explain = false;
+        }
+        if from_env("max_risk") {
+            self.max_risk = None;
+        }
+        if from_env("sandbox") {
+            self.sandbox = false;
+        }
+        if from_env("force_incompatible") {
+            self.force_incompatible = false;
+        }
+        if from_env("phase") {
+            self.phase = None;
+        }
+        if from_env("no_baseline") {
+            self.no_baseline = false;
+        }
+        if from_env("fail_on_empty") {
+            self.fail_on_empty = false;
+        }
+        if from_env("allow_empty") {
+            self.allow_empty = false;
+        }
+        if from_env("canary") {
+            self.canary = None;
+        }
+        if from_env("urls") {
+            self.cluster.urls.clear();
+        }
+        if from_env("urls_file") {
+            self.cluster.urls_file = None;
+        }
+    }
+
+    /// Resets the options which are only supported when reverting if they were set through the
+    /// env, so the env of a rollback job does not break running migrations.
+    pub fn reset_rev_only_env(&mut self, matches: &ArgMatches) {
+        let from_env = |id: &str| matches.value_source(id) == Some(ValueSource::EnvVariable);
+        if from_env("allow_changed_down") {
+            self.allow_changed_down = false;
+        }
+        if from_env("last_batch") {
+            self.last_batch = false;
+        }
+    }
+}
+
+impl ClusterArgs {
+    pub fn is_set(&self) -> bool {
+        !self.urls.is_empty() || self.urls_file.is_some()
+    }
+
+    pub fn check_not_set(&self) -> anyhow::Result<()> {
+        if self.is_set() {
+            return Err(anyhow::Error::msg(
+                "`--url` and `--urls-file` are only supported when running migrations",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the `KEY=VALUE` pairs of `vars`, or the comma separated ones of the env variable `var`
+/// if there are none. Only the env holds several pairs in one value, so values passed as flags may
+/// contain commas.
+pub fn vars_or_env(vars: &[String], var: &str) -> Vec<String> {
+    if !vars.is_empty() {
+        return vars.to_vec();
+    }
+    std::env::var(var)
+        .map(|value| {
+            value
+                .split(',')
+                .filter(|pair| !pair.trim().is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($t:tt)*) => {{
+        use colored::Colorize;
+        if $crate::output::enabled($crate::output::TRACE) {
+            $crate::output::print("TRACE".magenta(), format_args!($($t)*));
+        }
+    }};
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($t:tt)*) => {{
+        use colored::Colorize;
+        if $crate::output::enabled($crate::output::DEBUG) {
+            $crate::output::print("DEBUG".blue(), format_args!($($t)*));
+        }
+    }};
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($t:tt)*) => {{
+        use colored::Colorize;
+        if $crate::output::enabled($crate::output::INFO) {
+            $crate::output::print("INFO".green(), format_args!($($t)*));
+        }
+    }};
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($t:tt)*) => {{
+        use colored::Colorize;
+        if $crate::output::enabled($crate::output::INFO) {
+            $crate::output::print("WARN".yellow(), format_args!($($t)*));
+        }
+    }};
+}
+
+/// Prints the outcome of a command. Unlike [info], it is printed in quiet mode.
+#[macro_export]
+macro_rules! summary {
+    ($($t:tt)*) => {{
+        use colored::Colorize;
+        $crate::output::print("INFO".green(), format_args!($($t)*));
+    }};
+}
@@ -0,0 +1,348 @@
+use crate::config::{LintConfig, LintLevel};
+use crate::{debug, git, sql, trace, warn};
+use anyhow::Error;
+use colored::Colorize;
+use sqlparser::ast::{ColumnOption, Statement, TableConstraint};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A lint rule: its name, used to configure it, and a check returning a message
+/// if the normalized statement, or the parsed one if the parser supports it, violates it.
+type Rule = (
+    &'static str,
+    fn(&str, Option<&Statement>) -> Option<&'static str>,
+);
+
+/// Rules flagging statements known to be problematic on replicated setups.
+const REPLICATION_RULES: &[Rule] = &[
+    ("create-table-as", create_table_as),
+    ("create-table-without-pk", create_table_without_pk),
+    ("unlogged-table", unlogged_table),
+    ("unbounded-dml", unbounded_dml),
+];
+
+#[derive(Debug)]
+pub struct Violation {
+    pub migration: String,
+    pub rule: String,
+    pub level: LintLevel,
+    pub message: String,
+    pub statement: String,
+}
+
+/// Runs the replication rules on the given migration files.
+pub fn replication(paths: &[PathBuf], config: &LintConfig) -> anyhow::Result<Vec<Violation>> {
+    let mut violations = vec![];
+
+    for path in paths {
+        let migration = path
+            .parent()
+            .and_then(|p| p.file_name())
+            .and_then(|n| n.to_str())
+            .unwrap_or_default()
+            .to_string();
+
+        let sql = fs::read_to_string(path)?;
+
+        for statement in sql::statements(&sql) {
+            let normalized = sql::normalize(&statement);
+            let parsed = sql::parse(&statement);
+            for (rule, check) in REPLICATION_RULES {
+                let level = config.level(rule);
+                if level == LintLevel::Allow {
+                    continue;
+                }
+                if let Some(message) = check(&normalized, parsed.as_ref()) {
+                    violations.push(Violation {
+                        migration: migration.clone(),
+                        rule: rule.to_string(),
+                        level,
+                        message: message.to_string(),
+                        statement: statement.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(violations)
+}
+
+/// Checks that the `name` in the header of each migration file matches its directory.
+/// Missing headers are not reported.
+pub fn header(paths: &[PathBuf], config: &LintConfig) -> anyhow::Result<Vec<Violation>> {
+    const RULE: &str = "header-name";
+
+    let level = config.level_or(RULE, LintLevel::Deny);
+    let mut violations = vec![];
+
+    if level == LintLevel::Allow {
+        return Ok(violations);
+    }
+
+    for path in paths {
+        let migration = path
+            .parent()
+            .and_then(|p| p.file_name())
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+
+        // Strip the timestamp prefix
+        let expected = migration
+            .split_once('_')
+            .map(|(_, name)| name)
+            .unwrap_or(migration);
+
+        let sql = fs::read_to_string(path)?;
+
+        let Some(name) = sql::header_field(&sql, "name") else {
+            continue;
+        };
+
+        if name != expected {
+            let file = path.file_name().unwrap_or_default().to_string_lossy();
+            violations.push(Violation {
+                migration: migration.to_string(),
+                rule: RULE.to_string(),
+                level,
+                message: format!(
+                    "{file} header names the migration '{name}', expected '{expected}'"
+                ),
+                statement: sql.lines().next().unwrap_or_default().to_string(),
+            });
+        }
+    }
+
+    Ok(violations)
+}
+
+/// Checks that the migration directories have parseable timestamp prefixes, that no two migrations
+/// share a timestamp and that no two names differ only in case. Any of these break the ordering of
+/// migrations and their lookup by name.
+pub fn naming(dirs: &[PathBuf], config: &LintConfig) -> Vec<Violation> {
+    const TIMESTAMP: &str = "naming-timestamp";
+    const DUPLICATE: &str = "naming-duplicate-timestamp";
+    const CASE: &str = "naming-case";
+
+    let mut violations = vec![];
+    let mut timestamps: HashMap<&str, &str> = HashMap::new();
+    let mut names: HashMap<String, &str> = HashMap::new();
+
+    let mut push = |migration: &str, rule: &str, message| {
+        let level = config.level(rule);
+        if level != LintLevel::Allow {
+            violations.push(Violation {
+                migration: migration.to_string(),
+                rule: rule.to_string(),
+                level,
+                message,
+                statement: migration.to_string(),
+            });
+        }
+    };
+
+    for dir in dirs {
+        let Some(migration) = dir.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        let (timestamp, name) = migration.split_once('_').unwrap_or((migration, ""));
+
+        if !is_timestamp(timestamp) {
+            push(
+                migration,
+                TIMESTAMP,
+                format!("'{timestamp}' is not a valid timestamp, expected `YYYY-MM-DD-HHMMSS`"),
+            );
+        }
+
+        if let Some(other) = timestamps.insert(timestamp, migration) {
+            push(
+                migration,
+                DUPLICATE,
+                format!("shares its timestamp with {other}, their order is undefined"),
+            );
+        }
+
+        match names.insert(name.to_lowercase(), migration) {
+            Some(other) if other.split_once('_').map(|(_, n)| n) != Some(name) => push(
+                migration,
+                CASE,
+                format!("name differs only in case from {other}"),
+            ),
+            _ => {}
+        }
+    }
+
+    violations
+}
+
+/// Checks that the given pending migration files are committed to git. The rule is allowed by
+/// default since not every project keeps its migrations in git.
+pub fn uncommitted(path: &Path, pending: &[PathBuf], config: &LintConfig) -> Vec<Violation> {
+    const RULE: &str = "uncommitted-migration";
+
+    let level = config.level_or(RULE, LintLevel::Allow);
+    if level == LintLevel::Allow || pending.is_empty() {
+        return vec![];
+    }
+
+    let Some(uncommitted) = git::uncommitted(path) else {
+        debug!("Migrations are not in a git repository, skipping the {RULE} rule");
+        return vec![];
+    };
+
+    pending
+        .iter()
+        .filter_map(|p| p.parent()?.file_name()?.to_str())
+        .filter(|id| uncommitted.contains(*id))
+        .map(|migration| Violation {
+            migration: migration.to_string(),
+            rule: RULE.to_string(),
+            level,
+            message: String::from("pending migration has untracked or uncommitted changes"),
+            statement: migration.to_string(),
+        })
+        .collect()
+}
+
+/// Prints the violations and errors if any of them are denied.
+pub fn report(violations: &[Violation]) -> anyhow::Result<()> {
+    let denied = print(violations);
+
+    if denied > 0 {
+        return Err(Error::msg(format!(
+            "{denied} denied lint violation(s) found\nHint: Set the rule to `warn` or `allow` in the `[lint.rules]` section of migr.toml to proceed"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Prints the violations and returns how many of them are denied.
+pub(crate) fn print(violations: &[Violation]) -> usize {
+    for v in violations {
+        let rule = match v.level {
+            LintLevel::Deny => v.rule.red(),
+            _ => v.rule.yellow(),
+        };
+        warn!("{} [{rule}] {}", v.migration.blue(), v.message);
+        trace!("{}", v.statement);
+    }
+
+    violations
+        .iter()
+        .filter(|v| v.level == LintLevel::Deny)
+        .count()
+}
+
+/// Returns true if `s` is either a generated `YYYY-MM-DD-HHMMSS` timestamp or a plain number.
+fn is_timestamp(s: &str) -> bool {
+    let digits = |s: &str, len: usize| s.len() == len && s.bytes().all(|b| b.is_ascii_digit());
+
+    if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+        return true;
+    }
+
+    let parts = s.split('-').collect::<Vec<_>>();
+    let [year, month, day, hms] = parts[..] else {
+        return false;
+    };
+
+    if !(digits(year, 4) && digits(month, 2) && digits(day, 2) && digits(hms, 6)) {
+        return false;
+    }
+
+    // All parts are ASCII digits of bounded length at this point
+    let num = |s: &str| s.parse::<u8>().unwrap_or(u8::MAX);
+
+    let Ok(month) = time::Month::try_from(num(month)) else {
+        return false;
+    };
+
+    time::Date::from_calendar_date(year.parse().unwrap_or_default(), month, num(day)).is_ok()
+        && time::Time::from_hms(num(&hms[..2]), num(&hms[2..4]), num(&hms[4..])).is_ok()
+}
+
+fn create_table_as(s: &str, parsed: Option<&Statement>) -> Option<&'static str> {
+    let ctas = match parsed {
+        Some(Statement::CreateTable(table)) => !table.temporary && table.query.is_some(),
+        Some(_) => false,
+        None => is_create_table(s) && is_ctas(s),
+    };
+    ctas.then_some(
+        "`CREATE TABLE ... AS` creates a table without a primary key; updates and deletes on it cannot be logically replicated",
+    )
+}
+
+fn create_table_without_pk(s: &str, parsed: Option<&Statement>) -> Option<&'static str> {
+    let without_pk = match parsed {
+        Some(Statement::CreateTable(table)) => {
+            !table.temporary
+                && table.query.is_none()
+                && !table
+                    .constraints
+                    .iter()
+                    .any(|constraint| matches!(constraint, TableConstraint::PrimaryKey { .. }))
+                && !table.columns.iter().any(|column| {
+                    column.options.iter().any(|o| {
+                        matches!(
+                            o.option,
+                            ColumnOption::Unique {
+                                is_primary: true,
+                                ..
+                            }
+                        )
+                    })
+                })
+        }
+        Some(_) => false,
+        None => {
+            is_create_table(s)
+                && !is_ctas(s)
+                && !s.contains("PRIMARY KEY")
+                && !s.contains(" PARTITION OF ")
+        }
+    };
+    without_pk.then_some(
+        "table is created without a primary key; updates and deletes on it cannot be logically replicated",
+    )
+}
+
+fn unlogged_table(s: &str, _: Option<&Statement>) -> Option<&'static str> {
+    (s.starts_with("CREATE UNLOGGED TABLE")
+        || (s.starts_with("ALTER TABLE") && s.contains(" SET UNLOGGED")))
+    .then_some("unlogged tables are not written to the WAL and will be empty on replicas")
+}
+
+fn unbounded_dml(s: &str, parsed: Option<&Statement>) -> Option<&'static str> {
+    let unbounded = match parsed {
+        Some(Statement::Update { selection, .. }) => selection.is_none(),
+        Some(Statement::Delete(delete)) => delete.selection.is_none(),
+        Some(_) => false,
+        None => {
+            (s.starts_with("UPDATE ") || s.starts_with("DELETE FROM ")) && !s.contains(" WHERE ")
+        }
+    };
+    unbounded.then_some("statement touches every row of the table in a single transaction, which can cause significant replication lag")
+}
+
+/// Whether the normalized statement creates a regular or unlogged table. Temporary tables are never
+/// replicated so they are of no concern.
+pub(crate) fn is_create_table(s: &str) -> bool {
+    let s = s.strip_prefix("CREATE ").unwrap_or_default();
+    let s = s.strip_prefix("UNLOGGED ").unwrap_or(s);
+    s.starts_with("TABLE ")
+}
+
+pub(crate) fn is_ctas(s: &str) -> bool {
+    [
+        " AS SELECT",
+        " AS (",
+        " AS TABLE ",
+        " AS VALUES",
+        " AS WITH ",
+    ]
+    .iter()
+    .any(|p| s.contains(p))
+}
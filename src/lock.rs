@@ -0,0 +1,108 @@
+use crate::info;
+use anyhow::Error;
+use postgres::Client;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Key of the advisory lock held while migrations are executed, `migr` in ASCII.
+const KEY: i64 = 0x6d69_6772;
+
+/// How often to log that migr is still waiting for the lock.
+const HEARTBEAT: Duration = Duration::from_secs(10);
+
+/// How often to try taking the lock while waiting.
+const POLL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Acquired {
+    Immediately,
+    /// The lock was held by another instance, which has finished since.
+    AfterWaiting,
+}
+
+/// Takes the session level migration lock so only one instance of migr executes migrations on a
+/// database at a time. If another instance holds the lock, waits up to `wait` for it to be released
+/// or errors if `wait` is `None`. The lock must be released with [release] since pooled connections
+/// outlive commands.
+pub fn acquire(pg: &mut Client, wait: Option<Duration>) -> anyhow::Result<Acquired> {
+    if try_acquire(pg)? {
+        return Ok(Acquired::Immediately);
+    }
+
+    let Some(timeout) = wait else {
+        return Err(Error::msg(format!(
+            "Another instance of migr is executing migrations{}\nHint: Pass `--wait-if-locked` to wait for it to finish",
+            holder(pg)?
+        )));
+    };
+
+    info!("Waiting for another instance of migr{}", holder(pg)?);
+
+    let started = Instant::now();
+    let mut heartbeat = Instant::now();
+
+    loop {
+        thread::sleep(POLL);
+
+        if try_acquire(pg)? {
+            info!(
+                "Acquired the migration lock after {:.0?}",
+                started.elapsed()
+            );
+            return Ok(Acquired::AfterWaiting);
+        }
+
+        if started.elapsed() > timeout {
+            return Err(Error::msg(format!(
+                "Timed out after {timeout:.0?} waiting for another instance of migr{}\nHint: Increase `--lock-timeout`",
+                holder(pg)?
+            )));
+        }
+
+        if heartbeat.elapsed() >= HEARTBEAT {
+            info!(
+                "Still waiting for the migration lock after {:.0?}{}",
+                started.elapsed(),
+                holder(pg)?
+            );
+            heartbeat = Instant::now();
+        }
+    }
+}
+
+pub fn release(pg: &mut Client) -> anyhow::Result<()> {
+    pg.execute("SELECT pg_advisory_unlock($1)", &[&KEY])?;
+    Ok(())
+}
+
+fn try_acquire(pg: &mut Client) -> anyhow::Result<bool> {
+    Ok(pg
+        .query_one("SELECT pg_try_advisory_lock($1)", &[&KEY])?
+        .get::<_, bool>(0))
+}
+
+/// Describes the session holding the lock, for the logs.
+fn holder(pg: &mut Client) -> anyhow::Result<String> {
+    // The 64 bit key is split into two 32 bit halves in pg_locks
+    let row = pg.query_opt(
+        "SELECT a.pid, a.application_name, host(a.client_addr)
+         FROM pg_locks l JOIN pg_stat_activity a ON a.pid = l.pid
+         WHERE l.locktype = 'advisory' AND l.granted AND l.classid = 0 AND l.objid = $1::BIGINT::OID AND l.objsubid = 1",
+        &[&KEY],
+    )?;
+
+    let Some(row) = row else {
+        return Ok(String::new());
+    };
+
+    let pid = row.get::<_, i32>(0);
+    let application = row.get::<_, Option<String>>(1).unwrap_or_default();
+    let host = row
+        .get::<_, Option<String>>(2)
+        .unwrap_or_else(|| String::from("local"));
+
+    Ok(match application.as_str() {
+        "" => format!(" (pid {pid} on {host})"),
+        application => format!(" (pid {pid} on {host}, {application})"),
+    })
+}
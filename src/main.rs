@@ -1,216 +1,448 @@
-use crate::migration::migration_generate;
 use anyhow::Context;
-use clap::{Args, Parser, Subcommand};
-use migration::{migration_redo, migration_rev, migration_run, setup, status, sync};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::{env, path::PathBuf};
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use migr::config::{Config, Target};
+use migr::db::{self, Pin, Pool};
+use migr::migration::{
+    blame, capture, describe, ensure, fix, fmt, migration_generate, migration_redo, migration_rev,
+    migration_run, pending, resume, setup, show, skip, status, status_offline, sync, verify,
+    version_info, watch, NothingPending,
+};
+use migr::ssh::Jump;
+use migr::{
+    archive, baseline, canary, cluster, discover, docs, effective, environment, impact, init,
+    output, plugin, scrub, snippet, summary, template, workspace, Migr, MigrationSubcommand,
+};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
 
-mod migration;
+fn main() -> anyhow::Result<()> {
+    let matches = Migr::command().get_matches();
+    let mut migr = Migr::from_arg_matches(&matches)?;
 
-pub static VERBOSE: AtomicBool = AtomicBool::new(false);
+    if let (
+        MigrationSubcommand::Rev(ref mut args) | MigrationSubcommand::Redo(ref mut args),
+        Some((_, matches)),
+    ) = (&mut migr.command, matches.subcommand())
+    {
+        args.reset_run_only_env(matches);
+    }
+    if let (
+        MigrationSubcommand::Run(ref mut args)
+        | MigrationSubcommand::Ensure(ref mut args)
+        | MigrationSubcommand::Resume(ref mut args),
+        Some((_, matches)),
+    ) = (&mut migr.command, matches.subcommand())
+    {
+        args.reset_rev_only_env(matches);
+    }
 
-fn main() -> anyhow::Result<()> {
-    let migr = Migr::parse();
+    // `pending` only prints the IDs unless asked for more
+    let silent = matches!(migr.command, MigrationSubcommand::Pending) && migr.verbose == 0;
+    let verbosity = if migr.quiet || silent {
+        output::QUIET
+    } else {
+        i8::try_from(migr.verbose).unwrap_or(i8::MAX)
+    };
+    output::init(migr.no_color, verbosity);
+    output::set_non_interactive(migr.non_interactive);
 
-    if migr.verbose {
-        VERBOSE.fetch_or(true, Ordering::AcqRel);
+    if let MigrationSubcommand::External(ref args) = migr.command {
+        process::exit(plugin::external(&migr, args)?);
     }
 
-    let url = env::var("DATABASE_URL")
-        .context("`DATABASE_URL` must be set in the env before running migr")?;
+    // Needs to know where each flag came from, which only the matches tell
+    if let MigrationSubcommand::Env = migr.command {
+        return env_info(&migr, &matches);
+    }
 
-    let mut pg = establish_connection(&url);
+    let result = run(&migr);
 
-    match migr.command {
-        MigrationSubcommand::Status => status(&mut pg),
-        MigrationSubcommand::Setup => {
-            let path = format!("{}/migrations", migr.path.as_deref().unwrap_or("."));
-            setup(path.into(), &mut pg)
-        }
-        MigrationSubcommand::Sync(ref args) => {
-            let path = path(&migr)?;
-            sync(args.trim, &path, &mut pg)
-        }
-        MigrationSubcommand::Gen(ref args) => {
-            let path = path(&migr)?;
-            migration_generate(args, path, pg)
-        }
-        MigrationSubcommand::Run(ref args) => {
-            let path = path(&migr)?;
-            migration_run(args, path, pg)
-        }
-        MigrationSubcommand::Rev(ref args) => {
-            let path = path(&migr)?;
-            migration_rev(args, path, pg)
-        }
-        MigrationSubcommand::Redo(ref args) => {
-            let path = path(&migr)?;
-            migration_redo(args, path, pg)
+    // Scripts branch on the exit code of `pending`, so failures must not look like nothing pending
+    if let (MigrationSubcommand::Pending, Err(ref e)) = (&migr.command, &result) {
+        if e.is::<NothingPending>() {
+            process::exit(1);
         }
+        eprintln!("Error: {e:?}");
+        process::exit(2);
     }
-}
 
-fn establish_connection(url: &str) -> postgres::Client {
-    postgres::Client::connect(url, postgres::NoTls).expect("Could not establish PG connection")
+    result
 }
 
-fn path(migr: &Migr) -> anyhow::Result<PathBuf> {
-    let path = migr.path.as_ref().map(PathBuf::from);
-    if let Some(path) = path {
-        return Ok(path);
-    }
-    let current_dir = env::current_dir()?;
-    find_migrations(current_dir, 0, migr.depth)?
-        .ok_or(anyhow::Error::msg("Unable to locate migrations directory"))
-}
+/// Executes the command against every selected target.
+fn run(migr: &Migr) -> anyhow::Result<()> {
+    let mut config = Config::load(&migr.config)?;
+    let mut vars = vec![];
 
-#[derive(Debug, Parser)]
-#[command(name = "migr", author = "biblius", version = "0.1", about = "Minimal PG migration tool", long_about = None)]
-pub struct Migr {
-    #[clap(subcommand)]
-    pub command: MigrationSubcommand,
+    let args = match migr.command {
+        MigrationSubcommand::Run(ref args)
+        | MigrationSubcommand::Ensure(ref args)
+        | MigrationSubcommand::Resume(ref args)
+        | MigrationSubcommand::Rev(ref args)
+        | MigrationSubcommand::Redo(ref args) => Some(args),
+        MigrationSubcommand::Watch(ref args) => Some(&args.run),
+        _ => None,
+    };
+    if let Some(args) = args {
+        if args.run_as.is_some() {
+            config.run_as.clone_from(&args.run_as);
+        }
+        for var in &migr::vars_or_env(&args.vars, "MIGR_VARS") {
+            let Some((key, value)) = var.split_once('=') else {
+                return Err(anyhow::Error::msg(format!(
+                    "Invalid variable '{var}', expected `KEY=VALUE`"
+                )));
+            };
+            vars.push((key.trim().to_string(), value.to_string()));
+        }
+        config.params.extend(vars.iter().cloned());
+    }
 
-    /// If provided, migr will setup/load migrations in the given directory.
-    #[arg(long, short)]
-    path: Option<String>,
+    if migr.read_only && migr.command.writes() {
+        return Err(anyhow::Error::msg(
+            "The command writes to the database and is refused with `--read-only`",
+        ));
+    }
 
-    /// If a path is not provided, migr will search for a 'migrations' directory `depth` levels deep from the current one.
-    #[arg(long, short, default_value = "2")]
-    depth: usize,
+    // Closes the tunnels once dropped
+    let jump = migr.ssh.as_deref().map(Jump::new);
+    let jump = jump.as_ref();
 
-    /// Print migr plumbing to stdout.
-    #[arg(long, short, action)]
-    verbose: bool,
-}
+    let Some(ref target) = migr.target else {
+        return execute(migr, &config, None, jump);
+    };
 
-#[derive(Debug, Subcommand)]
-pub enum MigrationSubcommand {
-    /// Show the state of migrations in the metadata table.
-    Status,
-    /// Initialise a migration directory, set up the initial migration and create the metadata table.
-    Setup,
-    /// Sync existing/edited migrations with migr.
-    Sync(SyncArgs),
-    /// Generate a new migration
-    Gen(GenMigration),
-    /// Run pending migrations
-    Run(RunRevMigration),
-    /// Reverse migrations
-    Rev(RunRevMigration),
-    /// Redo migrations
-    Redo(RunRevMigration),
-}
+    let targets = config.targets(target)?;
 
-#[derive(Debug, Args, Default, Clone)]
-pub struct SyncArgs {
-    #[arg(long, short, action)]
-    /// Diffs the migrations directory with entries from the metadata table and removes all
-    /// table entries that do not exist in the directory.
-    trim: bool,
-}
+    // A new migration belongs to one database, copies of it would drift apart
+    if targets.len() > 1
+        && matches!(
+            migr.command,
+            MigrationSubcommand::Gen(_) | MigrationSubcommand::Capture(_)
+        )
+    {
+        return Err(anyhow::Error::msg(format!(
+            "Migrations are generated for a single target\nHint: Pass `--target` with one of: {}",
+            targets
+                .iter()
+                .map(|t| t.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )));
+    }
 
-#[derive(Debug, Args, Default, Clone)]
-pub struct GenMigration {
-    /// Migration name
-    pub name: String,
-}
+    let mut nothing_pending = true;
 
-#[derive(Debug, Args, Default, Clone)]
-pub struct RunRevMigration {
-    /// The exact migration to perform the action on. This will disregard the entry in the metadata table and will also update it.
-    #[arg(long, short)]
-    pub exact: Option<String>,
+    for target in targets {
+        if !matches!(migr.command, MigrationSubcommand::Pending) {
+            summary!("Target {}", target.name.blue());
+        }
+        // Policies are enforced per target, `--var` takes precedence over its params
+        let mut params = config.params.clone();
+        params.extend(target.params.clone());
+        params.extend(vars.iter().cloned());
+        let config = Config {
+            target: Some(target.name.clone()),
+            params,
+            ..config.clone()
+        };
+        match execute(migr, &config, Some(target), jump) {
+            Err(e) if e.is::<NothingPending>() => continue,
+            result => {
+                result.with_context(|| format!("Failed to migrate target '{}'", target.name))?
+            }
+        }
+        nothing_pending = false;
+    }
 
-    /// The number of migrations to run/revert/redo. Defaults to `1` when reverting.
-    #[arg(long, short)]
-    pub count: Option<usize>,
+    if nothing_pending && matches!(migr.command, MigrationSubcommand::Pending) {
+        return Err(NothingPending.into());
+    }
 
-    /// If true, performs the action on all migrations. Defaults to `true` when running.
-    #[arg(long, short, action)]
-    pub all: bool,
+    Ok(())
 }
 
-/// Gets the path of the directory where migrations are located. Skips `target` and any directories starting
-/// with `.`.
-fn find_migrations(
-    path: PathBuf,
-    depth: usize,
-    max_depth: usize,
-) -> Result<Option<PathBuf>, std::io::Error> {
-    if depth > max_depth {
-        return Ok(None);
-    }
-
-    // Try to find the migrations in root as usually that's where they're placed
-    if depth == 0 && path.is_dir() {
-        info!(
-            "Searching for migrations in {}",
-            path.display().to_string().purple()
-        );
-        for entry in path.read_dir()? {
-            let entry = entry?;
-            if entry.file_name() == "migrations" {
-                let path = entry.path();
-                info!(
-                    "Found migrations at {}",
-                    path.display().to_string().purple(),
-                );
-                return Ok(Some(path));
-            }
+/// Prints the resolved configuration of every selected target, or of the default database.
+fn env_info(migr: &Migr, matches: &ArgMatches) -> anyhow::Result<()> {
+    let config = Config::load(&migr.config)?;
+
+    let Some(ref target) = migr.target else {
+        effective::print(migr, matches, &config, None, path(migr, &config, None));
+        return Ok(());
+    };
+
+    for (i, target) in config.targets(target)?.into_iter().enumerate() {
+        if i > 0 {
+            println!();
         }
+        let mut config = config.clone();
+        config.params.extend(target.params.clone());
+        let path = path(migr, &config, Some(target));
+        effective::print(migr, matches, &config, Some(target), path);
     }
 
-    trace!("in {}", path.display().to_string().as_str().blue());
+    Ok(())
+}
 
-    for entry in path.read_dir()? {
-        let entry = entry?;
-        let path = entry.path();
+/// Executes the command against the given target, or the database at `DATABASE_URL` and the
+/// migrations directory from the arguments.
+fn execute(
+    migr: &Migr,
+    config: &Config,
+    target: Option<&Target>,
+    jump: Option<&Jump>,
+) -> anyhow::Result<()> {
+    let path = || path(migr, config, target);
+    let connect = || connect(migr, config, target, jump);
+    let manager = || db::manager(&url(target)?, migr.read_only, jump, &config.auth);
 
-        if !path.is_dir()
-            || entry.file_name() == "target"
-            || entry
-                .file_name()
-                .to_str()
-                .is_some_and(|s| s.starts_with('.'))
-        {
-            continue;
+    match migr.command {
+        MigrationSubcommand::Status(ref args) if args.env.is_some() => {
+            let path = path()?;
+            environment::status(args, &path, migr.read_only, jump, config)
         }
-
-        if entry.file_name() == "migrations" {
-            let path = entry.path();
-            info!(
-                "Found migrations at {}",
-                path.display().to_string().as_str().purple(),
-            );
-            return Ok(Some(path));
+        MigrationSubcommand::Status(ref args) if args.offline => {
+            let path = path()?;
+            status_offline(args, &path, config)
+        }
+        MigrationSubcommand::Status(ref args) => {
+            // The annotations in `meta.toml` are shown if the migrations are available
+            let path = match args.git || migr.crate_name.is_some() {
+                true => Some(path()?),
+                false => path().ok(),
+            };
+            status(args, path.as_deref(), &mut *connect()?.get()?)
+        }
+        MigrationSubcommand::Init(ref args) => {
+            init::init(args, &new_path(migr, target)?, &migr.config)
         }
+        MigrationSubcommand::Setup(ref args) => {
+            let path = new_path(migr, target)?;
+            setup(args, path, &mut *connect()?.get()?, config)
+        }
+        MigrationSubcommand::Sync(ref args) => {
+            let path = path()?;
+            sync(args.trim, &path, &mut *connect()?.get()?, config)
+        }
+        MigrationSubcommand::Gen(ref args) => {
+            let path = path()?;
+            if args.offline {
+                return migration_generate(args, path, None, config);
+            }
+            migration_generate(args, path, Some(&mut *connect()?.get()?), config)
+        }
+        MigrationSubcommand::Run(ref args) => {
+            let path = path()?;
+            if args.cluster.is_set() {
+                let pin = pin(migr, config, target);
+                return cluster::run(args, &path, migr.max_connections, jump, config, &pin);
+            }
+            canary::run(
+                args,
+                &path,
+                migr.max_connections,
+                jump,
+                config,
+                &connect()?,
+                migration_run,
+            )
+        }
+        MigrationSubcommand::Ensure(ref args) => {
+            let path = path()?;
+            if args.cluster.is_set() {
+                let pin = pin(migr, config, target);
+                return cluster::run(args, &path, migr.max_connections, jump, config, &pin);
+            }
+            canary::run(
+                args,
+                &path,
+                migr.max_connections,
+                jump,
+                config,
+                &connect()?,
+                ensure,
+            )
+        }
+        MigrationSubcommand::Watch(ref args) => {
+            args.run.cluster.check_not_set()?;
+            if args.run.canary.is_some() {
+                return Err(anyhow::Error::msg(
+                    "`--canary` is only supported when running migrations",
+                ));
+            }
+            let path = path()?;
+            watch(args, path, &connect()?, config)
+        }
+        MigrationSubcommand::Resume(ref args) => {
+            args.cluster.check_not_set()?;
+            if args.canary.is_some() {
+                return Err(anyhow::Error::msg(
+                    "`--canary` is only supported when running migrations",
+                ));
+            }
+            let path = path()?;
+            resume(args, path, &connect()?, config)
+        }
+        MigrationSubcommand::Skip(ref args) => {
+            let path = path()?;
+            skip(args, &path, &mut *connect()?.get()?)
+        }
+        MigrationSubcommand::Rev(ref args) => {
+            args.cluster.check_not_set()?;
+            let path = path()?;
+            migration_rev(args, path, &mut *connect()?.get()?, config)
+        }
+        MigrationSubcommand::Redo(ref args) => {
+            args.cluster.check_not_set()?;
+            let path = path()?;
+            migration_redo(args, path, &mut *connect()?.get()?, config)
+        }
+        MigrationSubcommand::Verify(ref args) => {
+            let path = path()?;
+            verify(args, &path, config)
+        }
+        MigrationSubcommand::Fmt(ref args) => {
+            let path = path()?;
+            fmt(args, &path, config)
+        }
+        MigrationSubcommand::Fix(ref args) => {
+            let path = path()?;
+            // Only named migrations are rewritten without knowing which ones are applied
+            let mut pg = match args.migrations.is_empty() {
+                true => Some(connect()?.get()?),
+                false => None,
+            };
+            fix(args, &path, pg.as_deref_mut(), config)
+        }
+        MigrationSubcommand::Capture(ref args) => {
+            let path = path()?;
+            capture(args, path, &mut *connect()?.get()?, config)
+        }
+        MigrationSubcommand::Describe(ref args) => describe(args, &mut *connect()?.get()?),
+        MigrationSubcommand::Show(ref args) => {
+            let path = path()?;
+            show(args, &path, &mut *connect()?.get()?, config)
+        }
+        MigrationSubcommand::Blame(ref args) => {
+            let path = path()?;
+            blame(args, &path, config)
+        }
+        MigrationSubcommand::Docs(ref args) => {
+            let path = path()?;
+            docs::docs(args, &path, config)
+        }
+        MigrationSubcommand::WhichMigrations(ref args) => {
+            let path = path()?;
+            impact::which_migrations(args, &path, config)
+        }
+        MigrationSubcommand::Template(ref args) => {
+            let path = path()?;
+            template::template(args, &path, &manager()?, config)
+        }
+        MigrationSubcommand::Snippet(ref args) => {
+            let path = path()?;
+            snippet::snippet(args, &path, config)
+        }
+        MigrationSubcommand::Archive(ref args) => {
+            let path = path()?;
+            archive::archive(args, &path, &mut *connect()?.get()?, config)
+        }
+        MigrationSubcommand::Baseline(ref args) => {
+            let path = path()?;
+            baseline::generate(args, &path, &url(target)?, &mut *connect()?.get()?, config)
+        }
+        MigrationSubcommand::Scrub(ref args) => {
+            let path = path()?;
+            scrub::scrub(args, &path, &mut *connect()?.get()?, config)
+        }
+        MigrationSubcommand::Promote(ref args) => {
+            let path = path()?;
+            environment::promote(args, &path, migr.max_connections, jump, config)
+        }
+        MigrationSubcommand::VersionInfo => version_info(&mut *connect()?.get()?),
+        MigrationSubcommand::Pending => {
+            let path = path()?;
+            pending(&path, &mut *connect()?.get()?, config)
+        }
+        MigrationSubcommand::Env | MigrationSubcommand::External(_) => {
+            unreachable!("env and external commands are run by main")
+        }
+    }
+}
 
-        let path = find_migrations(path, depth + 1, max_depth)?;
+/// Creates a connection pool for the database of the target, or the one at `DATABASE_URL`.
+fn connect(
+    migr: &Migr,
+    config: &Config,
+    target: Option<&Target>,
+    jump: Option<&Jump>,
+) -> anyhow::Result<Pool> {
+    let url = url(target)?;
+    let pool = db::pool(
+        &url,
+        migr.max_connections,
+        migr.read_only,
+        jump,
+        &config.auth,
+    )?;
 
-        if let Some(path) = path {
-            return Ok(Some(path));
-        }
+    let pin = pin(migr, config, target);
+    if pin.is_set() {
+        db::check_pin(&mut *pool.get()?, &url, &pin)?;
     }
 
-    Ok(None)
+    Ok(pool)
 }
 
-#[macro_export]
-macro_rules! trace {
-    ($($t:tt)*) => {{
-        use colored::Colorize;
-        if $crate::VERBOSE.load(std::sync::atomic::Ordering::Relaxed) {
-            print!("{:5} | ", "TRACE".blue());
-            println!($($t)*);
+/// Returns the pin of the database of the target, or the one at `DATABASE_URL`.
+fn pin(migr: &Migr, config: &Config, target: Option<&Target>) -> Pin {
+    match target {
+        Some(target) => target.pin(),
+        None => config.pin(&migr.config),
+    }
+}
+
+fn url(target: Option<&Target>) -> anyhow::Result<String> {
+    match target {
+        Some(target) => target.url(),
+        None => env::var("DATABASE_URL")
+            .context("`DATABASE_URL` must be set in the env before running migr"),
+    }
+}
+
+/// Returns the path of a migrations directory which may not exist yet.
+fn new_path(migr: &Migr, target: Option<&Target>) -> anyhow::Result<PathBuf> {
+    Ok(match (target, &migr.crate_name) {
+        (Some(target), _) => target.path.clone(),
+        (None, Some(name)) => {
+            workspace::crate_dir(name, &env::current_dir()?)?.join(discover::MIGRATIONS_DIR)
         }
-    }};
+        (None, None) => migr
+            .path
+            .as_deref()
+            .unwrap_or(Path::new("."))
+            .join("migrations"),
+    })
 }
 
-#[macro_export]
-macro_rules! info {
-    ($($t:tt)*) => {{
-        use colored::Colorize;
-            print!("{:5} | ", "INFO".green());
-            println!($($t)*);
-    }};
+fn path(migr: &Migr, config: &Config, target: Option<&Target>) -> anyhow::Result<PathBuf> {
+    if let Some(target) = target {
+        return Ok(target.path.clone());
+    }
+    if migr.path.is_some() && migr.crate_name.is_some() {
+        return Err(anyhow::Error::msg(
+            "`--path` and `--crate` cannot be used together",
+        ));
+    }
+    if let Some(ref path) = migr.path {
+        return Ok(path.clone());
+    }
+    if let Some(ref name) = migr.crate_name {
+        return workspace::locate(name, &config.paths, &env::current_dir()?);
+    }
+    if !config.paths.is_empty() {
+        return discover::choose_configured(&config.paths);
+    }
+    discover::choose(&discover::find(&env::current_dir()?, migr.depth)?)
 }
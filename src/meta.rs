@@ -0,0 +1,168 @@
+use anyhow::Context;
+use colored::{ColoredString, Colorize};
+use serde::Deserialize;
+use std::{fmt::Display, fs, path::Path};
+
+/// The name of the per-migration metadata file, located next to `up.sql` and `down.sql`.
+pub const META_FILE: &str = "meta.toml";
+
+/// Per-migration metadata loaded from `meta.toml`. Every section is optional.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MigrationMeta {
+    /// Whether the migration is executed in a transaction. Statements like
+    /// `CREATE INDEX CONCURRENTLY` can't run in a transaction block, so migrations containing them
+    /// are executed statement by statement instead.
+    pub transaction: bool,
+
+    /// Migrations this one depends on, by full id or name without the timestamp.
+    /// Used to determine which migrations can be executed in parallel.
+    pub depends_on: Option<Vec<String>>,
+
+    /// The release the migration ships in. The migrations of a release are applied and reverted
+    /// together in a single transaction and count as one migration.
+    pub release: Option<String>,
+
+    /// What the migration does and why.
+    pub description: Option<String>,
+
+    /// The ticket the migration was written for, e.g. its URL.
+    pub ticket: Option<String>,
+
+    /// Who to ask about the migration.
+    pub author: Option<String>,
+
+    /// The risk of applying the migration, as judged by its author.
+    pub risk: Option<Risk>,
+
+    /// The phase of a zero-downtime deploy the migration belongs to. Migrations without a phase are
+    /// applied in any phase.
+    pub phase: Option<Phase>,
+
+    /// Whether instances of the application built before the migration break once it is applied,
+    /// e.g. because it drops a column they read. See [crate::compat].
+    pub incompatible: bool,
+
+    /// Steps executed after `up.sql`.
+    pub up: Vec<ParamStep>,
+
+    /// Steps executed after `down.sql`.
+    pub down: Vec<ParamStep>,
+}
+
+impl Default for MigrationMeta {
+    fn default() -> Self {
+        Self {
+            transaction: true,
+            depends_on: None,
+            release: None,
+            description: None,
+            ticket: None,
+            author: None,
+            risk: None,
+            phase: None,
+            incompatible: false,
+            up: vec![],
+            down: vec![],
+        }
+    }
+}
+
+impl MigrationMeta {
+    /// Loads the metadata of the migration in `dir`. Returns the default if the migration has none.
+    pub fn load(dir: &Path) -> anyhow::Result<Self> {
+        let path = dir.join(META_FILE);
+
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Unable to read '{}'", path.display()))?;
+
+        toml::from_str(&contents)
+            .with_context(|| format!("Invalid metadata at '{}'", path.display()))
+    }
+
+    /// Returns the value of the annotation `field`, or `None` if it is not set or not an
+    /// annotation. See [ANNOTATIONS].
+    pub fn annotation(&self, field: &str) -> Option<String> {
+        match field {
+            "description" => self.description.clone(),
+            "ticket" => self.ticket.clone(),
+            "author" => self.author.clone(),
+            "risk" => self.risk.map(|risk| risk.to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// The fields of `meta.toml` describing a migration, which policies can require.
+pub const ANNOTATIONS: &[&str] = &["description", "ticket", "author", "risk"];
+
+/// How likely applying a migration is to cause trouble, e.g. lock contention or data loss.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Risk {
+    Low,
+    Medium,
+    High,
+}
+
+impl Risk {
+    pub fn colored(self) -> ColoredString {
+        match self {
+            Risk::Low => self.to_string().green(),
+            Risk::Medium => self.to_string().yellow(),
+            Risk::High => self.to_string().red(),
+        }
+    }
+}
+
+impl Display for Risk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Risk::Low => write!(f, "low"),
+            Risk::Medium => write!(f, "medium"),
+            Risk::High => write!(f, "high"),
+        }
+    }
+}
+
+/// The phases of an expand/contract deploy, in the order they are applied. Expanding migrations
+/// only add to the schema so the old and the new release of the application both work with it,
+/// and contracting ones remove what the old release needed once it is gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    Expand,
+    MigrateData,
+    Contract,
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Phase::Expand => write!(f, "expand"),
+            Phase::MigrateData => write!(f, "migrate-data"),
+            Phase::Contract => write!(f, "contract"),
+        }
+    }
+}
+
+/// A parameterized statement executed with the contents of files bound as `bytea` parameters.
+///
+/// ```toml
+/// [[up]]
+/// statement = "INSERT INTO certs(name, data) VALUES ('root', $1)"
+/// files = ["certs/root.der"]
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParamStep {
+    pub statement: String,
+
+    /// Paths relative to the migration directory, bound to `$1..$n` in order.
+    #[serde(default)]
+    pub files: Vec<String>,
+}
@@ -0,0 +1,108 @@
+use crate::summary::Report;
+use crate::{debug, http, MetricsArgs};
+use anyhow::{Context, Error};
+use std::fmt::Write;
+use std::fs;
+
+/// The grouping key used when the pushgateway URL does not specify one.
+const DEFAULT_JOB: &str = "/metrics/job/migr";
+
+/// Reports the outcome of a command to the configured sinks in the Prometheus text format.
+pub fn emit(args: &MetricsArgs, report: &Report) -> anyhow::Result<()> {
+    if args.metrics_pushgateway.is_none() && args.metrics_file.is_none() {
+        return Ok(());
+    }
+
+    let metrics = render(report);
+
+    if let Some(ref path) = args.metrics_file {
+        // Write to a temporary file first so collectors never read partial metrics
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, &metrics)
+            .and_then(|_| fs::rename(&tmp, path))
+            .with_context(|| format!("Unable to write metrics to '{}'", path.display()))?;
+        debug!("Wrote metrics to {}", path.display());
+    }
+
+    if let Some(ref url) = args.metrics_pushgateway {
+        let url = url.trim_end_matches('/');
+        let url = if url.contains("/metrics/job/") {
+            url.to_string()
+        } else {
+            format!("{url}{DEFAULT_JOB}")
+        };
+
+        // PUT replaces all metrics in the group so durations of previous runs do not linger
+        let status = http::send("PUT", &url, "text/plain; version=0.0.4", metrics.as_bytes())?;
+        if !(200..300).contains(&status) {
+            return Err(Error::msg(format!(
+                "Pushgateway at '{url}' responded with {status}"
+            )));
+        }
+        debug!("Pushed metrics to {url}");
+    }
+
+    Ok(())
+}
+
+fn render(report: &Report) -> String {
+    let mut out = String::new();
+    let action = format!("action=\"{}\"", report.action);
+
+    let mut gauge = |name: &str, help: &str, samples: &[(String, f64)]| {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{name}{{{labels}}} {value}");
+        }
+    };
+
+    gauge(
+        "migr_last_run_success",
+        "Whether the last run of migr succeeded.",
+        &[(action.clone(), report.success as u8 as f64)],
+    );
+    gauge(
+        "migr_last_run_timestamp_seconds",
+        "Unix time at which the last run of migr finished.",
+        &[(action.clone(), report.finished_at as f64)],
+    );
+    gauge(
+        "migr_last_run_duration_seconds",
+        "Duration of the last run of migr.",
+        &[(action.clone(), report.total_ms / 1000.)],
+    );
+    gauge(
+        "migr_last_run_migrations",
+        "Number of migrations processed by the last run of migr.",
+        &[(action.clone(), report.count as f64)],
+    );
+    gauge(
+        "migr_migration_duration_seconds",
+        "Duration of each migration processed by the last run of migr.",
+        &report
+            .migrations
+            .iter()
+            .map(|m| {
+                (
+                    format!(
+                        "{action},id=\"{}\",direction=\"{}\"",
+                        escape(&m.id),
+                        m.direction
+                    ),
+                    m.duration_ms / 1000.,
+                )
+            })
+            .collect::<Vec<_>>(),
+    );
+
+    out
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -1,10 +1,52 @@
-use crate::{info, trace, GenMigration, RunRevMigration};
+use crate::analyze;
+use crate::archive;
+use crate::backup;
+use crate::baseline;
+use crate::cache::{self, Cache};
+use crate::chaos;
+use crate::compat;
+use crate::config::{Config, GenClock, GrantsConfig};
+use crate::db::Pool;
+use crate::dependency;
+use crate::destructive;
+use crate::directive::{self, Directive, Step};
+use crate::explain;
+use crate::foreign;
+use crate::functions::{self, FUNCTIONS_DIR};
+use crate::ignore::{self, Ignore, IGNORE_FILE};
+use crate::lock::{self, Acquired};
+use crate::meta::{MigrationMeta, ParamStep, Phase, META_FILE};
+use crate::picker;
+use crate::plugin;
+use crate::policy;
+use crate::risk;
+use crate::safe_ddl;
+use crate::schema::{self, NAME_COLUMN, VERSION_COLUMN};
+use crate::snippet;
+use crate::sql::{self, quote_ident};
+use crate::summary::{current_version, Summary};
+use crate::views;
+use crate::volume;
+use crate::workspace;
+use crate::{
+    debug, fmt, git, highlight, idempotent, info, introspect, lint, output, scaffold, summary,
+    trace, warn, BlameArgs, CaptureArgs, DescribeArgs, FixArgs, FmtArgs, GenCommand, GenMigration,
+    RunRevMigration, SetupArgs, ShowArgs, SkipArgs, StatusArgs, VerifyArgs, WatchArgs,
+};
 use anyhow::{Context, Error};
 use colored::Colorize;
-use postgres::{Client, Transaction};
-use std::collections::HashSet;
+use postgres::types::ToSql;
+use postgres::{Client, GenericClient, Transaction};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Write};
-use std::{fs, path::PathBuf};
+use std::fs;
+use std::io::{self, BufRead, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
 const INITIAL: &str = "0000000000_pg_migrator";
 
@@ -14,22 +56,238 @@ CREATE TABLE __migr_meta__(
     pending BOOLEAN DEFAULT TRUE
 )";
 
-const INITIAL_ENTRY_QUERY: &str = "
-INSERT INTO __migr_meta__ VALUES (0, TRUE)
-";
-
 pub fn migration_generate(
     args: &GenMigration,
-    mut path: PathBuf,
-    mut pg: Client,
+    path: PathBuf,
+    mut pg: Option<&mut Client>,
+    config: &Config,
 ) -> anyhow::Result<()> {
-    check_table(&mut pg)?;
+    if let Some(pg) = pg.as_deref_mut() {
+        check_table(pg)?;
+    }
+
+    let (name, mut up, mut down) = match (
+        &args.generator,
+        &args.extension,
+        args.enum_change.as_slice(),
+    ) {
+        (Some(GenCommand::AddTable(table)), ..) => scaffold::table(table, &config.gen)?,
+        (_, _, [ty, action, value]) => {
+            let Ok(action) = action.parse::<EnumAction>() else {
+                return Err(Error::msg(format!(
+                    "Unknown enum action '{action}', expected `add-value`"
+                )));
+            };
+            let value = value.trim_matches('\'');
+            let name = match args.name {
+                Some(ref name) => name.clone(),
+                None => format!("{ty}_add_{value}")
+                    .replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+                    .to_lowercase(),
+            };
+            let (up, down) = action.sql(ty, value);
+            (name, up, down)
+        }
+        (_, Some(extension), _) => {
+            let name = match args.name {
+                Some(ref name) => name.clone(),
+                None => format!("enable_{}", extension.replace('-', "_")),
+            };
+            let up = format!(
+                "CREATE EXTENSION IF NOT EXISTS {};\n",
+                quote_ident(extension)
+            );
+            let down = match args.drop_extension {
+                true => format!("DROP EXTENSION IF EXISTS {};\n", quote_ident(extension)),
+                false => format!(
+                    "-- {extension} is left installed since other objects may depend on it\n"
+                ),
+            };
+            (name, up, down)
+        }
+        (_, None, _) => (
+            args.name.clone().unwrap_or_default(),
+            String::new(),
+            String::from("-- Revert everything from up.sql\n"),
+        ),
+    };
+    let name = &name;
+
+    if !args.snippets.is_empty() {
+        let (snippet_up, snippet_down) = snippet::expand(
+            &path,
+            &args.snippets,
+            &crate::vars_or_env(&args.vars, "MIGR_SNIPPET_VARS"),
+            name,
+        )?;
+        // Keep the expansions out of the header
+        up = format!("{up}\n{snippet_up}");
+        if !snippet_down.is_empty() {
+            down = format!("\n{snippet_down}\n{down}");
+        }
+    }
+
+    let id = migration_create(name, path.clone(), pg.as_deref_mut(), config, &up, &down)?;
+
+    if !args.enum_change.is_empty() {
+        fs::write(
+            path.join(&id).join(META_FILE),
+            "# Values added to an enum can't be used until the transaction adding them is committed\ntransaction = false\n",
+        )?;
+    }
+
+    let Some(pg) = pg else {
+        summary!("Successfully generated migration {}", name.green());
+        info!("Run `migr sync` once connected to register it in the metadata table");
+        return Ok(());
+    };
+
+    debug!("Updating metadata table");
+
+    let version = split_id(&id).0;
+    pg.execute(
+        "INSERT INTO __migr_meta__(id, version, name, pending) VALUES ($1, $2, $3, TRUE)",
+        &[&id, &version, name],
+    )
+    .context("Could not insert into __migr_meta__")?;
+
+    summary!("Successfully generated migration {}", name.green());
+
+    Ok(())
+}
+
+/// Changes to enum types `gen --enum` can generate.
+#[derive(Debug, Clone, Copy)]
+enum EnumAction {
+    AddValue,
+}
+
+impl std::str::FromStr for EnumAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "add-value" => Ok(Self::AddValue),
+            _ => Err(()),
+        }
+    }
+}
+
+impl EnumAction {
+    /// Returns the up and down migration applying the action to the type `ty`.
+    fn sql(self, ty: &str, value: &str) -> (String, String) {
+        let literal = format!("'{}'", value.replace('\'', "''"));
+        match self {
+            Self::AddValue => (
+                format!("ALTER TYPE {ty} ADD VALUE IF NOT EXISTS {literal};\n"),
+                format!("-- Values can't be removed from an enum, recreate {ty} without {literal} to revert\n"),
+            ),
+        }
+    }
+}
+
+/// Generates a migration from the current definitions of objects in the database. Since the objects
+/// already exist, the migration is recorded as executed.
+pub fn capture(
+    args: &CaptureArgs,
+    path: PathBuf,
+    pg: &mut Client,
+    config: &Config,
+) -> anyhow::Result<()> {
+    check_table(pg)?;
     let name = &args.name;
-    let date = time::OffsetDateTime::now_utc();
+
+    let mut objects = vec![];
+    for object in &args.objects {
+        objects.extend(introspect::objects(pg, object)?);
+    }
+
+    let up = objects
+        .iter()
+        .map(|o| o.create())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    // Drop in reverse order so dependent objects are dropped first
+    let down = objects.iter().rev().map(|o| o.drop()).collect::<String>();
+
+    let id = migration_create(name, path.clone(), Some(pg), config, &up, &down)?;
+
+    debug!("Updating metadata table");
+
+    let version = split_id(&id).0;
+    let up = path.join(&id).join("up.sql");
+    let checksum = directive::checksum(&fs::read(&up)?, &up);
+    // Captured objects already exist, so no run applied the migration
+    pg.execute(
+        "INSERT INTO __migr_meta__(id, version, name, pending, checksum, applied_at, batch) VALUES ($1, $2, $3, FALSE, $4, now(), 0)",
+        &[&id, &version, name, &checksum],
+    )
+    .context("Could not insert into __migr_meta__")?;
+
+    summary!(
+        "Successfully captured {} object(s) into migration {}",
+        objects.len(),
+        name.green()
+    );
+
+    Ok(())
+}
+
+/// Creates the directory of a new migration named `name` in `path` with the configured header
+/// followed by `up` and `down`. Returns the ID of the migration.
+fn migration_create(
+    name: &str,
+    mut path: PathBuf,
+    mut pg: Option<&mut Client>,
+    config: &Config,
+    up: &str,
+    down: &str,
+) -> anyhow::Result<String> {
+    let date = match (config.gen.clock, pg.as_deref_mut()) {
+        (GenClock::Local, _) => time::OffsetDateTime::now_utc(),
+        (GenClock::Database, Some(pg)) => {
+            let now = pg
+                .query_one("SELECT EXTRACT(EPOCH FROM now())::BIGINT", &[])?
+                .get::<_, i64>(0);
+            time::OffsetDateTime::from_unix_timestamp(now)?
+        }
+        (GenClock::Database, None) => {
+            warn!("Using the clock of this machine, since the clock of the database can't be read offline");
+            time::OffsetDateTime::now_utc()
+        }
+    };
     let (date, (h, m, s)) = (date.date(), date.time().as_hms());
 
     let full_name = format!("{date}-{h:02}{m:02}{s:02}_{name}");
 
+    // Migrations sorting before applied ones are never run in order on other databases
+    let current = match pg {
+        Some(pg) => current_version(pg)?,
+        None => None,
+    };
+    if let Some(current) = current {
+        if split_id(&full_name).0 <= current.as_str() {
+            let hint = match config.gen.clock {
+                GenClock::Local => {
+                    "the clock of this machine or set `clock = \"database\"` in the gen config"
+                }
+                GenClock::Database => "the clock of the database server",
+            };
+            warn!(
+                "The timestamp of {full_name} is not after the latest applied migration {current}\nHint: Check {hint}"
+            );
+        }
+    }
+
+    let header = config
+        .gen
+        .header
+        .replace("{name}", name)
+        .replace("{id}", &full_name)
+        .replace("{author}", &git::author().unwrap_or_default())
+        .replace("{created_at}", &format!("{date} {h:02}:{m:02}:{s:02} UTC"));
+
     path.push(&full_name);
 
     info!(
@@ -46,7 +304,7 @@ pub fn migration_generate(
         path.display().to_string().as_str().green()
     );
 
-    fs::write(&path, "")?;
+    fs::write(&path, generated(format!("{header}{up}"), config))?;
 
     path.pop();
     path.push("down.sql");
@@ -56,119 +314,545 @@ pub fn migration_generate(
         path.display().to_string().as_str().bright_red()
     );
 
-    fs::write(path, "-- Revert everything from up.sql")?;
+    fs::write(path, generated(format!("{header}{down}"), config))?;
+
+    Ok(full_name)
+}
+
+/// Formats the SQL of a generated migration file if enabled in the config.
+fn generated(sql: String, config: &Config) -> String {
+    if !config.fmt.on_gen {
+        return sql;
+    }
+    fmt::format(&sql, &config.fmt).unwrap_or(sql)
+}
 
-    trace!("Updating metadata table");
+pub fn migration_run(
+    args: &RunRevMigration,
+    path: PathBuf,
+    pool: &Pool,
+    config: &Config,
+) -> anyhow::Result<()> {
+    if args.fail_after.is_some() {
+        chaos::fail_after(args.fail_after.as_deref());
+    }
+    let mut pg = pool.get()?;
+    let summary = Summary::new("executed");
+    summary.begin(&mut *pg);
+    let wait = args
+        .wait_if_locked
+        .then(|| Duration::from_secs(args.lock_timeout));
+    let result = with_lock(&mut pg, wait, |pg, acquired| match acquired {
+        Acquired::Immediately => run(args, path.clone(), pool, pg, config, &summary),
+        Acquired::AfterWaiting => check_applied(&path, pg, config),
+    });
+    if result.is_ok() {
+        analyze::analyze(&path, &summary.applied(), &mut pg, config);
+    }
+    summary.finish(&mut pg, args, config, result)
+}
 
-    pg.execute("INSERT INTO __migr_meta__ VALUES ($1, TRUE)", &[&full_name])
-        .context("Could not insert into __migr_meta__")?;
+/// Syncs the metadata table with the migrations at `path`, creating it if it is missing, and runs
+/// the pending migrations.
+pub fn ensure(
+    args: &RunRevMigration,
+    path: PathBuf,
+    pool: &Pool,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let wait = args
+        .wait_if_locked
+        .then(|| Duration::from_secs(args.lock_timeout));
 
-    info!("Successfully generated migration {}", name.green());
+    // Instances started at once would race to create the metadata table
+    with_lock(&mut *pool.get()?, wait, |pg, _| {
+        sync(false, &path, pg, config)
+    })?;
 
-    Ok(())
+    migration_run(args, path, pool, config)
+}
+
+/// Runs [ensure] whenever the migrations at `path` change, until interrupted. Runs reuse the
+/// connections of the pool and failed runs are reported without ending the watch.
+pub fn watch(args: &WatchArgs, path: PathBuf, pool: &Pool, config: &Config) -> anyhow::Result<()> {
+    let interval = Duration::from_secs(args.interval.max(1));
+    let mut last = None;
+
+    info!(
+        "Watching {} for changes",
+        path.display().to_string().purple()
+    );
+
+    loop {
+        let current = fingerprint(&path, config)?;
+        if last.as_ref() != Some(&current) {
+            if let Err(e) = ensure(&args.run, path.clone(), pool, config) {
+                warn!("{e:?}");
+            }
+            // Running can rewrite the cache, e.g. when migrations are synced
+            last = Some(fingerprint(&path, config)?);
+            summary!("Waiting for changes");
+        }
+        std::thread::sleep(interval);
+    }
 }
 
-pub fn migration_run(args: &RunRevMigration, path: PathBuf, mut pg: Client) -> anyhow::Result<()> {
+/// Lifts the quarantine of the migrations which failed in previous runs and runs the pending
+/// migrations, starting with them.
+pub fn resume(
+    args: &RunRevMigration,
+    path: PathBuf,
+    pool: &Pool,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let mut pg = pool.get()?;
     check_table(&mut pg)?;
 
+    with_lock(&mut pg, None, |pg, _| {
+        let failed = pg.query(
+            "UPDATE __migr_meta__ SET error = NULL, failed_at = NULL WHERE error IS NOT NULL RETURNING id",
+            &[],
+        )?;
+        if failed.is_empty() {
+            return Err(Error::msg(
+                "No migration failed\nHint: Run the pending migrations with `migr run`",
+            ));
+        }
+        for row in failed {
+            info!("Retrying {}", row.get::<_, String>(0).blue());
+        }
+        Ok(())
+    })?;
+    drop(pg);
+
+    migration_run(args, path, pool, config)
+}
+
+/// Marks a pending migration as applied without executing it. Skipped migrations are in batch 0,
+/// so `rev --last-batch` never reverts them.
+pub fn skip(args: &SkipArgs, path: &Path, pg: &mut Client) -> anyhow::Result<()> {
+    check_table(pg)?;
+
+    with_lock(pg, None, |pg, _| {
+        let (dir, id) = find_exact(path, &args.migration, pg)?;
+
+        let pending = pg
+            .query_one("SELECT pending FROM __migr_meta__ WHERE id = $1", &[&id])?
+            .get::<_, bool>(0);
+        if !pending {
+            return Err(Error::msg(format!("Migration {id} is already applied")));
+        }
+
+        let up = dir.join("up.sql");
+        let checksum = directive::checksum(&fs::read(&up)?, &up);
+
+        let mut tx = pg.transaction()?;
+        tx.execute(
+            "UPDATE __migr_meta__ SET pending = FALSE, checksum = $2, applied_at = now(), batch = 0, error = NULL, failed_at = NULL WHERE id = $1",
+            &[&id, &checksum],
+        )?;
+        tx.execute(
+            "INSERT INTO __migr_history__(migration, direction, checksum, duration_ms) VALUES ($1, 'skip', $2, 0)",
+            &[&id, &checksum],
+        )?;
+        tx.commit()?;
+
+        summary!("Marked {} as applied without executing it", id.green());
+        Ok(())
+    })
+}
+
+pub fn migration_rev(
+    args: &RunRevMigration,
+    path: PathBuf,
+    pg: &mut Client,
+    config: &Config,
+) -> anyhow::Result<()> {
+    if args.fail_after.is_some() {
+        chaos::fail_after(args.fail_after.as_deref());
+    }
+    let summary = Summary::new("reverted");
+    summary.begin(pg);
+    let result = check_run_only(args)
+        .and_then(|_| with_lock(pg, None, |pg, _| rev(args, path, pg, config, &summary)));
+    summary.finish(pg, args, config, result)
+}
+
+pub fn migration_redo(
+    args: &RunRevMigration,
+    path: PathBuf,
+    pg: &mut Client,
+    config: &Config,
+) -> anyhow::Result<()> {
+    if args.fail_after.is_some() {
+        chaos::fail_after(args.fail_after.as_deref());
+    }
+    let summary = Summary::new("redone");
+    summary.begin(pg);
+    let result = check_run_only(args)
+        .and_then(|_| with_lock(pg, None, |pg, _| redo(args, path, pg, config, &summary)));
+    summary.finish(pg, args, config, result)
+}
+
+fn run(
+    args: &RunRevMigration,
+    path: PathBuf,
+    pool: &Pool,
+    pg: &mut Client,
+    config: &Config,
+    summary: &Summary,
+) -> anyhow::Result<()> {
+    if args.last_batch {
+        return Err(Error::msg(
+            "`--last-batch` is only supported when reverting migrations",
+        ));
+    }
+    if args.allow_changed_down {
+        return Err(Error::msg(
+            "`--allow-changed-down` is only supported when reverting migrations",
+        ));
+    }
+
+    // Runs of all pending migrations are skipped with a single query if the migrations did not
+    // change since the last one, which keeps starting services embedding migr cheap
+    let fingerprint = match (&args.exact, args.count, args.phase, &args.only) {
+        (None, None, None, None) => Some(fingerprint(&path, config)?),
+        _ => None,
+    };
+    if fingerprint.is_some() && schema::fingerprint(pg)? == fingerprint {
+        debug!("Migrations unchanged since the last run");
+        return Ok(());
+    }
+
+    check_table(pg)?;
+    check_extensions(pg, config)?;
+
+    if args.exact.is_none() {
+        check_failed(pg)?;
+    }
+
+    // Fresh databases start from the baseline instead of replaying the migrations it covers
+    if args.exact.is_none()
+        && args.count.is_none()
+        && args.only.is_none()
+        && !args.no_baseline
+        && !args.sandbox
+    {
+        baseline::apply(&path, pg, config)?;
+    }
+
+    lint::report(&lint::naming(&naming_dirs(&path, config)?, &config.lint))?;
+
+    let mut pending = match args.exact {
+        Some(ref name) => vec![find_exact(&path, name, pg)?.0.join("up.sql")],
+        None => migration_pending(&migration_files(&path, UpDown::Up, config)?, pg)?,
+    };
+
+    // Empty migrations and the ones of later phases are left pending
+    let mut skip = empty(&pending, args)?;
+    if let Some(phase) = args.phase {
+        skip.extend(gated(&pending, phase)?);
+    }
+    if let Some(ref only) = args.only {
+        skip.extend(
+            pending
+                .iter()
+                .filter_map(|file| migration_id(file))
+                .filter(|id| !only.contains(*id))
+                .map(String::from),
+        );
+    }
+    pending.retain(|file| migration_id(file).is_some_and(|id| !skip.contains(id)));
+    if args.exact.is_some() && pending.is_empty() {
+        return Ok(());
+    }
+
+    let mut violations = lint::uncommitted(&path, &pending, &config.lint);
+    if config.lint.replication {
+        violations.extend(lint::replication(&pending, &config.lint)?);
+    }
+    lint::report(&violations)?;
+    policy::report(&policy::check(
+        &pending,
+        &config.policies,
+        config.target.as_deref(),
+    )?)?;
+
+    let losses = destructive::scan(pg, &pending)?;
+    // Data deleted in the sandbox is restored by its rollback
+    if !args.sandbox {
+        destructive::confirm(&losses, args.allow_data_loss)?;
+    }
+    foreign::check(&foreign::scan(pg, &pending)?, args.allow_fdw)?;
+    if args.explain {
+        explain::report(pg, &pending)?;
+    }
+    if args.sandbox {
+        return sandbox(&pending, pg, config);
+    }
+    let max_risk = args.max_risk.or_else(|| {
+        let target = config.target.as_deref()?;
+        config.targets.iter().find(|t| t.name == target)?.max_risk
+    });
+    risk::check(&risk::assess(pg, &pending, &config.lint)?, max_risk)?;
+    safe_ddl::advise(pg, &pending)?;
+    compat::check(pg, &pending, args.force_incompatible)?;
+    backup::backup(pg, &losses, &config.backup)?;
+
+    // Migrations applied before a failure still make up a batch
     if let Some(ref name) = args.exact {
-        return find_and_execute(&path, name, &mut pg, UpDown::Up);
+        let result = find_and_execute(&path, name, pg, UpDown::Up, args, config, summary);
+        return result.and(record_batch(pg));
     }
 
     info!("Running migrations");
     let count = args.count;
-    let count = migration_up(count, path, &mut pg)?;
-    if count > 0 {
-        info!("{count} migrations successfully executed");
-    } else {
-        info!("Migrations already up to date");
+    let result = match args.parallel {
+        Some(parallel) => migration_up_parallel(count, parallel, pending, pool, config, summary),
+        None if args.isolate_failures => migration_up_isolated(count, pending, pg, config, summary),
+        None => migration_up(count, path.clone(), &skip, pg, config, summary),
+    };
+    result.and(record_batch(pg))?;
+
+    // Functions may depend on any migration, so they are only applied once all of them are
+    if let Some(fingerprint) = fingerprint {
+        functions::apply(&path, pg, config)?;
+        // Empty migrations are left pending, so the next run reports them again
+        if skip.is_empty() {
+            schema::record_fingerprint(pg, &fingerprint)?;
+        }
     }
+
     Ok(())
 }
 
-pub fn migration_rev(args: &RunRevMigration, path: PathBuf, mut pg: Client) -> anyhow::Result<()> {
-    check_table(&mut pg)?;
+/// Executes the pending migrations in a transaction which is rolled back once all of them were
+/// tried, and reports which of them failed and how long each took. Each migration is executed in a
+/// savepoint, so the ones after a failing migration are still tried. Migrations which can't run in
+/// a transaction are not executed.
+fn sandbox(pending: &[PathBuf], pg: &mut Client, config: &Config) -> anyhow::Result<()> {
+    let mut outcomes = vec![];
 
-    if let Some(ref name) = args.exact {
-        return find_and_execute(&path, name, &mut pg, UpDown::Down);
+    info!("Executing migrations in a sandbox");
+    let started = Instant::now();
+    let mut tx = pg.transaction()?;
+    for file in pending {
+        let id = migration_id(file).unwrap_or_default();
+        let dir = file.parent().unwrap_or(Path::new("."));
+        if !MigrationMeta::load(dir)?.transaction {
+            outcomes.push((id, None, Duration::ZERO));
+            continue;
+        }
+        let started = Instant::now();
+        let result = sandbox_execute(file, id, &mut tx, config);
+        outcomes.push((id, Some(result), started.elapsed()));
+    }
+    tx.rollback()?;
+    debug!("Rolled back the sandbox");
+
+    let width = outcomes.iter().map(|(id, ..)| id.len()).max().unwrap_or(0) + 3;
+    summary!("Sandbox:");
+    for (id, result, duration) in &outcomes {
+        let state = match result {
+            Some(Ok(_)) => format!("{:<7} {duration:.2?}", "ok".green()),
+            Some(Err(e)) => format!("{:<7} {duration:.2?}: {:#}", "failed".red(), e),
+            None => format!("{}, it can't run in a transaction", "skipped".yellow()),
+        };
+        summary!("{id:.<width$} {state}");
     }
 
-    info!("Reverting migrations");
-    let count = args.count.or((!args.all).then_some(1));
-    let count = migration_down(count, &path, &mut pg)?;
-    if count > 0 {
-        info!("{count} migrations successfully reverted");
-    } else {
-        info!("Migrations already up to date");
+    let failed = outcomes
+        .iter()
+        .filter(|(_, result, _)| matches!(result, Some(Err(_))))
+        .count();
+    if failed > 0 {
+        return Err(Error::msg(format!(
+            "{failed} of {} pending migrations failed in the sandbox, nothing was applied",
+            outcomes.len()
+        )));
     }
+    summary!(
+        "All pending migrations succeeded in {:.2?} and were rolled back",
+        started.elapsed()
+    );
+
     Ok(())
 }
 
-pub fn migration_redo(args: &RunRevMigration, path: PathBuf, mut pg: Client) -> anyhow::Result<()> {
-    check_table(&mut pg)?;
+/// Executes a migration in a savepoint of the sandbox, kept for the migrations after it if it
+/// succeeds. Unlike applying it, nothing is recorded, reported or handed to plugins, since nothing
+/// executed in the sandbox is kept.
+fn sandbox_execute(
+    file: &Path,
+    id: &str,
+    tx: &mut Transaction<'_>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let prepared = Prepared::load(file, false)?;
+    let mut savepoint = tx.transaction()?;
+    prepared.execute(id, &mut savepoint, UpDown::Up, config, true)?;
+    Ok(savepoint.commit()?)
+}
 
-    if let Some(ref name) = args.exact {
-        find_and_execute(&path, name, &mut pg, UpDown::Down)?;
-        return find_and_execute(&path, name, &mut pg, UpDown::Up);
+/// Returns the IDs of the pending migrations held back when running up to `phase`, i.e. the first
+/// one of a later phase and every one after it, so migrations are still applied in order.
+fn gated(pending: &[PathBuf], phase: Phase) -> anyhow::Result<Vec<String>> {
+    for (i, file) in pending.iter().enumerate() {
+        let dir = file.parent().unwrap_or(Path::new("."));
+        let Some(later) = MigrationMeta::load(dir)?.phase.filter(|p| *p > phase) else {
+            continue;
+        };
+        let gated = pending[i..]
+            .iter()
+            .filter_map(|file| migration_id(file).map(String::from))
+            .collect::<Vec<_>>();
+        info!(
+            "Holding back {} migration(s) from {}, which is in the {later} phase\nHint: Pass `--phase {later}` to apply them",
+            gated.len(),
+            gated[0].yellow()
+        );
+        return Ok(gated);
     }
-
-    info!("Redoing migrations");
-    let count = args.count.or((!args.all).then_some(1));
-    migration_down(count, &path, &mut pg)?;
-    migration_up(count, path, &mut pg)?;
-    info!("Successfully redone migrations");
-    Ok(())
+    Ok(vec![])
 }
 
-pub fn setup(mut path: PathBuf, pg: &mut Client) -> anyhow::Result<()> {
-    info!("Creating metadata table");
+fn rev(
+    args: &RunRevMigration,
+    path: PathBuf,
+    pg: &mut Client,
+    config: &Config,
+    summary: &Summary,
+) -> anyhow::Result<()> {
+    check_table(pg)?;
 
-    let query = format!("{INITIAL_TABLE_QUERY};{INITIAL_ENTRY_QUERY}");
+    if let Some(ref name) = args.exact {
+        return find_and_execute(&path, name, pg, UpDown::Down, args, config, summary);
+    }
 
-    if let Err(err) = pg.batch_execute(&query) {
-        let Some(e) = err.as_db_error() else {
-            return Err(err.into());
-        };
+    if args.last_batch {
+        return migration_down_last_batch(&path, pg, args, config, summary);
+    }
 
-        if *e.code() != postgres::error::SqlState::DUPLICATE_TABLE {
-            return Err(err.into());
+    // Asks how far to revert when nothing says so and someone is there to answer
+    let count = match args.count {
+        None if !args.all && !args.json && output::interactive() => {
+            let applied = pg
+                .query(
+                    &format!("SELECT id FROM __migr_meta__ WHERE NOT pending ORDER BY {VERSION_COLUMN} DESC, id DESC"),
+                    &[],
+                )?
+                .into_iter()
+                .map(|row| {
+                    let id = row.get::<_, String>(0);
+                    let release = MigrationMeta::load(&path.join(&id))?.release;
+                    Ok((id, release))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            match applied.is_empty() {
+                true => Some(1),
+                false => Some(picker::rev_count(&path, &applied)?),
+            }
         }
-
-        return Err(err).context("The migr metadata table already exists. Run `migr sync` if you need to sync it with existing migrations.");
+        count => count.or((!args.all).then_some(1)),
     };
 
-    info!("Creating migrations directory");
+    info!("Reverting migrations");
+    migration_down(count, &path, pg, args, config, summary)
+}
 
-    fs::create_dir(&path)
-        .with_context(|| format!("Unable to create migrations at '{}'", path.display()))?;
+fn redo(
+    args: &RunRevMigration,
+    path: PathBuf,
+    pg: &mut Client,
+    config: &Config,
+    summary: &Summary,
+) -> anyhow::Result<()> {
+    check_table(pg)?;
 
-    path.push(INITIAL);
+    if args.last_batch {
+        return Err(Error::msg(
+            "`--last-batch` is only supported when reverting migrations",
+        ));
+    }
 
-    fs::create_dir(&path)
-        .with_context(|| format!("Unable to create migration at '{}'", path.display()))?;
+    if let Some(ref name) = args.exact {
+        find_and_execute(&path, name, pg, UpDown::Down, args, config, summary)?;
+        let result = find_and_execute(&path, name, pg, UpDown::Up, args, config, summary);
+        return result.and(record_batch(pg));
+    }
 
-    path.push("up.sql");
+    info!("Redoing migrations");
+    let count = args.count.or((!args.all).then_some(1));
+    migration_down(count, &path, pg, args, config, summary)?;
+    let result = migration_up(count, path, &HashSet::new(), pg, config, summary);
+    result.and(record_batch(pg))
+}
 
-    trace!("Setting up initial 'up' migration");
+/// Creates the metadata table, the migrations directory and the initial migration, skipping the
+/// ones which already exist, so it can be run repeatedly.
+pub fn setup(
+    args: &SetupArgs,
+    path: PathBuf,
+    pg: &mut Client,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let mut created = vec![];
 
-    fs::write(&path, "-- Set up initial SQL dependencies here")?;
+    let exists = pg
+        .query_one("SELECT to_regclass('__migr_meta__') IS NOT NULL", &[])?
+        .get::<_, bool>(0);
+    if exists {
+        info!("Metadata table already exists");
+    } else {
+        info!("Creating metadata table");
+        pg.batch_execute(INITIAL_TABLE_QUERY)?;
+        created.push(String::from("metadata table"));
+    }
 
-    path.pop();
-    path.push("down.sql");
+    schema::upgrade(pg)?;
 
-    trace!("Setting up initial 'down' migration");
+    if path.is_dir() {
+        info!("Migrations directory already exists at {}", path.display());
+    } else {
+        info!("Creating migrations directory");
+        // Target directories may be nested, e.g. `db/primary`
+        fs::create_dir_all(&path)
+            .with_context(|| format!("Unable to create migrations at '{}'", path.display()))?;
+        created.push(format!("migrations directory at {}", path.display()));
+    }
 
-    fs::write(&path, "-- Revert everything from up.sql")?;
+    let initial = path.join(INITIAL);
 
-    info!(
-        "Successfully set up migrations directory at {}",
-        path.display().to_string().as_str().purple()
-    );
+    // Projects which already have migrations don't need the initial one
+    let wanted = config.setup.initial && !args.no_initial;
+    if wanted && !initial.exists() && migration_dirs(&path, config)?.is_empty() {
+        debug!("Setting up initial migration");
+
+        fs::create_dir(&initial)
+            .with_context(|| format!("Unable to create migration at '{}'", initial.display()))?;
+        fs::write(initial.join("up.sql"), &config.setup.initial_up)?;
+        fs::write(initial.join("down.sql"), &config.setup.initial_down)?;
+
+        created.push(String::from("initial migration"));
+    }
+
+    if initial.exists() {
+        register(&[initial], pg)?;
+    }
+
+    if created.is_empty() {
+        summary!(
+            "Migrations at {} are already set up",
+            path.display().to_string().as_str().purple()
+        );
+    } else {
+        summary!("Created {}", created.join(", ").purple());
+    }
 
     Ok(())
 }
 
-pub fn sync(trim: bool, path: &PathBuf, pg: &mut Client) -> anyhow::Result<()> {
+pub fn sync(trim: bool, path: &Path, pg: &mut Client, config: &Config) -> anyhow::Result<()> {
     info!("Syncing existing migrations with migr");
 
     let mut mig_metas = match pg.query("SELECT id FROM __migr_meta__", &[]) {
@@ -193,37 +877,15 @@ pub fn sync(trim: bool, path: &PathBuf, pg: &mut Client) -> anyhow::Result<()> {
         }
     };
 
-    let mut mig_dirs = fs::read_dir(path)?
-        .filter_map(Result::ok)
-        .filter(|e| e.path().is_dir())
-        .collect::<Vec<_>>();
-
-    mig_dirs.sort_by_key(|e| e.file_name());
+    schema::upgrade(pg)?;
 
-    let num_migs = mig_dirs.len();
-    let query = mig_dirs
-        .into_iter()
-        .filter_map(|d| d.file_name().to_str().map(String::from))
-        .enumerate()
-        .fold(
-            String::from("INSERT INTO __migr_meta__ VALUES "),
-            |mut query, (i, mig_name)| {
-                trace!("Syncing {} with metadata table", mig_name.blue());
-
-                if i == num_migs - 1 {
-                    // Ensures we only update entries not already present
-                    write!(query, "('{mig_name}', TRUE) ON CONFLICT DO NOTHING").unwrap();
-                } else {
-                    write!(query, "('{mig_name}', TRUE),").unwrap();
-                }
+    let mig_dirs = migration_dirs(path, config)?;
 
-                mig_metas.remove(&mig_name);
-                query
-            },
-        );
+    register(&mig_dirs, pg)?;
 
-    pg.execute(&query, &[])
-        .context("Could not insert into metadata table")?;
+    for name in mig_dirs.iter().filter_map(|d| d.file_name()?.to_str()) {
+        mig_metas.remove(name);
+    }
 
     if trim {
         for mig in mig_metas {
@@ -232,70 +894,1888 @@ pub fn sync(trim: bool, path: &PathBuf, pg: &mut Client) -> anyhow::Result<()> {
         }
     }
 
-    info!("Successfully synced migr with existing migrations");
+    summary!("Successfully synced migr with existing migrations");
 
     Ok(())
 }
 
-pub fn status(pg: &mut Client) -> anyhow::Result<()> {
-    let rows = pg.query("SELECT * FROM __migr_meta__ ORDER BY id ASC", &[])?;
-    let rows = rows
-        .into_iter()
-        .map(|row| (row.get::<_, String>(0), row.get::<_, bool>(1)));
-    info!("Status:");
-    for (id, pending) in rows {
-        let pending = if pending {
-            "pending".yellow()
-        } else {
-            "executed".green()
-        };
-        info!("{:.<50} {pending}", id);
+/// Inserts the migration directories not yet in the metadata table as pending.
+pub(crate) fn register(dirs: &[PathBuf], pg: &mut impl GenericClient) -> anyhow::Result<()> {
+    if dirs.is_empty() {
+        return Ok(());
     }
+
+    check_case_renames(dirs, pg)?;
+
+    let ids = dirs
+        .iter()
+        .filter_map(|d| d.file_name()?.to_str())
+        .collect::<Vec<_>>();
+    let (versions, names): (Vec<_>, Vec<_>) = ids
+        .iter()
+        .map(|id| {
+            debug!("Syncing {} with metadata table", id.blue());
+            split_id(id)
+        })
+        .unzip();
+
+    // Ensures we only update entries not already present
+    pg.execute(
+        "INSERT INTO __migr_meta__(id, version, name, pending)
+         SELECT id, version, name, TRUE FROM unnest($1::TEXT[], $2::TEXT[], $3::TEXT[]) AS m(id, version, name)
+         ON CONFLICT DO NOTHING",
+        &[&ids, &versions, &names],
+    )
+    .context("Could not insert into metadata table")?;
+
     Ok(())
 }
 
-fn migration_up(count: Option<usize>, path: PathBuf, pg: &mut Client) -> anyhow::Result<usize> {
-    let paths = migration_files(&path, UpDown::Up)?;
-    let meta = migration_meta(&paths, pg, UpDown::Up)?;
-    migrations_execute(count, &paths, &meta, pg, UpDown::Up)
+/// Errors if migration directories only differ by case from applied migrations, which registering
+/// would apply a second time. Happens when a rename changing only the case is lost, e.g. by git on
+/// case-insensitive filesystems.
+fn check_case_renames(dirs: &[PathBuf], pg: &mut impl GenericClient) -> anyhow::Result<()> {
+    let ids = dirs
+        .iter()
+        .filter_map(|d| d.file_name()?.to_str().map(String::from))
+        .collect::<Vec<_>>();
+    let lowered = ids.iter().map(|id| id.to_lowercase()).collect::<Vec<_>>();
+
+    let applied = pg
+        .query(
+            "SELECT id FROM __migr_meta__ WHERE NOT pending AND lower(id) = ANY($1) AND id <> ALL($2)",
+            &[&lowered, &ids],
+        )?
+        .into_iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<Vec<_>>();
+    let renamed = case_renames(&ids, &applied);
+    if renamed.is_empty() {
+        return Ok(());
+    }
+
+    let hint = match dirs.first().and_then(|dir| dir.parent()) {
+        Some(path) if volume::case_insensitive(path) => {
+            "The migrations are on a case-insensitive filesystem, where renames only changing the case are easily lost. Rename the directories back to the IDs they were applied as"
+        }
+        _ => "Rename the directories back to the IDs they were applied as",
+    };
+    Err(Error::msg(format!(
+        "Migrations only differ by case from applied migrations:\n{}\nHint: {hint}",
+        renamed.join("\n")
+    )))
 }
 
-fn migration_down(count: Option<usize>, path: &PathBuf, pg: &mut Client) -> anyhow::Result<usize> {
-    let mut paths = migration_files(path, UpDown::Down)?;
-    paths.reverse();
-    let meta = migration_meta(&paths, pg, UpDown::Down)?;
-    migrations_execute(count, &paths, &meta, pg, UpDown::Down)
+/// Pairs the directories in `ids` with the `applied` migrations only differing from them by case,
+/// sorted by directory so the report is the same whatever order they were listed in.
+fn case_renames(ids: &[String], applied: &[String]) -> Vec<String> {
+    let mut renamed = ids
+        .iter()
+        .flat_map(|dir| {
+            applied
+                .iter()
+                .filter(move |applied| *applied != dir && applied.eq_ignore_ascii_case(dir))
+                .map(move |applied| (dir, applied))
+        })
+        .collect::<Vec<_>>();
+    renamed.sort();
+    renamed.dedup();
+    renamed
+        .into_iter()
+        .map(|(dir, applied)| format!("  {dir} was applied as {applied}"))
+        .collect()
 }
 
-fn check_table(pg: &mut Client) -> anyhow::Result<()> {
-    if let Err(err) = pg.query("SELECT id FROM __migr_meta__ WHERE id='0'", &[]) {
-        let Some(e) = err.as_db_error() else {
-            return Err(Error::new(err));
-        };
+/// Runs the pending migrations at `path` in `tx` and returns the IDs of the executed migrations.
+///
+/// The metadata table is created and new migrations are registered if necessary, so `tx` can be
+/// opened on an empty database. Nothing is committed, which is up to the caller, e.g. test
+/// harnesses can roll back `tx` once the test finishes.
+pub fn run_pending_with(
+    tx: &mut Transaction<'_>,
+    path: &Path,
+    config: &Config,
+) -> anyhow::Result<Vec<String>> {
+    let fingerprint = fingerprint(path, config)?;
+    if schema::fingerprint(tx)?.as_ref() == Some(&fingerprint) {
+        debug!("Migrations unchanged since the last run");
+        return Ok(vec![]);
+    }
 
-        if *e.code() != postgres::error::SqlState::UNDEFINED_TABLE {
-            return Err(Error::new(err));
-        }
+    // Querying a missing table would abort the transaction
+    let exists = tx
+        .query_one("SELECT to_regclass('__migr_meta__') IS NOT NULL", &[])?
+        .get::<_, bool>(0);
+    if !exists {
+        debug!("Creating metadata table");
+        tx.batch_execute(INITIAL_TABLE_QUERY)?;
+    }
 
-        return Err(err).context(
-            "The metadata table does not exist.\nHint: Run `migr sync` to create it with existing migrations.",
-        );
+    schema::upgrade(tx)?;
+
+    register(&migration_dirs(path, config)?, tx)?;
+
+    let pending = migration_pending(&migration_files(path, UpDown::Up, config)?, tx)?;
+    let summary = Summary::new("executed");
+    let mut executed = vec![];
+
+    for path in &pending {
+        let id = migration_id(path).unwrap_or_default();
+        migration_execute_exact(path, id, tx, UpDown::Up, config, &summary)?;
+        executed.push(id.to_string());
+
+        // The transaction belongs to the caller, so nothing can be executed after it is committed
+        for statement in directive::after(path)? {
+            warn!("Skipping after step `{statement}` of {id}, which is not executed when running migrations in a transaction");
+        }
     }
-    Ok(())
+
+    record_batch(tx)?;
+    functions::apply(path, tx, config)?;
+    schema::record_fingerprint(tx, &fingerprint)?;
+
+    Ok(executed)
 }
 
-fn find_and_execute(path: &PathBuf, name: &str, pg: &mut Client, ud: UpDown) -> anyhow::Result<()> {
-    let (path, id) = find_exact(path, name, pg)?;
-    match ud {
-        UpDown::Up => info!("Running migration {}", id.blue()),
-        UpDown::Down => info!("Reverting migration {}", id.blue()),
+/// Returns an error listing the pending migrations if the database is behind, e.g. so a service
+/// refuses to start against an unmigrated schema. Migrations which were never synced with the
+/// database are unknown to it and not reported.
+pub fn assert_up_to_date(pg: &mut impl GenericClient) -> anyhow::Result<()> {
+    // Querying a missing table would abort a caller's transaction
+    let exists = pg
+        .query_one("SELECT to_regclass('__migr_meta__') IS NOT NULL", &[])?
+        .get::<_, bool>(0);
+    if !exists {
+        return Err(Error::msg(
+            "The database was never migrated, the metadata table does not exist",
+        ));
     }
-    let file = format!("{}/{ud}", path.display());
+
+    let pending = pg
+        .query(
+            &format!("SELECT id FROM __migr_meta__ WHERE pending ORDER BY {VERSION_COLUMN}, id"),
+            &[],
+        )?
+        .into_iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<Vec<_>>();
+
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    let mut message = format!(
+        "The database is behind, {} migrations are pending:",
+        pending.len()
+    );
+    for id in pending {
+        let _ = write!(message, "\n  {id}");
+    }
+
+    Err(Error::msg(message))
+}
+
+pub fn verify(args: &VerifyArgs, path: &Path, config: &Config) -> anyhow::Result<()> {
+    let paths = migration_files(path, UpDown::Up, config)?;
+    let mut violations = vec![];
+
+    let mut files = paths.clone();
+    files.extend(
+        migration_files(path, UpDown::Down, config)?
+            .into_iter()
+            .filter(|file| file.exists()),
+    );
+    violations.extend(lint::header(&files, &config.lint)?);
+
+    if args.naming {
+        violations.extend(lint::naming(&naming_dirs(path, config)?, &config.lint));
+    }
+
+    if args.replication || config.lint.replication {
+        info!("Running replication analysis");
+        violations.extend(lint::replication(&paths, &config.lint)?);
+    }
+
+    let policies = policy::check(&paths, &config.policies, config.target.as_deref())?;
+
+    lint::report(&violations)?;
+    policy::report(&policies)?;
+
+    let warnings = violations.len() + policies.len();
+    if warnings == 0 {
+        summary!("Successfully verified {} migrations", paths.len());
+    } else {
+        summary!(
+            "Verified {} migrations with {warnings} warning(s)",
+            paths.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Formats the `up.sql` and `down.sql` of the migrations, or lists the ones which are not formatted
+/// with `--check`.
+pub fn fmt(args: &FmtArgs, path: &Path, config: &Config) -> anyhow::Result<()> {
+    let files = sql_files(path, &args.migrations, config)?;
+    let mut unformatted = vec![];
+    let mut skipped = 0;
+
+    for file in &files {
+        let sql = fs::read_to_string(file)?;
+        let display = file
+            .strip_prefix(path)
+            .unwrap_or(file)
+            .display()
+            .to_string();
+
+        let Some(formatted) = fmt::format(&sql, &config.fmt) else {
+            warn!("Skipping {display}, formatting it would change more than whitespace");
+            skipped += 1;
+            continue;
+        };
+
+        if formatted == sql {
+            continue;
+        }
+
+        if args.check {
+            warn!("{} is not formatted", display.yellow());
+        } else {
+            fs::write(file, formatted)?;
+            debug!("Formatted {display}");
+        }
+        unformatted.push(display);
+    }
+
+    if args.check && !unformatted.is_empty() {
+        return Err(Error::msg(format!(
+            "{} file(s) are not formatted\nHint: Run `migr fmt` to format them",
+            unformatted.len()
+        )));
+    }
+
+    if args.check {
+        summary!("All {} file(s) are formatted", files.len() - skipped);
+    } else {
+        summary!(
+            "Formatted {} of {} file(s)",
+            unformatted.len(),
+            files.len() - skipped
+        );
+    }
+
+    Ok(())
+}
+
+/// Rewrites the statements of the migrations with the fixes given in `args`. Without named
+/// migrations only the ones `pg` has not applied are rewritten, since rewriting applied ones makes
+/// them drift.
+pub fn fix(
+    args: &FixArgs,
+    path: &Path,
+    pg: Option<&mut Client>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    if !args.idempotent && !args.rewrite_safe {
+        return Err(Error::msg(
+            "No fix given\nHint: Pass `--idempotent` to rewrite statements to their idempotent forms, or `--rewrite-safe` to validate constraints without blocking writes",
+        ));
+    }
+
+    let mut files = sql_files(path, &args.migrations, config)?;
+    if let Some(pg) = pg {
+        check_table(pg)?;
+        let applied = migration_meta(&files, pg, UpDown::Up)?
+            .into_iter()
+            .filter_map(|(id, pending)| (!pending).then_some(id))
+            .collect::<HashSet<_>>();
+        files.retain(|file| migration_id(file).is_some_and(|id| !applied.contains(id)));
+        debug!("Skipping {} applied migration(s)", applied.len());
+    }
+
+    let mut rewritten = 0;
+    let mut fixed = 0;
+
+    for file in &files {
+        let mut sql = fs::read_to_string(file)?;
+        let mut count = 0;
+        if args.idempotent {
+            let rewrite = idempotent::rewrite(&sql);
+            (sql, count) = (rewrite.0, count + rewrite.1);
+        }
+        if args.rewrite_safe {
+            let rewrite = safe_ddl::rewrite(&sql);
+            (sql, count) = (rewrite.0, count + rewrite.1);
+        }
+        if count == 0 {
+            continue;
+        }
+
+        fs::write(file, sql)?;
+        info!(
+            "Rewrote {count} statement(s) in {}",
+            file.strip_prefix(path).unwrap_or(file).display()
+        );
+        rewritten += count;
+        fixed += 1;
+    }
+
+    summary!(
+        "Rewrote {rewritten} statement(s) in {fixed} of {} file(s)",
+        files.len()
+    );
+
+    Ok(())
+}
+
+/// Returns the `up.sql` and `down.sql` files of the migrations given by ID or name, or of every
+/// migration if none are given.
+fn sql_files(path: &Path, migrations: &[String], config: &Config) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = migration_files(path, UpDown::Up, config)?;
+    files.extend(
+        migration_files(path, UpDown::Down, config)?
+            .into_iter()
+            .filter(|file| file.exists()),
+    );
+    // Archived migrations are only read
+    files.retain(|file| file.starts_with(path));
+    files.sort();
+
+    if migrations.is_empty() {
+        return Ok(files);
+    }
+
+    files.retain(|file| {
+        migration_id(file)
+            .is_some_and(|id| migrations.iter().any(|m| m == id || m == split_id(id).1))
+    });
+    if files.is_empty() {
+        return Err(Error::msg(format!(
+            "No migrations found matching {}",
+            migrations.join(", ")
+        )));
+    }
+
+    Ok(files)
+}
+
+/// Returned by [pending] when no migrations are pending.
+#[derive(Debug)]
+pub struct NothingPending;
+
+impl Display for NothingPending {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No migrations are pending")
+    }
+}
+
+impl std::error::Error for NothingPending {}
+
+/// Prints the IDs of the pending migrations in the order they would be run, one per line and
+/// without decoration. Errors with [NothingPending] if there are none.
+pub fn pending(path: &Path, pg: &mut Client, config: &Config) -> anyhow::Result<()> {
+    check_table(pg)?;
+
+    let pending = migration_pending(&migration_files(path, UpDown::Up, config)?, pg)?;
+    if pending.is_empty() {
+        return Err(NothingPending.into());
+    }
+
+    let mut out = io::stdout().lock();
+    for id in pending.iter().filter_map(|p| migration_id(p)) {
+        writeln!(out, "{id}")?;
+    }
+
+    Ok(())
+}
+
+pub fn status(args: &StatusArgs, path: Option<&Path>, pg: &mut Client) -> anyhow::Result<()> {
+    check_table(pg)?;
+
+    let introduced = match path {
+        Some(path) if args.git => Some(git::introduced(path).ok_or_else(not_a_repository)?),
+        _ => None,
+    };
+
+    let rows = match args.at {
+        Some(ref at) => status_at(at, pg)?,
+        None => pg.query(
+            &format!("SELECT id, pending, error IS NOT NULL FROM __migr_meta__ ORDER BY {VERSION_COLUMN} ASC, id ASC"),
+            &[],
+        )?,
+    };
+    let rows = rows
+        .into_iter()
+        .map(|row| {
+            (
+                row.get::<_, String>(0),
+                row.get::<_, bool>(1),
+                row.get::<_, bool>(2),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    // Align the states in a column after the longest ID
+    let width = rows.iter().map(|(id, ..)| id.len()).max().unwrap_or(0) + 3;
+    let latest = rows
+        .iter()
+        .rev()
+        .find(|(_, pending, _)| !pending)
+        .map(|(id, ..)| split_id(id).0.to_string());
+
+    if let Some(owner) = path.and_then(workspace::owner) {
+        summary!("Crate: {}", owner.name.blue());
+    }
+    summary!("Status:");
+    for (id, pending, failed) in rows {
+        let pending = match (pending, failed) {
+            (true, true) => "failed".red(),
+            (true, false) => "pending".yellow(),
+            (false, _) => "executed".green(),
+        };
+        let mut columns = vec![];
+        if let Some(ref introduced) = introduced {
+            columns.push(match introduced.get(&id) {
+                Some(c) => format!("{} {} {}", c.hash.yellow(), c.date, c.author),
+                None => "uncommitted".red().to_string(),
+            });
+        }
+        if let Some(meta) = path.and_then(|path| MigrationMeta::load(&path.join(&id)).ok()) {
+            columns.extend(annotations(meta));
+        }
+        match columns.is_empty() {
+            true => summary!("{id:.<width$} {pending}"),
+            false => summary!("{id:.<width$} {pending:<8} {}", columns.join(" ")),
+        }
+    }
+
+    if args.at.is_some() {
+        if let Some(latest) = latest {
+            summary!("Version: {}", latest.green());
+        }
+        return Ok(());
+    }
+
+    if let Some(current) = current_version(pg)? {
+        summary!("Current version: {}", current.green());
+    }
+
+    Ok(())
+}
+
+/// Shows the migrations on disk in the order they are applied, without connecting to the
+/// database: the checksum of each `up.sql`, whether it was never written or can't be reverted, and
+/// its annotations. Violations of the lint rules which don't need a database are listed after them.
+pub fn status_offline(args: &StatusArgs, path: &Path, config: &Config) -> anyhow::Result<()> {
+    let introduced = match args.git {
+        true => Some(git::introduced(path).ok_or_else(not_a_repository)?),
+        false => None,
+    };
+
+    let dirs = migration_dirs(path, config)?;
+    let files = dirs
+        .iter()
+        .map(|dir| dir.join("up.sql"))
+        .collect::<Vec<_>>();
+    let width = dirs
+        .iter()
+        .filter_map(|dir| dir.file_name().map(|n| n.len()))
+        .max()
+        .unwrap_or(0)
+        + 3;
+
+    if let Some(owner) = workspace::owner(path) {
+        summary!("Crate: {}", owner.name.blue());
+    }
+    summary!("Status (offline):");
+    for file in &files {
+        let id = migration_id(file).unwrap_or_default();
+        let dir = file.parent().unwrap_or(Path::new("."));
+        let checksum = match fs::read(file) {
+            Ok(sql) => directive::checksum(&sql, file)[..12].to_string(),
+            Err(_) => "missing".red().to_string(),
+        };
+
+        let mut columns = vec![];
+        if !executes(file)? {
+            columns.push("empty".yellow().to_string());
+        }
+        if directive::irreversible(&dir.join("down.sql"))? {
+            columns.push("irreversible".dimmed().to_string());
+        }
+        if let Some(ref introduced) = introduced {
+            columns.push(match introduced.get(id) {
+                Some(c) => format!("{} {} {}", c.hash.yellow(), c.date, c.author),
+                None => "uncommitted".red().to_string(),
+            });
+        }
+        columns.extend(annotations(MigrationMeta::load(dir)?));
+        match columns.is_empty() {
+            true => summary!("{id:.<width$} {}", checksum.dimmed()),
+            false => summary!("{id:.<width$} {} {}", checksum.dimmed(), columns.join(" ")),
+        }
+    }
+
+    let mut violations = lint::naming(&naming_dirs(path, config)?, &config.lint);
+    violations.extend(lint::header(&files, &config.lint)?);
+    lint::print(&violations);
+
+    if let Some(latest) = files.last().and_then(|file| migration_id(file)) {
+        summary!("Latest version: {}", split_id(latest).0.green());
+    }
+
+    Ok(())
+}
+
+/// Returns the annotations of a migration shown next to it by `status`.
+fn annotations(meta: MigrationMeta) -> Vec<String> {
+    let mut columns = vec![];
+    columns.extend(meta.phase.map(|phase| phase.to_string().cyan().to_string()));
+    columns.extend(meta.risk.map(|risk| risk.colored().to_string()));
+    columns.extend(
+        meta.description
+            .as_deref()
+            .and_then(|d| d.lines().next())
+            .map(String::from),
+    );
+    columns.extend(meta.ticket.map(|ticket| ticket.dimmed().to_string()));
+    columns
+}
+
+/// Reconstructs the state of the migrations at the given time from the last execution of each
+/// before it. Migrations applied before executions were recorded count as applied since their
+/// application time. Returns the same columns as the metadata query of `status`.
+fn status_at(at: &str, pg: &mut Client) -> anyhow::Result<Vec<postgres::Row>> {
+    let time = pg
+        .query_one("SELECT $1::TEXT::TIMESTAMPTZ::TEXT", &[&at])
+        .map_err(|_| {
+            Error::msg(format!(
+                "Invalid time '{at}'\nHint: Pass a date or a time, e.g. `2024-06-01` or `'2024-06-01 14:30+02'`"
+            ))
+        })?
+        .get::<_, String>(0);
+
+    summary!("As of {}", time.yellow());
+
+    Ok(pg.query(
+        "WITH last AS (
+            SELECT DISTINCT ON (migration) migration, direction FROM __migr_history__
+            WHERE executed_at <= $1::TEXT::TIMESTAMPTZ
+            ORDER BY migration, executed_at DESC, id DESC
+         )
+         SELECT ids.id, NOT COALESCE(
+                last.direction IN ('up', 'skip', 'base'),
+                m.applied_at <= $1::TEXT::TIMESTAMPTZ
+                    AND NOT EXISTS (SELECT 1 FROM __migr_history__ h WHERE h.migration = ids.id),
+                FALSE
+             ), FALSE
+         FROM (SELECT id FROM __migr_meta__ UNION SELECT migration FROM __migr_history__) ids
+         LEFT JOIN last ON last.migration = ids.id
+         LEFT JOIN __migr_meta__ m ON m.id = ids.id
+         ORDER BY split_part(ids.id, '_', 1), ids.id",
+        &[&time],
+    )?)
+}
+
+/// The versions of everything involved in migrating a database.
+#[derive(Debug, Clone, Serialize)]
+pub struct VersionInfo {
+    pub migr: &'static str,
+    /// The version of the metadata table layout this release of migr works with.
+    pub metadata_supported: i32,
+    /// The version of the metadata table in the database, if it exists.
+    pub metadata: Option<i32>,
+    /// The release of migr which last upgraded the metadata table, if recorded.
+    pub metadata_upgraded_by: Option<String>,
+    pub server: String,
+    pub server_version_num: i32,
+    /// The ID of the latest applied migration.
+    pub latest_migration: Option<String>,
+    /// The time the latest migration was applied, as a unix timestamp.
+    pub latest_applied_at: Option<i64>,
+}
+
+/// Prints the versions of migr, the metadata table, the server and the latest applied migration as
+/// a single JSON object, without writing to the database.
+pub fn version_info(pg: &mut Client) -> anyhow::Result<()> {
+    let row = pg.query_one(
+        "SELECT current_setting('server_version'), current_setting('server_version_num')::INTEGER, to_regclass('__migr_meta__') IS NOT NULL",
+        &[],
+    )?;
+    let (server, server_version_num, exists) = (row.get(0), row.get(1), row.get::<_, bool>(2));
+
+    let mut info = VersionInfo {
+        migr: env!("CARGO_PKG_VERSION"),
+        metadata_supported: schema::VERSION,
+        metadata: None,
+        metadata_upgraded_by: None,
+        server,
+        server_version_num,
+        latest_migration: None,
+        latest_applied_at: None,
+    };
+
+    if exists {
+        let (version, upgraded_by) = schema::version(pg)?;
+        info.metadata = Some(version);
+        info.metadata_upgraded_by = upgraded_by;
+
+        // Tables older than version 1 have no application times
+        let applied_at = if version >= 1 {
+            "EXTRACT(EPOCH FROM applied_at)::BIGINT"
+        } else {
+            "NULL::BIGINT"
+        };
+        if let Some(row) = pg.query_opt(
+            &format!(
+                "SELECT id, {applied_at} FROM __migr_meta__ WHERE NOT pending ORDER BY {VERSION_COLUMN} DESC, id DESC LIMIT 1"
+            ),
+            &[],
+        )? {
+            info.latest_migration = Some(row.get(0));
+            info.latest_applied_at = row.get(1);
+        }
+    }
+
+    println!("{}", serde_json::to_string(&info)?);
+
+    Ok(())
+}
+
+/// The state of the migrations at a path in a database, e.g. for a health or readiness endpoint.
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationHealth {
+    /// The version of the latest applied migration.
+    pub current: Option<String>,
+    /// IDs of the migrations which are not applied, including ones the database does not know yet.
+    pub pending: Vec<String>,
+    /// IDs of the applied migrations whose `up.sql` changed since they were applied.
+    pub drift: Vec<String>,
+}
+
+impl MigrationHealth {
+    /// Returns true if every migration is applied as it is.
+    pub fn is_healthy(&self) -> bool {
+        self.pending.is_empty() && self.drift.is_empty()
+    }
+}
+
+/// Compares the migrations at `path` with the ones applied to the database without writing to it.
+pub fn health(
+    pg: &mut impl GenericClient,
+    path: &Path,
+    config: &Config,
+) -> anyhow::Result<MigrationHealth> {
+    // Querying a missing table would abort a caller's transaction
+    let exists = pg
+        .query_one("SELECT to_regclass('__migr_meta__') IS NOT NULL", &[])?
+        .get::<_, bool>(0);
+
+    let applied = if exists {
+        schema::check(pg)?;
+        pg.query(
+            "SELECT id, checksum FROM __migr_meta__ WHERE NOT pending",
+            &[],
+        )?
+        .into_iter()
+        .map(|row| (row.get::<_, String>(0), row.get::<_, Option<String>>(1)))
+        .collect::<HashMap<_, _>>()
+    } else {
+        HashMap::new()
+    };
+
+    let mut pending = vec![];
+    let mut drift = vec![];
+
+    for dir in migration_dirs(path, config)? {
+        let id = dir.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        match applied.get(id) {
+            None => pending.push(id.to_string()),
+            // Rows applied by releases which did not record checksums can't drift
+            Some(Some(checksum)) if drifted(&dir.join("up.sql"), checksum) => {
+                drift.push(id.to_string())
+            }
+            Some(_) => {}
+        }
+    }
+
+    let current = match exists {
+        true => current_version(pg)?,
+        false => None,
+    };
+
+    Ok(MigrationHealth {
+        current,
+        pending,
+        drift,
+    })
+}
+
+/// Whether the migration file at `path` no longer has the checksum it was applied with.
+fn drifted(path: &Path, applied: &str) -> bool {
+    fs::read(path).map_or(true, |sql| directive::checksum(&sql, path) != applied)
+}
+
+/// Executes pending migrations, except the ones in `skip`.
+fn migration_up(
+    count: Option<usize>,
+    path: PathBuf,
+    skip: &HashSet<String>,
+    pg: &mut Client,
+    config: &Config,
+    summary: &Summary,
+) -> anyhow::Result<()> {
+    let mut paths = migration_files(&path, UpDown::Up, config)?;
+    paths.retain(|file| migration_id(file).is_some_and(|id| !skip.contains(id)));
+    let meta = migration_meta(&paths, pg, UpDown::Up)?;
+    migrations_execute(count, &paths, &meta, pg, UpDown::Up, false, config, summary)
+}
+
+/// Executes the `pending` migrations in order, each in a savepoint of a single transaction, so a
+/// failing migration is rolled back on its own and the migrations which don't depend on it are
+/// still applied. The failures, and the migrations left pending because they depend on them, are
+/// reported once all of them were tried.
+fn migration_up_isolated(
+    count: Option<usize>,
+    mut pending: Vec<PathBuf>,
+    pg: &mut Client,
+    config: &Config,
+    summary: &Summary,
+) -> anyhow::Result<()> {
+    if let Some(count) = count {
+        pending.truncate(count);
+    }
+    // Migrations left pending because they depend on failed ones are reported as not attempted
+    summary.plan(&pending, UpDown::Up.direction());
+
+    let mut transactional = vec![];
+    for file in &pending {
+        let meta = MigrationMeta::load(file.parent().unwrap_or(Path::new(".")))?;
+        if let Some(release) = meta.release {
+            return Err(Error::msg(format!(
+                "Release '{release}' is pending, which is applied atomically in a single transaction, so it can not be run with `--isolate-failures`"
+            )));
+        }
+        transactional.push(meta.transaction);
+    }
+
+    let deps = dependency::dependencies(&pending)?;
+    let mut failed = vec![];
+    let mut skipped = vec![];
+    // Migrations which failed or were skipped, which the ones depending on them are skipped for
+    let mut blocked = HashSet::new();
+    let mut blocks = |i: usize, blocked: &mut HashSet<usize>| {
+        let dependent = deps[i].iter().any(|dep| blocked.contains(dep));
+        if dependent {
+            blocked.insert(i);
+            skipped.push(i);
+        }
+        dependent
+    };
+
+    let indices = (0..pending.len()).collect::<Vec<_>>();
+    for batch in indices.chunk_by(|a, b| transactional[*a] && transactional[*b]) {
+        if let [i] = batch {
+            if !transactional[*i] {
+                if blocks(*i, &mut blocked) {
+                    continue;
+                }
+                let path = &pending[*i];
+                let id = migration_id(path).unwrap_or_default();
+                match migration_execute_outside(path, id, pg, UpDown::Up, config, summary) {
+                    Ok(_) => {
+                        info!("Executed {}", path.display().to_string().blue());
+                        if let Err(e) = after_execute(path, pg) {
+                            warn!(
+                                "The after steps of {} failed, continuing with the migrations independent of it",
+                                id.red()
+                            );
+                            blocked.insert(*i);
+                            failed.push((*i, e));
+                        }
+                    }
+                    Err(e) => {
+                        if !chaos::injected(&e) {
+                            record_failure(pg, id, UpDown::Up, &e);
+                        }
+                        warn!(
+                            "{} failed, continuing with the migrations independent of it",
+                            id.red()
+                        );
+                        blocked.insert(*i);
+                        failed.push((*i, e));
+                    }
+                }
+                continue;
+            }
+        }
+
+        let mut tx = pg.build_transaction().start()?;
+        let mut executed = vec![];
+        let mut errors = vec![];
+
+        for i in batch {
+            if blocks(*i, &mut blocked) {
+                continue;
+            }
+            let path = &pending[*i];
+            let id = migration_id(path).unwrap_or_default();
+            match migration_execute_exact(path, id, &mut tx, UpDown::Up, config, summary) {
+                Ok(_) => {
+                    info!("Executed {}", path.display().to_string().blue());
+                    executed.push(*i);
+                }
+                Err(e) => {
+                    warn!(
+                        "{} failed, continuing with the migrations independent of it",
+                        id.red()
+                    );
+                    blocked.insert(*i);
+                    errors.push(*i);
+                    failed.push((*i, e));
+                }
+            }
+        }
+
+        if let Err(e) = tx.commit() {
+            summary.roll_back(executed.iter().filter_map(|i| migration_id(&pending[*i])));
+            return Err(e.into());
+        }
+
+        for i in executed {
+            if let Err(e) = after_execute(&pending[i], pg) {
+                warn!(
+                    "The after steps of {} failed, continuing with the migrations independent of it",
+                    migration_id(&pending[i]).unwrap_or_default().red()
+                );
+                blocked.insert(i);
+                failed.push((i, e));
+            }
+        }
+        // Recorded once the transaction is committed, since the failures are rolled back with it
+        for (i, e) in failed
+            .iter()
+            .filter(|(i, e)| errors.contains(i) && !chaos::injected(e))
+        {
+            record_failure(
+                pg,
+                migration_id(&pending[*i]).unwrap_or_default(),
+                UpDown::Up,
+                e,
+            );
+        }
+    }
+
+    if failed.is_empty() {
+        return Ok(());
+    }
+
+    let id = |i: &usize| migration_id(&pending[*i]).unwrap_or_default();
+    let mut message = format!(
+        "{} of {} pending migrations failed:",
+        failed.len(),
+        pending.len()
+    );
+    for (i, e) in &failed {
+        let _ = write!(message, "\n  {}: {e:#}", id(i));
+    }
+    if !skipped.is_empty() {
+        message.push_str("\nLeft pending, since they depend on failed migrations:");
+        for i in &skipped {
+            let _ = write!(message, "\n  {}", id(i));
+        }
+    }
+    message.push_str("\nHint: Fix the failed migrations and run `migr resume`");
+
+    Err(Error::msg(message))
+}
+
+/// Executes the `pending` migrations on up to `parallel` connections from the pool. Each migration is
+/// executed in its own transaction and only once all the migrations it depends on have been executed.
+fn migration_up_parallel(
+    count: Option<usize>,
+    parallel: usize,
+    mut pending: Vec<PathBuf>,
+    pool: &Pool,
+    config: &Config,
+    summary: &Summary,
+) -> anyhow::Result<()> {
+    // One connection from the pool is already in use by the caller
+    let workers = parallel.min(pool.max_size() as usize - 1);
+    if workers == 0 {
+        return Err(Error::msg(
+            "Parallel runs require at least 2 connections\nHint: Increase `--max-connections`",
+        ));
+    }
+
+    if let Some(count) = count {
+        pending.truncate(count);
+    }
+
+    for file in &pending {
+        let dir = file.parent().unwrap_or(Path::new("."));
+        if let Some(release) = MigrationMeta::load(dir)?.release {
+            return Err(Error::msg(format!(
+                "Release '{release}' is pending, which is applied atomically in a single transaction, so it can not be run with `--parallel`"
+            )));
+        }
+    }
+
+    summary.plan(&pending, UpDown::Up.direction());
+
+    let deps = dependency::dependencies(&pending)?;
+    let levels = dependency::levels(&deps);
+
+    debug!(
+        "Executing {} migrations in {} levels",
+        pending.len(),
+        levels.len()
+    );
+
+    let executed = AtomicUsize::new(0);
+
+    for level in levels {
+        let queue = Mutex::new(level.into_iter());
+        let errors = Mutex::new(vec![]);
+
+        thread::scope(|s| {
+            for _ in 0..workers {
+                s.spawn(|| {
+                    let result = (|| -> anyhow::Result<()> {
+                        let mut pg = None;
+                        loop {
+                            let Some(i) = queue.lock().unwrap().next() else {
+                                return Ok(());
+                            };
+                            if !errors.lock().unwrap().is_empty() {
+                                return Ok(());
+                            }
+
+                            let pg = match pg {
+                                Some(ref mut pg) => pg,
+                                None => pg.insert(pool.get()?),
+                            };
+
+                            let path = &pending[i];
+                            let id = migration_id(path).unwrap_or_default();
+                            if let Err(e) =
+                                migration_execute(path, id, pg, UpDown::Up, config, summary)
+                            {
+                                if !chaos::injected(&e) {
+                                    record_failure(pg, id, UpDown::Up, &e);
+                                }
+                                return Err(e);
+                            }
+
+                            executed.fetch_add(1, Ordering::Relaxed);
+                            info!("Executed {}", path.display().to_string().blue());
+                        }
+                    })();
+
+                    if let Err(e) = result {
+                        errors.lock().unwrap().push(e);
+                    }
+                });
+            }
+        });
+
+        if let Some(e) = errors.into_inner().unwrap().into_iter().next() {
+            return Err(e).context(format!(
+                "{} migrations were executed before the failure",
+                executed.load(Ordering::Relaxed)
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn check_run_only(args: &RunRevMigration) -> anyhow::Result<()> {
+    if args.parallel.is_some() {
+        return Err(Error::msg(
+            "`--parallel` is only supported when running migrations",
+        ));
+    }
+    if args.isolate_failures {
+        return Err(Error::msg(
+            "`--isolate-failures` is only supported when running migrations",
+        ));
+    }
+    if args.cluster.keep_going {
+        return Err(Error::msg(
+            "`--keep-going` is only supported when running migrations",
+        ));
+    }
+    if args.wait_if_locked {
+        return Err(Error::msg(
+            "`--wait-if-locked` is only supported when running migrations",
+        ));
+    }
+    if args.allow_data_loss {
+        return Err(Error::msg(
+            "`--allow-data-loss` is only supported when running migrations",
+        ));
+    }
+    if args.allow_fdw {
+        return Err(Error::msg(
+            "`--allow-fdw` is only supported when running migrations",
+        ));
+    }
+    if args.explain {
+        return Err(Error::msg(
+            "`--explain` is only supported when running migrations",
+        ));
+    }
+    if args.sandbox {
+        return Err(Error::msg(
+            "`--sandbox` is only supported when running migrations",
+        ));
+    }
+    if args.force_incompatible {
+        return Err(Error::msg(
+            "`--force-incompatible` is only supported when running migrations",
+        ));
+    }
+    if args.phase.is_some() {
+        return Err(Error::msg(
+            "`--phase` is only supported when running migrations",
+        ));
+    }
+    if args.no_baseline {
+        return Err(Error::msg(
+            "`--no-baseline` is only supported when running migrations",
+        ));
+    }
+    if args.max_risk.is_some() {
+        return Err(Error::msg(
+            "`--max-risk` is only supported when running migrations",
+        ));
+    }
+    if args.canary.is_some() {
+        return Err(Error::msg(
+            "`--canary` is only supported when running migrations",
+        ));
+    }
+    if args.fail_on_empty || args.allow_empty {
+        return Err(Error::msg(
+            "`--fail-on-empty` and `--allow-empty` are only supported when running migrations",
+        ));
+    }
+    Ok(())
+}
+
+/// Executes `f` while holding the migration lock.
+fn with_lock(
+    pg: &mut Client,
+    wait: Option<Duration>,
+    f: impl FnOnce(&mut Client, Acquired) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let acquired = lock::acquire(pg, wait)?;
+    let result = f(pg, acquired);
+    let released = lock::release(pg);
+    result.and(released)
+}
+
+/// Verifies the instance of migr which held the migration lock applied all pending migrations.
+fn check_applied(path: &Path, pg: &mut Client, config: &Config) -> anyhow::Result<()> {
+    check_table(pg)?;
+
+    let pending = migration_pending(&migration_files(path, UpDown::Up, config)?, pg)?;
+    if pending.is_empty() {
+        info!("Migrations were applied by another instance of migr");
+        return Ok(());
+    }
+
+    let mut message = format!(
+        "Another instance of migr finished with {} migrations pending:",
+        pending.len()
+    );
+    for path in pending {
+        let _ = write!(message, "\n  {}", migration_id(&path).unwrap_or_default());
+    }
+
+    Err(Error::msg(message))
+}
+
+fn migration_down(
+    count: Option<usize>,
+    path: &Path,
+    pg: &mut Client,
+    args: &RunRevMigration,
+    config: &Config,
+    summary: &Summary,
+) -> anyhow::Result<()> {
+    let mut paths = migration_files(path, UpDown::Down, config)?;
+    paths.reverse();
+    let meta = migration_meta(&paths, pg, UpDown::Down)?;
+    let allow = args.allow_changed_down;
+    migrations_execute(
+        count,
+        &paths,
+        &meta,
+        pg,
+        UpDown::Down,
+        allow,
+        config,
+        summary,
+    )
+}
+
+/// Reverts the migrations applied by the most recent run, even if older migrations were applied
+/// after them.
+fn migration_down_last_batch(
+    path: &Path,
+    pg: &mut Client,
+    args: &RunRevMigration,
+    config: &Config,
+    summary: &Summary,
+) -> anyhow::Result<()> {
+    let batch = pg
+        .query_one(
+            "SELECT MAX(batch) FROM __migr_meta__ WHERE NOT pending",
+            &[],
+        )?
+        .get::<_, Option<i32>>(0);
+    let batch = match batch {
+        Some(0) => {
+            return Err(Error::msg(
+                "The applied migrations were applied before migr recorded batches\nHint: Revert them with `--count`",
+            ))
+        }
+        Some(batch) => batch,
+        None => {
+            info!("No migrations are applied");
+            return Ok(());
+        }
+    };
+
+    let mut ids = pg
+        .query(
+            "SELECT id FROM __migr_meta__ WHERE NOT pending AND batch = $1",
+            &[&batch],
+        )?
+        .into_iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<HashSet<_>>();
+
+    let mut paths = migration_files(path, UpDown::Down, config)?;
+    paths.retain(|p| migration_id(p).is_some_and(|id| ids.remove(id)));
+    paths.reverse();
+
+    if !ids.is_empty() {
+        let mut missing = ids.into_iter().collect::<Vec<_>>();
+        missing.sort();
+        return Err(Error::msg(format!(
+            "Batch {batch} contains migrations missing from the migrations directory: {}",
+            missing.join(", ")
+        )));
+    }
+
+    info!("Reverting batch {batch}");
+    let meta = migration_meta(&paths, pg, UpDown::Down)?;
+    let allow = args.allow_changed_down;
+    migrations_execute(
+        None,
+        &paths,
+        &meta,
+        pg,
+        UpDown::Down,
+        allow,
+        config,
+        summary,
+    )
+}
+
+/// Checks the metadata table exists and upgrades it to the current version if necessary.
+pub(crate) fn check_table(pg: &mut Client) -> anyhow::Result<()> {
+    if let Err(err) = pg.query("SELECT id FROM __migr_meta__ WHERE id='0'", &[]) {
+        let Some(e) = err.as_db_error() else {
+            return Err(Error::new(err));
+        };
+
+        if *e.code() != postgres::error::SqlState::UNDEFINED_TABLE {
+            return Err(Error::new(err));
+        }
+
+        return Err(err).context(
+            "The metadata table does not exist.\nHint: Run `migr sync` to create it with existing migrations.",
+        );
+    }
+    schema::upgrade(pg)
+}
+
+/// Checks the extensions required by the config are available on the server, so a missing package
+/// is reported before any migration is executed.
+fn check_extensions(pg: &mut Client, config: &Config) -> anyhow::Result<()> {
+    if config.required_extensions.is_empty() {
+        return Ok(());
+    }
+
+    let available = pg
+        .query(
+            "SELECT name::TEXT FROM pg_available_extensions WHERE name = ANY($1)",
+            &[&config.required_extensions],
+        )?
+        .into_iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<HashSet<_>>();
+
+    let missing = config
+        .required_extensions
+        .iter()
+        .filter(|e| !available.contains(*e))
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    Err(Error::msg(format!(
+        "Required extensions are not available on the server: {}\nHint: Install the packages providing them on the database server",
+        missing.join(", ")
+    )))
+}
+
+fn find_and_execute(
+    path: &Path,
+    name: &str,
+    pg: &mut Client,
+    ud: UpDown,
+    args: &RunRevMigration,
+    config: &Config,
+    summary: &Summary,
+) -> anyhow::Result<()> {
+    let (path, id) = find_exact(path, name, pg)?;
+    match ud {
+        UpDown::Up => info!("Running migration {}", id.blue()),
+        UpDown::Down => info!("Reverting migration {}", id.blue()),
+    }
+    if let Some(release) = MigrationMeta::load(&path)?.release {
+        warn!("{id} is part of release '{release}', the other migrations of the release are left as they are");
+    }
+    let file = path.join(ud.to_string());
+    if matches!(ud, UpDown::Down) {
+        check_reversible([&file])?;
+        check_down_unchanged([&file], pg, args.allow_changed_down)?;
+    }
+    summary.plan([&file], ud.direction());
+    migration_execute(&file, &id, pg, ud, config, summary)
+}
+
+/// Finds the exact migration by its name without the timestamp, or by its full ID, and returns its path
+/// and meta ID.
+/// `path` is a path pointing to the migrations dir.
+fn find_exact(path: &Path, name: &str, pg: &mut Client) -> anyhow::Result<(PathBuf, String)> {
+    let ids = pg
+        .query(
+            &format!(
+                "SELECT id FROM __migr_meta__ WHERE id = $1 OR {NAME_COLUMN} = $1 ORDER BY {VERSION_COLUMN}"
+            ),
+            &[&name],
+        )?
+        .into_iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<Vec<_>>();
+
+    let id = match ids.as_slice() {
+        [id] => id.clone(),
+        [] => {
+            // Distinguish migrations missing from the metadata from ones which do not exist
+            let exists = Cache::load(path)?
+                .migrations(path)
+                .iter()
+                .filter_map(|p| p.file_name()?.to_str().map(String::from))
+                .any(|id| id == name || split_id(&id).1 == name);
+
+            if exists {
+                return Err(Error::msg(format!(
+                    "No entry found in metadata for {}\nHint: Run `migr sync` to sync the metadata table",
+                    name.red()
+                )));
+            }
+
+            return Err(Error::msg(format!("No migration found for name '{name}'")));
+        }
+        _ => {
+            return Err(Error::msg(format!(
+                "The name '{name}' matches multiple migrations: {}\nHint: Use the full migration ID",
+                ids.join(", ")
+            )));
+        }
+    };
+
+    let migration_path = path.join(&id);
+
+    if !migration_path.is_dir() {
+        return Err(Error::msg(format!(
+            "Migration {} exists in the metadata table but not in {}",
+            id.red(),
+            path.display()
+        )));
+    }
+
+    debug!(
+        "Found migration {}",
+        migration_path.display().to_string().blue()
+    );
+
+    Ok((migration_path, id))
+}
+
+/// Prints the columns, indexes and constraints of a table.
+pub fn describe(args: &DescribeArgs, pg: &mut Client) -> anyhow::Result<()> {
+    let table = match introspect::objects(pg, &args.table)?.pop() {
+        Some(introspect::Object::Table(table)) => table,
+        _ => return Err(Error::msg(format!("'{}' is not a table", args.table))),
+    };
+
+    summary!("Table {}", table.name.blue());
+
+    let name_width = table
+        .columns
+        .iter()
+        .map(|c| c.name.len())
+        .max()
+        .unwrap_or(0)
+        + 3;
+    let ty_width = table.columns.iter().map(|c| c.ty.len()).max().unwrap_or(0);
+
+    summary!("Columns:");
+    for c in &table.columns {
+        let mut attributes = vec![];
+        if c.not_null {
+            attributes.push(String::from("not null"));
+        }
+        if let Some(identity) = c.identity {
+            attributes.push(format!("generated {} as identity", identity.to_lowercase()));
+        }
+        match c.default {
+            Some(ref expr) if c.generated => attributes.push(format!("generated as ({expr})")),
+            Some(ref expr) => attributes.push(format!("default {expr}")),
+            None => {}
+        }
+        summary!(
+            "  {:.<name_width$} {:<ty_width$} {}",
+            c.name,
+            c.ty,
+            attributes.join(", ")
+        );
+    }
+
+    if !table.indexes.is_empty() {
+        summary!("Indexes:");
+        for i in &table.indexes {
+            summary!("  {} {}", i.name.yellow(), i.definition);
+        }
+    }
+
+    if !table.constraints.is_empty() {
+        summary!("Constraints:");
+        for c in &table.constraints {
+            summary!("  {} {}", c.name.yellow(), c.definition);
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the SQL of a migration with its state in the metadata table and its history.
+pub fn show(args: &ShowArgs, path: &Path, pg: &mut Client, config: &Config) -> anyhow::Result<()> {
+    check_table(pg)?;
+
+    let dirs = migration_dirs(path, config)?;
+    let dir = resolve(&dirs, &args.name)?;
+    let id = dir.file_name().unwrap_or_default().to_string_lossy();
+
+    summary!("Migration {}", id.blue());
+
+    let row = pg.query_opt(
+        "SELECT pending, checksum, to_char(applied_at AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS'), backup, batch,
+                to_char(failed_at AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS'), error
+         FROM __migr_meta__ WHERE id = $1",
+        &[&id],
+    )?;
+    let up = dir.join("up.sql");
+    let checksum = directive::checksum(&fs::read(&up).unwrap_or_default(), &up);
+
+    match row {
+        None => summary!("State: {}", "not in the metadata table".red()),
+        Some(ref row) if row.get::<_, Option<String>>(6).is_some() => {
+            summary!(
+                "State: {} at {} UTC: {}",
+                "failed".red(),
+                row.get::<_, Option<String>>(5).unwrap_or_default(),
+                row.get::<_, Option<String>>(6).unwrap_or_default()
+            );
+        }
+        Some(ref row) if row.get::<_, bool>(0) => summary!("State: {}", "pending".yellow()),
+        Some(ref row) => {
+            let applied_at = row.get::<_, Option<String>>(2);
+            let batch = row.get::<_, Option<i32>>(4).filter(|batch| *batch > 0);
+            summary!(
+                "State: {} {}{}",
+                "applied".green(),
+                applied_at
+                    .map(|at| format!("at {at} UTC"))
+                    .unwrap_or_default(),
+                batch
+                    .map(|batch| format!(" in batch {batch}"))
+                    .unwrap_or_default()
+            );
+        }
+    }
+
+    let meta = MigrationMeta::load(dir)?;
+    if let Some(phase) = meta.phase {
+        summary!("Phase: {}", phase.to_string().cyan());
+    }
+    if let Some(risk) = meta.risk {
+        summary!("Risk: {}", risk.colored());
+    }
+    if meta.incompatible {
+        summary!("{}", "Incompatible with instances built before it".yellow());
+    }
+    for field in ["description", "ticket", "author"] {
+        if let Some(value) = meta.annotation(field) {
+            let mut label = field.to_string();
+            label[..1].make_ascii_uppercase();
+            summary!("{label}: {}", value.trim());
+        }
+    }
+
+    summary!("Checksum: {checksum}");
+    if let Some(applied) = row.as_ref().and_then(|r| r.get::<_, Option<String>>(1)) {
+        if applied != checksum {
+            summary!("Applied checksum: {} {}", applied, "(drifted)".red());
+        }
+    }
+    if let Some(backup) = row.as_ref().and_then(|r| r.get::<_, Option<String>>(3)) {
+        summary!("Backup: {backup}");
+    }
+
+    let history = pg.query(
+        "SELECT direction, to_char(executed_at AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS'), executed_by, duration_ms
+         FROM __migr_history__ WHERE migration = $1 ORDER BY id",
+        &[&id],
+    )?;
+    if history.is_empty() {
+        summary!("History: none recorded");
+    } else {
+        summary!("History:");
+    }
+    for event in history {
+        summary!(
+            "  {:<4} {} UTC by {} in {:.2?}",
+            event.get::<_, String>(0),
+            event.get::<_, String>(1),
+            event.get::<_, String>(2),
+            Duration::from_secs_f64(event.get::<_, f64>(3) / 1000.0)
+        );
+    }
+
+    if args.executed {
+        let executed = pg.query_opt(
+            "SELECT direction, to_char(executed_at AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS'), sql
+             FROM __migr_history__ WHERE migration = $1 AND sql IS NOT NULL ORDER BY id DESC LIMIT 1",
+            &[&id],
+        )?;
+        match executed {
+            Some(row) => {
+                summary!(
+                    "{}",
+                    format!(
+                        "{id} {} at {} UTC",
+                        row.get::<_, String>(0),
+                        row.get::<_, String>(1)
+                    )
+                    .purple()
+                );
+                println!("{}", highlight::sql(row.get::<_, String>(2).trim_end()));
+            }
+            None => summary!(
+                "Executed SQL: none recorded\nHint: Set `sql = true` in the `[history]` section of the config to record it"
+            ),
+        }
+        return Ok(());
+    }
+
+    for file in ["up.sql", "down.sql"] {
+        summary!("{}", format!("{id}/{file}").purple());
+        match fs::read_to_string(dir.join(file)) {
+            Ok(sql) => println!("{}", highlight::sql(sql.trim_end())),
+            Err(_) => summary!("{}", "missing".red()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves the migration directory the user means by `name`. The full ID or the name without the
+/// timestamp match exactly, like `--exact`. Otherwise the name is compared ignoring case, then as a
+/// prefix of the ID, e.g. its timestamp, and finally as part of the name. Errors if the first of
+/// these matching anything matches more than one migration.
+fn resolve<'a>(dirs: &'a [PathBuf], name: &str) -> anyhow::Result<&'a PathBuf> {
+    let ids = dirs
+        .iter()
+        .filter_map(|dir| Some((dir, dir.file_name()?.to_str()?)))
+        .collect::<Vec<_>>();
+    let lower = name.to_lowercase().replace('-', "_");
+
+    let tiers: [&dyn Fn(&str) -> bool; 4] = [
+        &|id| id == name || split_id(id).1 == name,
+        &|id| split_id(id).1.to_lowercase() == lower,
+        &|id| id.starts_with(name),
+        &|id| split_id(id).1.to_lowercase().contains(&lower),
+    ];
+
+    for matches in tiers {
+        let found = ids.iter().filter(|(_, id)| matches(id)).collect::<Vec<_>>();
+        match found.as_slice() {
+            [] => continue,
+            [(dir, _)] => return Ok(dir),
+            _ => {
+                return Err(Error::msg(format!(
+                    "The name '{name}' matches multiple migrations: {}\nHint: Use the full migration ID",
+                    found
+                        .iter()
+                        .map(|(_, id)| *id)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )));
+            }
+        }
+    }
+
+    Err(Error::msg(format!("No migration found for name '{name}'")))
+}
+
+/// Shows the commits which introduced and changed a migration.
+pub fn blame(args: &BlameArgs, path: &Path, config: &Config) -> anyhow::Result<()> {
+    let dirs = migration_dirs(path, config)?;
+    let dir = resolve(&dirs, &args.name)?;
+
+    let history = git::history(dir).ok_or_else(not_a_repository)?;
+
+    summary!(
+        "Migration {}",
+        dir.file_name().unwrap_or_default().to_string_lossy().blue()
+    );
+
+    let Some((introduced, changes)) = history.split_last() else {
+        summary!("{}", "Not committed".red());
+        return Ok(());
+    };
+
+    summary!(
+        "Introduced in {} by {} on {}: {}",
+        introduced.hash.yellow(),
+        introduced.author,
+        introduced.date,
+        introduced.subject
+    );
+
+    for c in changes.iter().rev() {
+        summary!(
+            "Changed in {} by {} on {}: {}",
+            c.hash.yellow(),
+            c.author,
+            c.date,
+            c.subject
+        );
+    }
+
+    Ok(())
+}
+
+fn not_a_repository() -> Error {
+    Error::msg("The migrations directory is not inside a git repository")
+}
+
+/// Splits a migration ID into its timestamp and name.
+fn split_id(id: &str) -> (&str, &str) {
+    id.split_once('_').unwrap_or((id, id))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn migrations_execute(
+    exec_count: Option<usize>,
+    paths: &[PathBuf],
+    meta: &[(String, bool)],
+    pg: &mut Client,
+    ud: UpDown,
+    allow_changed_down: bool,
+    config: &Config,
+    summary: &Summary,
+) -> anyhow::Result<()> {
+    let candidates = paths
+        .iter()
+        .zip(meta.iter())
+        .filter(|(_, (_, pending))| match ud {
+            UpDown::Up => *pending,
+            UpDown::Down => !pending,
+        })
+        .map(|(path, (id, _))| {
+            let meta = MigrationMeta::load(path.parent().unwrap_or(Path::new(".")))?;
+            Ok((path, id, meta.transaction, meta.release))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    // The migrations of a release count as one and are never split
+    let mut selected = vec![];
+    let mut releases = HashSet::new();
+    for group in candidates
+        .chunk_by(|a, b| a.3.is_some() && a.3 == b.3)
+        .take(exec_count.unwrap_or(usize::MAX))
+    {
+        if let Some(ref release) = group[0].3 {
+            if !releases.insert(release) {
+                return Err(Error::msg(format!(
+                    "The migrations of release '{release}' are not consecutive\nHint: Give them timestamps which are not interleaved with other migrations"
+                )));
+            }
+            if let Some((path, ..)) = group.iter().find(|m| !m.2) {
+                return Err(Error::msg(format!(
+                    "{} can not be executed in a transaction, so it can not be part of release '{release}', which is executed atomically",
+                    path.display()
+                )));
+            }
+        }
+        selected.extend(group.iter().map(|(path, id, tx, _)| (*path, *id, *tx)));
+    }
+
+    if matches!(ud, UpDown::Down) {
+        check_reversible(selected.iter().map(|(path, ..)| *path))?;
+        check_down_unchanged(
+            selected.iter().map(|(path, ..)| *path),
+            pg,
+            allow_changed_down,
+        )?;
+    }
+
+    summary.plan(selected.iter().map(|(path, ..)| *path), ud.direction());
+
+    // An injected failure stands for the migration after the one it was injected after failing
+    let failed = |e: &Error, id: &str| {
+        let mut rest = selected
+            .iter()
+            .map(|(_, id, _)| id.as_str())
+            .skip_while(move |other| *other != id);
+        match chaos::injected(e) {
+            true => rest.nth(1),
+            false => rest.next(),
+        }
+    };
+
+    // Migrations are executed in a single transaction, which is committed before each migration
+    // that has to be executed outside of one
+    for batch in selected.chunk_by(|a, b| a.2 && b.2) {
+        if let [(path, id, false)] = batch {
+            if let Err(e) = migration_execute_outside(path, id, pg, ud, config, summary) {
+                if let Some(id) = failed(&e, id) {
+                    record_failure(pg, id, ud, &e);
+                }
+                return Err(e);
+            }
+            info!("Executed {}", path.display().to_string().blue());
+            after_execute(path, pg)?;
+            continue;
+        }
+
+        let mut tx = pg.build_transaction().start()?;
+
+        for (path, id, _) in batch {
+            if let Err(e) = migration_execute_exact(path, id, &mut tx, ud, config, summary) {
+                tx.rollback()?;
+                summary.roll_back(batch.iter().map(|(_, id, _)| id.as_str()));
+                if let Some(id) = failed(&e, id) {
+                    record_failure(pg, id, ud, &e);
+                }
+                return Err(e);
+            };
+
+            info!("Executed {}", path.display().to_string().blue());
+        }
+
+        if let Err(e) = tx.commit() {
+            summary.roll_back(batch.iter().map(|(_, id, _)| id.as_str()));
+            return Err(e.into());
+        }
+
+        for (path, ..) in batch {
+            after_execute(path, pg)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the IDs of the pending migrations which are empty or only contain comments, e.g. ones
+/// generated but never written, which are left pending unless `--allow-empty` is given. Errors with
+/// `--fail-on-empty` if there are any. The initial migration is often left empty on purpose.
+fn empty(pending: &[PathBuf], args: &RunRevMigration) -> anyhow::Result<HashSet<String>> {
+    let mut empty = HashSet::new();
+    if args.allow_empty {
+        return Ok(empty);
+    }
+
+    let mut ids = vec![];
+    for file in pending {
+        if migration_id(file) == Some(INITIAL) {
+            continue;
+        }
+
+        if !executes(file)? {
+            let id = migration_id(file).unwrap_or_default();
+            ids.push(id);
+            empty.insert(id.to_string());
+        }
+    }
+
+    if ids.is_empty() {
+        return Ok(empty);
+    }
+
+    if args.fail_on_empty {
+        return Err(Error::msg(format!(
+            "Pending migrations are empty or only contain comments: {}\nHint: Write the migrations or remove them",
+            ids.join(", ").red()
+        )));
+    }
+
+    warn!(
+        "Leaving empty migrations pending: {}\nHint: Pass `--allow-empty` to mark them as applied",
+        ids.join(", ").yellow()
+    );
+
+    Ok(empty)
+}
+
+/// Whether the `up.sql` at `file` executes anything, unlike e.g. a generated migration which was
+/// never written.
+fn executes(file: &Path) -> anyhow::Result<bool> {
+    let sql = fs::read_to_string(file)?;
+    let executes = directive::steps(&sql, file)?.iter().any(|step| match step {
+        Step::Sql(sql) => !sql::statements(sql).is_empty(),
+        Step::Directive(Directive::Irreversible) => false,
+        Step::Directive(_) => true,
+    });
+    let dir = file.parent().unwrap_or(Path::new("."));
+    Ok(executes || !MigrationMeta::load(dir)?.up.is_empty())
+}
+
+/// Errors listing the migrations which can not be reverted, because their `down.sql` is missing or
+/// marked as irreversible, before any migration is reverted.
+fn check_reversible<'a>(paths: impl IntoIterator<Item = &'a PathBuf>) -> anyhow::Result<()> {
+    let mut irreversible = vec![];
+    for path in paths {
+        if directive::irreversible(path)? {
+            irreversible.push(migration_id(path).unwrap_or_default());
+        }
+    }
+
+    if irreversible.is_empty() {
+        return Ok(());
+    }
+
+    let mut message = String::from("Refusing to revert irreversible migrations:");
+    for id in irreversible {
+        let _ = write!(message, "\n  {}", id.red());
+    }
+    message.push_str("\nHint: Only revert the migrations applied after them, or write a `down.sql` reverting them");
+
+    Err(Error::msg(message))
+}
+
+/// Warns about the `down.sql` files which changed since their migrations were applied, since
+/// reverting them may not undo what was applied, and asks for confirmation when attached to a
+/// terminal. Errors unless the changes are allowed or confirmed. Migrations applied before migr
+/// recorded the checksums of `down.sql` are not checked.
+fn check_down_unchanged<'a>(
+    paths: impl IntoIterator<Item = &'a PathBuf>,
+    pg: &mut Client,
+    allow: bool,
+) -> anyhow::Result<()> {
+    let mut changed = vec![];
+    for path in paths {
+        let Some(id) = migration_id(path) else {
+            continue;
+        };
+        let recorded = pg
+            .query_opt(
+                "SELECT down_checksum FROM __migr_meta__ WHERE id = $1",
+                &[&id],
+            )?
+            .and_then(|row| row.get::<_, Option<String>>(0));
+        let Some(recorded) = recorded else {
+            continue;
+        };
+        if directive::checksum(&fs::read(path)?, path) != recorded {
+            changed.push(id.to_string());
+        }
+    }
+
+    if changed.is_empty() {
+        return Ok(());
+    }
+
+    let interactive = output::interactive();
+    if allow || interactive {
+        for id in &changed {
+            warn!("{id}/down.sql changed since the migration was applied, reverting it may not undo what was applied");
+        }
+    }
+
+    if allow {
+        return Ok(());
+    }
+
+    if interactive {
+        eprint!("Revert the migrations anyway? [y/N] ");
+        io::stderr().flush()?;
+        let mut answer = String::new();
+        io::stdin().lock().read_line(&mut answer)?;
+        if matches!(answer.trim(), "y" | "Y" | "yes") {
+            return Ok(());
+        }
+        return Err(Error::msg("Aborted, no migrations were reverted"));
+    }
+
+    Err(Error::msg(format!(
+        "The down migrations changed since these migrations were applied:\n  {}\nHint: Pass `--allow-changed-down` to revert them anyway",
+        changed.join("\n  ")
+    )))
+}
+
+/// Whether the migration file at `path` is executed in a transaction.
+fn transactional(path: &Path) -> anyhow::Result<bool> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    Ok(MigrationMeta::load(dir)?.transaction)
+}
+
+/// Executes a migration in its own transaction, or outside of one if its `meta.toml` says so.
+fn migration_execute(
+    path: &Path,
+    id: &str,
+    pg: &mut Client,
+    ud: UpDown,
+    config: &Config,
+    summary: &Summary,
+) -> anyhow::Result<()> {
+    if !transactional(path)? {
+        migration_execute_outside(path, id, pg, ud, config, summary)?;
+        return after_execute(path, pg);
+    }
+
     let mut tx = pg.transaction()?;
-    match migration_execute_exact(&file.into(), &id, &mut tx, ud) {
+    match migration_execute_exact(path, id, &mut tx, ud, config, summary) {
         Ok(_) => {
-            tx.commit()?;
-            Ok(())
+            if let Err(e) = tx.commit() {
+                summary.roll_back([id]);
+                return Err(e.into());
+            }
+            after_execute(path, pg)
         }
         Err(e) => {
             tx.rollback()?;
@@ -304,112 +2784,195 @@ fn find_and_execute(path: &PathBuf, name: &str, pg: &mut Client, ud: UpDown) ->
     }
 }
 
-/// Finds the exact migration by stripping the ts prefix in the name and returns its path and meta ID.
-/// `path` is a path pointing to the migrations dir.
-/// `name` is the name of the migration without the timestamp
-fn find_exact(path: &PathBuf, name: &str, pg: &mut Client) -> anyhow::Result<(PathBuf, String)> {
-    let Some(migration_path) = fs::read_dir(path)?
-        .filter_map(Result::ok)
-        .find(|f| {
-            let path = f.path();
-            let Some(full_name) = path.file_name() else {
-                return false;
-            };
-            let Some(migration) = full_name.to_str().map(|n| n.to_string()) else {
-                return false;
+/// Executes the statements of the `-- migr:after` directives of a committed migration one by one,
+/// outside of a transaction, stopping at the first failing one since the ones after it may depend
+/// on it. The migration stays applied if they fail, the error names the statements left to execute.
+fn after_execute(path: &Path, pg: &mut Client) -> anyhow::Result<()> {
+    let statements = directive::after(path).map_err(|e| {
+        Error::msg(format!(
+            "Unable to read the after steps of {}: {e:#}\nHint: The migration is applied, execute its after steps manually",
+            path.display()
+        ))
+    })?;
+
+    for (i, statement) in statements.iter().enumerate() {
+        debug!("Executing after step `{statement}`");
+        if let Err(e) = pg.batch_execute(statement) {
+            let skipped = match statements.len() - i - 1 {
+                0 => String::new(),
+                n => format!(" and the {n} after step(s) following it"),
             };
-            let Some(prefix_end) = migration.chars().position(|c| c == '_') else {
-                return false;
-            };
-            name == &migration[prefix_end + 1..]
-        })
-        .map(|e| e.path())
-    else {
-        return Err(Error::msg(format!("No migration found for name '{name}'")));
-    };
-
-    let Some(name) = migration_path.file_name() else {
-        return Err(Error::msg("Unsupported file found for migration"));
-    };
-
-    let Some(name) = name.to_str() else {
-        return Err(Error::msg("Unsupported file found for migration"));
-    };
-
-    trace!(
-        "Found migration {}",
-        migration_path.display().to_string().blue()
-    );
-
-    let count = pg
-        .query_one("SELECT COUNT(*) from __migr_meta__ WHERE id = $1", &[&name])?
-        .get::<usize, i64>(0);
-
-    if count == 0 {
-        return Err(Error::msg(format!(
-            "No entry found in metadata for {}\nHint: Run `migr sync` to sync the metadata table",
-            name.red()
-        )));
+            return Err(Error::msg(format!(
+                "After step `{statement}` of {} failed: {:#}\nHint: The migration is applied, execute the statement{skipped} manually",
+                path.display(),
+                Error::from(e)
+            )));
+        }
     }
 
-    let name = name.to_string();
+    Ok(())
+}
 
-    Ok((migration_path, name))
+/// The files of a migration about to be executed.
+struct Prepared<'a> {
+    sql: String,
+    steps: Vec<Step>,
+    dir: &'a Path,
+    meta: MigrationMeta,
 }
 
-fn migrations_execute(
-    exec_count: Option<usize>,
-    paths: &[PathBuf],
-    meta: &[(String, bool)],
-    pg: &mut Client,
-    ud: UpDown,
-) -> anyhow::Result<usize> {
-    let mut count = 0;
+impl Prepared<'_> {
+    /// Reads the migration at `path`. Statements are split into steps of their own if they are
+    /// executed outside of a transaction.
+    fn load(path: &Path, outside: bool) -> anyhow::Result<Prepared<'_>> {
+        let sql = fs::read_to_string(path)?;
+        let mut steps = directive::steps(&sql, path)?;
+        if outside {
+            steps = steps
+                .into_iter()
+                .flat_map(|step| match step {
+                    Step::Sql(sql) => sql::statements(&sql).into_iter().map(Step::Sql).collect(),
+                    step => vec![step],
+                })
+                .collect();
+        }
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let meta = MigrationMeta::load(dir)?;
+        Ok(Prepared {
+            sql,
+            steps,
+            dir,
+            meta,
+        })
+    }
 
-    let mut tx = pg.build_transaction().start()?;
+    fn param_steps(&self, ud: UpDown) -> &[ParamStep] {
+        match ud {
+            UpDown::Up => &self.meta.up,
+            UpDown::Down => &self.meta.down,
+        }
+    }
 
-    for (path, (id, pending)) in paths.iter().zip(meta.iter()) {
-        if let Some(exec_count) = exec_count {
-            if count >= exec_count {
-                break;
-            }
+    /// Executes the steps of the migration as the configured role, then the grants as the session
+    /// user. In a transaction the role is set locally and left to the rollback if a step fails,
+    /// otherwise it is always reset, since it outlives the migration on the session which returns
+    /// to the pool.
+    fn execute(
+        &self,
+        id: &str,
+        pg: &mut impl GenericClient,
+        ud: UpDown,
+        config: &Config,
+        in_transaction: bool,
+    ) -> anyhow::Result<()> {
+        // Objects created by the migration are owned by the role, grants and the metadata are still
+        // applied as the session user
+        if let Some(ref role) = config.run_as {
+            trace!("Switching to role {role}");
+            let local = if in_transaction { "LOCAL " } else { "" };
+            pg.batch_execute(&format!("SET {local}ROLE {}", quote_ident(role)))?;
         }
 
-        if matches!(ud, UpDown::Up) && !pending {
-            continue;
+        let result = steps_execute(&self.steps, &config.params, pg, in_transaction)
+            .and_then(|_| param_steps_execute(self.param_steps(ud), self.dir, pg));
+
+        if config.run_as.is_some() && (result.is_ok() || !in_transaction) {
+            pg.batch_execute("RESET ROLE")?;
         }
+        result?;
 
-        if matches!(ud, UpDown::Down) && *pending {
-            continue;
+        match ud {
+            UpDown::Up => grants_execute(id, &config.grants, pg),
+            UpDown::Down => Ok(()),
         }
+    }
 
-        if let Err(e) = migration_execute_exact(path, id, &mut tx, ud) {
-            tx.rollback()?;
-            return Err(e);
-        };
+    /// Records the execution of the migration in the metadata and its history.
+    fn record(
+        &self,
+        id: &str,
+        pg: &mut impl GenericClient,
+        ud: UpDown,
+        config: &Config,
+        duration: Duration,
+    ) -> anyhow::Result<()> {
+        let executed = executed_sql(&self.steps, self.param_steps(ud), id, ud, config)?;
+        record(
+            pg,
+            id,
+            ud,
+            self.dir,
+            &self.sql,
+            executed.as_deref(),
+            duration,
+        )
+    }
+}
 
-        count += 1;
+/// Reports an executed migration once its changes are committed.
+fn executed(id: &str, ud: UpDown, started: Instant, summary: &Summary) -> anyhow::Result<()> {
+    summary.record(id, ud.direction(), started.elapsed());
+    plugin::after_migration(id, ud.direction(), started.elapsed());
+    chaos::check(id)?;
 
-        info!("Executed {}", path.display().to_string().blue());
+    match ud {
+        UpDown::Up => info!("Successfully executed migration"),
+        UpDown::Down => info!("Successfully reverted migration"),
     }
 
-    tx.commit()?;
+    Ok(())
+}
+
+/// Executes a migration which can't run in a transaction block. Statements are sent one by one,
+/// since several statements sent at once run in an implicit transaction, and each is committed on
+/// its own, so a failing migration may be left partially applied.
+fn migration_execute_outside(
+    path: &Path,
+    id: &str,
+    pg: &mut Client,
+    ud: UpDown,
+    config: &Config,
+    summary: &Summary,
+) -> anyhow::Result<()> {
+    plugin::before_migration(id, ud.direction())?;
+    let started = Instant::now();
+    let prepared = Prepared::load(path, true)?;
+
+    debug!("Executing migration outside of a transaction");
+
+    if let Err(e) = prepared.execute(id, pg, ud, config, false) {
+        summary.fail(path, ud.direction(), &e);
+        return Err(e).with_context(|| {
+            format!(
+                "while executing migration {}, which was not executed in a transaction and may be partially applied",
+                path.display().to_string().red(),
+            )
+        });
+    }
 
-    Ok(count)
+    prepared.record(id, pg, ud, config, started.elapsed())?;
+
+    executed(id, ud, started, summary)
 }
 
 fn migration_execute_exact(
-    path: &PathBuf,
+    path: &Path,
     id: &str,
     tx_outer: &mut Transaction<'_>,
     ud: UpDown,
+    config: &Config,
+    summary: &Summary,
 ) -> anyhow::Result<()> {
-    let sql = fs::read_to_string(path)?;
+    plugin::before_migration(id, ud.direction())?;
+    let started = Instant::now();
+    let prepared = Prepared::load(path, false)?;
 
     let mut tx = tx_outer.transaction()?;
 
-    if let Err(e) = tx.batch_execute(&sql) {
+    let result = prepared.execute(id, &mut tx, ud, config, true);
+    if let Err(e) = result {
         tx.rollback()?;
+        summary.fail(path, ud.direction(), &e);
         return Err(e).with_context(|| {
             format!(
                 "while executing migration {}",
@@ -418,12 +2981,7 @@ fn migration_execute_exact(
         });
     }
 
-    let query = match ud {
-        UpDown::Up => "UPDATE __migr_meta__ SET pending=FALSE WHERE id=$1",
-        UpDown::Down => "UPDATE __migr_meta__ SET pending=TRUE WHERE id=$1",
-    };
-
-    if let Err(e) = tx.execute(query, &[&id]) {
+    if let Err(e) = prepared.record(id, &mut tx, ud, config, started.elapsed()) {
         tx.rollback()?;
         return Err(e).with_context(|| {
             format!(
@@ -435,17 +2993,384 @@ fn migration_execute_exact(
 
     tx.commit()?;
 
-    match ud {
-        UpDown::Up => info!("Successfully executed migration"),
-        UpDown::Down => info!("Successfully reverted migration"),
+    executed(id, ud, started, summary)
+}
+
+/// Updates the metadata of an executed migration and adds the execution to its history.
+fn record(
+    pg: &mut impl GenericClient,
+    id: &str,
+    ud: UpDown,
+    dir: &Path,
+    sql: &str,
+    executed: Option<&str>,
+    duration: Duration,
+) -> anyhow::Result<()> {
+    // Applied migrations are assigned to a batch once the run finishes, see `record_batch`
+    let query = match ud {
+        UpDown::Up => {
+            "UPDATE __migr_meta__ SET pending=FALSE, checksum=$2, down_checksum=$3, applied_at=now(), batch=NULL, error=NULL, failed_at=NULL WHERE id=$1"
+        }
+        UpDown::Down => {
+            "UPDATE __migr_meta__ SET pending=TRUE, checksum=$2, down_checksum=$3, applied_at=NULL, batch=NULL WHERE id=$1"
+        }
+    };
+
+    // Reverted migrations have no applied checksum. The `down.sql` is recorded as it was when
+    // the migration was applied, so reverts notice when it changed since
+    let checksum =
+        matches!(ud, UpDown::Up).then(|| directive::checksum(sql.as_bytes(), &dir.join("up.sql")));
+    let down = dir.join("down.sql");
+    let down_checksum = match ud {
+        UpDown::Up => fs::read(&down)
+            .ok()
+            .map(|sql| directive::checksum(&sql, &down)),
+        UpDown::Down => None,
+    };
+    pg.execute(query, &[&id, &checksum, &down_checksum])?;
+
+    pg.execute(
+        "INSERT INTO __migr_history__(migration, direction, checksum, duration_ms, sql) VALUES ($1, $2, $3, $4, $5)",
+        &[&id, &ud.direction(), &checksum, &(duration.as_secs_f64() * 1000.0), &executed],
+    )?;
+
+    Ok(())
+}
+
+/// Quarantines a migration which failed to apply by recording its error, which holds back runs
+/// until it is resumed or skipped. Failures of reverted migrations are not recorded.
+fn record_failure(pg: &mut Client, id: &str, ud: UpDown, error: &Error) {
+    if matches!(ud, UpDown::Down) {
+        return;
+    }
+
+    let error = output::strip_ansi(&format!("{error:#}"));
+
+    // The connection may be unusable after the failure, which must still be reported
+    if let Err(e) = pg.execute(
+        "UPDATE __migr_meta__ SET error = $2, failed_at = now() WHERE id = $1 AND pending",
+        &[&id, &error],
+    ) {
+        warn!("Unable to record the failure of {id}: {e}");
+    }
+}
+
+/// Errors if a migration failed in a previous run, so later migrations are not applied on top of a
+/// partially migrated schema.
+fn check_failed(pg: &mut Client) -> anyhow::Result<()> {
+    let failed = pg.query_opt(
+        &format!(
+            "SELECT id, error FROM __migr_meta__ WHERE pending AND error IS NOT NULL
+             ORDER BY {VERSION_COLUMN}, id LIMIT 1"
+        ),
+        &[],
+    )?;
+
+    let Some(failed) = failed else {
+        return Ok(());
+    };
+    let id = failed.get::<_, String>(0);
+
+    Err(Error::msg(format!(
+        "Migration {} failed in a previous run: {}\nHint: Fix it and run `migr resume`, or apply it manually and run `migr skip {id}`",
+        id.red(),
+        failed.get::<_, String>(1)
+    )))
+}
+
+/// Assigns the migrations applied since the last batch was recorded to a new batch, which
+/// `rev --last-batch` reverts. Each command applying migrations records one batch, if it applied any.
+fn record_batch(pg: &mut impl GenericClient) -> anyhow::Result<()> {
+    let recorded = pg.execute(
+        "UPDATE __migr_meta__ SET batch = (SELECT COALESCE(MAX(batch), 0) + 1 FROM __migr_meta__)
+         WHERE NOT pending AND batch IS NULL",
+        &[],
+    )?;
+    if recorded > 0 {
+        debug!("Recorded batch of {recorded} migration(s)");
+    }
+    Ok(())
+}
+
+/// Executes the statements configured to run after each up migration, as the session user.
+fn grants_execute(
+    id: &str,
+    grants: &GrantsConfig,
+    tx: &mut impl GenericClient,
+) -> anyhow::Result<()> {
+    for sql in grants_sql(id, grants) {
+        trace!("{}", sql.trim());
+        tx.batch_execute(&sql)
+            .with_context(|| format!("Could not apply grants: {}", sql.trim()))?;
+    }
+    Ok(())
+}
+
+/// Returns the statements configured to run after the up migration `id`.
+fn grants_sql(id: &str, grants: &GrantsConfig) -> Vec<String> {
+    let (version, name) = split_id(id);
+    grants
+        .after_up
+        .iter()
+        .map(|grant| {
+            grant
+                .replace("{id}", id)
+                .replace("{version}", version)
+                .replace("{name}", name)
+        })
+        .collect()
+}
+
+/// Renders the SQL a migration executes, with included files expanded, if the config records it.
+/// Writes it to the artifacts directory, if set, and returns it if it is stored in the history.
+/// Data streamed by `COPY` and bound to parameters is referenced by its file.
+fn executed_sql(
+    steps: &[Step],
+    param_steps: &[ParamStep],
+    id: &str,
+    ud: UpDown,
+    config: &Config,
+) -> anyhow::Result<Option<String>> {
+    let history = &config.history;
+    if !history.sql && history.artifacts.is_none() {
+        return Ok(None);
+    }
+
+    let mut executed = String::new();
+    if let Some(ref role) = config.run_as {
+        let _ = writeln!(executed, "SET ROLE {};", quote_ident(role));
+    }
+    for step in steps {
+        match step {
+            Step::Sql(sql) => {
+                let _ = writeln!(executed, "{}", sql.trim());
+            }
+            Step::Directive(Directive::Copy {
+                target,
+                file,
+                options,
+            }) => {
+                let _ = writeln!(
+                    executed,
+                    "COPY {target} FROM STDIN {options}; -- {}",
+                    file.display()
+                );
+            }
+            Step::Directive(Directive::RecreateViews { table, sql }) => {
+                let _ = writeln!(
+                    executed,
+                    "{}recreate-views {table}\n{}",
+                    directive::PREFIX,
+                    sql.trim()
+                );
+            }
+            Step::Directive(Directive::WaitUntil { query, timeout }) => {
+                let _ = writeln!(
+                    executed,
+                    "{}wait-until \"{query}\" timeout={}s",
+                    directive::PREFIX,
+                    timeout.as_secs()
+                );
+            }
+            // The values are left out, they may be secrets
+            Step::Directive(Directive::Params { names, sql }) => {
+                let _ = writeln!(
+                    executed,
+                    "{}params {}\n{}",
+                    directive::PREFIX,
+                    names.join(" "),
+                    sql.trim()
+                );
+            }
+            Step::Directive(Directive::Irreversible | Directive::After(_)) => {}
+        }
+    }
+    for step in param_steps {
+        let _ = writeln!(
+            executed,
+            "{}; -- {}",
+            step.statement.trim().trim_end_matches(';'),
+            step.files.join(", ")
+        );
+    }
+    if config.run_as.is_some() {
+        executed.push_str("RESET ROLE;\n");
+    }
+    if matches!(ud, UpDown::Up) {
+        for sql in grants_sql(id, &config.grants) {
+            let _ = writeln!(executed, "{};", sql.trim().trim_end_matches(';'));
+        }
+    }
+
+    if let Some(ref dir) = history.artifacts {
+        let date = time::OffsetDateTime::now_utc();
+        let (date, (h, m, s, ms)) = (date.date(), date.time().as_hms_milli());
+        let dir = dir.join(id);
+        let file = dir.join(format!(
+            "{date}-{h:02}{m:02}{s:02}.{ms:03}.{}.sql",
+            ud.direction()
+        ));
+        fs::create_dir_all(&dir)
+            .and_then(|_| fs::write(&file, &executed))
+            .with_context(|| format!("Unable to write the executed SQL to '{}'", file.display()))?;
+        debug!("Recorded the executed SQL at {}", file.display());
+    }
+
+    Ok(history.sql.then_some(executed))
+}
+
+fn steps_execute(
+    steps: &[Step],
+    params: &HashMap<String, String>,
+    tx: &mut impl GenericClient,
+    in_transaction: bool,
+) -> anyhow::Result<()> {
+    for step in steps {
+        match step {
+            Step::Sql(sql) => {
+                trace!("{}", sql.trim());
+                tx.batch_execute(sql)?
+            }
+            Step::Directive(Directive::Copy {
+                target,
+                file,
+                options,
+            }) => {
+                debug!(
+                    "Copying {} into {}",
+                    file.display().to_string().blue(),
+                    target.blue()
+                );
+                let mut data = fs::File::open(file)
+                    .with_context(|| format!("Unable to open '{}'", file.display()))?;
+                let mut writer = tx.copy_in(&format!("COPY {target} FROM STDIN {options}"))?;
+                let rows = std::io::copy(&mut data, &mut writer)
+                    .and_then(|_| writer.finish().map_err(std::io::Error::other))?;
+                debug!("Copied {rows} rows");
+            }
+            Step::Directive(Directive::RecreateViews { table, sql }) => {
+                views::recreate(tx, table, sql)?
+            }
+            // Reverting irreversible migrations is refused before any of them is executed
+            Step::Directive(Directive::Irreversible) => {}
+            // Executed once the migration is committed, see `after_execute`
+            Step::Directive(Directive::After(_)) => {}
+            Step::Directive(Directive::WaitUntil { query, timeout }) => {
+                wait_until(tx, query, *timeout)?
+            }
+            Step::Directive(Directive::Params { names, sql }) => {
+                params_execute(tx, names, params, sql, in_transaction)?
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Executes `sql` with the values of the parameters bound to the settings `migr.<name>`. The
+/// values are passed as statement parameters, so they never end up in the SQL.
+fn params_execute(
+    tx: &mut impl GenericClient,
+    names: &[String],
+    params: &HashMap<String, String>,
+    sql: &str,
+    in_transaction: bool,
+) -> anyhow::Result<()> {
+    for name in names {
+        let Some(value) = params.get(name) else {
+            return Err(Error::msg(format!(
+                "No value for the parameter '{name}'\nHint: Pass `--var {name}=<value>` or set it in the `[params]` of migr.toml"
+            )));
+        };
+        trace!("Binding parameter {name}");
+        tx.execute(
+            "SELECT set_config($1, $2, $3)",
+            &[&format!("migr.{name}"), value, &in_transaction],
+        )?;
     }
 
+    trace!("{}", sql.trim());
+    let result = tx.batch_execute(sql);
+
+    // Settings local to the transaction end with it, outside of one they would outlive the
+    // migration on the session
+    if !in_transaction {
+        for name in names {
+            if let Err(e) = tx.execute(
+                "SELECT set_config($1, '', false)",
+                &[&format!("migr.{name}")],
+            ) {
+                warn!("Unable to reset the parameter {name}: {e}");
+            }
+        }
+    }
+
+    Ok(result?)
+}
+
+/// How often [Directive::WaitUntil] polls its condition.
+const WAIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Polls `query` until it returns true, or errors once `timeout` is exceeded. Each poll sees the
+/// changes committed since the last one, as the transaction of the migration is read committed.
+fn wait_until(tx: &mut impl GenericClient, query: &str, timeout: Duration) -> anyhow::Result<()> {
+    debug!("Waiting until {}", query.blue());
+    let started = Instant::now();
+    loop {
+        let row = tx
+            .query_opt(query, &[])
+            .with_context(|| format!("while waiting until {}", query.red()))?;
+        let done = row
+            .as_ref()
+            .and_then(|row| row.try_get::<_, bool>(0).ok())
+            .ok_or_else(|| {
+                Error::msg(format!(
+                    "The wait-until query '{query}' must return a single boolean"
+                ))
+            })?;
+        if done {
+            debug!("Waited {:.2?}", started.elapsed());
+            return Ok(());
+        }
+        if started.elapsed() >= timeout {
+            return Err(Error::msg(format!(
+                "Timed out after {timeout:.0?} waiting until {query}\nHint: Raise the `timeout=` of the directive if the condition takes longer to be met"
+            )));
+        }
+        trace!("Condition not met, polling again in {WAIT_INTERVAL:.0?}");
+        std::thread::sleep(WAIT_INTERVAL);
+    }
+}
+
+fn param_steps_execute(
+    steps: &[ParamStep],
+    dir: &Path,
+    tx: &mut impl GenericClient,
+) -> anyhow::Result<()> {
+    for step in steps {
+        let files = step
+            .files
+            .iter()
+            .map(|file| {
+                let file = dir.join(file);
+                debug!("Binding {}", file.display().to_string().blue());
+                fs::read(&file).with_context(|| format!("Unable to read '{}'", file.display()))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let params = files
+            .iter()
+            .map(|f| f as &(dyn ToSql + Sync))
+            .collect::<Vec<_>>();
+
+        trace!("{}", step.statement.trim());
+        tx.execute(&step.statement, &params)?;
+    }
     Ok(())
 }
 
 fn migration_meta(
     paths: &[PathBuf],
-    pg: &mut Client,
+    pg: &mut impl GenericClient,
     ud: UpDown,
 ) -> Result<Vec<(String, bool)>, Error> {
     let mig_ids = paths
@@ -456,12 +3381,15 @@ fn migration_meta(
         })
         .collect::<Vec<_>>();
 
-    let query = match ud {
-        UpDown::Up => "SELECT * FROM __migr_meta__ WHERE id = ANY($1) ORDER BY id ASC",
-        UpDown::Down => "SELECT * FROM __migr_meta__ WHERE id = ANY($1) ORDER BY id DESC",
+    let order = match ud {
+        UpDown::Up => "ASC",
+        UpDown::Down => "DESC",
     };
+    let query = format!(
+        "SELECT id, pending FROM __migr_meta__ WHERE id = ANY($1) ORDER BY {VERSION_COLUMN} {order}, id {order}"
+    );
 
-    let migs = match pg.query(query, &[&mig_ids]) {
+    let migs = match pg.query(&query, &[&mig_ids]) {
         Ok(rows) => rows
             .into_iter()
             .map(|r| (r.get::<usize, String>(0), r.get::<usize, bool>(1))),
@@ -471,40 +3399,165 @@ fn migration_meta(
     Ok(migs.collect())
 }
 
-fn migration_files(path: &PathBuf, ud: UpDown) -> Result<Vec<PathBuf>, Error> {
-    let mig_dirs = fs::read_dir(path)?;
+/// Returns the paths of the migrations which are pending in the metadata table.
+fn migration_pending(
+    paths: &[PathBuf],
+    pg: &mut impl GenericClient,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let pending = migration_meta(paths, pg, UpDown::Up)?
+        .into_iter()
+        .filter_map(|(id, pending)| pending.then_some(id))
+        .collect::<HashSet<_>>();
+
+    Ok(paths
+        .iter()
+        .filter(|path| migration_id(path).is_some_and(|id| pending.contains(id)))
+        .cloned()
+        .collect())
+}
+
+/// Returns the metadata ID of the migration file at `path`, i.e. the name of its directory.
+pub(crate) fn migration_id(path: &Path) -> Option<&str> {
+    path.parent()?.file_name()?.to_str()
+}
+
+/// Returns the sorted paths of the migration directories, skipping ignored entries and directories
+/// without a timestamp prefix.
+pub(crate) fn migration_dirs(path: &Path, config: &Config) -> anyhow::Result<Vec<PathBuf>> {
+    let cache = Cache::load(path)?;
+
+    let invalid = cache.invalid().collect::<Vec<_>>();
+    if !invalid.is_empty() {
+        warn!(
+            "Skipping entries with names that are not valid UTF-8: {}",
+            invalid.join(", ").yellow()
+        );
+    }
+
+    let broken = cache.broken().collect::<Vec<_>>();
+    if !broken.is_empty() {
+        warn!(
+            "Skipping symlinks pointing nowhere: {}\nHint: Check that the volumes they point into are mounted",
+            broken.join(", ").yellow()
+        );
+    }
+
+    let (dirs, skipped) = candidates(path, &cache, config)?;
+
+    if !skipped.is_empty() {
+        warn!(
+            "Skipping entries without a timestamp prefix: {}\nHint: Add them to {} to silence this warning",
+            skipped.join(", ").yellow(),
+            path.join(IGNORE_FILE).display()
+        );
+    }
+
+    // Migrations restored to the directory take precedence over their archived copies
+    let mut dirs = dirs;
+    let ids = dirs
+        .iter()
+        .filter_map(|dir| dir.file_name().map(|name| name.to_os_string()))
+        .collect::<HashSet<_>>();
+    dirs.extend(
+        archive::extracted(path)?
+            .into_iter()
+            .filter(|dir| dir.file_name().is_some_and(|name| !ids.contains(name))),
+    );
+    dirs.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+
+    Ok(dirs)
+}
+
+/// Splits the entries of the migrations directory which are not ignored into the migration
+/// directories and the names of the entries without a timestamp prefix.
+fn candidates(
+    path: &Path,
+    cache: &Cache,
+    config: &Config,
+) -> anyhow::Result<(Vec<PathBuf>, Vec<String>)> {
+    let ignore = Ignore::load(path, config)?;
+    let mut skipped = vec![];
+
+    let dirs = cache
+        .migrations(path)
+        .into_iter()
+        .filter(|dir| {
+            let name = dir.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+            if ignore.is_ignored(name) {
+                return false;
+            }
+            if !ignore::has_timestamp(name) {
+                skipped.push(name.to_string());
+                return false;
+            }
+            true
+        })
+        .collect::<Vec<_>>();
+
+    Ok((dirs, skipped))
+}
+
+/// Returns the migration directories and the entries [migration_dirs] skips for lacking a
+/// timestamp prefix, which the naming rules report.
+pub(crate) fn naming_dirs(path: &Path, config: &Config) -> anyhow::Result<Vec<PathBuf>> {
+    let mut dirs = migration_dirs(path, config)?;
+    let (_, skipped) = candidates(path, &Cache::load(path)?, config)?;
+    dirs.extend(skipped.into_iter().map(|name| path.join(name)));
+    dirs.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+    Ok(dirs)
+}
+
+/// Returns a checksum of the IDs and `up.sql` files of the migrations at `path` and the files
+/// they include, which changes whenever a migration is added, removed or edited.
+pub fn fingerprint(path: &Path, config: &Config) -> anyhow::Result<String> {
+    let mut history = String::new();
+    for file in migration_files(path, UpDown::Up, config)? {
+        let id = migration_id(&file).unwrap_or_default();
+        // Read rather than cached, since included files change without the migration changing
+        let checksum = fs::read(&file)
+            .map(|sql| directive::checksum(&sql, &file))
+            .unwrap_or_default();
+        let _ = writeln!(history, "{id} {checksum}");
+    }
+    for (name, checksum) in functions::checksums(path)? {
+        let _ = writeln!(history, "{FUNCTIONS_DIR}/{name} {checksum}");
+    }
+    Ok(cache::checksum(history.as_bytes()))
+}
+
+fn migration_files(path: &Path, ud: UpDown, config: &Config) -> Result<Vec<PathBuf>, Error> {
+    let cache = Cache::load(path)?;
     let mut pending = vec![];
     let ty = match ud {
         UpDown::Up => "up.sql",
         UpDown::Down => "down.sql",
     };
 
-    for mig in mig_dirs {
-        let entry = mig?.path();
-
-        if !entry.is_dir() {
-            continue;
-        }
-
-        let updown = entry.read_dir()?;
-
-        let file = updown
-            .filter_map(Result::ok)
-            .find(|e| match e.file_name().into_string() {
-                Ok(e) => e.contains(ty),
-                Err(_) => false,
-            })
-            .ok_or_else(|| {
-                Error::msg(format!(
+    for entry in migration_dirs(path, config)? {
+        let id = entry
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+
+        // Migrations without a `down.sql` are irreversible, which is reported when reverting them
+        let file = match cache.files(id).find(|f| f.contains(ty)) {
+            Some(file) => file,
+            // Archived migrations are not in the cache of the migrations directory
+            None if entry.join(ty).exists() => ty,
+            None if matches!(ud, UpDown::Down) => ty,
+            None => {
+                return Err(Error::msg(format!(
                     "{} does not contain the necessary `{ty}` file.",
                     entry.display(),
-                ))
-            })?;
+                )))
+            }
+        };
 
-        pending.push(file.path())
+        pending.push(entry.join(file))
     }
 
-    pending.sort();
+    // Archived migrations live outside of the migrations directory, so they are sorted by ID
+    pending.sort_by(|a, b| migration_id(a).cmp(&migration_id(b)));
 
     Ok(pending)
 }
@@ -515,6 +3568,15 @@ enum UpDown {
     Down,
 }
 
+impl UpDown {
+    fn direction(self) -> &'static str {
+        match self {
+            UpDown::Up => "up",
+            UpDown::Down => "down",
+        }
+    }
+}
+
 impl Display for UpDown {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -523,3 +3585,190 @@ impl Display for UpDown {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn migration_id_is_the_directory_name() {
+        let file = Path::new("migrations")
+            .join("2024-01-01-000000_create_users")
+            .join("up.sql");
+        assert_eq!(migration_id(&file), Some("2024-01-01-000000_create_users"));
+        assert_eq!(migration_id(Path::new("up.sql")), None);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn migration_id_of_windows_paths() {
+        let file = Path::new(r"C:\app\migrations\2024-01-01-000000_create_users\up.sql");
+        assert_eq!(migration_id(file), Some("2024-01-01-000000_create_users"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn migration_id_of_non_utf8_directories() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let file = Path::new("migrations")
+            .join(OsStr::from_bytes(b"2024-01-01-000000_\xff"))
+            .join("up.sql");
+        assert_eq!(migration_id(&file), None);
+    }
+
+    #[test]
+    fn migration_files_are_ordered_by_id() {
+        let root = env::temp_dir().join(format!("migr-order-{}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let path = root.join("migrations");
+        // Created out of order, since read order follows creation order on some filesystems
+        for id in [
+            "2024-03-01-000000_c",
+            "2024-01-01-000000_a",
+            "2024-02-01-000000_b",
+        ] {
+            fs::create_dir_all(path.join(id)).unwrap();
+            fs::write(path.join(id).join("up.sql"), "SELECT 1;").unwrap();
+        }
+        #[cfg(unix)]
+        {
+            let target = root.join("shared").join("2024-01-15-000000_linked");
+            fs::create_dir_all(&target).unwrap();
+            fs::write(target.join("up.sql"), "SELECT 1;").unwrap();
+            std::os::unix::fs::symlink(&target, path.join("2024-01-15-000000_linked")).unwrap();
+        }
+
+        let ids = |files: Vec<PathBuf>| {
+            files
+                .iter()
+                .filter_map(|file| migration_id(file).map(String::from))
+                .collect::<Vec<_>>()
+        };
+        let config = Config::default();
+        // Once without and once with the cache written by the first load
+        let fresh = ids(migration_files(&path, UpDown::Up, &config).unwrap());
+        let cached = ids(migration_files(&path, UpDown::Up, &config).unwrap());
+
+        let mut sorted = fresh.clone();
+        sorted.sort();
+        assert_eq!(fresh, sorted);
+        assert_eq!(fresh, cached);
+        assert_eq!(fresh.first().unwrap(), "2024-01-01-000000_a");
+        #[cfg(unix)]
+        assert_eq!(fresh[1], "2024-01-15-000000_linked");
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn case_renames_are_sorted_by_directory() {
+        let ids = [
+            String::from("2024-02-01-000000_Add_Index"),
+            String::from("2024-01-01-000000_create_Users"),
+        ];
+        let applied = [
+            String::from("2024-02-01-000000_add_index"),
+            String::from("2024-01-01-000000_create_users"),
+        ];
+        let expected = [
+            "  2024-01-01-000000_create_Users was applied as 2024-01-01-000000_create_users",
+            "  2024-02-01-000000_Add_Index was applied as 2024-02-01-000000_add_index",
+        ];
+        assert_eq!(case_renames(&ids, &applied), expected);
+
+        let (mut ids, mut applied) = (ids.to_vec(), applied.to_vec());
+        ids.reverse();
+        applied.reverse();
+        assert_eq!(case_renames(&ids, &applied), expected);
+        assert!(case_renames(&ids[..1], &ids[..1]).is_empty());
+    }
+
+    /// Needs a disposable database, given by `MIGR_TEST_DATABASE_URL`, and is skipped without one.
+    #[test]
+    fn injected_failures_roll_back_and_resume() {
+        let Ok(url) = env::var("MIGR_TEST_DATABASE_URL") else {
+            return;
+        };
+        let root = env::temp_dir().join(format!("migr-chaos-{}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let path = root.join("migrations");
+        let ids = [
+            "2024-01-01-000000_chaos_a",
+            "2024-01-02-000000_chaos_b",
+            "2024-01-03-000000_chaos_c",
+        ];
+        for id in ids {
+            let table = id.split_once('_').unwrap().1;
+            fs::create_dir_all(path.join(id)).unwrap();
+            fs::write(
+                path.join(id).join("up.sql"),
+                format!("CREATE TABLE {table} (id INT PRIMARY KEY);"),
+            )
+            .unwrap();
+            fs::write(
+                path.join(id).join("down.sql"),
+                format!("DROP TABLE {table};"),
+            )
+            .unwrap();
+        }
+
+        let mut pg = Client::connect(&url, postgres::NoTls).unwrap();
+        pg.batch_execute(
+            "DROP TABLE IF EXISTS chaos_a, chaos_b, chaos_c;
+             DO $$ BEGIN
+                 IF to_regclass('__migr_meta__') IS NOT NULL THEN
+                     DELETE FROM __migr_meta__ WHERE id LIKE '%_chaos_%';
+                 END IF;
+             END $$;",
+        )
+        .unwrap();
+        let exists = |pg: &mut Client, table: &str| {
+            pg.query_one("SELECT to_regclass($1) IS NOT NULL", &[&table])
+                .unwrap()
+                .get::<_, bool>(0)
+        };
+        let state = |pg: &mut Client, id: &str| {
+            let row = pg
+                .query_one(
+                    "SELECT pending, error IS NOT NULL FROM __migr_meta__ WHERE id = $1",
+                    &[&id],
+                )
+                .unwrap();
+            (row.get::<_, bool>(0), row.get::<_, bool>(1))
+        };
+
+        let pool = crate::db::pool(&url, 2, false, None, &Default::default()).unwrap();
+        let config = Config::default();
+        let args = RunRevMigration {
+            fail_after: Some(String::from("chaos_b")),
+            ..Default::default()
+        };
+        let e = ensure(&args, path.clone(), &pool, &config).unwrap_err();
+        let injected = e.downcast_ref::<chaos::InjectedFailure>().unwrap();
+        assert_eq!(injected.after, ids[1]);
+
+        // The migrations share a transaction, so all of them are rolled back
+        for table in ["chaos_a", "chaos_b", "chaos_c"] {
+            assert!(!exists(&mut pg, table));
+        }
+        assert_eq!(state(&mut pg, ids[0]), (true, false));
+        assert_eq!(state(&mut pg, ids[1]), (true, false));
+        assert_eq!(state(&mut pg, ids[2]), (true, true));
+
+        resume(&RunRevMigration::default(), path, &pool, &config).unwrap();
+        for (table, id) in ["chaos_a", "chaos_b", "chaos_c"].into_iter().zip(ids) {
+            assert!(exists(&mut pg, table));
+            assert_eq!(state(&mut pg, id), (false, false));
+        }
+
+        pg.batch_execute(
+            "DROP TABLE chaos_a, chaos_b, chaos_c;
+             DELETE FROM __migr_meta__ WHERE id LIKE '%_chaos_%';",
+        )
+        .unwrap();
+        let _ = fs::remove_dir_all(&root);
+    }
+}
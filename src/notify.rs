@@ -0,0 +1,63 @@
+use crate::config::{NotifyConfig, NotifyOn};
+use crate::summary::Report;
+use crate::{debug, http};
+use anyhow::Error;
+
+/// POSTs the outcome of a command to the configured webhook.
+pub fn send(config: &NotifyConfig, report: &Report) -> anyhow::Result<()> {
+    let Some(ref url) = config.url else {
+        return Ok(());
+    };
+
+    if config.on == NotifyOn::Failure && report.success {
+        return Ok(());
+    }
+
+    let body = render(&config.template, report)?;
+
+    let status = http::send("POST", url, &config.content_type, body.as_bytes())?;
+    if !(200..300).contains(&status) {
+        return Err(Error::msg(format!("Webhook responded with {status}")));
+    }
+
+    debug!("Sent notification to {url}");
+
+    Ok(())
+}
+
+/// Replaces the placeholders in `template` with the values from the report.
+fn render(template: &str, report: &Report) -> anyhow::Result<String> {
+    let status = if report.success {
+        "succeeded"
+    } else {
+        "failed"
+    };
+
+    let placeholders = [
+        ("{action}", escape(report.action)),
+        ("{status}", escape(status)),
+        ("{count}", report.count.to_string()),
+        (
+            "{current_version}",
+            escape(report.current_version.as_deref().unwrap_or_default()),
+        ),
+        (
+            "{error}",
+            escape(report.error.as_deref().unwrap_or_default()),
+        ),
+        ("{migrations}", serde_json::to_string(&report.migrations)?),
+        ("{summary}", serde_json::to_string(report)?),
+    ];
+
+    Ok(placeholders
+        .iter()
+        .fold(template.to_string(), |body, (key, value)| {
+            body.replace(key, value)
+        }))
+}
+
+/// Escapes `value` for use inside a JSON string.
+fn escape(value: &str) -> String {
+    let quoted = serde_json::Value::from(value).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
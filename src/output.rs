@@ -0,0 +1,152 @@
+use colored::ColoredString;
+use std::env;
+use std::fmt::Arguments;
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, AtomicI8, AtomicUsize, Ordering};
+
+/// Only errors and summaries are printed.
+pub const QUIET: i8 = -1;
+/// The default verbosity.
+pub const INFO: i8 = 0;
+/// Plumbing, enabled with `-v`.
+pub const DEBUG: i8 = 1;
+/// Executed statements, enabled with `-vv`.
+pub const TRACE: i8 = 2;
+/// Server notices, enabled with `-vvv`.
+pub const NOTICE: i8 = 3;
+
+// Applications using migr as a library only get errors unless they call `init`
+static VERBOSITY: AtomicI8 = AtomicI8::new(QUIET);
+
+/// Whether prompts are refused even when attached to a terminal.
+static NON_INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// The width log lines are wrapped at, `0` disables wrapping.
+static WIDTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Width of the `LEVEL | ` gutter preceding every line.
+const GUTTER: usize = 8;
+
+/// The width used when stdout is a terminal and `COLUMNS` is not set.
+const DEFAULT_WIDTH: usize = 100;
+
+/// Configures the verbosity, colors and wrapping. Colors are disabled with `--no-color`, a non-empty `NO_COLOR`, or
+/// when stdout is not a terminal, in which case lines are not wrapped either so logs stay greppable.
+pub fn init(no_color: bool, verbosity: i8) {
+    VERBOSITY.store(verbosity, Ordering::Relaxed);
+
+    let terminal = io::stdout().is_terminal();
+    let no_color_env = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+
+    colored::control::set_override(terminal && !no_color && !no_color_env);
+
+    if terminal {
+        let width = env::var("COLUMNS")
+            .ok()
+            .and_then(|c| c.parse().ok())
+            .unwrap_or(DEFAULT_WIDTH);
+        WIDTH.store(width, Ordering::Relaxed);
+    }
+}
+
+/// Refuses every prompt, even when attached to a terminal. Commands which would ask fail instead,
+/// naming the flag which answers the question.
+pub fn set_non_interactive(non_interactive: bool) {
+    NON_INTERACTIVE.store(non_interactive, Ordering::Relaxed);
+}
+
+/// Whether migr can ask the user, i.e. prompts are not refused and both stdin and stderr are
+/// attached to a terminal.
+pub fn interactive() -> bool {
+    !NON_INTERACTIVE.load(Ordering::Relaxed)
+        && io::stdin().is_terminal()
+        && io::stderr().is_terminal()
+}
+
+/// Returns true if messages of the given verbosity level are printed.
+pub fn enabled(level: i8) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= level
+}
+
+/// Prints a log message under `level`. Continuation lines, both explicit and wrapped, are aligned
+/// with the gutter of the first line.
+pub fn print(level: ColoredString, message: Arguments) {
+    let message = message.to_string();
+    let width = WIDTH.load(Ordering::Relaxed).saturating_sub(GUTTER);
+
+    let mut out = String::new();
+    let mut first = true;
+
+    for line in message.lines().flat_map(|line| wrap(line, width)) {
+        if first {
+            out.push_str(&format!("{level:5} | {line}\n"));
+            first = false;
+        } else {
+            out.push_str(&format!("{:5} | {line}\n", ""));
+        }
+    }
+
+    if first {
+        out.push_str(&format!("{level:5} | \n"));
+    }
+
+    // Write the whole message at once so messages from parallel workers don't interleave
+    let _ = io::stdout().lock().write_all(out.as_bytes());
+}
+
+/// Splits `line` on whitespace into lines at most `width` visible characters wide. Words longer than
+/// the width are kept whole.
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    if width == 0 || visible_len(line) <= width {
+        return vec![line.to_string()];
+    }
+
+    let indent = &line[..line.len() - line.trim_start().len()];
+    let mut lines = vec![];
+    let mut current = indent.to_string();
+    let mut current_len = indent.len();
+
+    for word in line.split_whitespace() {
+        let len = visible_len(word);
+        if current_len > indent.len() && current_len + 1 + len > width {
+            lines.push(current);
+            current = indent.to_string();
+            current_len = indent.len();
+        }
+        if current_len > indent.len() {
+            current.push(' ');
+            current_len += 1;
+        }
+        current.push_str(word);
+        current_len += len;
+    }
+
+    lines.push(current);
+    lines
+}
+
+/// Returns the number of characters in `s` excluding ANSI escape sequences.
+fn visible_len(s: &str) -> usize {
+    visible(s).count()
+}
+
+/// Removes ANSI escape sequences from `s`, e.g. before sending messages to external services.
+pub fn strip_ansi(s: &str) -> String {
+    visible(s).collect()
+}
+
+fn visible(s: &str) -> impl Iterator<Item = char> + '_ {
+    let mut chars = s.chars();
+    std::iter::from_fn(move || loop {
+        let c = chars.next()?;
+        if c != '\x1b' {
+            return Some(c);
+        }
+        // Skip until the terminating letter of the sequence
+        for c in chars.by_ref() {
+            if c.is_ascii_alphabetic() {
+                break;
+            }
+        }
+    })
+}
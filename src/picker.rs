@@ -0,0 +1,106 @@
+use anyhow::Error;
+use colored::Colorize;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+/// The number of lines of `down.sql` previewed before confirming.
+const PREVIEW_LINES: usize = 20;
+
+/// Lists the applied migrations with their releases, most recent first, and asks how far to revert.
+/// Choosing a migration of a release reverts the whole release. The `down.sql` of the last migration
+/// reverted is previewed before asking for confirmation; declining returns to the list. Returns the
+/// number of migrations to revert, counting each release as one.
+pub fn rev_count(path: &Path, applied: &[(String, Option<String>)]) -> anyhow::Result<usize> {
+    let stdin = io::stdin();
+    let mut stdin = stdin.lock();
+
+    let list = applied
+        .iter()
+        .enumerate()
+        .map(|(i, (id, release))| match release {
+            Some(release) => format!("  {:>2}) {id} (release {release})", i + 1),
+            None => format!("  {:>2}) {id}", i + 1),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    loop {
+        eprintln!("Applied migrations, most recent first:\n{list}");
+        eprint!(
+            "Revert up to and including which migration? [1-{}, q to quit] ",
+            applied.len()
+        );
+        io::stderr().flush()?;
+
+        let Some(answer) = read(&mut stdin)? else {
+            return Err(Error::msg("Aborted, no migrations were reverted"));
+        };
+        if answer == "q" {
+            return Err(Error::msg("Aborted, no migrations were reverted"));
+        }
+        let mut count = match answer.parse::<usize>() {
+            Ok(i) if (1..=applied.len()).contains(&i) => i,
+            _ => continue,
+        };
+
+        // Releases are only reverted as a whole
+        while count < applied.len()
+            && applied[count].1.is_some()
+            && applied[count].1 == applied[count - 1].1
+        {
+            count += 1;
+        }
+
+        let (id, _) = &applied[count - 1];
+        eprintln!("\n{}", format!("{id}/down.sql").blue());
+        eprintln!("{}\n", preview(&path.join(id).join("down.sql")).dimmed());
+
+        eprint!("Revert {count} migration(s)? [y/N] ");
+        io::stderr().flush()?;
+        match read(&mut stdin)?.as_deref() {
+            Some("y" | "Y" | "yes") => {
+                return Ok(applied[..count]
+                    .chunk_by(|a, b| a.1.is_some() && a.1 == b.1)
+                    .count())
+            }
+            Some(_) => eprintln!(),
+            None => return Err(Error::msg("Aborted, no migrations were reverted")),
+        }
+    }
+}
+
+/// Reads a trimmed line, or `None` once stdin is closed.
+fn read(stdin: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut answer = String::new();
+    if stdin.read_line(&mut answer)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(answer.trim().to_string()))
+}
+
+/// Returns the first lines of the file, noting how many more there are.
+fn preview(file: &Path) -> String {
+    let Ok(sql) = fs::read_to_string(file) else {
+        return String::from("(missing)");
+    };
+
+    let lines = sql.trim().lines().collect::<Vec<_>>();
+    if lines.is_empty() {
+        return String::from("(empty)");
+    }
+
+    let mut preview = lines
+        .iter()
+        .take(PREVIEW_LINES)
+        .copied()
+        .collect::<Vec<_>>()
+        .join("\n");
+    if lines.len() > PREVIEW_LINES {
+        preview.push_str(&format!(
+            "\n... {} more line(s)",
+            lines.len() - PREVIEW_LINES
+        ));
+    }
+    preview
+}
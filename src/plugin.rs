@@ -0,0 +1,120 @@
+//! Extending migr without forking it.
+//!
+//! Applications embedding migr register [Plugin]s to hook into the lifecycle of migrations. The
+//! CLI runs unknown commands as executables named `migr-<command>` on the `PATH`, see [external].
+
+use crate::summary::Report;
+use crate::{debug, Migr};
+use anyhow::{Context, Error};
+use std::ffi::OsString;
+use std::io;
+use std::process::Command;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// The prefix of executables implementing external commands.
+pub const EXECUTABLE_PREFIX: &str = "migr-";
+
+static PLUGINS: RwLock<Vec<Arc<dyn Plugin>>> = RwLock::new(Vec::new());
+
+/// Hooks into the lifecycle of `run`, `rev` and `redo`. Every hook does nothing by default.
+pub trait Plugin: Send + Sync {
+    /// Called before a migration is executed in `direction`, `up` or `down`. Returning an error
+    /// fails the migration before any of it is executed.
+    fn before_migration(&self, _id: &str, _direction: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called once a migration was executed in `direction`. Migrations of a run share a
+    /// transaction, so it is still rolled back if a later migration fails.
+    fn after_migration(&self, _id: &str, _direction: &str, _duration: Duration) {}
+
+    /// Called once a command finished, whether it succeeded or not.
+    fn after_command(&self, _report: &Report) {}
+}
+
+/// Registers a plugin for the rest of the process. Plugins are called in the order they are
+/// registered.
+pub fn register(plugin: impl Plugin + 'static) {
+    PLUGINS.write().unwrap().push(Arc::new(plugin));
+}
+
+/// Returns the registered plugins, so none of them is called while the registry is locked.
+fn plugins() -> Vec<Arc<dyn Plugin>> {
+    PLUGINS.read().unwrap().clone()
+}
+
+pub(crate) fn before_migration(id: &str, direction: &str) -> anyhow::Result<()> {
+    for plugin in plugins() {
+        plugin.before_migration(id, direction)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn after_migration(id: &str, direction: &str, duration: Duration) {
+    for plugin in plugins() {
+        plugin.after_migration(id, direction, duration);
+    }
+}
+
+pub(crate) fn after_command(report: &Report) {
+    for plugin in plugins() {
+        plugin.after_command(report);
+    }
+}
+
+/// Runs the external command `args[0]` as the executable `migr-<command>` with the remaining
+/// arguments and returns its exit code. The global options are passed to it as the `MIGR_`
+/// environment variables migr reads them from, so the command can call `migr` with the same ones.
+pub fn external(migr: &Migr, args: &[OsString]) -> anyhow::Result<i32> {
+    let Some((name, args)) = args.split_first() else {
+        return Err(Error::msg("No command given"));
+    };
+
+    let mut executable = OsString::from(EXECUTABLE_PREFIX);
+    executable.push(name);
+
+    let mut command = Command::new(&executable);
+    command.args(args).env("MIGR_CONFIG", &migr.config);
+    if let Some(ref path) = migr.path {
+        command.env("MIGR_PATH", path);
+    }
+    if let Some(ref target) = migr.target {
+        command.env("MIGR_TARGET", target);
+    }
+    if let Some(ref ssh) = migr.ssh {
+        command.env("MIGR_SSH", ssh);
+    }
+    if migr.read_only {
+        command.env("MIGR_READ_ONLY", "true");
+    }
+    if migr.quiet {
+        command.env("MIGR_QUIET", "true");
+    }
+    if migr.no_color {
+        command.env("MIGR_NO_COLOR", "true");
+    }
+    if migr.non_interactive {
+        command.env("MIGR_NON_INTERACTIVE", "true");
+    }
+
+    debug!("Running {}", executable.to_string_lossy());
+
+    let status = match command.status() {
+        Ok(status) => status,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::msg(format!(
+                "Unknown command '{}'\nHint: Install an executable named `{}` on the PATH to add it as a command",
+                name.to_string_lossy(),
+                executable.to_string_lossy()
+            )));
+        }
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Unable to run `{}`", executable.to_string_lossy()))
+        }
+    };
+
+    // Terminated by a signal
+    Ok(status.code().unwrap_or(1))
+}
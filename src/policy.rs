@@ -0,0 +1,277 @@
+use crate::config::{LintLevel, Policy};
+use crate::destructive::ident;
+use crate::lint::{self, Violation};
+use crate::meta::{MigrationMeta, ANNOTATIONS, META_FILE};
+use crate::sql;
+use anyhow::Error;
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+
+/// Keywords starting table constraints instead of column definitions.
+const CONSTRAINT_KEYWORDS: &[&str] = &[
+    "CONSTRAINT",
+    "PRIMARY",
+    "UNIQUE",
+    "CHECK",
+    "FOREIGN",
+    "EXCLUDE",
+    "LIKE",
+];
+
+/// Checks the migration files against the policies enforced for `target`. Policies restricted to
+/// targets are skipped if no target is given.
+pub fn check(
+    paths: &[PathBuf],
+    policies: &[Policy],
+    target: Option<&str>,
+) -> anyhow::Result<Vec<Violation>> {
+    let policies = policies
+        .iter()
+        .filter(|p| p.level != LintLevel::Allow)
+        .filter(|p| {
+            p.targets.is_empty() || target.is_some_and(|t| p.targets.iter().any(|pt| pt == t))
+        })
+        .collect::<Vec<_>>();
+
+    let mut violations = vec![];
+
+    if policies.is_empty() {
+        return Ok(violations);
+    }
+
+    for policy in &policies {
+        if let Some(field) = policy
+            .require_meta
+            .iter()
+            .find(|field| !ANNOTATIONS.contains(&field.as_str()))
+        {
+            return Err(Error::msg(format!(
+                "Policy '{}' requires unknown meta.toml field '{field}'\nHint: Valid fields are {}",
+                policy.name,
+                ANNOTATIONS.join(", ")
+            )));
+        }
+    }
+
+    for path in paths {
+        let migration = path
+            .parent()
+            .and_then(|p| p.file_name())
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+
+        let sql = fs::read_to_string(path)?;
+        let tags = sql::header_field(&sql, "tags")
+            .map(|tags| tags.split(',').map(str::trim).collect::<HashSet<_>>())
+            .unwrap_or_default();
+        let statements = sql::statements(&sql);
+        let meta = match policies.iter().any(|p| !p.require_meta.is_empty()) {
+            true => path.parent().map(MigrationMeta::load).transpose()?,
+            false => None,
+        }
+        .unwrap_or_default();
+
+        // Primary keys are often added after the table is created
+        let primary_keys = statements
+            .iter()
+            .filter_map(|s| added_primary_key(s))
+            .collect::<HashSet<_>>();
+
+        let version = migration.split_once('_').map_or(migration, |(v, _)| v);
+
+        for policy in &policies {
+            if policy.since.as_deref().is_some_and(|since| version < since) {
+                continue;
+            }
+            if policy
+                .exempt_tag
+                .as_deref()
+                .is_some_and(|tag| tags.contains(tag))
+            {
+                continue;
+            }
+            if !policy.schemas.is_empty() && !touches_schemas(&statements, &policy.schemas) {
+                continue;
+            }
+
+            let missing = policy
+                .require_meta
+                .iter()
+                .filter(|field| meta.annotation(field).is_none())
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            if !missing.is_empty() {
+                let message = format!("meta.toml does not set {}", missing.join(", "));
+                violations.push(Violation {
+                    migration: migration.to_string(),
+                    rule: policy.name.clone(),
+                    level: policy.level,
+                    message: match policy.description {
+                        Some(ref description) => format!("{message}; {description}"),
+                        None => message,
+                    },
+                    statement: META_FILE.to_string(),
+                });
+            }
+
+            for statement in &statements {
+                for message in violated(policy, statement, &primary_keys) {
+                    let message = match policy.description {
+                        Some(ref description) => format!("{message}; {description}"),
+                        None => message,
+                    };
+                    violations.push(Violation {
+                        migration: migration.to_string(),
+                        rule: policy.name.clone(),
+                        level: policy.level,
+                        message,
+                        statement: statement.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(violations)
+}
+
+/// Prints the violations and errors if any of them are denied.
+pub fn report(violations: &[Violation]) -> anyhow::Result<()> {
+    let denied = lint::print(violations);
+
+    if denied > 0 {
+        return Err(Error::msg(format!(
+            "{denied} denied policy violation(s) found\nHint: Migrations listing the `exempt_tag` of a policy in the `tags` field of their header are exempt from it"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Returns a message for each check of the policy the statement violates.
+fn violated(policy: &Policy, statement: &str, primary_keys: &HashSet<String>) -> Vec<String> {
+    let normalized = sql::normalize(statement);
+    let mut messages = vec![];
+
+    if lint::is_create_table(&normalized)
+        && !lint::is_ctas(&normalized)
+        && !normalized.contains(" PARTITION OF ")
+    {
+        let (table, columns) = created_table(statement);
+
+        if policy.require_primary_key
+            && !normalized.contains("PRIMARY KEY")
+            && !primary_keys.contains(&ident(&table))
+        {
+            messages.push(format!("table {table} is created without a primary key"));
+        }
+
+        let missing = policy
+            .require_columns
+            .iter()
+            .filter(|required| !columns.iter().any(|c| ident(c) == **required))
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            messages.push(format!(
+                "table {table} is created without column(s) {}",
+                missing.join(", ")
+            ));
+        }
+    }
+
+    let words = format!(" {normalized} ");
+    for phrase in &policy.forbid {
+        if words.contains(&format!(" {} ", sql::normalize(phrase))) {
+            messages.push(format!("statement contains `{phrase}`"));
+        }
+    }
+
+    messages
+}
+
+/// Whether any of the statements references an object qualified with one of the schemas, or the
+/// schema itself, e.g. in `CREATE SCHEMA` or `ALTER DEFAULT PRIVILEGES IN SCHEMA`.
+fn touches_schemas(statements: &[String], schemas: &[String]) -> bool {
+    statements.iter().any(|statement| {
+        let words = statement
+            .split(|c: char| c.is_whitespace() || matches!(c, '(' | ')' | ',' | ';'))
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<_>>();
+
+        words.iter().enumerate().any(|(i, word)| {
+            let schema = match word.split_once('.') {
+                Some((schema, _)) => schema,
+                None if i > 0 && words[i - 1].eq_ignore_ascii_case("SCHEMA") => word,
+                None => return false,
+            };
+            schemas.contains(&ident(schema))
+        })
+    })
+}
+
+/// Returns the name and the columns of the table created by a `CREATE TABLE` statement.
+fn created_table(statement: &str) -> (String, Vec<String>) {
+    let Some(open) = statement.find('(') else {
+        return (String::new(), vec![]);
+    };
+
+    let head = statement[..open].split_whitespace().collect::<Vec<_>>();
+    let table = head.last().copied().unwrap_or_default().to_string();
+
+    // Column definitions are separated by commas outside of parentheses
+    let mut elements = vec![];
+    let mut depth = 0;
+    let mut start = open + 1;
+    for (i, c) in statement.char_indices().skip_while(|(i, _)| *i <= open) {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => {
+                elements.push(&statement[start..i]);
+                break;
+            }
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                elements.push(&statement[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+
+    let columns = elements
+        .into_iter()
+        .filter_map(|e| e.split_whitespace().next())
+        .filter(|first| {
+            !CONSTRAINT_KEYWORDS
+                .iter()
+                .any(|k| first.eq_ignore_ascii_case(k))
+        })
+        .map(String::from)
+        .collect();
+
+    (table, columns)
+}
+
+/// Returns the table an `ALTER TABLE ... ADD [CONSTRAINT name] PRIMARY KEY` statement adds a primary
+/// key to.
+fn added_primary_key(statement: &str) -> Option<String> {
+    let normalized = sql::normalize(statement);
+    if !normalized.starts_with("ALTER TABLE ")
+        || !normalized.contains(" ADD ")
+        || !normalized.contains(" PRIMARY KEY")
+    {
+        return None;
+    }
+
+    statement
+        .split_whitespace()
+        .skip(2)
+        .find(|t| {
+            !["IF", "EXISTS", "ONLY"]
+                .iter()
+                .any(|k| t.eq_ignore_ascii_case(k))
+        })
+        .map(ident)
+}
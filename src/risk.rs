@@ -0,0 +1,170 @@
+use crate::config::LintConfig;
+use crate::destructive::{self, approx};
+use crate::lint;
+use crate::meta::{MigrationMeta, Risk};
+use crate::sql;
+use crate::{debug, info};
+use anyhow::Error;
+use postgres::Client;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Locking statements on tables estimated to hold at least this many rows are of medium risk.
+pub(crate) const LARGE_TABLE_ROWS: i64 = 100_000;
+
+/// Locking statements on tables estimated to hold at least this many rows are of high risk.
+const HUGE_TABLE_ROWS: i64 = 1_000_000;
+
+/// Statements holding a lock blocking writes, or reads, on the table for as long as they take.
+/// Their risk grows with the size of the table.
+const LOCKING_PREFIXES: &[&str] = &[
+    "ALTER TABLE ",
+    "CREATE INDEX ",
+    "CREATE UNIQUE INDEX ",
+    "UPDATE ",
+    "DELETE FROM ",
+];
+
+/// The risk of applying a pending migration and what it is made of.
+#[derive(Debug)]
+pub struct Assessment {
+    pub migration: String,
+    pub risk: Risk,
+    pub reasons: Vec<String>,
+}
+
+/// Scores the risk of each migration from the lint rules its `up.sql` violates, the kinds of its
+/// statements and the sizes of the tables they lock. The risk declared in `meta.toml` is the
+/// minimum.
+pub fn assess(
+    pg: &mut Client,
+    files: &[PathBuf],
+    config: &LintConfig,
+) -> anyhow::Result<Vec<Assessment>> {
+    let mut assessments = vec![];
+
+    for file in files {
+        let Some(dir) = file.parent() else {
+            continue;
+        };
+        let migration = dir
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default()
+            .to_string();
+
+        // Reasons are kept per risk so the highest ones are listed first
+        let mut reasons = BTreeMap::<Risk, Vec<String>>::new();
+
+        if let Some(risk) = MigrationMeta::load(dir)?.risk {
+            reasons
+                .entry(risk)
+                .or_default()
+                .push(format!("declared {risk}"));
+        }
+
+        for violation in lint::replication(std::slice::from_ref(file), config)? {
+            reasons
+                .entry(Risk::Medium)
+                .or_default()
+                .push(format!("violates {}", violation.rule));
+        }
+
+        for loss in destructive::scan(pg, std::slice::from_ref(file))? {
+            reasons
+                .entry(Risk::High)
+                .or_default()
+                .push(loss.to_string());
+        }
+
+        let sql = fs::read_to_string(file)?;
+        for statement in sql::statements(&sql) {
+            let normalized = sql::normalize(&statement);
+            if !LOCKING_PREFIXES.iter().any(|p| normalized.starts_with(p))
+                || normalized.contains(" CONCURRENTLY ")
+            {
+                continue;
+            }
+
+            let mut relations = sql::relations(&normalized).unwrap_or_default();
+            relations.sort();
+            relations.dedup();
+            for relation in relations {
+                let rows = table_rows(pg, &relation)?;
+                let risk = match rows {
+                    HUGE_TABLE_ROWS.. => Risk::High,
+                    LARGE_TABLE_ROWS.. => Risk::Medium,
+                    _ => continue,
+                };
+                let verb = normalized.split_whitespace().next().unwrap_or_default();
+                reasons.entry(risk).or_default().push(format!(
+                    "{} locks {} (~{} rows)",
+                    verb.to_lowercase(),
+                    relation.to_lowercase(),
+                    approx(rows)
+                ));
+            }
+        }
+
+        let risk = reasons.keys().next_back().copied().unwrap_or(Risk::Low);
+        assessments.push(Assessment {
+            migration,
+            risk,
+            reasons: reasons.into_values().rev().flatten().collect(),
+        });
+    }
+
+    Ok(assessments)
+}
+
+/// Prints the assessments and errors if any of them exceeds `max`.
+pub fn check(assessments: &[Assessment], max: Option<Risk>) -> anyhow::Result<()> {
+    for assessment in assessments {
+        let reasons = match assessment.reasons.is_empty() {
+            true => String::new(),
+            false => format!(": {}", assessment.reasons.join("; ")),
+        };
+        match assessment.risk {
+            Risk::Low => debug!("{} risk {}{reasons}", assessment.migration, assessment.risk),
+            _ => info!(
+                "{} risk {}{reasons}",
+                assessment.migration,
+                assessment.risk.colored()
+            ),
+        }
+    }
+
+    let Some(max) = max else {
+        return Ok(());
+    };
+    let exceeding = assessments
+        .iter()
+        .filter(|assessment| assessment.risk > max)
+        .collect::<Vec<_>>();
+    if exceeding.is_empty() {
+        return Ok(());
+    }
+
+    Err(Error::msg(format!(
+        "Pending migrations exceed the maximum risk '{max}':\n{}\nHint: Pass `--max-risk {}` to apply them anyway",
+        exceeding
+            .iter()
+            .map(|assessment| format!("  {}: {}", assessment.migration, assessment.risk))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        exceeding.iter().map(|a| a.risk).max().unwrap_or(Risk::High)
+    )))
+}
+
+/// Returns the planner's estimate of the rows of the largest table with the given name, or 0 if
+/// there is none, e.g. because it is created by a pending migration.
+pub(crate) fn table_rows(pg: &mut Client, name: &str) -> anyhow::Result<i64> {
+    Ok(pg
+        .query_one(
+            "SELECT COALESCE(max(GREATEST(reltuples, 0))::BIGINT, 0) FROM pg_class
+             WHERE relname = lower($1) AND relkind IN ('r', 'p')",
+            &[&name],
+        )?
+        .get(0))
+}
@@ -0,0 +1,250 @@
+use crate::idempotent::{tokenize, Token};
+use crate::risk::{table_rows, LARGE_TABLE_ROWS};
+use crate::warn;
+use crate::{destructive::approx, directive, sql};
+use postgres::Client;
+use std::fs;
+use std::path::PathBuf;
+
+/// A statement holding a lock which blocks writes, or reads, for as long as it scans the table,
+/// although Postgres offers a pattern validating it under a weaker lock.
+struct Unsafe {
+    /// The table as written in the statement.
+    table: String,
+    kind: Kind,
+    /// The offsets of the statement in the script, excluding its semicolon.
+    start: usize,
+    end: usize,
+}
+
+enum Kind {
+    /// `ADD [CONSTRAINT <name>] FOREIGN KEY` without `NOT VALID`, which scans the table while
+    /// blocking writes to it and the referenced table.
+    ForeignKey { constraint: Option<String> },
+    /// `ALTER [COLUMN] <column> SET NOT NULL`, which scans the table while blocking reads and
+    /// writes.
+    SetNotNull { column: String },
+}
+
+/// Warns about statements of the pending migrations which scan tables with
+/// [LARGE_TABLE_ROWS] or more while blocking writes, suggesting `migr fix --rewrite-safe`.
+pub fn advise(pg: &mut Client, files: &[PathBuf]) -> anyhow::Result<()> {
+    for file in files {
+        let migration = file
+            .parent()
+            .and_then(|dir| dir.file_name())
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+        let sql = fs::read_to_string(file)?;
+
+        for found in find(&sql) {
+            let rows = table_rows(pg, &sql::relation_name(&found.table))?;
+            if rows < LARGE_TABLE_ROWS {
+                continue;
+            }
+            let action = match found.kind {
+                Kind::ForeignKey { constraint: None } => {
+                    warn!(
+                        "{} adds an unnamed foreign key to {} (~{} rows), which scans it while blocking writes\nHint: Name the constraint and run `migr fix --rewrite-safe {migration}` to validate it once the migration is committed",
+                        migration.blue(),
+                        found.table,
+                        approx(rows)
+                    );
+                    continue;
+                }
+                Kind::ForeignKey { .. } => "adds a foreign key to",
+                Kind::SetNotNull { .. } => "sets a column NOT NULL on",
+            };
+            warn!(
+                "{} {action} {} (~{} rows), which scans it while blocking writes\nHint: Run `migr fix --rewrite-safe {migration}` to validate it once the migration is committed",
+                migration.blue(),
+                found.table,
+                approx(rows)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Rewrites foreign keys and `SET NOT NULL` to constraints added as `NOT VALID` and validated by
+/// `-- migr:after` steps, which run once the migration is committed and only block writes while
+/// adding the constraint. Unnamed foreign keys are kept, since they can't be validated by name.
+/// Returns the script and the number of rewritten statements.
+pub fn rewrite(script: &str) -> (String, usize) {
+    let mut edits = vec![];
+
+    for found in find(script) {
+        // Match the case of the statement
+        let upper = script[found.start..found.end]
+            .chars()
+            .find(|c| c.is_alphabetic())
+            .is_some_and(char::is_uppercase);
+        let case = |s: &str| match upper {
+            true => s.to_string(),
+            false => s.to_lowercase(),
+        };
+        let table = &found.table;
+
+        let (replacement, after) = match found.kind {
+            Kind::ForeignKey {
+                constraint: Some(ref constraint),
+            } => (
+                format!("{}{}", &script[found.start..found.end], case(" NOT VALID")),
+                vec![format!(
+                    "{} {table} {} {constraint}",
+                    case("ALTER TABLE"),
+                    case("VALIDATE CONSTRAINT")
+                )],
+            ),
+            Kind::ForeignKey { constraint: None } => continue,
+            Kind::SetNotNull { ref column } => {
+                let constraint = constraint_name(table, column);
+                (
+                    format!(
+                        "{} {table} {} {constraint} {} ({column} {}) {}",
+                        case("ALTER TABLE"),
+                        case("ADD CONSTRAINT"),
+                        case("CHECK"),
+                        case("IS NOT NULL"),
+                        case("NOT VALID")
+                    ),
+                    vec![
+                        format!(
+                            "{} {table} {} {constraint}",
+                            case("ALTER TABLE"),
+                            case("VALIDATE CONSTRAINT")
+                        ),
+                        // Validated check constraints spare `SET NOT NULL` the scan
+                        format!(
+                            "{} {table} {} {column} {}",
+                            case("ALTER TABLE"),
+                            case("ALTER COLUMN"),
+                            case("SET NOT NULL")
+                        ),
+                        format!(
+                            "{} {table} {} {constraint}",
+                            case("ALTER TABLE"),
+                            case("DROP CONSTRAINT")
+                        ),
+                    ],
+                )
+            }
+        };
+
+        // The steps go after the semicolon ending the statement, which may be missing
+        let (semicolon, end) = match script[found.end..].trim_start().starts_with(';') {
+            true => (
+                "",
+                found.end + script[found.end..].find(';').unwrap_or(0) + 1,
+            ),
+            false => (";", found.end),
+        };
+        let after = after
+            .iter()
+            .map(|statement| format!("\n{}after {statement}", directive::PREFIX))
+            .collect::<String>();
+        edits.push((found.start, found.end, replacement));
+        edits.push((end, end, format!("{semicolon}{after}")));
+    }
+
+    let count = edits.len() / 2;
+    let mut rewritten = script.to_string();
+    for (start, end, replacement) in edits.iter().rev() {
+        rewritten.replace_range(*start..*end, replacement);
+    }
+
+    (rewritten, count)
+}
+
+/// Returns the statements of the script which could use a safer pattern. Only `ALTER TABLE`
+/// statements with a single action are considered.
+fn find(script: &str) -> Vec<Unsafe> {
+    let tokens = tokenize(script);
+    tokens
+        .split(|t| t.text == ";")
+        .filter_map(|statement| unsafe_ddl(script, statement))
+        .collect()
+}
+
+fn unsafe_ddl(script: &str, t: &[Token]) -> Option<Unsafe> {
+    let kw = |i: usize, keyword: &str| {
+        t.get(i)
+            .is_some_and(|t| t.text.eq_ignore_ascii_case(keyword))
+    };
+    let start = |i: usize| t[i].end - t[i].text.len();
+
+    if !(kw(0, "ALTER") && kw(1, "TABLE")) {
+        return None;
+    }
+    let mut i = 2;
+    if kw(i, "IF") {
+        i += 2;
+    }
+    if kw(i, "ONLY") {
+        i += 1;
+    }
+
+    // The table name, possibly schema qualified
+    let table_start = i;
+    i += 1;
+    while t.get(i).is_some_and(|t| t.text == ".") {
+        i += 2;
+    }
+    let table = script[start(table_start)..t.get(i - 1)?.end].to_string();
+
+    let mut depth = 0;
+    for token in &t[i..] {
+        match token.text {
+            "(" => depth += 1,
+            ")" => depth -= 1,
+            "," if depth == 0 => return None,
+            _ => {}
+        }
+    }
+    let not_valid = (i..t.len()).any(|j| kw(j, "NOT") && kw(j + 1, "VALID"));
+
+    let kind = if kw(i, "ADD") {
+        let (constraint, j) = match kw(i + 1, "CONSTRAINT") {
+            true => (Some(t.get(i + 2)?.text.to_string()), i + 3),
+            false => (None, i + 1),
+        };
+        if !(kw(j, "FOREIGN") && kw(j + 1, "KEY")) || not_valid {
+            return None;
+        }
+        Kind::ForeignKey { constraint }
+    } else if kw(i, "ALTER") {
+        let j = if kw(i + 1, "COLUMN") { i + 2 } else { i + 1 };
+        if !(kw(j + 1, "SET") && kw(j + 2, "NOT") && kw(j + 3, "NULL") && t.len() == j + 4) {
+            return None;
+        }
+        Kind::SetNotNull {
+            column: t[j].text.to_string(),
+        }
+    } else {
+        return None;
+    };
+
+    Some(Unsafe {
+        table,
+        kind,
+        start: start(0),
+        end: t.last()?.end,
+    })
+}
+
+/// Names the check constraint standing in for `NOT NULL`, e.g. `orders_customer_id_not_null`.
+fn constraint_name(table: &str, column: &str) -> String {
+    let name = format!(
+        "{}_{}_not_null",
+        sql::relation_name(table),
+        column.trim_matches('"')
+    );
+    match name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        true => name,
+        false => sql::quote_ident(&name),
+    }
+}
@@ -0,0 +1,132 @@
+use crate::config::GenConfig;
+use crate::GenTableArgs;
+use anyhow::Error;
+
+/// Returns the name, up and down migration creating the table from the templates in the config.
+pub fn table(args: &GenTableArgs, config: &GenConfig) -> anyhow::Result<(String, String, String)> {
+    let table = args.table.as_str();
+    let valid =
+        !table.is_empty() && table.split('.').count() <= 2 && table.split('.').all(identifier);
+    if !valid {
+        return Err(Error::msg(format!(
+            "Invalid table name '{table}'\nHint: Table names may only contain letters, digits and `_`, optionally qualified with a schema"
+        )));
+    }
+    let table_name = table.rsplit('.').next().unwrap_or(table);
+
+    let columns = split_columns(&args.columns)
+        .into_iter()
+        .map(str::trim)
+        .filter(|column| !column.is_empty())
+        .map(|column| Ok(format!("    {},\n", column_sql(column)?)))
+        .collect::<anyhow::Result<String>>()?;
+
+    let fill = |template: &str| {
+        template
+            .replace("{table}", table)
+            .replace("{table_name}", table_name)
+            .replace("{columns}", &columns)
+    };
+
+    let name = match args.name {
+        Some(ref name) => name.clone(),
+        None => format!("create_{}", table.replace('.', "_")),
+    };
+
+    Ok((name, fill(&config.table_up), fill(&config.table_down)))
+}
+
+/// Returns the definition of a column given as `name:type[:modifier]...`.
+fn column_sql(column: &str) -> anyhow::Result<String> {
+    let invalid = || {
+        Error::msg(format!(
+            "Invalid column '{column}', expected `name:type` followed by any of the `unique`, `not-null`, `default=<expr>` and `references=<table>` modifiers"
+        ))
+    };
+
+    let mut parts = split_parts(column).into_iter().map(str::trim);
+    let (Some(name), Some(ty)) = (parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+    if !identifier(name) || ty.is_empty() {
+        return Err(invalid());
+    }
+
+    let mut sql = format!("{name} {ty}");
+    for modifier in parts {
+        match modifier.split_once('=') {
+            None if modifier.eq_ignore_ascii_case("unique") => sql.push_str(" UNIQUE"),
+            None if modifier.eq_ignore_ascii_case("not-null") => sql.push_str(" NOT NULL"),
+            Some((key, value)) if key.eq_ignore_ascii_case("default") && !value.is_empty() => {
+                sql.push_str(&format!(" DEFAULT {value}"))
+            }
+            Some((key, value)) if key.eq_ignore_ascii_case("references") && !value.is_empty() => {
+                sql.push_str(&format!(" REFERENCES {value}"))
+            }
+            _ => return Err(invalid()),
+        }
+    }
+
+    Ok(sql)
+}
+
+/// Splits the columns at commas outside of parentheses, which separate e.g. the precision and scale
+/// of `numeric(10, 2)`.
+fn split_columns(columns: &str) -> Vec<&str> {
+    let mut split = vec![];
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in columns.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                split.push(&columns[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    split.push(&columns[start..]);
+    split
+}
+
+/// Splits a column at single colons, leaving the `::` casts of e.g. `default='{}'::jsonb` intact.
+fn split_parts(column: &str) -> Vec<&str> {
+    let mut split = vec![];
+    let (mut start, mut chars) = (0, column.char_indices().peekable());
+    while let Some((i, c)) = chars.next() {
+        if c != ':' {
+            continue;
+        }
+        if chars.next_if(|(_, c)| *c == ':').is_some() {
+            continue;
+        }
+        split.push(&column[start..i]);
+        start = i + 1;
+    }
+    split.push(&column[start..]);
+    split
+}
+
+fn identifier(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn casts_in_defaults_are_kept() {
+        assert_eq!(
+            column_sql("tags:jsonb:not-null:default='[]'::jsonb").unwrap(),
+            "tags jsonb NOT NULL DEFAULT '[]'::jsonb"
+        );
+        assert_eq!(
+            column_sql("at:timestamptz:default=now()::timestamptz:unique").unwrap(),
+            "at timestamptz DEFAULT now()::timestamptz UNIQUE"
+        );
+        assert!(column_sql("id::int").is_err());
+    }
+}
@@ -0,0 +1,297 @@
+use crate::{debug, info};
+use anyhow::{Context, Error};
+use postgres::GenericClient;
+use std::cmp::Ordering;
+
+/// Upgrade steps of the metadata table. The step at index `i` upgrades the table from version `i` to
+/// `i + 1`, where version `0` is the original `(id, pending)` layout. Steps must never be edited
+/// once released, only appended.
+const UPGRADES: &[&str] = &[
+    // 1: Constraints, checksums and application times
+    "
+    UPDATE __migr_meta__ SET pending = TRUE WHERE pending IS NULL;
+    ALTER TABLE __migr_meta__ ALTER COLUMN pending SET NOT NULL;
+    ALTER TABLE __migr_meta__ ADD COLUMN checksum VARCHAR(64);
+    ALTER TABLE __migr_meta__ ADD COLUMN applied_at TIMESTAMPTZ;
+    CREATE INDEX __migr_meta_pending_idx__ ON __migr_meta__(pending);
+    ",
+    // 2: Record the release of migr which last upgraded the table
+    "ALTER TABLE __migr_schema__ ADD COLUMN IF NOT EXISTS migr_version VARCHAR(32)",
+    // 3: Separate timestamp and name of each migration
+    "
+    ALTER TABLE __migr_meta__ ADD COLUMN version VARCHAR(255);
+    ALTER TABLE __migr_meta__ ADD COLUMN name VARCHAR(255);
+    UPDATE __migr_meta__ SET version = split_part(id, '_', 1), name = substr(id, strpos(id, '_') + 1);
+    CREATE INDEX __migr_meta_version_idx__ ON __migr_meta__(version);
+    ",
+    // 4: Fingerprint of the migrations after the last complete run, cleared whenever the metadata
+    // changes
+    "
+    ALTER TABLE __migr_schema__ ADD COLUMN fingerprint VARCHAR(64);
+    CREATE FUNCTION __migr_clear_fingerprint__() RETURNS TRIGGER AS $$
+    BEGIN
+        UPDATE __migr_schema__ SET fingerprint = NULL WHERE fingerprint IS NOT NULL;
+        RETURN NULL;
+    END
+    $$ LANGUAGE plpgsql;
+    CREATE TRIGGER __migr_meta_fingerprint__ AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON __migr_meta__
+    FOR EACH STATEMENT EXECUTE PROCEDURE __migr_clear_fingerprint__();
+    ",
+    // 5: Checksums of the applied function definitions
+    "
+    CREATE TABLE __migr_functions__(
+        name VARCHAR(255) PRIMARY KEY,
+        checksum VARCHAR(64) NOT NULL,
+        applied_at TIMESTAMPTZ NOT NULL
+    );
+    ",
+    // 6: Location of the backup taken before a migration deleting data
+    "ALTER TABLE __migr_meta__ ADD COLUMN backup TEXT",
+    // 7: Every execution of a migration in either direction
+    "
+    CREATE TABLE __migr_history__(
+        id BIGSERIAL PRIMARY KEY,
+        migration VARCHAR(255) NOT NULL,
+        direction VARCHAR(4) NOT NULL,
+        checksum VARCHAR(64),
+        duration_ms DOUBLE PRECISION NOT NULL,
+        executed_by TEXT NOT NULL DEFAULT session_user,
+        executed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    );
+    CREATE INDEX __migr_history_migration_idx__ ON __migr_history__(migration);
+    ",
+    // 8: The run which applied each migration, where batch 0 holds the migrations applied before
+    // batches were recorded
+    "
+    ALTER TABLE __migr_meta__ ADD COLUMN batch INTEGER;
+    UPDATE __migr_meta__ SET batch = 0 WHERE NOT pending;
+    CREATE INDEX __migr_meta_batch_idx__ ON __migr_meta__(batch);
+    ",
+    // 9: The error of a pending migration which failed to apply, which holds back runs until it is
+    // resumed or skipped
+    "
+    ALTER TABLE __migr_meta__ ADD COLUMN error TEXT;
+    ALTER TABLE __migr_meta__ ADD COLUMN failed_at TIMESTAMPTZ;
+    ",
+    // 10: The SQL executed by each execution, if configured to be recorded
+    "ALTER TABLE __migr_history__ ADD COLUMN sql TEXT",
+    // 11: Leases of the running instances of the application on the schema versions they support
+    "
+    CREATE TABLE __migr_compat__(
+        instance TEXT PRIMARY KEY,
+        version VARCHAR(255) NOT NULL,
+        expires_at TIMESTAMPTZ NOT NULL,
+        renewed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    );
+    ",
+    // 12: Checksums of the executed scrub scripts
+    "
+    CREATE TABLE __migr_scrub__(
+        name VARCHAR(255) PRIMARY KEY,
+        checksum VARCHAR(64) NOT NULL,
+        applied_at TIMESTAMPTZ NOT NULL
+    );
+    ",
+    // 13: Checksums of the down migrations at the time the up migrations were applied
+    "
+    ALTER TABLE __migr_meta__ ADD COLUMN down_checksum VARCHAR(64);
+    ",
+    // 14: Clear the fingerprint only when rows change, since statement triggers also fire for
+    // syncs inserting nothing
+    "
+    DROP TRIGGER IF EXISTS __migr_meta_fingerprint__ ON __migr_meta__;
+    DROP TRIGGER IF EXISTS __migr_meta_truncate_fingerprint__ ON __migr_meta__;
+    CREATE TRIGGER __migr_meta_fingerprint__ AFTER INSERT OR UPDATE OR DELETE ON __migr_meta__
+    FOR EACH ROW EXECUTE PROCEDURE __migr_clear_fingerprint__();
+    CREATE TRIGGER __migr_meta_truncate_fingerprint__ AFTER TRUNCATE ON __migr_meta__
+    FOR EACH STATEMENT EXECUTE PROCEDURE __migr_clear_fingerprint__();
+    ",
+];
+
+/// Objects created by upgrades which outlive the metadata tables, e.g. when they are dropped to set
+/// migr up again, paired with the upgrade creating them. They are dropped before that upgrade runs
+/// on a table whose version was detected rather than recorded.
+const LEFTOVERS: &[(i32, &str)] = &[
+    (
+        4,
+        "DROP FUNCTION IF EXISTS __migr_clear_fingerprint__() CASCADE",
+    ),
+    (5, "DROP TABLE IF EXISTS __migr_functions__"),
+    (7, "DROP TABLE IF EXISTS __migr_history__"),
+    (11, "DROP TABLE IF EXISTS __migr_compat__"),
+    (12, "DROP TABLE IF EXISTS __migr_scrub__"),
+];
+
+/// Reads the version of a row. Rows written by releases which only stored the ID have no version.
+pub const VERSION_COLUMN: &str = "COALESCE(version, split_part(id, '_', 1))";
+
+/// Reads the name of a row. Rows written by releases which only stored the ID have no name.
+pub const NAME_COLUMN: &str = "COALESCE(name, substr(id, strpos(id, '_') + 1))";
+
+/// Columns of the metadata table introduced by each version, used to detect the version of tables
+/// created before the version was recorded.
+const LAYOUTS: &[(i32, &[&str])] = &[(1, &["checksum", "applied_at"]), (3, &["version", "name"])];
+
+/// The version of the metadata table this release of migr works with.
+pub const VERSION: i32 = UPGRADES.len() as i32;
+
+const VERSION_TABLE_QUERY: &str = "
+CREATE TABLE IF NOT EXISTS __migr_schema__(
+    version INTEGER NOT NULL,
+    migr_version VARCHAR(32)
+)";
+
+/// Brings the metadata table up to [VERSION] by running the upgrade steps it is missing.
+/// The current version is kept in the single row of `__migr_schema__`. Refuses to work with tables
+/// upgraded by a newer release of migr. Read-only sessions only check the version.
+pub fn upgrade(pg: &mut impl GenericClient) -> anyhow::Result<()> {
+    let read_only = pg
+        .query_one(
+            "SELECT current_setting('transaction_read_only') = 'on'",
+            &[],
+        )?
+        .get::<_, bool>(0);
+    if read_only {
+        return check(pg);
+    }
+
+    let mut tx = pg.transaction()?;
+
+    tx.batch_execute(VERSION_TABLE_QUERY)?;
+
+    // Serialize concurrent upgrades
+    tx.batch_execute("LOCK TABLE __migr_schema__ IN EXCLUSIVE MODE")?;
+
+    let version = match tx.query_opt("SELECT version FROM __migr_schema__", &[])? {
+        Some(row) => row.get::<_, i32>(0),
+        None => {
+            let version = detect(&mut tx)?;
+            debug!("Detected metadata table layout version {version}");
+            for (_, leftover) in LEFTOVERS.iter().filter(|(v, _)| version < *v) {
+                tx.batch_execute(leftover)?;
+            }
+            tx.execute(
+                "INSERT INTO __migr_schema__(version) VALUES ($1)",
+                &[&version],
+            )?;
+            version
+        }
+    };
+
+    debug!("Metadata table at version {version}");
+
+    if version > VERSION {
+        let migr_version = tx
+            .query_one("SELECT migr_version FROM __migr_schema__", &[])?
+            .get::<_, Option<String>>(0)
+            .unwrap_or_else(|| String::from("a newer release"));
+        return Err(Error::msg(format!(
+            "The metadata table was upgraded to version {version} by migr {migr_version}, but this release of migr ({}) only supports up to version {VERSION}\nHint: Upgrade migr to work with this database",
+            env!("CARGO_PKG_VERSION"),
+        )));
+    }
+
+    if version == VERSION {
+        return Ok(tx.commit()?);
+    }
+
+    for (i, upgrade) in UPGRADES.iter().enumerate().skip(version as usize) {
+        info!("Upgrading metadata table to version {}", i + 1);
+        tx.batch_execute(upgrade)
+            .with_context(|| format!("Could not upgrade metadata table to version {}", i + 1))?;
+    }
+
+    tx.execute(
+        "UPDATE __migr_schema__ SET version = $1, migr_version = $2",
+        &[&VERSION, &env!("CARGO_PKG_VERSION")],
+    )?;
+
+    Ok(tx.commit()?)
+}
+
+/// Returns the version of the metadata table and the release of migr which last upgraded it, if
+/// it was recorded, without writing to the database.
+pub fn version(pg: &mut impl GenericClient) -> anyhow::Result<(i32, Option<String>)> {
+    let recorded = pg
+        .query_one("SELECT to_regclass('__migr_schema__') IS NOT NULL", &[])?
+        .get::<_, bool>(0);
+
+    let row = match recorded {
+        true => pg.query_opt("SELECT version, migr_version FROM __migr_schema__", &[])?,
+        false => None,
+    };
+
+    match row {
+        Some(row) => Ok((row.get(0), row.get(1))),
+        None => Ok((detect(pg)?, None)),
+    }
+}
+
+/// Checks the metadata table is at [VERSION] without writing to the database.
+pub fn check(pg: &mut impl GenericClient) -> anyhow::Result<()> {
+    let (version, _) = version(pg)?;
+
+    debug!("Metadata table at version {version}");
+
+    match version.cmp(&VERSION) {
+        Ordering::Equal => Ok(()),
+        Ordering::Less => Err(Error::msg(format!(
+            "The metadata table at version {version} must be upgraded to version {VERSION}, which is not possible in a read-only session\nHint: Run migr without `--read-only` once to upgrade it",
+        ))),
+        Ordering::Greater => Err(Error::msg(format!(
+            "The metadata table was upgraded to version {version} by a newer release of migr, but this release of migr ({}) only supports up to version {VERSION}\nHint: Upgrade migr to work with this database",
+            env!("CARGO_PKG_VERSION"),
+        ))),
+    }
+}
+
+/// Returns the fingerprint recorded by the last complete run, or `None` if the metadata changed
+/// since then or the metadata table is missing or at another version.
+pub fn fingerprint(pg: &mut impl GenericClient) -> anyhow::Result<Option<String>> {
+    // Querying in a savepoint keeps a missing table or column from aborting a caller's transaction
+    let mut tx = pg.transaction()?;
+    match tx.query_opt(
+        "SELECT fingerprint FROM __migr_schema__ WHERE version = $1",
+        &[&VERSION],
+    ) {
+        Ok(row) => {
+            tx.commit()?;
+            Ok(row.and_then(|row| row.get::<_, Option<String>>(0)))
+        }
+        Err(e) => {
+            debug!("No fingerprint recorded: {e}");
+            tx.rollback()?;
+            Ok(None)
+        }
+    }
+}
+
+/// Records the fingerprint of the migrations once all of them are applied. It is cleared by a
+/// trigger as soon as the metadata table is written to.
+pub fn record_fingerprint(pg: &mut impl GenericClient, fingerprint: &str) -> anyhow::Result<()> {
+    pg.execute(
+        "UPDATE __migr_schema__ SET fingerprint = $1",
+        &[&fingerprint],
+    )?;
+    Ok(())
+}
+
+/// Detects the version of a metadata table created before its version was recorded by the columns it has.
+fn detect(tx: &mut impl GenericClient) -> anyhow::Result<i32> {
+    let columns = tx
+        .query(
+            "SELECT column_name::TEXT FROM information_schema.columns
+             WHERE table_name = '__migr_meta__' AND table_schema = current_schema()",
+            &[],
+        )?
+        .into_iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<Vec<_>>();
+
+    Ok(LAYOUTS
+        .iter()
+        .filter(|(_, layout)| layout.iter().all(|c| columns.iter().any(|col| col == c)))
+        .map(|(version, _)| *version)
+        .max()
+        .unwrap_or(0))
+}
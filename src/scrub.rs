@@ -0,0 +1,162 @@
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::migration::check_table;
+use crate::sql::quote_ident;
+use crate::{debug, info, output, summary, trace, ScrubArgs};
+use anyhow::{Context, Error};
+use colored::Colorize;
+use postgres::Client;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::time::Instant;
+
+pub use migr_common::SCRUB_DIR;
+
+/// The schema holding the helper functions scrub scripts can call. It only exists while they are
+/// executed.
+const HELPERS_SCHEMA: &str = "migr_scrub";
+
+/// Helper functions for common scrubbing patterns. `{salt}` is replaced with a secret generated for
+/// each scrub, so hashed values can't be looked up but stay consistent across tables.
+const HELPERS: &str = "
+CREATE SCHEMA migr_scrub;
+CREATE FUNCTION migr_scrub.hash(value TEXT) RETURNS TEXT
+    LANGUAGE SQL IMMUTABLE STRICT AS $$ SELECT md5('{salt}' || value) $$;
+CREATE FUNCTION migr_scrub.email(email TEXT) RETURNS TEXT
+    LANGUAGE SQL IMMUTABLE STRICT AS $$ SELECT 'user-' || left(migr_scrub.hash(email), 16) || '@example.invalid' $$;
+CREATE FUNCTION migr_scrub.text(value TEXT) RETURNS TEXT
+    LANGUAGE SQL IMMUTABLE STRICT AS $$ SELECT repeat('x', length(value)) $$;
+";
+
+/// Executes the scrub scripts in [SCRUB_DIR] which were not executed on the database yet, or
+/// changed since, in the order of their names and in a single transaction. Their executions are
+/// tracked in `__migr_scrub__`, separately from the migrations.
+pub fn scrub(
+    args: &ScrubArgs,
+    path: &Path,
+    pg: &mut Client,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let cache = Cache::load(path)?;
+    let mut files = cache
+        .files(SCRUB_DIR)
+        .filter(|name| name.ends_with(".sql"))
+        .filter_map(|name| {
+            Some((
+                name.to_string(),
+                cache.checksum(SCRUB_DIR, name)?.to_string(),
+            ))
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+
+    if files.is_empty() {
+        info!("No scrub scripts in {}", path.join(SCRUB_DIR).display());
+        return Ok(());
+    }
+
+    check_table(pg)?;
+
+    let applied = pg
+        .query("SELECT name, checksum FROM __migr_scrub__", &[])?
+        .into_iter()
+        .map(|row| (row.get::<_, String>(0), row.get::<_, String>(1)))
+        .collect::<HashMap<_, _>>();
+    let scripts = files
+        .into_iter()
+        .filter(|(name, checksum)| args.all || applied.get(name) != Some(checksum))
+        .collect::<Vec<_>>();
+
+    if scripts.is_empty() {
+        info!("Every scrub script was executed already\nHint: Pass `--all` to execute them again");
+        return Ok(());
+    }
+
+    let database = pg
+        .query_one("SELECT current_database()", &[])?
+        .get::<_, String>(0);
+    confirm(&database, scripts.len(), args.yes)?;
+
+    let started = Instant::now();
+    let mut tx = pg.transaction()?;
+
+    let salt = tx
+        .query_one("SELECT md5(random()::TEXT || clock_timestamp()::TEXT)", &[])?
+        .get::<_, String>(0);
+    tx.batch_execute(&HELPERS.replace("{salt}", &salt))
+        .context("Unable to create the scrub helpers")?;
+    if let Some(ref role) = config.run_as {
+        tx.batch_execute(&format!(
+            "GRANT USAGE ON SCHEMA {HELPERS_SCHEMA} TO {}",
+            quote_ident(role)
+        ))?;
+    }
+
+    for (name, checksum) in &scripts {
+        let started = Instant::now();
+        let file = path.join(SCRUB_DIR).join(name);
+        let sql = fs::read_to_string(&file)
+            .with_context(|| format!("Unable to read '{}'", file.display()))?;
+
+        if let Some(ref role) = config.run_as {
+            tx.batch_execute(&format!("SET LOCAL ROLE {}", quote_ident(role)))?;
+        }
+        trace!("{}", sql.trim());
+        tx.batch_execute(&sql).with_context(|| {
+            format!("while scrubbing with {}", file.display().to_string().red())
+        })?;
+        if config.run_as.is_some() {
+            tx.batch_execute("RESET ROLE")?;
+        }
+
+        tx.execute(
+            "INSERT INTO __migr_scrub__(name, checksum, applied_at) VALUES ($1, $2, now())
+             ON CONFLICT (name) DO UPDATE SET checksum = EXCLUDED.checksum, applied_at = EXCLUDED.applied_at",
+            &[name, checksum],
+        )?;
+
+        info!("Scrubbed with {} in {:.2?}", name.blue(), started.elapsed());
+    }
+
+    tx.batch_execute(&format!("DROP SCHEMA {HELPERS_SCHEMA} CASCADE"))?;
+    tx.commit()?;
+    debug!("Dropped the scrub helpers");
+
+    summary!(
+        "Executed {} scrub scripts on {} in {:.2?}",
+        scripts.len(),
+        database.green(),
+        started.elapsed()
+    );
+
+    Ok(())
+}
+
+/// Asks for confirmation before scrubbing, since scrubbing the wrong database, e.g. production,
+/// destroys its data. Refuses without `--yes` when there is no terminal to ask at.
+fn confirm(database: &str, scripts: usize, yes: bool) -> anyhow::Result<()> {
+    if yes {
+        return Ok(());
+    }
+
+    let stdin = io::stdin();
+    if !output::interactive() {
+        return Err(Error::msg(format!(
+            "Scrubbing overwrites the data of the database '{database}'\nHint: Pass `--yes` to scrub it without confirmation"
+        )));
+    }
+
+    eprint!(
+        "Execute {scripts} scrub scripts, overwriting the data of the database '{}'? [y/N] ",
+        database.yellow()
+    );
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    stdin.lock().read_line(&mut answer)?;
+    if matches!(answer.trim(), "y" | "Y" | "yes") {
+        return Ok(());
+    }
+    Err(Error::msg("Aborted, nothing was scrubbed"))
+}
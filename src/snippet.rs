@@ -0,0 +1,291 @@
+use crate::cache;
+use crate::config::Config;
+use crate::migration::migration_dirs;
+use crate::{info, summary, SnippetAddArgs, SnippetArgs, SnippetCommand};
+use anyhow::{Context, Error};
+use colored::Colorize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+pub use migr_common::SNIPPETS_DIR;
+
+/// Starts the expansion of a snippet in a migration, followed by `<name>@<version>`.
+const START_MARKER: &str = "-- snippet: ";
+
+/// Ends the expansion of a snippet in a migration, followed by its name.
+const END_MARKER: &str = "-- end snippet: ";
+
+/// A fragment of SQL shared by migrations, stored in `snippets/<name>.sql` and optionally reverted
+/// by `snippets/<name>.down.sql`. Leading comments of the files describe the snippet and are not
+/// expanded.
+struct Snippet {
+    name: String,
+    description: String,
+    up: String,
+    down: Option<String>,
+}
+
+impl Snippet {
+    fn load(path: &Path, name: &str) -> anyhow::Result<Self> {
+        let dir = path.join(SNIPPETS_DIR);
+        let sql = fs::read_to_string(dir.join(format!("{name}.sql"))).map_err(|_| {
+            Error::msg(format!(
+                "Unknown snippet '{name}'\nHint: Add it with `migr snippet add {name}`, or list the existing ones with `migr snippet list`"
+            ))
+        })?;
+        let (description, up) = split_description(&sql);
+        let down = fs::read_to_string(dir.join(format!("{name}.down.sql")))
+            .ok()
+            .map(|sql| split_description(&sql).1);
+
+        Ok(Self {
+            name: name.to_string(),
+            description,
+            up,
+            down,
+        })
+    }
+
+    /// The first 8 characters of the checksum of the expanded SQL. Changing the description keeps
+    /// the version.
+    fn version(&self) -> String {
+        let down = self.down.as_deref().unwrap_or_default();
+        cache::checksum(format!("{}\0{down}", self.up).as_bytes())[..8].to_string()
+    }
+}
+
+pub fn snippet(args: &SnippetArgs, path: &Path, config: &Config) -> anyhow::Result<()> {
+    match args.command {
+        SnippetCommand::Add(ref add) => snippet_add(add, path),
+        SnippetCommand::List => snippet_list(path, config),
+    }
+}
+
+/// Creates the files of a new snippet, copying the SQL from `--from` if given.
+fn snippet_add(args: &SnippetAddArgs, path: &Path) -> anyhow::Result<()> {
+    let name = &args.name;
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(Error::msg(format!(
+            "Invalid snippet name '{name}'\nHint: Snippet names may only contain letters, digits, `_` and `-`"
+        )));
+    }
+
+    let dir = path.join(SNIPPETS_DIR);
+    let up = dir.join(format!("{name}.sql"));
+    if up.exists() {
+        return Err(Error::msg(format!(
+            "Snippet {name} already exists at {}",
+            up.display()
+        )));
+    }
+
+    let sql = match args.from {
+        Some(ref from) => fs::read_to_string(from)
+            .with_context(|| format!("Unable to read '{}'", from.display()))?,
+        None => String::from(
+            "-- Describe the snippet here, these leading comments are not expanded\n-- Placeholders like {table} are replaced with `--var table=<value>`, {name} with the name of the migration\n",
+        ),
+    };
+
+    fs::create_dir_all(&dir)?;
+
+    info!(
+        "Creating snippet at {}",
+        up.display().to_string().as_str().green()
+    );
+    fs::write(&up, sql)?;
+
+    if args.down {
+        let down = dir.join(format!("{name}.down.sql"));
+        info!(
+            "Creating down snippet at {}",
+            down.display().to_string().as_str().green()
+        );
+        fs::write(&down, "-- Revert everything from the snippet\n")?;
+    }
+
+    summary!("Successfully added snippet {}", name.green());
+
+    Ok(())
+}
+
+/// Lists the snippets with their current version and the migrations which expanded them, noting the
+/// ones which expanded an older version.
+fn snippet_list(path: &Path, config: &Config) -> anyhow::Result<()> {
+    let mut names = match fs::read_dir(path.join(SNIPPETS_DIR)) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter_map(|file| {
+                let name = file.strip_suffix(".sql")?;
+                (!name.ends_with(".down")).then(|| name.to_string())
+            })
+            .collect::<Vec<_>>(),
+        Err(_) => vec![],
+    };
+    names.sort();
+
+    if names.is_empty() {
+        summary!("No snippets, add one with `migr snippet add <name>`");
+        return Ok(());
+    }
+
+    // Versions expanded into migrations by snippet
+    let mut pinned = HashMap::<String, Vec<String>>::new();
+    for dir in migration_dirs(path, config)? {
+        let Ok(sql) = fs::read_to_string(dir.join("up.sql")) else {
+            continue;
+        };
+        for (name, version) in pins(&sql) {
+            pinned
+                .entry(name.to_string())
+                .or_default()
+                .push(version.to_string());
+        }
+    }
+
+    let snippets = names
+        .iter()
+        .map(|name| Snippet::load(path, name))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let width = names.iter().map(String::len).max().unwrap_or(0) + 3;
+
+    summary!("Snippets:");
+    for snippet in snippets {
+        let version = snippet.version();
+        let pins = pinned.remove(&snippet.name).unwrap_or_default();
+        let stale = pins.iter().filter(|v| **v != version).count();
+
+        let mut usage = format!("used by {} migration(s)", pins.len());
+        if stale > 0 {
+            usage.push_str(&format!(
+                ", {}",
+                format!("{stale} at an older version").yellow()
+            ));
+        }
+        let down = if snippet.down.is_some() {
+            "up/down"
+        } else {
+            "up"
+        };
+        summary!(
+            "{:.<width$} {} {down:<7} {usage}",
+            snippet.name,
+            version.green()
+        );
+        if let Some(line) = snippet.description.lines().next() {
+            summary!("    {}", line.dimmed());
+        }
+    }
+
+    Ok(())
+}
+
+/// Expands the snippets into the SQL added to `up.sql` and `down.sql` of a generated migration.
+/// Each expansion is wrapped in markers recording the version of the snippet, so migrations keep
+/// the SQL they were generated with when the snippet changes. Down snippets are expanded in reverse
+/// order.
+pub fn expand(
+    path: &Path,
+    names: &[String],
+    vars: &[String],
+    migration: &str,
+) -> anyhow::Result<(String, String)> {
+    let mut values = vec![(String::from("name"), migration.to_string())];
+    for var in vars {
+        let Some((key, value)) = var.split_once('=') else {
+            return Err(Error::msg(format!(
+                "Invalid variable '{var}', expected `KEY=VALUE`"
+            )));
+        };
+        values.push((key.trim().to_string(), value.to_string()));
+    }
+
+    let mut up = vec![];
+    let mut down = vec![];
+
+    for name in names {
+        let snippet = Snippet::load(path, name)?;
+        let version = snippet.version();
+        let wrap = |sql: &str| -> anyhow::Result<String> {
+            let sql = substitute(name, sql, &values)?;
+            Ok(format!(
+                "{START_MARKER}{name}@{version}\n{}\n{END_MARKER}{name}\n",
+                sql.trim()
+            ))
+        };
+
+        up.push(wrap(&snippet.up)?);
+        if let Some(ref sql) = snippet.down {
+            down.push(wrap(sql)?);
+        }
+    }
+
+    down.reverse();
+    Ok((up.join("\n"), down.join("\n")))
+}
+
+/// Returns the name and version of each snippet expanded into the SQL.
+fn pins(sql: &str) -> impl Iterator<Item = (&str, &str)> {
+    sql.lines()
+        .filter_map(|line| line.trim().strip_prefix(START_MARKER)?.split_once('@'))
+}
+
+/// Replaces the `{key}` placeholders of the snippet, failing on the ones without a value.
+fn substitute(name: &str, sql: &str, values: &[(String, String)]) -> anyhow::Result<String> {
+    let mut sql = sql.to_string();
+    for (key, value) in values {
+        sql = sql.replace(&format!("{{{key}}}"), value);
+    }
+
+    let mut missing = placeholders(&sql);
+    missing.sort();
+    missing.dedup();
+    if !missing.is_empty() {
+        return Err(Error::msg(format!(
+            "Snippet {name} has placeholders without a value: {}\nHint: Pass them with `--var {}=<value>`",
+            missing
+                .iter()
+                .map(|p| format!("{{{p}}}"))
+                .collect::<Vec<_>>()
+                .join(", "),
+            missing[0],
+        )));
+    }
+
+    Ok(sql)
+}
+
+/// Returns the `{key}` placeholders of the SQL, where keys are identifiers.
+fn placeholders(sql: &str) -> Vec<&str> {
+    sql.split('{')
+        .skip(1)
+        .filter_map(|rest| {
+            let (key, _) = rest.split_once('}')?;
+            let mut chars = key.chars();
+            let valid = chars
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+            valid.then_some(key)
+        })
+        .collect()
+}
+
+/// Splits the leading comments describing a snippet from its SQL.
+fn split_description(sql: &str) -> (String, String) {
+    let mut description = vec![];
+    let mut lines = sql.lines().peekable();
+    while let Some(comment) = lines.peek().and_then(|l| l.trim().strip_prefix("--")) {
+        description.push(comment.trim());
+        lines.next();
+    }
+    (
+        description.join("\n"),
+        lines.collect::<Vec<_>>().join("\n").trim().to_string(),
+    )
+}
@@ -0,0 +1,503 @@
+//! The SQL analysis shared by lint rules, statement splitting, destructive statement detection and
+//! impact indexing. Statements are parsed with `sqlparser` and its Postgres dialect. Statements the
+//! parser does not support, e.g. because they use newer syntax, are analyzed with keyword matching
+//! on their [normalized][normalize] form instead, so every analysis has a fallback.
+
+use sqlparser::ast::{
+    visit_relations, AlterTableOperation, ColumnOption, ObjectName, ObjectType, Statement,
+    TableConstraint,
+};
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::parser::Parser;
+use sqlparser::tokenizer::{Location, Token, Tokenizer, Whitespace};
+use std::ops::ControlFlow;
+
+/// Parses a single statement. Returns `None` if the parser does not support it, in which case
+/// callers fall back to keyword matching.
+pub(crate) fn parse(statement: &str) -> Option<Statement> {
+    let mut parsed = Parser::parse_sql(&PostgreSqlDialect {}, statement).ok()?;
+    (parsed.len() == 1).then(|| parsed.remove(0))
+}
+
+/// Splits a SQL script into its statements, discarding comments and empty statements.
+/// String literals, quoted identifiers and dollar quoted bodies are kept intact so semicolons
+/// inside of them do not split the statement. The statements keep the text of the script.
+pub fn statements(sql: &str) -> Vec<String> {
+    tokenized(sql).unwrap_or_else(|| split(sql))
+}
+
+/// Splits the script at the semicolons found by the tokenizer of the parser. Returns `None` if the
+/// script can't be tokenized or the locations of the tokens do not match its text.
+fn tokenized(sql: &str) -> Option<Vec<String>> {
+    let tokens = Tokenizer::new(&PostgreSqlDialect {}, sql)
+        .tokenize_with_location()
+        .ok()?;
+
+    // Locations are lines and characters, the statements are sliced by bytes
+    let lines = std::iter::once(0)
+        .chain(sql.match_indices('\n').map(|(i, _)| i + 1))
+        .collect::<Vec<_>>();
+    let offset = |location: Location| {
+        let line = *lines.get(usize::try_from(location.line).ok()?.checked_sub(1)?)?;
+        let column = usize::try_from(location.column).ok()?.checked_sub(1)?;
+        sql[line..]
+            .char_indices()
+            .map(|(i, _)| line + i)
+            .chain(std::iter::once(sql.len()))
+            .nth(column)
+    };
+    let starts = tokens
+        .iter()
+        .map(|token| offset(token.span.start))
+        .collect::<Option<Vec<_>>>()?;
+
+    let mut statements = vec![];
+    let mut current = String::new();
+    for (i, token) in tokens.iter().enumerate() {
+        let start = starts[i];
+        let end = starts.get(i + 1).copied().unwrap_or(sql.len());
+        let text = sql.get(start..end)?;
+
+        match token.token {
+            Token::SemiColon if text.starts_with(';') => {
+                let statement = current.trim();
+                if !statement.is_empty() {
+                    statements.push(statement.to_string());
+                }
+                current.clear();
+            }
+            Token::Whitespace(Whitespace::SingleLineComment { .. }) if text.starts_with("--") => {
+                current.push('\n')
+            }
+            Token::Whitespace(Whitespace::MultiLineComment(_)) if text.starts_with("/*") => {
+                current.push(' ')
+            }
+            Token::SemiColon | Token::Whitespace(Whitespace::SingleLineComment { .. }) => {
+                return None
+            }
+            Token::Whitespace(Whitespace::MultiLineComment(_)) => return None,
+            _ => current.push_str(text),
+        }
+    }
+
+    let statement = current.trim();
+    if !statement.is_empty() {
+        statements.push(statement.to_string());
+    }
+
+    Some(statements)
+}
+
+/// Splits the script by scanning for semicolons outside of literals and comments, for scripts the
+/// tokenizer rejects.
+fn split(sql: &str) -> Vec<String> {
+    let mut statements = vec![];
+    let mut current = String::new();
+    let mut chars = sql.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '-' if sql[i..].starts_with("--") => {
+                // Line comment, skip to the end of the line
+                for (_, c) in chars.by_ref() {
+                    if c == '\n' {
+                        current.push('\n');
+                        break;
+                    }
+                }
+            }
+            '/' if sql[i..].starts_with("/*") => {
+                chars.next();
+                let mut depth = 1;
+                while let Some((j, c)) = chars.next() {
+                    if c == '*' && sql[j..].starts_with("*/") {
+                        chars.next();
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    } else if c == '/' && sql[j..].starts_with("/*") {
+                        chars.next();
+                        depth += 1;
+                    }
+                }
+                current.push(' ');
+            }
+            '\'' | '"' => {
+                current.push(c);
+                for (_, q) in chars.by_ref() {
+                    current.push(q);
+                    if q == c {
+                        break;
+                    }
+                }
+            }
+            '$' => {
+                let Some(tag) = dollar_tag(&sql[i..]) else {
+                    current.push(c);
+                    continue;
+                };
+                let body_start = i + tag.len();
+                let end = sql[body_start..]
+                    .find(tag)
+                    .map(|e| body_start + e + tag.len())
+                    .unwrap_or(sql.len());
+                current.push_str(&sql[i..end]);
+                while chars.peek().is_some_and(|(j, _)| *j < end) {
+                    chars.next();
+                }
+            }
+            ';' => {
+                let statement = current.trim();
+                if !statement.is_empty() {
+                    statements.push(statement.to_string());
+                }
+                current.clear();
+            }
+            _ => current.push(c),
+        }
+    }
+
+    let statement = current.trim();
+    if !statement.is_empty() {
+        statements.push(statement.to_string());
+    }
+
+    statements
+}
+
+/// Returns the uppercased statement with all whitespace collapsed to single spaces,
+/// suitable for keyword matching.
+pub fn normalize(statement: &str) -> String {
+    statement
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_uppercase()
+}
+
+/// Statement prefixes for which [relations] can determine every object touched. Statements creating
+/// other kinds of objects (types, functions, sequences) are deliberately omitted so that migrations
+/// containing them are never considered independent.
+const ANALYZABLE: &[&str] = &[
+    "CREATE TABLE ",
+    "CREATE UNLOGGED TABLE ",
+    "CREATE INDEX ",
+    "CREATE UNIQUE INDEX ",
+    "CREATE VIEW ",
+    "CREATE OR REPLACE VIEW ",
+    "ALTER TABLE ",
+    "DROP TABLE ",
+    "DROP INDEX ",
+    "DROP VIEW ",
+    "INSERT INTO ",
+    "UPDATE ",
+    "DELETE FROM ",
+];
+
+/// Keywords followed by the name of a relation.
+const RELATION_KEYWORDS: &[&str] = &[
+    "TABLE",
+    "INDEX",
+    "VIEW",
+    "INTO",
+    "FROM",
+    "JOIN",
+    "UPDATE",
+    "REFERENCES",
+    "ON",
+    "TO",
+];
+
+/// Keywords ending the list of relations in a `FROM` clause.
+const FROM_TERMINATORS: &[&str] = &[
+    "WHERE",
+    "JOIN",
+    "GROUP",
+    "ORDER",
+    "LIMIT",
+    "HAVING",
+    "UNION",
+    "ON",
+    "RETURNING",
+    "SELECT",
+];
+
+/// Keywords which can appear between a [RELATION_KEYWORDS] keyword and the relation name.
+const RELATION_MODIFIERS: &[&str] = &["IF", "NOT", "EXISTS", "ONLY", "CONCURRENTLY"];
+
+/// Returns the names of the relations touched by a [normalized][normalize] statement, without their
+/// schema. Returns `None` if the statement is not one that can be reliably analyzed.
+pub fn relations(statement: &str) -> Option<Vec<String>> {
+    if !ANALYZABLE.iter().any(|p| statement.starts_with(p)) {
+        return None;
+    }
+    if let Some(parsed) = parse(statement) {
+        return Some(parsed_relations(&parsed));
+    }
+
+    let tokens = statement
+        .split(|c: char| c.is_whitespace() || matches!(c, '(' | ')' | ';'))
+        .flat_map(|t| {
+            // Keep commas as separate tokens to detect lists of relations
+            let mut parts = t.split(',').peekable();
+            std::iter::from_fn(move || {
+                let part = parts.next()?;
+                Some(match parts.peek() {
+                    Some(_) => vec![part, ","],
+                    None => vec![part],
+                })
+            })
+            .flatten()
+        })
+        .filter(|t| !t.is_empty())
+        .collect::<Vec<_>>();
+
+    let mut relations = vec![];
+    let mut tokens = tokens.into_iter().peekable();
+    let is_drop = statement.starts_with("DROP ");
+
+    while let Some(token) = tokens.next() {
+        if !RELATION_KEYWORDS.contains(&token) {
+            continue;
+        }
+
+        let Some(name) = tokens.find(|t| !RELATION_MODIFIERS.contains(t)) else {
+            break;
+        };
+        relations.push(relation_name(name));
+
+        // `DROP TABLE a, b`
+        if is_drop {
+            while tokens.next_if_eq(&",").is_some() {
+                if let Some(name) = tokens.next() {
+                    relations.push(relation_name(name));
+                }
+            }
+        }
+
+        // `FROM a x, b AS y`
+        if token == "FROM" {
+            while let Some(t) = tokens.next_if(|t| !FROM_TERMINATORS.contains(t)) {
+                if t == "," {
+                    if let Some(name) = tokens.next() {
+                        relations.push(relation_name(name));
+                    }
+                }
+            }
+        }
+    }
+
+    Some(relations)
+}
+
+/// Returns the relations of a parsed statement, the created or dropped index first, followed by
+/// the tables foreign keys reference and the relations read or written.
+fn parsed_relations(statement: &Statement) -> Vec<String> {
+    let mut names = vec![];
+
+    match statement {
+        Statement::CreateIndex(index) => names.extend(index.name.iter()),
+        Statement::CreateView { name, .. } => names.push(name),
+        Statement::Drop {
+            object_type: ObjectType::Table | ObjectType::Index | ObjectType::View,
+            names: dropped,
+            ..
+        } => names.extend(dropped),
+        Statement::CreateTable(table) => {
+            for column in &table.columns {
+                names.extend(column.options.iter().filter_map(|o| match o.option {
+                    ColumnOption::ForeignKey {
+                        ref foreign_table, ..
+                    } => Some(foreign_table),
+                    _ => None,
+                }));
+            }
+            names.extend(table.constraints.iter().filter_map(references));
+        }
+        Statement::AlterTable { operations, .. } => {
+            for operation in operations {
+                match operation {
+                    AlterTableOperation::AddConstraint(constraint) => {
+                        names.extend(references(constraint))
+                    }
+                    AlterTableOperation::AddColumn { column_def, .. } => {
+                        names.extend(column_def.options.iter().filter_map(|o| match o.option {
+                            ColumnOption::ForeignKey {
+                                ref foreign_table, ..
+                            } => Some(foreign_table),
+                            _ => None,
+                        }))
+                    }
+                    AlterTableOperation::RenameTable { table_name } => names.push(table_name),
+                    _ => {}
+                }
+            }
+        }
+        _ => {}
+    }
+
+    let mut relations = names.into_iter().map(object_name).collect::<Vec<_>>();
+    let _ = visit_relations(statement, |name| {
+        relations.push(object_name(name));
+        ControlFlow::<()>::Continue(())
+    });
+    relations
+}
+
+/// Returns the table a foreign key constraint references.
+fn references(constraint: &TableConstraint) -> Option<&ObjectName> {
+    match constraint {
+        TableConstraint::ForeignKey { foreign_table, .. } => Some(foreign_table),
+        _ => None,
+    }
+}
+
+/// Returns the name of a relation without its schema.
+pub(crate) fn object_name(name: &ObjectName) -> String {
+    name.0
+        .last()
+        .map(|ident| ident.value.clone())
+        .unwrap_or_default()
+}
+
+/// Strips the schema and quotes from a relation name.
+pub(crate) fn relation_name(name: &str) -> String {
+    name.rsplit('.')
+        .next()
+        .unwrap_or(name)
+        .trim_matches('"')
+        .to_string()
+}
+
+/// Returns the value of `-- key: value` in the leading comment block of a migration file.
+pub fn header_field<'a>(sql: &'a str, key: &str) -> Option<&'a str> {
+    sql.lines()
+        .map(str::trim)
+        .take_while(|line| line.starts_with("--"))
+        .find_map(|line| {
+            let (k, v) = line.trim_start_matches('-').split_once(':')?;
+            (k.trim() == key).then(|| v.trim())
+        })
+}
+
+/// Quotes an identifier, e.g. the name of a role or database, so it can be embedded in a statement.
+pub fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+/// If `s` starts with a dollar quote tag (`$$` or `$tag$`), returns it.
+pub(crate) fn dollar_tag(s: &str) -> Option<&str> {
+    let end = s[1..].find('$')? + 2;
+    let tag = &s[..end];
+    tag[1..end - 1]
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '_')
+        .then_some(tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = "-- migr:no-transaction
+CREATE TABLE orders (id INT, note TEXT DEFAULT 'a;b');
+/* a comment; with a semicolon */
+CREATE INDEX CONCURRENTLY idx_orders_note ON orders (note);
+CREATE FUNCTION touch() RETURNS trigger AS $body$
+BEGIN
+    NEW.note := 'touched;';
+    RETURN NEW;
+END;
+$body$ LANGUAGE plpgsql;
+DO $$ BEGIN PERFORM 1; END $$;
+COMMENT ON TABLE \"my;table\" IS 'x';";
+
+    #[test]
+    fn statements_keep_literals_and_drop_comments() {
+        let statements = statements(SCRIPT);
+        assert_eq!(statements.len(), 5);
+        assert_eq!(
+            statements[0],
+            "CREATE TABLE orders (id INT, note TEXT DEFAULT 'a;b')"
+        );
+        assert_eq!(
+            statements[1],
+            "CREATE INDEX CONCURRENTLY idx_orders_note ON orders (note)"
+        );
+        assert!(statements[2].starts_with("CREATE FUNCTION touch()"));
+        assert!(statements[2].contains("NEW.note := 'touched;';"));
+        assert!(statements[2].ends_with("$body$ LANGUAGE plpgsql"));
+        assert_eq!(statements[3], "DO $$ BEGIN PERFORM 1; END $$");
+        assert_eq!(statements[4], "COMMENT ON TABLE \"my;table\" IS 'x'");
+        assert!(statements.iter().all(|s| !s.contains("comment")));
+    }
+
+    #[test]
+    fn tokenized_and_split_agree() {
+        // Non-transactional migrations used to be split with `split` only
+        assert_eq!(tokenized(SCRIPT), Some(split(SCRIPT)));
+        for sql in [
+            "SELECT 1; SELECT 2",
+            "SELECT 1;; ;\n-- trailing comment",
+            "/* only a comment */",
+            "VACUUM orders; -- no semicolon here;\nANALYZE orders;",
+            "SELECT 'it''s; fine'; SELECT $tag$ $$ ; $tag$",
+        ] {
+            assert_eq!(tokenized(sql), Some(split(sql)), "{sql}");
+        }
+    }
+
+    #[test]
+    fn untokenizable_scripts_are_split() {
+        let sql = "SELECT 1; SELECT 'unterminated; SELECT 2";
+        assert_eq!(tokenized(sql), None);
+        assert_eq!(
+            statements(sql),
+            vec!["SELECT 1", "SELECT 'unterminated; SELECT 2"]
+        );
+    }
+
+    #[test]
+    fn relations_of_parsed_statements() {
+        let relations = |sql: &str| relations(&normalize(sql));
+        assert_eq!(
+            relations("CREATE TABLE items (id INT, order_id INT REFERENCES public.orders (id))"),
+            Some(vec![String::from("ORDERS"), String::from("ITEMS")])
+        );
+        assert_eq!(
+            relations("CREATE INDEX idx ON items (order_id)"),
+            Some(vec![String::from("IDX"), String::from("ITEMS")])
+        );
+        assert_eq!(
+            relations("DROP TABLE a, b"),
+            Some(vec![String::from("A"), String::from("B")])
+        );
+        assert_eq!(
+            relations("CREATE FUNCTION f() RETURNS INT AS 'SELECT 1' LANGUAGE sql"),
+            None
+        );
+    }
+
+    #[test]
+    fn relations_fall_back_to_keywords() {
+        // Unsupported by the parser, so the keywords are matched
+        let statement =
+            normalize("ALTER TABLE orders ATTACH PARTITION orders_2024 DEFAULT FROM x, y");
+        assert!(parse(&statement).is_none());
+        let relations = relations(&statement).unwrap();
+        assert!(relations.contains(&String::from("ORDERS")));
+        assert!(relations.contains(&String::from("X")));
+        assert!(relations.contains(&String::from("Y")));
+    }
+
+    #[test]
+    fn identifiers_are_quoted() {
+        assert_eq!(quote_ident("app_prod"), "\"app_prod\"");
+        assert_eq!(quote_ident("Mixed Case"), "\"Mixed Case\"");
+        assert_eq!(
+            quote_ident("a\"; DROP ROLE x; --"),
+            "\"a\"\"; DROP ROLE x; --\""
+        );
+    }
+}
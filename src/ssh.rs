@@ -0,0 +1,107 @@
+use crate::{debug, info};
+use anyhow::{Context, Error};
+use std::io::Read;
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for a tunnel to accept connections, including authenticating with the jump
+/// host.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A jump host databases are reached through, given as an `ssh` destination, e.g.
+/// `user@bastion`. Tunnels are opened on demand and closed once the jump host is dropped.
+#[derive(Debug)]
+pub struct Jump {
+    destination: String,
+    tunnels: Mutex<Vec<Tunnel>>,
+}
+
+#[derive(Debug)]
+struct Tunnel {
+    host: String,
+    port: u16,
+    local: u16,
+    child: Child,
+}
+
+impl Jump {
+    pub fn new(destination: &str) -> Self {
+        Self {
+            destination: destination.to_string(),
+            tunnels: Mutex::new(vec![]),
+        }
+    }
+
+    /// Forwards a local port to `host:port`, as seen from the jump host, and returns the local
+    /// port. Tunnels to the same address are reused.
+    pub fn forward(&self, host: &str, port: u16) -> anyhow::Result<u16> {
+        let mut tunnels = self.tunnels.lock().unwrap();
+
+        if let Some(tunnel) = tunnels.iter().find(|t| t.host == host && t.port == port) {
+            return Ok(tunnel.local);
+        }
+
+        // Let the OS pick a free port for ssh to listen on
+        let local = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+
+        info!(
+            "Opening SSH tunnel to {host}:{port} through {}",
+            self.destination
+        );
+
+        let child = Command::new("ssh")
+            .args(["-N", "-o", "ExitOnForwardFailure=yes", "-L"])
+            .arg(format!("127.0.0.1:{local}:{host}:{port}"))
+            .arg(&self.destination)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Unable to run `ssh`\nHint: `--ssh` requires OpenSSH to be installed")?;
+
+        let mut tunnel = Tunnel {
+            host: host.to_string(),
+            port,
+            local,
+            child,
+        };
+
+        let started = Instant::now();
+        while TcpStream::connect(("127.0.0.1", local)).is_err() {
+            if let Some(status) = tunnel.child.try_wait()? {
+                let mut stderr = String::new();
+                if let Some(mut out) = tunnel.child.stderr.take() {
+                    let _ = out.read_to_string(&mut stderr);
+                }
+                return Err(Error::msg(format!(
+                    "Unable to open SSH tunnel through '{}', ssh exited with {status}: {}",
+                    self.destination,
+                    stderr.trim()
+                )));
+            }
+            if started.elapsed() > TIMEOUT {
+                return Err(Error::msg(format!(
+                    "Timed out opening SSH tunnel through '{}'",
+                    self.destination
+                )));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        debug!("Forwarding 127.0.0.1:{local} to {host}:{port}");
+
+        tunnels.push(tunnel);
+
+        Ok(local)
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
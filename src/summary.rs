@@ -0,0 +1,418 @@
+use crate::config::Config;
+use crate::directive;
+use crate::meta::MigrationMeta;
+use crate::migration::migration_id;
+use crate::schema::VERSION_COLUMN;
+use crate::{metrics, notify, output, plugin, summary, warn, RunRevMigration};
+use anyhow::Context;
+use postgres::{Client, GenericClient};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Collects the migrations executed by a command and reports them once it finishes.
+#[derive(Debug)]
+pub struct Summary {
+    /// The past tense of the command, used in the report, e.g. `executed`.
+    action: &'static str,
+    started: Instant,
+    started_at: SystemTime,
+    /// The current version before the command executed anything.
+    before: Mutex<Option<String>>,
+    migrations: Mutex<Vec<Executed>>,
+    /// The migrations the command set out to execute, in order.
+    plan: Mutex<Vec<Planned>>,
+    /// The migrations which failed with their errors.
+    failed: Mutex<Vec<(Planned, String)>>,
+    /// Migrations which were executed, but rolled back with the transaction they were executed in.
+    rolled_back: Mutex<Vec<Executed>>,
+}
+
+/// A migration a command set out to execute.
+#[derive(Debug, Clone, Serialize)]
+pub struct Planned {
+    pub id: String,
+    pub direction: &'static str,
+    /// The checksum of the file to execute.
+    pub checksum: Option<String>,
+    /// The annotations of the migration's `meta.toml`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub risk: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ticket: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+}
+
+/// What happened to a planned migration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Executed,
+    Failed,
+    RolledBack,
+    NotAttempted,
+}
+
+/// The outcome of a migration in the report written with `--report-file`.
+#[derive(Debug, Serialize)]
+pub struct Outcome {
+    pub id: String,
+    pub direction: &'static str,
+    pub status: Status,
+    pub checksum: Option<String>,
+    pub duration_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The full record of a command written with `--report-file`, for deployment systems to archive.
+#[derive(Debug, Serialize)]
+pub struct ExecutionReport<'a> {
+    pub action: &'static str,
+    pub success: bool,
+    /// Seconds since the unix epoch at which the command started.
+    pub started_at: u64,
+    /// Seconds since the unix epoch at which the command finished.
+    pub finished_at: u64,
+    pub total_ms: f64,
+    pub before_version: Option<String>,
+    pub after_version: Option<String>,
+    pub plan: &'a [Planned],
+    pub migrations: Vec<Outcome>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Executed {
+    pub id: String,
+    pub direction: &'static str,
+    pub duration_ms: f64,
+}
+
+/// The outcome of a command, printed as JSON with `--json` and reported to the metrics sinks and
+/// webhook.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub action: &'static str,
+    pub success: bool,
+    pub count: usize,
+    pub total_ms: f64,
+    /// Seconds since the unix epoch at which the command finished.
+    pub finished_at: u64,
+    pub current_version: Option<String>,
+    pub migrations: Vec<Executed>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Summary {
+    pub fn new(action: &'static str) -> Self {
+        Self {
+            action,
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            before: Mutex::new(None),
+            migrations: Mutex::new(vec![]),
+            plan: Mutex::new(vec![]),
+            failed: Mutex::new(vec![]),
+            rolled_back: Mutex::new(vec![]),
+        }
+    }
+
+    /// Records the current version before the command executes anything. A missing metadata table
+    /// is no version.
+    pub fn begin(&self, pg: &mut impl GenericClient) {
+        let exists = pg
+            .query_one("SELECT to_regclass('__migr_meta__') IS NOT NULL", &[])
+            .is_ok_and(|row| row.get::<_, bool>(0));
+        if exists {
+            *self.before.lock().unwrap() = current_version(pg).ok().flatten();
+        }
+    }
+
+    /// Records the migration files the command is about to execute in `direction`.
+    pub fn plan<'a>(&self, files: impl IntoIterator<Item = &'a PathBuf>, direction: &'static str) {
+        let mut plan = self.plan.lock().unwrap();
+        for file in files {
+            plan.push(planned(file, direction));
+        }
+    }
+
+    /// Records a migration file which failed to execute in `direction`.
+    pub fn fail(&self, file: &Path, direction: &'static str, error: &anyhow::Error) {
+        let error = output::strip_ansi(&format!("{error:#}"));
+        self.failed
+            .lock()
+            .unwrap()
+            .push((planned(file, direction), error));
+    }
+
+    /// Moves the executed migrations `ids` to the rolled back ones, since the transaction they
+    /// were executed in was rolled back.
+    pub fn roll_back<'a>(&self, ids: impl IntoIterator<Item = &'a str>) {
+        let ids = ids.into_iter().collect::<Vec<_>>();
+        let mut migrations = self.migrations.lock().unwrap();
+        let (rolled_back, kept) = migrations
+            .drain(..)
+            .partition::<Vec<_>, _>(|m| ids.contains(&m.id.as_str()));
+        *migrations = kept;
+        self.rolled_back.lock().unwrap().extend(rolled_back);
+    }
+
+    /// Records a migration executed in `direction` (`up` or `down`) which took `duration`.
+    pub fn record(&self, id: &str, direction: &'static str, duration: Duration) {
+        self.migrations.lock().unwrap().push(Executed {
+            id: id.to_string(),
+            direction,
+            duration_ms: duration.as_secs_f64() * 1000.,
+        });
+    }
+
+    /// Returns the IDs of the migrations executed up so far, in the order they were executed.
+    pub fn applied(&self) -> Vec<String> {
+        self.migrations
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| m.direction == "up")
+            .map(|m| m.id.clone())
+            .collect()
+    }
+
+    /// Prints the summary if the command succeeded, or writes it to stdout as a single JSON object
+    /// with `--json`, and reports it to the configured metrics sinks and webhook. Returns the
+    /// `result` of the command.
+    pub fn finish(
+        self,
+        pg: &mut Client,
+        args: &RunRevMigration,
+        config: &Config,
+        result: anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let total = self.started.elapsed();
+        let migrations = self.migrations.into_inner().unwrap();
+        let plan = self.plan.into_inner().unwrap();
+        let failed = self.failed.into_inner().unwrap();
+        let rolled_back = self.rolled_back.into_inner().unwrap();
+        let before = self.before.into_inner().unwrap();
+
+        // The connection may be unusable after a failure, in which case the version is unknown
+        let current = match current_version(pg) {
+            Ok(current) => current,
+            Err(e) if result.is_ok() => return Err(e),
+            Err(_) => None,
+        };
+
+        // Redone migrations are executed in both directions, count them once
+        let count = migrations
+            .iter()
+            .filter(|m| self.action != "redone" || m.direction == "up")
+            .count();
+
+        let report = Report {
+            action: self.action,
+            success: result.is_ok(),
+            count,
+            total_ms: total.as_secs_f64() * 1000.,
+            finished_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            current_version: current,
+            migrations,
+            error: result
+                .as_ref()
+                .err()
+                .map(|e| output::strip_ansi(&format!("{e:#}"))),
+        };
+
+        plugin::after_command(&report);
+
+        if let Some(ref file) = args.report_file {
+            let execution = ExecutionReport {
+                action: report.action,
+                success: report.success,
+                started_at: self
+                    .started_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                finished_at: report.finished_at,
+                total_ms: report.total_ms,
+                before_version: before,
+                after_version: report.current_version.clone(),
+                plan: &plan,
+                migrations: outcomes(&plan, &report.migrations, &failed, &rolled_back),
+                error: report.error.clone(),
+            };
+            let written = serde_json::to_vec_pretty(&execution)
+                .map_err(anyhow::Error::from)
+                .and_then(|json| Ok(fs::write(file, json)?))
+                .with_context(|| format!("Unable to write the report to '{}'", file.display()));
+            // The report is the record of the change, so a successful command without one fails
+            match written {
+                Err(e) if result.is_ok() => return Err(e),
+                Err(e) => warn!("{e:#}"),
+                Ok(()) => {}
+            }
+        }
+
+        // Failing to report metrics must not fail an otherwise successful command
+        if let Err(e) = metrics::emit(&args.metrics, &report) {
+            warn!("Unable to report metrics: {e:#}");
+        }
+
+        if let Err(e) = notify::send(&config.notify, &report) {
+            warn!("Unable to send notification: {e:#}");
+        }
+
+        result?;
+
+        if args.json {
+            println!("{}", serde_json::to_string(&report)?);
+            return Ok(());
+        }
+
+        print(&report, total);
+
+        Ok(())
+    }
+}
+
+/// Returns the outcome of every planned migration, followed by the ones executed without being
+/// planned.
+fn outcomes(
+    plan: &[Planned],
+    executed: &[Executed],
+    failed: &[(Planned, String)],
+    rolled_back: &[Executed],
+) -> Vec<Outcome> {
+    let find = |list: &[Executed], id: &str, direction: &str| {
+        list.iter()
+            .find(|m| m.id == id && m.direction == direction)
+            .map(|m| m.duration_ms)
+    };
+
+    let mut outcomes = plan
+        .iter()
+        .map(|planned| {
+            let (id, direction) = (planned.id.as_str(), planned.direction);
+            let error = failed
+                .iter()
+                .find(|(f, _)| f.id == id && f.direction == direction)
+                .map(|(_, error)| error.clone());
+            let (status, duration_ms) = match (find(executed, id, direction), error.is_some()) {
+                (Some(duration), _) => (Status::Executed, Some(duration)),
+                (None, true) => (Status::Failed, None),
+                (None, false) => match find(rolled_back, id, direction) {
+                    Some(duration) => (Status::RolledBack, Some(duration)),
+                    None => (Status::NotAttempted, None),
+                },
+            };
+            Outcome {
+                id: id.to_string(),
+                direction,
+                status,
+                checksum: planned.checksum.clone(),
+                duration_ms,
+                error,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let unplanned = executed.iter().filter(|m| {
+        !plan
+            .iter()
+            .any(|p| p.id == m.id && p.direction == m.direction)
+    });
+    for m in unplanned {
+        outcomes.push(Outcome {
+            id: m.id.clone(),
+            direction: m.direction,
+            status: Status::Executed,
+            checksum: None,
+            duration_ms: Some(m.duration_ms),
+            error: None,
+        });
+    }
+    for (f, error) in failed {
+        if !plan
+            .iter()
+            .any(|p| p.id == f.id && p.direction == f.direction)
+        {
+            outcomes.push(Outcome {
+                id: f.id.clone(),
+                direction: f.direction,
+                status: Status::Failed,
+                checksum: f.checksum.clone(),
+                duration_ms: None,
+                error: Some(error.clone()),
+            });
+        }
+    }
+
+    outcomes
+}
+
+/// Returns the planned execution of the migration file in `direction`.
+fn planned(file: &Path, direction: &'static str) -> Planned {
+    // Invalid metadata fails the migration, which is reported on its own
+    let meta = MigrationMeta::load(file.parent().unwrap_or(Path::new("."))).unwrap_or_default();
+    Planned {
+        id: migration_id(file).unwrap_or_default().to_string(),
+        direction,
+        checksum: fs::read(file)
+            .ok()
+            .map(|sql| directive::checksum(&sql, file)),
+        risk: meta.risk.map(|risk| risk.to_string()),
+        description: meta.description,
+        ticket: meta.ticket,
+        author: meta.author,
+    }
+}
+
+fn print(report: &Report, total: Duration) {
+    if report.migrations.is_empty() {
+        summary!("Migrations already up to date");
+    } else {
+        summary!(
+            "{} migrations successfully {} in {total:.2?}",
+            report.count,
+            report.action
+        );
+
+        let width = report
+            .migrations
+            .iter()
+            .map(|m| m.id.len())
+            .max()
+            .unwrap_or(0)
+            + 3;
+
+        for m in &report.migrations {
+            let duration = Duration::from_secs_f64(m.duration_ms / 1000.);
+            summary!("  {:<4} {:.<width$} {duration:.2?}", m.direction, m.id);
+        }
+    }
+
+    match report.current_version {
+        Some(ref current) => summary!("Current version: {}", current.green()),
+        None => summary!("No migrations are applied"),
+    }
+}
+
+/// Returns the version of the latest applied migration.
+pub fn current_version(pg: &mut impl GenericClient) -> anyhow::Result<Option<String>> {
+    Ok(pg
+        .query_one(
+            &format!("SELECT MAX({VERSION_COLUMN}) FROM __migr_meta__ WHERE NOT pending"),
+            &[],
+        )?
+        .get::<_, Option<String>>(0))
+}
@@ -0,0 +1,164 @@
+use crate::config::Config;
+use crate::db::Manager;
+use crate::migration::{fingerprint, run_pending_with};
+use crate::sql::quote_ident;
+use crate::{
+    debug, info, summary, TemplateArgs, TemplateCloneArgs, TemplateCommand, TemplateCreateArgs,
+};
+use anyhow::Error;
+use postgres::Client;
+use r2d2::ManageConnection;
+use std::path::Path;
+use std::time::Instant;
+
+/// Prefixes the fingerprint of the migrations in the comment of a template database.
+const COMMENT_PREFIX: &str = "migr template ";
+
+pub fn template(
+    args: &TemplateArgs,
+    path: &Path,
+    manager: &Manager,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let mut pg = manager.connect()?;
+
+    let name = match args.template {
+        Some(ref name) => name.clone(),
+        None => {
+            let database = pg
+                .query_one("SELECT current_database()::TEXT", &[])?
+                .get::<_, String>(0);
+            format!("{database}_template")
+        }
+    };
+
+    match args.command {
+        TemplateCommand::Create(ref create) => {
+            template_create(create, &name, path, manager, &mut pg, config)
+        }
+        TemplateCommand::Clone(ref clone) => template_clone(clone, &name, path, &mut pg, config),
+    }
+}
+
+/// Creates the template database with all migrations applied. The template is only rebuilt if the
+/// migrations changed since it was created, or with `--force`.
+fn template_create(
+    args: &TemplateCreateArgs,
+    name: &str,
+    path: &Path,
+    manager: &Manager,
+    pg: &mut Client,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let started = Instant::now();
+    let current = fingerprint(path, config)?;
+
+    match stored_fingerprint(pg, name)? {
+        Some(Some(ref stored)) if *stored == current && !args.force => {
+            summary!("Template {name} is up to date");
+            return Ok(());
+        }
+        Some(None) => {
+            return Err(Error::msg(format!(
+                "Database {name} exists and is not a template created by migr\nHint: Pass another name with `--template`"
+            )));
+        }
+        Some(Some(_)) => {
+            info!("Dropping stale template {name}");
+            // DROP DATABASE can not run in the implicit transaction of a multi statement batch
+            pg.batch_execute(&format!(
+                "ALTER DATABASE {} IS_TEMPLATE FALSE",
+                quote_ident(name)
+            ))?;
+            pg.batch_execute(&format!("DROP DATABASE {}", quote_ident(name)))?;
+        }
+        None => {}
+    }
+
+    info!("Creating template {name}");
+    pg.batch_execute(&format!("CREATE DATABASE {}", quote_ident(name)))?;
+
+    let result = (|| {
+        let mut template = manager.connect_to(name)?;
+        let mut tx = template.transaction()?;
+        let executed = run_pending_with(&mut tx, path, config)?;
+        tx.commit()?;
+        anyhow::Ok(executed)
+    })();
+
+    // Do not leave a half migrated template behind
+    let executed = match result {
+        Ok(executed) => executed,
+        Err(e) => {
+            pg.batch_execute(&format!("DROP DATABASE IF EXISTS {}", quote_ident(name)))?;
+            return Err(e.context(format!("Could not migrate template {name}")));
+        }
+    };
+
+    pg.batch_execute(&format!(
+        "COMMENT ON DATABASE {0} IS '{COMMENT_PREFIX}{current}'; ALTER DATABASE {0} IS_TEMPLATE TRUE",
+        quote_ident(name)
+    ))?;
+
+    summary!(
+        "Created template {name} with {} migrations in {:.2?}",
+        executed.len(),
+        started.elapsed()
+    );
+
+    Ok(())
+}
+
+/// Creates a database from the template. Refuses to clone a template which is missing migrations.
+fn template_clone(
+    args: &TemplateCloneArgs,
+    template: &str,
+    path: &Path,
+    pg: &mut Client,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let started = Instant::now();
+
+    let Some(stored) = stored_fingerprint(pg, template)? else {
+        return Err(Error::msg(format!(
+            "Template {template} does not exist\nHint: Run `migr template create` to create it"
+        )));
+    };
+
+    let current = fingerprint(path, config)?;
+    debug!("Migrations fingerprint {current}");
+
+    if stored.as_deref() != Some(current.as_str()) {
+        return Err(Error::msg(format!(
+            "Template {template} is stale, the migrations changed since it was created\nHint: Run `migr template create` to rebuild it"
+        )));
+    }
+
+    pg.batch_execute(&format!(
+        "CREATE DATABASE {} TEMPLATE {}",
+        quote_ident(&args.name),
+        quote_ident(template)
+    ))?;
+
+    summary!(
+        "Created database {} from template {template} in {:.2?}",
+        args.name,
+        started.elapsed()
+    );
+
+    Ok(())
+}
+
+/// Returns `None` if the database does not exist, otherwise the fingerprint it was created with,
+/// which is `None` if it was not created by migr.
+fn stored_fingerprint(pg: &mut Client, name: &str) -> anyhow::Result<Option<Option<String>>> {
+    let row = pg.query_opt(
+        "SELECT shobj_description(oid, 'pg_database') FROM pg_database WHERE datname = $1",
+        &[&name],
+    )?;
+
+    Ok(row.map(|row| {
+        row.get::<_, Option<String>>(0)
+            .and_then(|comment| comment.strip_prefix(COMMENT_PREFIX).map(String::from))
+    }))
+}
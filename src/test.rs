@@ -0,0 +1,103 @@
+use crate::config::{Config, CONFIG_FILE};
+use crate::migration::run_pending_with;
+use crate::warn;
+use anyhow::Context;
+use postgres::{Client, NoTls};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Distinguishes schemas created by the same process.
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A uniquely named schema with all migrations applied, dropped with everything in it once the
+/// guard is dropped. Each test can use its own schema, so tests can run in parallel against a
+/// single database.
+///
+/// The schema is dropped with a blocking connection, so the guard must not be dropped inside an
+/// async runtime.
+#[derive(Debug)]
+pub struct TestSchema {
+    /// The URL the schema was created with.
+    base: String,
+    url: String,
+    name: String,
+}
+
+impl TestSchema {
+    /// Creates a schema in the database at `url` and applies the migrations at `path` to it with
+    /// the project config, i.e. `MIGR_CONFIG` or `migr.toml` in the current directory, which is the
+    /// crate root in cargo tests. `url` must not set any `options`, which are used to select the
+    /// schema.
+    pub fn new(url: &str, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let config = env::var_os("MIGR_CONFIG")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(CONFIG_FILE));
+        Self::with_config(url, path, &Config::load(&config)?)
+    }
+
+    /// Like [TestSchema::new], with the given config instead of the project config.
+    pub fn with_config(url: &str, path: impl AsRef<Path>, config: &Config) -> anyhow::Result<Self> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        let name = format!(
+            "migr_test_{}_{}_{nanos}",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+
+        let mut pg = Client::connect(url, NoTls).context("Could not establish PG connection")?;
+        pg.batch_execute(&format!("CREATE SCHEMA {name}"))?;
+
+        // Dropped on error from here on
+        let schema = Self {
+            base: url.to_string(),
+            url: with_search_path(url, &name),
+            name,
+        };
+
+        let mut pg = Client::connect(&schema.url, NoTls)?;
+        let mut tx = pg.transaction()?;
+        run_pending_with(&mut tx, path.as_ref(), config)
+            .context("Could not migrate the test schema")?;
+        tx.commit()?;
+
+        Ok(schema)
+    }
+
+    /// The connection string of the schema, which sets the search path to it.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The name of the schema.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for TestSchema {
+    fn drop(&mut self) {
+        let result = Client::connect(&self.base, NoTls).and_then(|mut pg| {
+            pg.batch_execute(&format!("DROP SCHEMA IF EXISTS {} CASCADE", self.name))
+        });
+        if let Err(e) = result {
+            warn!("Unable to drop test schema {}: {e}", self.name);
+        }
+    }
+}
+
+/// Adds the option setting the search path to `schema`, followed by `public` so extensions and
+/// objects installed there stay visible, to a URL or key-value connection string.
+fn with_search_path(url: &str, schema: &str) -> String {
+    if url.contains("://") {
+        let separator = if url.contains('?') { '&' } else { '?' };
+        format!("{url}{separator}options=-c%20search_path%3D{schema}%2Cpublic")
+    } else {
+        format!("{url} options='-c search_path={schema},public'")
+    }
+}
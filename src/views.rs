@@ -0,0 +1,176 @@
+use crate::{debug, trace};
+use anyhow::Error;
+use postgres::GenericClient;
+use std::fmt::Write;
+
+/// A view depending on a table, captured so it can be recreated after the table changed.
+#[derive(Debug)]
+struct View {
+    /// The schema qualified, quoted name of the view.
+    name: String,
+    materialized: bool,
+    definition: String,
+    options: Option<String>,
+    comment: Option<String>,
+    /// `GRANT` statements restoring the privileges on the view.
+    grants: Vec<String>,
+    /// The quoted name of the role owning the view.
+    owner: String,
+    /// `CREATE INDEX` statements restoring the indexes of a materialized view.
+    indexes: Vec<String>,
+}
+
+/// Executes `sql` with the views depending on `table`, directly or through other views, dropped
+/// beforehand and recreated afterwards with their owners, privileges, comments and indexes. Everything is sent in
+/// a single batch, so it is applied atomically even outside of a transaction.
+pub fn recreate(pg: &mut impl GenericClient, table: &str, sql: &str) -> anyhow::Result<()> {
+    let views = dependent(pg, table)?;
+
+    if views.is_empty() {
+        debug!("No views depend on {table}");
+        trace!("{}", sql.trim());
+        pg.batch_execute(sql)?;
+        return Ok(());
+    }
+
+    debug!(
+        "Recreating views depending on {table}: {}",
+        views
+            .iter()
+            .map(|v| v.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let mut batch = String::new();
+
+    // Views are ordered so that each one comes after the ones it depends on
+    for view in views.iter().rev() {
+        let kind = if view.materialized {
+            "MATERIALIZED VIEW"
+        } else {
+            "VIEW"
+        };
+        let _ = writeln!(batch, "DROP {kind} {};", view.name);
+    }
+
+    // On its own line, so a trailing comment can not swallow it
+    let _ = writeln!(batch, "{}\n;", sql.trim());
+
+    for view in &views {
+        let kind = if view.materialized {
+            "MATERIALIZED VIEW"
+        } else {
+            "VIEW"
+        };
+        let options = view
+            .options
+            .as_ref()
+            .map(|o| format!(" WITH ({o})"))
+            .unwrap_or_default();
+        let _ = writeln!(
+            batch,
+            "CREATE {kind} {}{options} AS {};",
+            view.name, view.definition
+        );
+        for index in &view.indexes {
+            let _ = writeln!(batch, "{index};");
+        }
+        if let Some(ref comment) = view.comment {
+            let _ = writeln!(batch, "COMMENT ON {kind} {} IS {comment};", view.name);
+        }
+        for grant in &view.grants {
+            let _ = writeln!(batch, "{grant};");
+        }
+    }
+
+    // Last, since the privileges granted by the session user pass to the new owners, and views
+    // created from views check the privileges of the owners
+    for view in &views {
+        let kind = if view.materialized {
+            "MATERIALIZED VIEW"
+        } else {
+            "VIEW"
+        };
+        let _ = writeln!(batch, "ALTER {kind} {} OWNER TO {};", view.name, view.owner);
+    }
+
+    trace!("{}", batch.trim());
+    pg.batch_execute(&batch)?;
+
+    Ok(())
+}
+
+/// Returns the views depending on `table`, ordered so that each view comes after the views it
+/// depends on.
+fn dependent(pg: &mut impl GenericClient, table: &str) -> anyhow::Result<Vec<View>> {
+    let exists = pg
+        .query_one("SELECT to_regclass($1::TEXT) IS NOT NULL", &[&table])?
+        .get::<_, bool>(0);
+    if !exists {
+        return Err(Error::msg(format!(
+            "Relation '{table}' does not exist, can not recreate the views depending on it"
+        )));
+    }
+
+    // Views depend on relations through the rewrite rules holding their definitions. A view is
+    // placed at the longest path from the table, so it is created after everything it selects from
+    let rows = pg.query(
+        "WITH RECURSIVE deps(oid, depth) AS (
+             SELECT r.ev_class, 1
+             FROM pg_depend d JOIN pg_rewrite r ON r.oid = d.objid
+             WHERE d.classid = 'pg_rewrite'::REGCLASS AND d.refobjid = to_regclass($1::TEXT)
+                 AND r.ev_class <> d.refobjid
+             UNION
+             SELECT r.ev_class, deps.depth + 1
+             FROM deps
+             JOIN pg_depend d ON d.refobjid = deps.oid
+             JOIN pg_rewrite r ON r.oid = d.objid
+             WHERE d.classid = 'pg_rewrite'::REGCLASS AND r.ev_class <> deps.oid
+         )
+         SELECT format('%I.%I', n.nspname, c.relname),
+             c.relkind = 'm',
+             pg_get_viewdef(c.oid, TRUE),
+             array_to_string(c.reloptions, ', '),
+             quote_literal(obj_description(c.oid, 'pg_class')),
+             ARRAY(
+                 SELECT format('GRANT %s ON %I.%I TO %s%s',
+                     a.privilege_type, n.nspname, c.relname,
+                     CASE WHEN a.grantee = 0 THEN 'PUBLIC' ELSE a.grantee::REGROLE::TEXT END,
+                     CASE WHEN a.is_grantable THEN ' WITH GRANT OPTION' ELSE '' END)
+                 FROM aclexplode(c.relacl) a
+                 WHERE a.grantee <> c.relowner
+             ),
+             c.relowner::REGROLE::TEXT,
+             ARRAY(
+                 SELECT i.indexdef
+                 FROM pg_indexes i
+                 WHERE i.schemaname = n.nspname AND i.tablename = c.relname
+                 ORDER BY i.indexname
+             )
+         FROM (SELECT oid, max(depth) AS depth FROM deps GROUP BY oid) deps
+         JOIN pg_class c ON c.oid = deps.oid
+         JOIN pg_namespace n ON n.oid = c.relnamespace
+         WHERE c.relkind IN ('v', 'm')
+         ORDER BY deps.depth, c.oid",
+        &[&table],
+    )?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| View {
+            name: row.get(0),
+            materialized: row.get(1),
+            definition: row
+                .get::<_, String>(2)
+                .trim()
+                .trim_end_matches(';')
+                .to_string(),
+            options: row.get::<_, Option<String>>(3).filter(|o| !o.is_empty()),
+            comment: row.get(4),
+            grants: row.get(5),
+            owner: row.get(6),
+            indexes: row.get(7),
+        })
+        .collect())
+}
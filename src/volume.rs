@@ -0,0 +1,106 @@
+//! Quirks of the filesystem holding the migrations directory, e.g. Docker volumes, network shares
+//! and the case-insensitive filesystems of macOS and Windows.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Filesystems whose modification times are unreliable, e.g. cached by the client of a network
+/// share or not propagated through the file sharing of a VM running Docker.
+const UNRELIABLE_MTIME_FS: &[&str] = &[
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "smbfs",
+    "9p",
+    "virtiofs",
+    "vboxsf",
+    "fakeowner",
+    "fuse",
+];
+
+/// Returns the type of the filesystem holding `path` if its modification times cannot be trusted
+/// to change whenever a file changes. Only detected on Linux, where containers run.
+pub(crate) fn unreliable_mtimes(path: &Path) -> Option<String> {
+    let path = fs::canonicalize(path).ok()?;
+    let mounts = fs::read_to_string("/proc/self/mounts").ok()?;
+
+    // The innermost mount containing the path holds it
+    let (_, fs_type) = mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let point = unescape(fields.nth(1)?);
+            let fs_type = fields.next()?;
+            path.starts_with(&point).then_some((point, fs_type))
+        })
+        .max_by_key(|(point, _)| point.components().count())?;
+
+    let base = fs_type.split('.').next().unwrap_or(fs_type);
+    UNRELIABLE_MTIME_FS
+        .contains(&base)
+        .then(|| fs_type.to_string())
+}
+
+/// Decodes the octal escapes of whitespace and backslashes in a mount point of `/proc/self/mounts`.
+fn unescape(point: &str) -> PathBuf {
+    PathBuf::from(
+        point
+            .replace("\\040", " ")
+            .replace("\\011", "\t")
+            .replace("\\012", "\n")
+            .replace("\\134", "\\"),
+    )
+}
+
+/// Whether the filesystem holding the directory at `path` ignores case, detected by looking the
+/// directory up with its case swapped.
+pub(crate) fn case_insensitive(path: &Path) -> bool {
+    let Ok(path) = fs::canonicalize(path) else {
+        return false;
+    };
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name().and_then(|n| n.to_str()))
+    else {
+        return false;
+    };
+
+    let swapped = name
+        .chars()
+        .map(|c| match c.is_ascii_uppercase() {
+            true => c.to_ascii_lowercase(),
+            false => c.to_ascii_uppercase(),
+        })
+        .collect::<String>();
+    if swapped == name {
+        return false;
+    }
+
+    // A directory with the swapped name may exist next to it on case-sensitive filesystems
+    let listed = fs::read_dir(parent)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .any(|entry| entry.file_name() == swapped.as_str())
+        })
+        .unwrap_or(true);
+
+    !listed && parent.join(swapped).is_dir()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescape_decodes_mount_points() {
+        assert_eq!(
+            unescape("/mnt/my\\040share"),
+            PathBuf::from("/mnt/my share")
+        );
+        assert_eq!(
+            unescape("/mnt/a\\134b\\011c"),
+            PathBuf::from("/mnt/a\\b\tc")
+        );
+        assert_eq!(unescape("/srv/app"), PathBuf::from("/srv/app"));
+    }
+}
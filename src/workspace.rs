@@ -0,0 +1,143 @@
+//! Attributing migrations directories to the crates of a Cargo workspace which own them.
+
+use crate::discover::MIGRATIONS_DIR;
+use crate::{debug, info};
+use anyhow::Error;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How many directories below the workspace root manifests of member crates are searched for.
+const MAX_DEPTH: usize = 4;
+
+/// The crate owning a migrations directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Owner {
+    /// The name of the package.
+    pub name: String,
+    /// The `Cargo.toml` of the package.
+    pub manifest: PathBuf,
+}
+
+/// Returns the crate owning the migrations directory at `path`, i.e. the package of the nearest
+/// `Cargo.toml` containing it. Manifests only defining a workspace own nothing.
+pub fn owner(path: &Path) -> Option<Owner> {
+    let path = absolute(path);
+    path.ancestors().find_map(|dir| {
+        let manifest = dir.join("Cargo.toml");
+        let name = package(&manifest)?;
+        Some(Owner { name, manifest })
+    })
+}
+
+/// Returns the migrations directory of the crate `name`. The directory is picked from `paths` if
+/// the config lists any, and is the `migrations` directory of the crate otherwise.
+pub fn locate(name: &str, paths: &[PathBuf], start: &Path) -> anyhow::Result<PathBuf> {
+    let path = match paths.is_empty() {
+        true => crate_dir(name, start)?.join(MIGRATIONS_DIR),
+        false => paths
+            .iter()
+            .find(|path| owner(path).is_some_and(|owner| owner.name == name))
+            .cloned()
+            .ok_or_else(|| {
+                Error::msg(format!(
+                    "None of the configured migrations directories is owned by crate '{name}'"
+                ))
+            })?,
+    };
+
+    if !path.is_dir() {
+        return Err(Error::msg(format!(
+            "Crate '{name}' has no migrations directory at '{}'\nHint: Create it with `migr --crate {name} setup`",
+            path.display()
+        )));
+    }
+
+    info!(
+        "Found migrations of crate {name} at {}",
+        path.display().to_string().purple()
+    );
+
+    Ok(path)
+}
+
+/// Returns the directory of the crate `name` in the workspace containing `start`.
+pub fn crate_dir(name: &str, start: &Path) -> anyhow::Result<PathBuf> {
+    let start = absolute(start);
+    let root = start
+        .ancestors()
+        .find(|dir| workspace(&dir.join("Cargo.toml")))
+        .unwrap_or(&start);
+    debug!("Searching for crate {name} in {}", root.display());
+
+    let mut manifests = vec![];
+    search(root, 0, &mut manifests)?;
+    manifests.sort();
+
+    let mut crates = vec![];
+    for manifest in manifests {
+        let Some(package) = package(&manifest) else {
+            continue;
+        };
+        if package == name {
+            return Ok(manifest.parent().unwrap_or(root).to_path_buf());
+        }
+        crates.push(package);
+    }
+
+    crates.sort();
+    Err(Error::msg(format!(
+        "No crate named '{name}' in the workspace at '{}'\nHint: The workspace contains {}",
+        root.display(),
+        match crates.is_empty() {
+            true => "no crates".to_string(),
+            false => crates.join(", "),
+        }
+    )))
+}
+
+fn search(path: &Path, depth: usize, manifests: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    if depth > MAX_DEPTH {
+        return Ok(());
+    }
+
+    let manifest = path.join("Cargo.toml");
+    if manifest.is_file() {
+        manifests.push(manifest);
+    }
+
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name() else {
+            continue;
+        };
+        if !path.is_dir() || name == "target" || name.as_encoded_bytes().starts_with(b".") {
+            continue;
+        }
+        search(&path, depth + 1, manifests)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the name of the package defined by the manifest, if it defines one.
+fn package(manifest: &Path) -> Option<String> {
+    let manifest = fs::read_to_string(manifest).ok()?;
+    let manifest = toml::from_str::<toml::Table>(&manifest).ok()?;
+    let name = manifest.get("package")?.get("name")?.as_str()?;
+    Some(name.to_string())
+}
+
+/// Whether the manifest defines a workspace.
+fn workspace(manifest: &Path) -> bool {
+    fs::read_to_string(manifest)
+        .ok()
+        .and_then(|manifest| toml::from_str::<toml::Table>(&manifest).ok())
+        .is_some_and(|manifest| manifest.contains_key("workspace"))
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    fs::canonicalize(path)
+        .or_else(|_| env::current_dir().map(|dir| dir.join(path)))
+        .unwrap_or_else(|_| path.to_path_buf())
+}